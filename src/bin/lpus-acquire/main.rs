@@ -0,0 +1,66 @@
+// The driver is only built for Windows, elsewhere main() reports it
+#![cfg_attr(not(windows), allow(unused_imports, dead_code))]
+
+use clap::{App, Arg};
+use std::error::Error;
+use std::path::Path;
+
+#[cfg(windows)]
+use lpus::{
+    driver_session::DriverSession,
+    driver_state::DriverState,
+    memory::acquire::{acquire, AcquireFormat},
+};
+
+#[cfg(windows)]
+fn main() -> Result<(), Box<dyn Error>> {
+    let matches = App::new("Acquire physical memory to disk")
+        .arg(
+            Arg::with_name("output")
+                .help("Image file to write, the metadata goes to <output>.json")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .help("Output format")
+                .possible_values(&["raw", "dump"])
+                .default_value("raw")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let format = match matches.value_of("format") {
+        Some("dump") => AcquireFormat::CrashDump,
+        _ => AcquireFormat::Raw,
+    };
+
+    let session = DriverSession::start(DriverState::new()?)?;
+    println!("NtLoadDriver()   -> 0x{:x}", session.load_status());
+    let driver: &DriverState = &session;
+
+    let result = acquire(
+        driver,
+        Path::new(matches.value_of("output").unwrap()),
+        format,
+    );
+    match &result {
+        Ok(metadata) => println!(
+            "Wrote {} bytes, sha256 {}, {} unreadable ranges",
+            metadata["size"],
+            metadata["sha256"],
+            metadata["errors"].as_array().map_or(0, |e| e.len())
+        ),
+        Err(e) => println!("Acquisition failed: {}", e),
+    }
+
+    println!("NtUnloadDriver() -> 0x{:x}", session.end());
+    result.map(|_| ())
+}
+
+#[cfg(not(windows))]
+fn main() -> Result<(), Box<dyn Error>> {
+    Err("lpus-acquire loads lpus.sys, it only runs on Windows".into())
+}
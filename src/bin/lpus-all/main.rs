@@ -1,39 +1,43 @@
-use serde_json::json;
+use clap::{App, Arg, ArgGroup, ArgMatches};
+use parse_int::parse;
+use serde_json::{json, Value};
 use std::error::Error;
 use std::fs;
+use std::path::Path;
 
 use lpus::{
-    driver_state::DriverState, scan_driver, scan_eprocess, scan_ethread, scan_kernel_module,
-    ssdt_table, traverse_activehead, traverse_handletable, traverse_kiprocesslist,
+    bindings::write_bindings,
+    isf::{parse_isf_file, write_isf},
+    memory::{
+        format::{open_image, ImageFormat},
+        replay::ReplayState,
+        MemoryReader,
+    },
+    overlay::{parse_overlay_file, Overlay},
+    pdb_store::{parse_pdb_file, parse_pdb_for_pe, PdbStore},
+    scan_driver, scan_eprocess, scan_ethread, scan_kernel_module, ssdt_table,
+    symbol_path::SymbolPath, traverse_activehead, traverse_handletable, traverse_kiprocesslist,
     traverse_loadedmodulelist, traverse_unloadeddrivers,
 };
+#[cfg(windows)]
+use lpus::{driver_session::DriverSession, driver_state::DriverState};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut driver = DriverState::new();
-    if !driver.is_supported() {
-        return Err(format!(
-            "Windows version {:?} is not supported",
-            driver.windows_ffi.short_version
-        )
-        .into());
-    }
-    println!("NtLoadDriver()   -> 0x{:x}", driver.startup());
-
-    let eprocess_1 = scan_eprocess(&driver)?;
-    let eprocess_2 = traverse_activehead(&driver)?;
-    let eprocess_3 = traverse_kiprocesslist(&driver)?;
-    let eprocess_4 = traverse_handletable(&driver)?;
-    let ethread = scan_ethread(&driver)?;
-    let drivers = scan_driver(&driver)?;
-    let kernel_module_1 = scan_kernel_module(&driver)?;
-    let kernel_module_2 = traverse_loadedmodulelist(&driver)?;
-    let unloaded_driver = traverse_unloadeddrivers(&driver)?;
-    let ssdt: Vec<String> = ssdt_table(&driver)?
+fn scan_all(driver: &dyn MemoryReader) -> Result<Value, Box<dyn Error>> {
+    let eprocess_1 = scan_eprocess(driver)?;
+    let eprocess_2 = traverse_activehead(driver)?;
+    let eprocess_3 = traverse_kiprocesslist(driver)?;
+    let eprocess_4 = traverse_handletable(driver)?;
+    let ethread = scan_ethread(driver)?;
+    let drivers = scan_driver(driver)?;
+    let kernel_module_1 = scan_kernel_module(driver)?;
+    let kernel_module_2 = traverse_loadedmodulelist(driver)?;
+    let unloaded_driver = traverse_unloadeddrivers(driver)?;
+    let ssdt: Vec<String> = ssdt_table(driver)?
         .into_iter()
         .map(|x| format!("0x{:x}", x))
         .collect();
 
-    let result = json!({
+    Ok(json!({
         "scan_eprocess": eprocess_1,
         "traverse_activehead": eprocess_2,
         "traverse_kiprocesslist": eprocess_3,
@@ -44,10 +48,229 @@ fn main() -> Result<(), Box<dyn Error>> {
         "traverse_loadedmodulelist": kernel_module_2,
         "traverse_unloadeddrivers": unloaded_driver,
         "ssdt_table": ssdt
-    });
+    }))
+}
+
+fn load_pdb(matches: &ArgMatches) -> Result<PdbStore, Box<dyn Error>> {
+    if let Some(pdb) = matches.value_of("pdb") {
+        return parse_pdb_file(Path::new(pdb));
+    }
+    if let Some(isf) = matches.value_of("isf") {
+        return parse_isf_file(Path::new(isf));
+    }
+    // --pe: look up the PDB it names in --symbol-path, _NT_SYMBOL_PATH or msdl
+    let symbol_path = match matches.value_of("symbol-path") {
+        Some(symbol_path) => SymbolPath::parse(symbol_path)?,
+        None => SymbolPath::from_env().unwrap_or_else(SymbolPath::microsoft)?,
+    };
+    parse_pdb_for_pe(Path::new(matches.value_of("pe").unwrap()), &symbol_path)
+}
+
+fn export_isf(matches: &ArgMatches, pdb_store: &PdbStore) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = matches.value_of("export-isf") {
+        write_isf(pdb_store, Path::new(path))?;
+        println!("Symbols written to {}", path);
+    }
+    Ok(())
+}
+
+fn export_header(matches: &ArgMatches, pdb_store: &PdbStore) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = matches.value_of("export-header") {
+        let names: Vec<&str> = matches.values_of("export-types").unwrap().collect();
+        write_bindings(pdb_store, &names, Path::new(path))?;
+        println!("Types written to {}", path);
+    }
+    Ok(())
+}
+
+fn load_overlays(matches: &ArgMatches) -> Result<Vec<Overlay>, Box<dyn Error>> {
+    // In the order given, a later file overrides an earlier one
+    let mut overlays = Vec::new();
+    for path in matches.values_of("overlay").into_iter().flatten() {
+        overlays.extend(parse_overlay_file(Path::new(path))?);
+    }
+    Ok(overlays)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let matches = App::new("Run every scan and traversal")
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .short("i")
+                .help("Scan a memory image instead of the live system: raw, crash dump, hiberfil.sys, LiME, ELF core or VMware .vmem/.vmss/.vmsn")
+                .takes_value(true)
+                .requires("symbols"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .help("Save every IOCTL with the driver to a session file")
+                .takes_value(true)
+                .conflicts_with_all(&["image", "replay"]),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .help("Scan a session saved with --record instead of the live system")
+                .takes_value(true)
+                .requires("symbols")
+                .conflicts_with("image"),
+        )
+        .arg(
+            Arg::with_name("cache-pages")
+                .long("cache-pages")
+                .help("Pages of kernel memory cached between driver reads, 0 to disable")
+                .takes_value(true)
+                .conflicts_with_all(&["image", "replay"]),
+        )
+        .arg(
+            Arg::with_name("pdb")
+                .long("pdb")
+                .help("ntkrnlmp.pdb matching the kernel in the image or session")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pe")
+                .long("pe")
+                .help("ntoskrnl.exe of the system in the image or session, its PDB is looked up")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("symbol-path")
+                .long("symbol-path")
+                .help("Where --pe looks for the PDB, in _NT_SYMBOL_PATH syntax")
+                .takes_value(true)
+                .requires("pe"),
+        )
+        .arg(
+            Arg::with_name("isf")
+                .long("isf")
+                .help("Volatility 3 ISF JSON of the kernel, instead of a PDB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export-isf")
+                .long("export-isf")
+                .help("Write the kernel symbols in use as a Volatility 3 ISF JSON file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("export-header")
+                .long("export-header")
+                .help("Write --export-types as a C header, or as Rust for a .rs file")
+                .takes_value(true)
+                .requires("export-types"),
+        )
+        .arg(
+            Arg::with_name("export-types")
+                .long("export-types")
+                .help("Structs, unions and enums for --export-header, e.g. _EPROCESS,_MMPTE")
+                .takes_value(true)
+                .use_delimiter(true)
+                .requires("export-header"),
+        )
+        .arg(
+            Arg::with_name("overlay")
+                .long("overlay")
+                .help("JSON or TOML file of structs and symbols to add to or override in the PDB")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .group(ArgGroup::with_name("symbols").args(&["pdb", "pe", "isf"]))
+        .arg(
+            Arg::with_name("dtb")
+                .long("dtb")
+                .help("Kernel directory table base, if the low stub is not in the image")
+                .takes_value(true)
+                .requires("kernel-base"),
+        )
+        .arg(
+            Arg::with_name("kernel-base")
+                .long("kernel-base")
+                .help("Virtual address of ntoskrnl.exe, used with --dtb")
+                .takes_value(true)
+                .requires("dtb"),
+        )
+        .get_matches();
+    let overlays = load_overlays(&matches)?;
+
+    if let Some(image) = matches.value_of("image") {
+        let pdb_store = load_pdb(&matches)?;
+        export_isf(&matches, &pdb_store)?;
+        export_header(&matches, &pdb_store)?;
+        let path = Path::new(image);
+        let dtb_and_base = match (matches.value_of("dtb"), matches.value_of("kernel-base")) {
+            (Some(dtb), Some(kernel_base)) => {
+                Some((parse::<u64>(dtb)?, parse::<u64>(kernel_base)?))
+            }
+            _ => None,
+        };
+        println!("Image format: {:?}", ImageFormat::detect(path)?);
+        let mut state = open_image(path, pdb_store, dtb_and_base)?;
+        state.apply_overlays(&overlays)?;
+        println!(
+            "DTB: 0x{:x}, kernel base: {}, Windows version: {:?}",
+            state.get_dtb(),
+            state.get_kernel_base(),
+            state.windows_version()
+        );
 
+        let result = scan_all(&state)?;
+        fs::write("./lpus.json", format!("{:#}", result)).ok();
+        return Ok(());
+    }
+
+    if let Some(session) = matches.value_of("replay") {
+        let pdb_store = load_pdb(&matches)?;
+        export_isf(&matches, &pdb_store)?;
+        export_header(&matches, &pdb_store)?;
+        let mut state = ReplayState::open(Path::new(session), pdb_store)?;
+        state.apply_overlays(&overlays)?;
+        println!(
+            "Replay of build {}, kernel base: {}",
+            state.get_build_number(),
+            state.get_kernel_base()
+        );
+
+        let result = scan_all(&state)?;
+        fs::write("./lpus.json", format!("{:#}", result)).ok();
+        return Ok(());
+    }
+
+    scan_live(&matches, &overlays)
+}
+
+#[cfg(windows)]
+fn scan_live(matches: &ArgMatches, overlays: &[Overlay]) -> Result<(), Box<dyn Error>> {
+    let mut driver = DriverState::new()?;
+    driver.apply_overlays(overlays)?;
+    export_isf(matches, &driver.pdb_store)?;
+    export_header(matches, &driver.pdb_store)?;
+    if let Some(pages) = matches.value_of("cache-pages") {
+        driver.set_cache_capacity(parse::<usize>(pages)?);
+    }
+    let mut session = DriverSession::start(driver)?;
+    println!("NtLoadDriver()   -> 0x{:x}", session.load_status());
+    if let Some(path) = matches.value_of("record") {
+        session.record_session(Path::new(path))?;
+    }
+    let driver: &DriverState = &session;
+
+    let result = scan_all(driver)?;
     fs::write("./lpus.json", format!("{:#}", result)).ok();
+    let stats = driver.cache_stats();
+    println!(
+        "Page cache: {} hits, {} misses, {}/{} pages",
+        stats.hits, stats.misses, stats.pages, stats.capacity
+    );
 
-    println!("NtUnloadDriver() -> 0x{:x}", driver.shutdown());
+    println!("NtUnloadDriver() -> 0x{:x}", session.end());
     Ok(())
 }
+
+#[cfg(not(windows))]
+fn scan_live(_matches: &ArgMatches, _overlays: &[Overlay]) -> Result<(), Box<dyn Error>> {
+    Err("the live system needs lpus.sys on Windows, use --image or --replay".into())
+}
@@ -0,0 +1,75 @@
+use clap::{App, Arg};
+use std::error::Error;
+use std::path::Path;
+
+use lpus::{
+    isf::parse_isf_file,
+    layout_diff::diff_pdb,
+    pdb_store::{parse_pdb_file, parse_pdb_for_pe, PdbStore},
+    symbol_path::SymbolPath,
+};
+
+fn load_pdb(path: &str) -> Result<PdbStore, Box<dyn Error>> {
+    // A PDB, an ISF JSON, or an ntoskrnl.exe whose PDB is looked up
+    let path = Path::new(path);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => parse_isf_file(path),
+        Some("exe") => {
+            let symbol_path = SymbolPath::from_env().unwrap_or_else(SymbolPath::microsoft)?;
+            parse_pdb_for_pe(path, &symbol_path)
+        }
+        _ => parse_pdb_file(path),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let matches = App::new("Compare the struct layouts of two kernel builds")
+        .arg(
+            Arg::with_name("old")
+                .help("ntkrnlmp.pdb, ISF JSON or ntoskrnl.exe of the old build")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("new")
+                .help("ntkrnlmp.pdb, ISF JSON or ntoskrnl.exe of the new build")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("used")
+                .long("used")
+                .help("Only the structs lpus reads a changed member of"),
+        )
+        .arg(
+            Arg::with_name("struct")
+                .long("struct")
+                .short("s")
+                .help("Only these structs")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .get_matches();
+
+    let old = load_pdb(matches.value_of("old").unwrap())?;
+    let new = load_pdb(matches.value_of("new").unwrap())?;
+    let only: Option<Vec<&str>> = matches.values_of("struct").map(|s| s.collect());
+
+    let diffs: Vec<_> = diff_pdb(&old, &new)
+        .into_iter()
+        .filter(|diff| !matches.is_present("used") || diff.used)
+        .filter(|diff| {
+            only.as_ref()
+                .map_or(true, |only| only.contains(&diff.name.as_str()))
+        })
+        .collect();
+    for diff in &diffs {
+        println!("{}", diff);
+    }
+    println!(
+        "{} structs changed, {} of them break what lpus reads (members marked *)",
+        diffs.len(),
+        diffs.iter().filter(|diff| diff.used).count()
+    );
+    Ok(())
+}
@@ -1,13 +1,20 @@
+// The driver is only built for Windows, elsewhere main() reports it
+#![cfg_attr(not(windows), allow(unused_imports, dead_code))]
+
 use clap::{App, Arg};
 use lpus::utils::hex_dump::print_hex_dump;
 use lpus::utils::mask_cast::MaskCast;
-use lpus::{driver_state::DriverState, scan_eprocess};
+#[cfg(windows)]
+use lpus::{driver_state::DriverState, memory::MemoryReader, scan_eprocess};
 use lpus::address::*;
 use lpus::pdb_store::*;
 use lpus::utils::*;
 use std::error::Error;
 use std::mem::{size_of};
+#[cfg(windows)]
+use lpus::driver_session::DriverSession;
 
+#[cfg(windows)]
 fn main() -> Result<(), Box<dyn Error>> {
     // let mut driver = DriverState::new();
     // if !driver.is_supported() {
@@ -54,15 +61,9 @@ fn main() -> Result<(), Box<dyn Error>> {
     // let offset = pdb.get_offset_r("MiGetPteAddress").unwrap();
     // println!("nt!MiGetPteAddress: 0x{:x}", offset);
 
-    let mut driver = DriverState::new();
-    if !driver.is_supported() {
-        return Err(format!(
-            "Windows version {:?} is not supported",
-            driver.windows_ffi.short_version
-        )
-            .into());
-    }
-    println!("NtLoadDriver()   -> 0x{:x}", driver.startup());
+    let session = DriverSession::start(DriverState::new()?)?;
+    println!("NtLoadDriver()   -> 0x{:x}", session.load_status());
+    let driver: &DriverState = &session;
     let ntosbase = driver.get_kernel_base();
     let pte_base = driver.get_pte_base();
     println!("Kernel base: 0x{:x}", ntosbase.address());
@@ -70,4 +71,9 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
     
-}
\ No newline at end of file
+}
+
+#[cfg(not(windows))]
+fn main() -> Result<(), Box<dyn Error>> {
+    Err("test loads lpus.sys, it only runs on Windows".into())
+}
@@ -80,6 +80,25 @@ impl Address {
             self.base + self.offset
         }
     }
+    pub fn try_get<F, E>(&self, resolver: &F) -> Result<u64, E>
+    where
+        F: Fn(u64) -> Result<u64, E>,
+    {
+        // get() with a resolver that can fail, the first failed pointer read is returned
+        match &self.pointer {
+            Some(p) => {
+                let addr = p.try_get(resolver)?;
+                let base = if addr != 0 { resolver(addr)? } else { 0 };
+                if base == 0 {
+                    Ok(0)
+                } else {
+                    Ok(base + self.offset)
+                }
+            }
+            None if self.base == 0 => Ok(0),
+            None => Ok(self.base + self.offset),
+        }
+    }
     pub fn address(&self) -> u64 {
         self.base + self.offset
     }
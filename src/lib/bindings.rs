@@ -0,0 +1,1242 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::Path;
+
+use crate::error::LpusError;
+use crate::pdb_store::{is_kernel_module, split_module, EnumStore, PdbStore, StructStore};
+use crate::type_info::{StructInfo, TypeInfo, POINTER_SIZE};
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// C headers and Rust #[repr(C)] definitions of PDB structs for x64.
+// The PDB only has member offsets, the layout is rebuilt from them: members that
+// overlap go in an anonymous union, gaps are explicit padding, and a struct is
+// packed only if natural alignment cannot give its offsets.
+
+// (pdb PrimitiveKind, C type, Rust type), other primitives are written as bytes
+const PRIMITIVES: [(&str, &str, &str); 24] = [
+    ("Void", "void", "core::ffi::c_void"),
+    ("RChar", "char", "i8"),
+    ("Char", "char", "i8"),
+    ("I8", "int8_t", "i8"),
+    ("UChar", "uint8_t", "u8"),
+    ("U8", "uint8_t", "u8"),
+    ("Bool8", "uint8_t", "u8"),
+    ("WChar", "uint16_t", "u16"),
+    ("RChar16", "uint16_t", "u16"),
+    ("I16", "int16_t", "i16"),
+    ("U16", "uint16_t", "u16"),
+    ("F16", "uint16_t", "u16"),
+    ("Bool16", "uint16_t", "u16"),
+    ("RChar32", "uint32_t", "u32"),
+    ("I32", "int32_t", "i32"),
+    ("HRESULT", "int32_t", "i32"),
+    ("U32", "uint32_t", "u32"),
+    ("Bool32", "uint32_t", "u32"),
+    ("F32", "float", "f32"),
+    ("F32PP", "float", "f32"),
+    ("I64", "int64_t", "i64"),
+    ("U64", "uint64_t", "u64"),
+    ("Bool64", "uint64_t", "u64"),
+    ("F64", "double", "f64"),
+];
+
+// Rust keywords that are still usable as r#name
+const RUST_KEYWORDS: [&str; 46] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop",
+    "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static",
+    "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+    "where", "while",
+];
+
+fn c_primitive(kind: &str) -> Option<&'static str> {
+    PRIMITIVES.iter().find(|p| p.0 == kind).map(|p| p.1)
+}
+
+fn rust_primitive(kind: &str) -> Option<&'static str> {
+    PRIMITIVES.iter().find(|p| p.0 == kind).map(|p| p.2)
+}
+
+fn c_bitfield_type(unit: &TypeInfo) -> String {
+    // The integer a bitfield is declared with, signed if the PDB has it signed
+    match unit {
+        TypeInfo::Primitive { name, .. } => match rust_primitive(name) {
+            Some(rust) if rust.starts_with('i') || rust.starts_with('u') => {
+                c_primitive(name).unwrap_or_default().to_string()
+            }
+            _ => unsigned_type(unit.size()).0.to_string(),
+        },
+        TypeInfo::Enum { underlying, .. } => c_bitfield_type(underlying),
+        _ => unsigned_type(unit.size()).0.to_string(),
+    }
+}
+
+fn unsigned_type(size: u64) -> (&'static str, &'static str) {
+    // The C and Rust integer of a bitfield storage unit
+    match size {
+        1 => ("uint8_t", "u8"),
+        2 => ("uint16_t", "u16"),
+        4 => ("uint32_t", "u32"),
+        _ => ("uint64_t", "u64"),
+    }
+}
+
+fn ident(name: &str) -> String {
+    // _MMPFN::<unnamed-type-u4> -> _MMPFN___unnamed_type_u4_
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+fn rust_ident(name: &str) -> String {
+    let ident = ident(name);
+    match ident.as_str() {
+        "self" | "Self" | "super" | "crate" => format!("{}_", ident),
+        i if RUST_KEYWORDS.contains(&i) => format!("r#{}", ident),
+        _ => ident,
+    }
+}
+
+fn round_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+// A member at its offset in the record
+#[derive(Clone, Copy)]
+struct Field<'a> {
+    name: &'a str,
+    typ: &'a TypeInfo,
+    offset: u64,
+}
+
+impl Field<'_> {
+    fn end(&self) -> u64 {
+        self.offset + self.typ.size()
+    }
+
+    fn overlaps(&self, other: &Field) -> bool {
+        // Bitfields of one storage unit overlap by their bits, anything else by its bytes
+        match (self.typ.bitfield(), other.typ.bitfield()) {
+            (Some((p1, l1)), Some((p2, l2)))
+                if self.offset == other.offset && self.typ.size() == other.typ.size() =>
+            {
+                p1 < p2 + l2 && p2 < p1 + l1
+            }
+            _ => self.offset < other.end() && other.offset < self.end(),
+        }
+    }
+}
+
+enum Node<'a> {
+    Field(Field<'a>),
+    // members that overlap, split in alternatives that do not
+    Union {
+        offset: u64,
+        size: u64,
+        alternatives: Vec<Vec<Field<'a>>>,
+    },
+}
+
+// What a record is written as, in order
+enum Slot<'a> {
+    Member(Field<'a>),
+    Padding {
+        offset: u64,
+        size: u64,
+    },
+    // bitfields sharing the storage unit at `offset`
+    Bits {
+        offset: u64,
+        unit: &'a TypeInfo,
+        fields: Vec<Field<'a>>,
+    },
+    // an anonymous union, each alternative a member or an anonymous struct
+    Union {
+        offset: u64,
+        size: u64,
+        alternatives: Vec<Vec<Slot<'a>>>,
+    },
+}
+
+impl Slot<'_> {
+    fn offset(&self) -> u64 {
+        match self {
+            Slot::Member(field) => field.offset,
+            Slot::Padding { offset, .. }
+            | Slot::Bits { offset, .. }
+            | Slot::Union { offset, .. } => *offset,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        match self {
+            Slot::Member(field) => field.end(),
+            Slot::Padding { offset, size } | Slot::Union { offset, size, .. } => offset + size,
+            Slot::Bits { offset, unit, .. } => offset + unit.size(),
+        }
+    }
+}
+
+fn slots_end(slots: &[Slot], start: u64) -> u64 {
+    slots.last().map_or(start, |slot| slot.end())
+}
+
+fn member_offsets<'a>(slots: &[Slot<'a>], offsets: &mut Vec<(&'a str, u64)>) {
+    // The named members at any depth, offsetof() reaches into anonymous unions
+    for slot in slots {
+        match slot {
+            Slot::Member(field) => offsets.push((field.name, field.offset)),
+            Slot::Union { alternatives, .. } => {
+                for alternative in alternatives {
+                    member_offsets(alternative, offsets);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn partition<'a>(fields: &[Field<'a>]) -> Vec<Vec<Field<'a>>> {
+    // Each member goes in the first alternative it does not overlap
+    let mut alternatives: Vec<Vec<Field>> = Vec::new();
+    for field in fields {
+        match alternatives
+            .iter_mut()
+            .find(|alternative| !alternative.iter().any(|f| f.overlaps(field)))
+        {
+            Some(alternative) => alternative.push(*field),
+            None => alternatives.push(vec![*field]),
+        }
+    }
+    alternatives
+}
+
+fn group<'a>(fields: &[Field<'a>]) -> Vec<Node<'a>> {
+    // `fields` sorted by offset, a run of overlapping members becomes a union
+    let mut nodes = Vec::new();
+    let mut i = 0;
+    while i < fields.len() {
+        let mut end = fields[i].end();
+        let mut j = i + 1;
+        while j < fields.len() && fields[j].offset < end {
+            end = end.max(fields[j].end());
+            j += 1;
+        }
+        let mut alternatives = partition(&fields[i..j]);
+        if alternatives.len() == 1 {
+            nodes.extend(alternatives.pop().unwrap().into_iter().map(Node::Field));
+        } else {
+            nodes.push(Node::Union {
+                offset: fields[i].offset,
+                size: end - fields[i].offset,
+                alternatives,
+            });
+        }
+        i = j;
+    }
+    nodes
+}
+
+fn slots<'a>(fields: &[Field<'a>], start: u64, end: Option<u64>) -> Vec<Slot<'a>> {
+    // The members from `start`, the gaps and the tail up to `end` as padding
+    let mut slots: Vec<Slot> = Vec::new();
+    let mut cursor = start;
+    for node in group(fields) {
+        let slot = match node {
+            Node::Field(field) => match field.typ {
+                TypeInfo::Bitfield { underlying, .. } => {
+                    if let Some(Slot::Bits {
+                        offset,
+                        unit,
+                        fields,
+                    }) = slots.last_mut()
+                    {
+                        if *offset == field.offset && unit.size() == underlying.size() {
+                            fields.push(field);
+                            continue;
+                        }
+                    }
+                    Slot::Bits {
+                        offset: field.offset,
+                        unit: underlying,
+                        fields: vec![field],
+                    }
+                }
+                _ => Slot::Member(field),
+            },
+            Node::Union {
+                offset,
+                size,
+                alternatives,
+            } => Slot::Union {
+                offset,
+                size,
+                alternatives: alternatives
+                    .iter()
+                    .map(|alternative| self::slots(alternative, offset, None))
+                    .collect(),
+            },
+        };
+        if slot.offset() > cursor {
+            slots.push(Slot::Padding {
+                offset: cursor,
+                size: slot.offset() - cursor,
+            });
+        }
+        cursor = slot.end();
+        slots.push(slot);
+    }
+    match end {
+        Some(end) if end > cursor => slots.push(Slot::Padding {
+            offset: cursor,
+            size: end - cursor,
+        }),
+        _ => {}
+    }
+    slots
+}
+
+struct Layout<'a> {
+    info: &'a StructInfo,
+    // a union record is one Slot::Union
+    slots: Vec<Slot<'a>>,
+    packed: bool,
+    align: u64,
+}
+
+struct Generator<'a> {
+    structs: &'a StructStore,
+    enums: &'a EnumStore,
+    // records to define, dependencies first
+    records: Vec<&'a str>,
+    layouts: HashMap<&'a str, Layout<'a>>,
+    visiting: HashSet<&'a str>,
+    enum_names: Vec<&'a str>,
+    // records behind a pointer: (name, is_union)
+    pointed: Vec<(&'a str, bool)>,
+    names: Vec<String>,
+    guid: String,
+    age: u32,
+}
+
+impl<'a> Generator<'a> {
+    fn new(pdb_store: &'a PdbStore, names: &[&str]) -> BoxResult<Self> {
+        // All types of one module, "_EPROCESS" or "tcpip!_TCB"
+        let module = names.first().and_then(|name| split_module(name).0);
+        let module = module.filter(|module| !is_kernel_module(module));
+        for name in names {
+            let other = split_module(name)
+                .0
+                .filter(|module| !is_kernel_module(module));
+            if other.map(str::to_lowercase) != module.map(str::to_lowercase) {
+                return Err("Types are generated for one module at a time".into());
+            }
+        }
+        let (_, structs) = pdb_store.tables(module)?;
+        let enums = pdb_store.enum_table(module)?;
+        let (guid, age) = match module {
+            Some(module) => {
+                let symbols = &pdb_store.modules[&module.to_lowercase()];
+                (symbols.guid.clone(), symbols.age)
+            }
+            None => (pdb_store.guid.clone(), pdb_store.age),
+        };
+        let mut generator = Generator {
+            structs,
+            enums,
+            records: Vec::new(),
+            layouts: HashMap::new(),
+            visiting: HashSet::new(),
+            enum_names: Vec::new(),
+            pointed: Vec::new(),
+            names: names.iter().map(|name| name.to_string()).collect(),
+            guid,
+            age,
+        };
+        for name in names {
+            let (_, bare_name) = split_module(name);
+            if let Some((key, _)) = structs.get_key_value(bare_name) {
+                generator.visit_record(key);
+            } else if let Some((key, _)) = enums.get_key_value(bare_name) {
+                generator.add_enum(key);
+            } else {
+                return Err(LpusError::StructNotFound(name.to_string()).into());
+            }
+        }
+        Ok(generator)
+    }
+
+    fn visit_record(&mut self, name: &'a str) {
+        // Records a member holds by value are defined before it
+        if self.layouts.contains_key(name) || !self.visiting.insert(name) {
+            return;
+        }
+        let info = match self.structs.get(name) {
+            Some(info) => info,
+            None => return,
+        };
+        for (_, member_type, _) in info.sorted_members() {
+            self.visit_type(member_type);
+        }
+        let layout = self.layout(info);
+        self.layouts.insert(name, layout);
+        self.records.push(name);
+    }
+
+    fn visit_type(&mut self, t: &'a TypeInfo) {
+        match t {
+            TypeInfo::Struct { name, .. } | TypeInfo::Union { name, .. } => self.visit_record(name),
+            TypeInfo::Array { element, .. } => self.visit_type(element),
+            TypeInfo::Bitfield { underlying, .. } => self.visit_type(underlying),
+            TypeInfo::Enum { name, .. } => self.add_enum(name),
+            TypeInfo::Pointer(pointee) => self.visit_pointee(pointee),
+            _ => {}
+        }
+    }
+
+    fn visit_pointee(&mut self, t: &'a TypeInfo) {
+        match t {
+            TypeInfo::Struct { name, .. } => self.add_pointed(name, false),
+            TypeInfo::Union { name, .. } => self.add_pointed(name, true),
+            TypeInfo::Pointer(pointee)
+            | TypeInfo::Array {
+                element: pointee, ..
+            } => self.visit_pointee(pointee),
+            TypeInfo::Enum { name, .. } => self.add_enum(name),
+            _ => {}
+        }
+    }
+
+    fn add_pointed(&mut self, name: &'a str, is_union: bool) {
+        if !self.pointed.iter().any(|(pointed, _)| *pointed == name) {
+            self.pointed.push((name, is_union));
+        }
+    }
+
+    fn add_enum(&mut self, name: &'a str) {
+        if self.enums.contains_key(name) && !self.enum_names.contains(&name) {
+            self.enum_names.push(name);
+        }
+    }
+
+    fn layout(&self, info: &'a StructInfo) -> Layout<'a> {
+        // Members without a size (a zero length array, a method) take no space
+        let fields: Vec<Field> = info
+            .sorted_members()
+            .into_iter()
+            .filter(|(_, t, _)| t.size() > 0)
+            .map(|(name, typ, offset)| Field { name, typ, offset })
+            .collect();
+        let slots = if info.is_union {
+            let mut alternatives: Vec<Vec<Slot>> = partition(&fields)
+                .iter()
+                .map(|alternative| slots(alternative, 0, None))
+                .collect();
+            if alternatives.iter().all(|a| slots_end(a, 0) < info.size) {
+                alternatives.push(vec![Slot::Padding {
+                    offset: 0,
+                    size: info.size,
+                }]);
+            }
+            vec![Slot::Union {
+                offset: 0,
+                size: info.size,
+                alternatives,
+            }]
+        } else {
+            slots(&fields, 0, Some(info.size))
+        };
+        match self.slots_align(&slots) {
+            Some(align) if info.size.is_multiple_of(align) => Layout {
+                info,
+                slots,
+                packed: false,
+                align,
+            },
+            _ => Layout {
+                info,
+                slots,
+                packed: true,
+                align: 1,
+            },
+        }
+    }
+
+    fn align_of(&self, t: &TypeInfo) -> u64 {
+        // As the generated type is aligned, a packed record and bytes have 1
+        match t {
+            TypeInfo::Primitive { name, size } => match c_primitive(name) {
+                Some(_) => (*size).max(1),
+                None => 1,
+            },
+            TypeInfo::Pointer(_) => POINTER_SIZE,
+            TypeInfo::Array { element, .. } => self.align_of(element),
+            TypeInfo::Bitfield { underlying, .. } | TypeInfo::Enum { underlying, .. } => {
+                self.align_of(underlying)
+            }
+            TypeInfo::Struct { name, .. } | TypeInfo::Union { name, .. } => self
+                .layouts
+                .get(name.as_str())
+                .map_or(1, |layout| layout.align),
+            _ => 1,
+        }
+    }
+
+    fn slots_align(&self, slots: &[Slot]) -> Option<u64> {
+        // The alignment of these slots in a struct, None if natural alignment would
+        // move one of them or make a union bigger
+        let mut align = 1;
+        for slot in slots {
+            let slot_align = match slot {
+                Slot::Member(field) => self.align_of(field.typ),
+                Slot::Padding { .. } => 1,
+                Slot::Bits { unit, .. } => self.align_of(unit),
+                Slot::Union {
+                    offset,
+                    size,
+                    alternatives,
+                } => {
+                    let mut union_align = 1;
+                    for alternative in alternatives {
+                        let alternative_align = self.slots_align(alternative)?;
+                        let alternative_size = slots_end(alternative, *offset) - offset;
+                        if round_up(alternative_size, alternative_align) > *size {
+                            return None;
+                        }
+                        union_align = union_align.max(alternative_align);
+                    }
+                    if size % union_align != 0 {
+                        return None;
+                    }
+                    union_align
+                }
+            };
+            if slot.offset() % slot_align != 0 {
+                return None;
+            }
+            align = align.max(slot_align);
+        }
+        Some(align)
+    }
+
+    fn header(&self, out: &mut String) -> fmt::Result {
+        writeln!(
+            out,
+            "// {} from the PDB {}{:X}, generated by lpus",
+            self.names.join(", "),
+            self.guid,
+            self.age
+        )?;
+        writeln!(out, "// x64 layouts, the sizes are checked at compile time")
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.structs.contains_key(name)
+    }
+
+    // C
+
+    fn c_type(&self, t: &TypeInfo) -> (String, String) {
+        // (type, array dimensions) for `type name[dimensions]`
+        let bytes = |size: u64| ("uint8_t".to_string(), format!("[0x{:x}]", size));
+        match t {
+            TypeInfo::Primitive { name, size } => match c_primitive(name) {
+                Some(c) => (c.to_string(), String::new()),
+                None => bytes(*size),
+            },
+            TypeInfo::Pointer(pointee) => (self.c_pointer(pointee), String::new()),
+            TypeInfo::Array { element, count } => {
+                let (element, dimensions) = self.c_type(element);
+                (element, format!("[{}]{}", count, dimensions))
+            }
+            TypeInfo::Struct { name, .. } if self.is_defined(name) => {
+                (format!("struct {}", ident(name)), String::new())
+            }
+            TypeInfo::Union { name, .. } if self.is_defined(name) => {
+                (format!("union {}", ident(name)), String::new())
+            }
+            TypeInfo::Bitfield { underlying, .. } | TypeInfo::Enum { underlying, .. } => {
+                self.c_type(underlying)
+            }
+            _ => bytes(t.size()),
+        }
+    }
+
+    fn c_pointer(&self, pointee: &TypeInfo) -> String {
+        match pointee {
+            TypeInfo::Primitive { name, .. } => {
+                format!("{}*", c_primitive(name).unwrap_or("uint8_t"))
+            }
+            TypeInfo::Pointer(pointee) => format!("{}*", self.c_pointer(pointee)),
+            // a pointer to an array is written as a pointer to its first element
+            TypeInfo::Array { element, .. } => self.c_pointer(element),
+            TypeInfo::Struct { name, .. } => format!("struct {}*", ident(name)),
+            TypeInfo::Union { name, .. } => format!("union {}*", ident(name)),
+            TypeInfo::Enum { underlying, .. } => self.c_pointer(underlying),
+            _ => "void*".to_string(),
+        }
+    }
+
+    fn c_enum_comment(&self, t: &TypeInfo) -> String {
+        match t {
+            TypeInfo::Enum { name, .. } => format!(" enum {}", ident(name)),
+            TypeInfo::Array { element, .. } => self.c_enum_comment(element),
+            _ => String::new(),
+        }
+    }
+
+    fn c_slot(&self, out: &mut String, slot: &Slot, depth: usize, pads: &mut usize) -> fmt::Result {
+        let indent = "    ".repeat(depth);
+        match slot {
+            Slot::Member(field) => {
+                let (t, dimensions) = self.c_type(field.typ);
+                writeln!(
+                    out,
+                    "{}{} {}{}; // +0x{:x}{}",
+                    indent,
+                    t,
+                    field.name,
+                    dimensions,
+                    field.offset,
+                    self.c_enum_comment(field.typ)
+                )
+            }
+            Slot::Padding { offset, size } => {
+                *pads += 1;
+                writeln!(
+                    out,
+                    "{}uint8_t _padding{}[0x{:x}]; // +0x{:x}",
+                    indent,
+                    *pads - 1,
+                    size,
+                    offset
+                )
+            }
+            Slot::Bits { unit, fields, .. } => {
+                let t = c_bitfield_type(unit);
+                let mut bit = 0;
+                for field in fields {
+                    let (position, length) = field.typ.bitfield().unwrap_or_default();
+                    if position > bit {
+                        writeln!(out, "{}{} : {};", indent, t, position - bit)?;
+                    }
+                    writeln!(
+                        out,
+                        "{}{} {} : {}; // +0x{:x} Pos {}",
+                        indent, t, field.name, length, field.offset, position
+                    )?;
+                    bit = position + length;
+                }
+                // the rest of the unit, a following bitfield starts a new one
+                if bit < unit.size() * 8 {
+                    writeln!(out, "{}{} : {};", indent, t, unit.size() * 8 - bit)?;
+                }
+                Ok(())
+            }
+            Slot::Union { alternatives, .. } => {
+                writeln!(out, "{}union {{", indent)?;
+                self.c_alternatives(out, alternatives, depth + 1, pads)?;
+                writeln!(out, "{}}};", indent)
+            }
+        }
+    }
+
+    fn c_alternatives(
+        &self,
+        out: &mut String,
+        alternatives: &[Vec<Slot>],
+        depth: usize,
+        pads: &mut usize,
+    ) -> fmt::Result {
+        // A lone member at the start of the union is in it, anything else in a struct
+        for alternative in alternatives {
+            match alternative.as_slice() {
+                [slot @ Slot::Member(_)] | [slot @ Slot::Padding { .. }] => {
+                    self.c_slot(out, slot, depth, pads)?
+                }
+                slots => {
+                    writeln!(out, "{}struct {{", "    ".repeat(depth))?;
+                    for slot in slots {
+                        self.c_slot(out, slot, depth + 1, pads)?;
+                    }
+                    writeln!(out, "{}}};", "    ".repeat(depth))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn c_record(&self, out: &mut String, name: &str, layout: &Layout) -> fmt::Result {
+        let kind = if layout.info.is_union {
+            "union"
+        } else {
+            "struct"
+        };
+        let name = ident(name);
+        writeln!(out, "// 0x{:x} bytes", layout.info.size)?;
+        if layout.packed {
+            writeln!(out, "#pragma pack(push, 1)")?;
+        }
+        writeln!(out, "{} {} {{", kind, name)?;
+        let mut pads = 0;
+        match layout.slots.as_slice() {
+            [Slot::Union { alternatives, .. }] if layout.info.is_union => {
+                self.c_alternatives(out, alternatives, 1, &mut pads)?
+            }
+            slots => {
+                for slot in slots {
+                    self.c_slot(out, slot, 1, &mut pads)?;
+                }
+            }
+        }
+        writeln!(out, "}};")?;
+        if layout.packed {
+            writeln!(out, "#pragma pack(pop)")?;
+        }
+        writeln!(
+            out,
+            "LPUS_STATIC_ASSERT(sizeof({} {}) == 0x{:x}, \"{}\");",
+            kind, name, layout.info.size, name
+        )?;
+        let mut offsets = Vec::new();
+        member_offsets(&layout.slots, &mut offsets);
+        for (member, offset) in offsets {
+            writeln!(
+                out,
+                "LPUS_STATIC_ASSERT(offsetof({} {}, {}) == 0x{:x}, \"{}.{}\");",
+                kind, name, member, offset, name, member
+            )?;
+        }
+        writeln!(out)
+    }
+
+    fn c_header(&self) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        self.header(&mut out)?;
+        writeln!(out, "#pragma once\n")?;
+        writeln!(out, "#include <stddef.h>\n#include <stdint.h>\n")?;
+        writeln!(out, "#ifdef __cplusplus")?;
+        writeln!(out, "#define LPUS_STATIC_ASSERT(e, m) static_assert(e, m)")?;
+        writeln!(out, "#else")?;
+        writeln!(out, "#define LPUS_STATIC_ASSERT(e, m) _Static_assert(e, m)")?;
+        writeln!(out, "#endif\n")?;
+
+        let mut declared = HashSet::new();
+        for name in &self.records {
+            let is_union = self.layouts[name].info.is_union;
+            self.c_declaration(&mut out, name, is_union, &mut declared)?;
+        }
+        for (name, is_union) in &self.pointed {
+            self.c_declaration(&mut out, name, *is_union, &mut declared)?;
+        }
+        writeln!(out)?;
+
+        for name in &self.enum_names {
+            let info = &self.enums[*name];
+            if info.values.is_empty() {
+                continue;
+            }
+            writeln!(out, "enum {} {{", ident(name))?;
+            for (enumerator, value) in &info.values {
+                writeln!(out, "    {} = {},", ident(enumerator), value)?;
+            }
+            writeln!(out, "}};\n")?;
+        }
+
+        for name in &self.records {
+            self.c_record(&mut out, name, &self.layouts[name])?;
+        }
+        Ok(out)
+    }
+
+    fn c_declaration(
+        &self,
+        out: &mut String,
+        name: &'a str,
+        is_union: bool,
+        declared: &mut HashSet<&'a str>,
+    ) -> fmt::Result {
+        if !declared.insert(name) {
+            return Ok(());
+        }
+        let kind = if is_union { "union" } else { "struct" };
+        writeln!(out, "{} {};", kind, ident(name))
+    }
+
+    // Rust
+
+    fn rust_type(&self, t: &TypeInfo) -> String {
+        match t {
+            TypeInfo::Primitive { name, size } => match rust_primitive(name) {
+                Some(rust) => rust.to_string(),
+                None => format!("[u8; 0x{:x}]", size),
+            },
+            TypeInfo::Pointer(pointee) => format!("*mut {}", self.rust_pointee(pointee)),
+            TypeInfo::Array { element, count } => {
+                format!("[{}; {}]", self.rust_type(element), count)
+            }
+            TypeInfo::Struct { name, .. } | TypeInfo::Union { name, .. }
+                if self.is_defined(name) =>
+            {
+                ident(name)
+            }
+            TypeInfo::Enum { name, .. } if self.enums.contains_key(name) => ident(name),
+            TypeInfo::Bitfield { underlying, .. } | TypeInfo::Enum { underlying, .. } => {
+                self.rust_type(underlying)
+            }
+            _ => format!("[u8; 0x{:x}]", t.size()),
+        }
+    }
+
+    fn rust_pointee(&self, pointee: &TypeInfo) -> String {
+        // A record that is not defined is declared opaque, a function is c_void
+        match pointee {
+            TypeInfo::Struct { name, .. } | TypeInfo::Union { name, .. } => ident(name),
+            TypeInfo::Function { .. } | TypeInfo::Unknown(_) => "core::ffi::c_void".to_string(),
+            TypeInfo::Pointer(pointee) => format!("*mut {}", self.rust_pointee(pointee)),
+            TypeInfo::Array { element, count } => {
+                format!("[{}; {}]", self.rust_pointee(element), count)
+            }
+            _ => self.rust_type(pointee),
+        }
+    }
+
+    fn rust_slots(&self, record: &mut RustRecord, slots: &[Slot]) -> Result<RustBody, fmt::Error> {
+        let mut body = RustBody::default();
+        for slot in slots {
+            self.rust_slot(record, slot, &mut body)?;
+        }
+        Ok(body)
+    }
+
+    fn rust_slot(&self, record: &mut RustRecord, slot: &Slot, body: &mut RustBody) -> fmt::Result {
+        match slot {
+            Slot::Member(field) => writeln!(
+                body.fields,
+                "    pub {}: {}, // +0x{:x}",
+                rust_ident(field.name),
+                self.rust_type(field.typ),
+                field.offset
+            ),
+            Slot::Padding { offset, size } => {
+                record.padding += 1;
+                writeln!(
+                    body.fields,
+                    "    _padding{}: [u8; 0x{:x}], // +0x{:x}",
+                    record.padding - 1,
+                    size,
+                    offset
+                )
+            }
+            Slot::Bits {
+                offset,
+                unit,
+                fields,
+            } => {
+                // Rust has no bitfields, the unit is a field with accessors for the bits
+                let storage = format!("_bitfield{}", record.bitfields);
+                let t = unsigned_type(unit.size()).1;
+                record.bitfields += 1;
+                writeln!(
+                    body.fields,
+                    "    pub {}: {}, // +0x{:x}",
+                    storage, t, offset
+                )?;
+                for field in fields {
+                    let (position, length) = field.typ.bitfield().unwrap_or_default();
+                    let mask = if length >= 64 {
+                        u64::MAX
+                    } else {
+                        (1 << length) - 1
+                    };
+                    writeln!(body.methods, "    // Pos {}, {} Bit", position, length)?;
+                    writeln!(
+                        body.methods,
+                        "    pub fn {}(&self) -> {} {{\n        (self.{} >> {}) & 0x{:x}\n    }}",
+                        rust_ident(field.name),
+                        t,
+                        storage,
+                        position,
+                        mask
+                    )?;
+                    writeln!(
+                        body.methods,
+                        "    pub fn set_{}(&mut self, value: {}) {{\n        \
+                         self.{} = (self.{} & !(0x{:x} << {})) | ((value & 0x{:x}) << {});\n    }}",
+                        ident(field.name),
+                        t,
+                        storage,
+                        storage,
+                        mask,
+                        position,
+                        mask,
+                        position
+                    )?;
+                }
+                Ok(())
+            }
+            Slot::Union {
+                offset,
+                alternatives,
+                ..
+            } => {
+                let field = format!("u{}", record.unions);
+                let name = format!("{}_{}", record.name, field);
+                record.unions += 1;
+                self.rust_union(record, &name, alternatives)?;
+                writeln!(
+                    body.fields,
+                    "    pub {}: {}, // +0x{:x}",
+                    field, name, offset
+                )
+            }
+        }
+    }
+
+    fn rust_union(
+        &self,
+        record: &mut RustRecord,
+        name: &str,
+        alternatives: &[Vec<Slot>],
+    ) -> fmt::Result {
+        // Anonymous unions and structs are named after the record, _KTHREAD_u0_s1
+        let mut body = RustBody::default();
+        let mut structs = 0;
+        for alternative in alternatives {
+            match alternative.as_slice() {
+                [slot @ Slot::Member(_)] | [slot @ Slot::Padding { .. }] => {
+                    self.rust_slot(record, slot, &mut body)?
+                }
+                slots => {
+                    let field = format!("s{}", structs);
+                    let struct_name = format!("{}_{}", name, field);
+                    structs += 1;
+                    let struct_body = self.rust_slots(record, slots)?;
+                    record.define("struct", &struct_name, struct_body)?;
+                    writeln!(
+                        body.fields,
+                        "    pub {}: {}, // +0x{:x}",
+                        field,
+                        struct_name,
+                        slots[0].offset()
+                    )?;
+                }
+            }
+        }
+        record.define("union", name, body)
+    }
+
+    fn rust_record(&self, out: &mut String, name: &str, layout: &Layout) -> fmt::Result {
+        let mut record = RustRecord {
+            name: ident(name),
+            size: layout.info.size,
+            packed: layout.packed,
+            unions: 0,
+            padding: 0,
+            bitfields: 0,
+            definitions: String::new(),
+        };
+        let record_name = record.name.clone();
+        match layout.slots.as_slice() {
+            [Slot::Union { alternatives, .. }] if layout.info.is_union => {
+                self.rust_union(&mut record, &record_name, alternatives)?
+            }
+            slots => {
+                let body = self.rust_slots(&mut record, slots)?;
+                record.define("struct", &record_name, body)?;
+            }
+        }
+        out.push_str(&record.definitions);
+        writeln!(
+            out,
+            "const _: [(); 0x{:x}] = [(); core::mem::size_of::<{}>()];\n",
+            layout.info.size, record_name
+        )
+    }
+
+    fn rust_bindings(&self) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        self.header(&mut out)?;
+        writeln!(
+            out,
+            "#![allow(non_camel_case_types, non_snake_case, non_upper_case_globals, dead_code)]\n"
+        )?;
+
+        for name in &self.enum_names {
+            let info = &self.enums[*name];
+            let t = self.rust_type(&info.underlying);
+            let bits = info.size() * 8;
+            writeln!(out, "pub type {} = {};", ident(name), t)?;
+            for (enumerator, value) in &info.values {
+                // an unsigned enum gets the bits of a negative enumerator
+                let value = if t.starts_with('i') || bits == 0 || bits >= 64 {
+                    value.to_string()
+                } else {
+                    format!("0x{:x}", *value as u64 & ((1 << bits) - 1))
+                };
+                writeln!(
+                    out,
+                    "pub const {}_{}: {} = {};",
+                    ident(name),
+                    ident(enumerator),
+                    ident(name),
+                    value
+                )?;
+            }
+            writeln!(out)?;
+        }
+
+        for (name, _) in &self.pointed {
+            if !self.layouts.contains_key(name) {
+                writeln!(out, "#[repr(C)]")?;
+                writeln!(
+                    out,
+                    "pub struct {} {{\n    _opaque: [u8; 0],\n}}\n",
+                    ident(name)
+                )?;
+            }
+        }
+
+        for name in &self.records {
+            self.rust_record(&mut out, name, &self.layouts[name])?;
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Default)]
+struct RustBody {
+    fields: String,
+    methods: String,
+}
+
+// A record with the anonymous types it needs, written out as they are completed
+struct RustRecord {
+    name: String,
+    size: u64,
+    packed: bool,
+    unions: usize,
+    padding: usize,
+    bitfields: usize,
+    definitions: String,
+}
+
+impl RustRecord {
+    fn define(&mut self, kind: &str, name: &str, body: RustBody) -> fmt::Result {
+        let repr = if self.packed { "C, packed" } else { "C" };
+        let out = &mut self.definitions;
+        if name == self.name {
+            writeln!(out, "// 0x{:x} bytes", self.size)?;
+        }
+        writeln!(out, "#[repr({})]\n#[derive(Clone, Copy)]", repr)?;
+        writeln!(out, "pub {} {} {{\n{}}}", kind, name, body.fields)?;
+        if !body.methods.is_empty() {
+            writeln!(out, "impl {} {{\n{}}}", name, body.methods)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn to_c_header(pdb_store: &PdbStore, names: &[&str]) -> BoxResult<String> {
+    // `names` and the records they hold by value, records behind a pointer are declared
+    Ok(Generator::new(pdb_store, names)?.c_header()?)
+}
+
+pub fn to_rust(pdb_store: &PdbStore, names: &[&str]) -> BoxResult<String> {
+    // As to_c_header, with accessors for the bitfields and opaque pointed-to records
+    Ok(Generator::new(pdb_store, names)?.rust_bindings()?)
+}
+
+pub fn write_bindings(pdb_store: &PdbStore, names: &[&str], path: &Path) -> BoxResult<()> {
+    // Rust for a .rs file, a C header for anything else
+    let content = match path.extension().and_then(|e| e.to_str()) {
+        Some("rs") => to_rust(pdb_store, names)?,
+        _ => to_c_header(pdb_store, names)?,
+    };
+    fs::write(path, content)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isf::parse_isf_file;
+    use crate::type_info::EnumInfo;
+
+    fn store() -> PdbStore {
+        // _SAMPLE: bitfields, members of anonymous unions as the PDB flattens them,
+        // a union by value, an enum and a pointer to a record that is not defined
+        let u32_bits = |position, length| TypeInfo::Bitfield {
+            underlying: Box::new(TypeInfo::primitive("U32")),
+            position,
+            length,
+        };
+        let mut sample = StructInfo::new(0x28, false);
+        sample.add_member("Header", TypeInfo::primitive("U32"), 0);
+        sample.add_member("Enabled", u32_bits(0, 1), 4);
+        sample.add_member("Mode", u32_bits(1, 3), 4);
+        sample.add_member("Value", TypeInfo::primitive("U64"), 8);
+        let bytes = TypeInfo::Array {
+            element: Box::new(TypeInfo::primitive("UChar")),
+            count: 8,
+        };
+        sample.add_member("Bytes", bytes, 8);
+        sample.add_member("Low", TypeInfo::primitive("U32"), 0x10);
+        sample.add_member("High", TypeInfo::primitive("U32"), 0x14);
+        sample.add_member("Whole", TypeInfo::primitive("U64"), 0x10);
+        let number = TypeInfo::Union {
+            name: "_NUMBER".to_string(),
+            size: 8,
+        };
+        sample.add_member("Number", number, 0x18);
+        let state = TypeInfo::Enum {
+            name: "_STATE".to_string(),
+            underlying: Box::new(TypeInfo::primitive("I32")),
+        };
+        sample.add_member("State", state, 0x20);
+        let opaque = TypeInfo::Struct {
+            name: "_OPAQUE".to_string(),
+            size: 0,
+        };
+        sample.add_member("Owner", TypeInfo::pointer_to(opaque), 0x20);
+
+        let mut number = StructInfo::new(8, true);
+        number.add_member("AsU64", TypeInfo::primitive("U64"), 0);
+        number.add_member("AsI32", TypeInfo::primitive("I32"), 0);
+
+        let mut state = EnumInfo::new(TypeInfo::primitive("I32"));
+        state.values.push(("Idle".to_string(), 0));
+        state.values.push(("Busy".to_string(), 1));
+
+        let isf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/win7_isf.json");
+        let mut pdb_store = parse_isf_file(&isf).unwrap();
+        pdb_store.structs = vec![("_SAMPLE", sample), ("_NUMBER", number)]
+            .into_iter()
+            .map(|(name, info)| (name.to_string(), info))
+            .collect();
+        pdb_store.enums = std::iter::once(("_STATE".to_string(), state)).collect();
+        pdb_store
+    }
+
+    #[test]
+    fn c_header() {
+        let header = to_c_header(&store(), &["_SAMPLE"]).unwrap();
+        // the union by value first, the record behind a pointer only declared
+        assert!(header.contains("union _NUMBER;\nstruct _SAMPLE;\nstruct _OPAQUE;\n"));
+        assert!(!header.contains("struct _OPAQUE {"));
+        let (number, sample) = (
+            header.find("union _NUMBER {"),
+            header.find("struct _SAMPLE {"),
+        );
+        assert!(number.unwrap() < sample.unwrap());
+        assert!(header.contains("enum _STATE {\n    Idle = 0,\n    Busy = 1,\n};"));
+        for fragment in [
+            "    uint32_t Enabled : 1; // +0x4 Pos 0\n\
+             \x20   uint32_t Mode : 3; // +0x4 Pos 1\n\
+             \x20   uint32_t : 28;\n",
+            "    union {\n\
+             \x20       uint8_t Bytes[8]; // +0x8\n\
+             \x20       uint64_t Value; // +0x8\n\
+             \x20   };\n",
+            "    union {\n\
+             \x20       struct {\n\
+             \x20           uint32_t Low; // +0x10\n\
+             \x20           uint32_t High; // +0x14\n\
+             \x20       };\n\
+             \x20       uint64_t Whole; // +0x10\n\
+             \x20   };\n",
+            "    union _NUMBER Number; // +0x18\n",
+            "        struct _OPAQUE* Owner; // +0x20\n\
+             \x20       int32_t State; // +0x20 enum _STATE\n",
+        ]
+        .iter()
+        {
+            assert!(header.contains(fragment), "{}", fragment);
+        }
+
+        let asserts: Vec<&str> = header
+            .lines()
+            .filter(|line| line.starts_with("LPUS_STATIC_ASSERT"))
+            .collect();
+        let mut expected = vec![
+            "sizeof(union _NUMBER) == 0x8".to_string(),
+            "offsetof(union _NUMBER, AsI32) == 0x0".to_string(),
+            "offsetof(union _NUMBER, AsU64) == 0x0".to_string(),
+            "sizeof(struct _SAMPLE) == 0x28".to_string(),
+        ];
+        for (member, offset) in [
+            ("Header", 0),
+            ("Bytes", 8),
+            ("Value", 8),
+            ("Low", 0x10),
+            ("High", 0x14),
+            ("Whole", 0x10),
+            ("Number", 0x18),
+            ("Owner", 0x20),
+            ("State", 0x20),
+        ]
+        .iter()
+        {
+            expected.push(format!(
+                "offsetof(struct _SAMPLE, {}) == 0x{:x}",
+                member, offset
+            ));
+        }
+        assert_eq!(asserts.len(), expected.len());
+        for (line, expected) in asserts.iter().zip(expected.iter()) {
+            assert!(
+                line.starts_with(&format!("LPUS_STATIC_ASSERT({}, ", expected)),
+                "{}",
+                line
+            );
+        }
+        // bitfields have no offsetof()
+        assert!(!header.contains("_SAMPLE, Mode"));
+    }
+
+    #[test]
+    fn rust() {
+        let rust = to_rust(&store(), &["_SAMPLE"]).unwrap();
+        assert!(rust.contains("pub type _STATE = i32;\npub const _STATE_Idle: _STATE = 0;\n"));
+        assert!(rust.contains("pub struct _OPAQUE {\n    _opaque: [u8; 0],\n}"));
+        for fragment in [
+            "pub union _NUMBER {\n    pub AsI32: i32, // +0x0\n    pub AsU64: u64, // +0x0\n}",
+            "pub union _SAMPLE_u0 {\n\
+             \x20   pub Bytes: [u8; 8], // +0x8\n\
+             \x20   pub Value: u64, // +0x8\n}",
+            "pub struct _SAMPLE_u1_s0 {\n\
+             \x20   pub Low: u32, // +0x10\n\
+             \x20   pub High: u32, // +0x14\n}",
+            "pub union _SAMPLE_u1 {\n\
+             \x20   pub s0: _SAMPLE_u1_s0, // +0x10\n\
+             \x20   pub Whole: u64, // +0x10\n}",
+            "pub union _SAMPLE_u2 {\n\
+             \x20   pub Owner: *mut _OPAQUE, // +0x20\n\
+             \x20   pub State: _STATE, // +0x20\n}",
+            "pub struct _SAMPLE {\n\
+             \x20   pub Header: u32, // +0x0\n\
+             \x20   pub _bitfield0: u32, // +0x4\n\
+             \x20   pub u0: _SAMPLE_u0, // +0x8\n\
+             \x20   pub u1: _SAMPLE_u1, // +0x10\n\
+             \x20   pub Number: _NUMBER, // +0x18\n\
+             \x20   pub u2: _SAMPLE_u2, // +0x20\n}",
+            "    pub fn Mode(&self) -> u32 {\n        (self._bitfield0 >> 1) & 0x7\n    }",
+            "self._bitfield0 = (self._bitfield0 & !(0x7 << 1)) | ((value & 0x7) << 1);",
+            "const _: [(); 0x8] = [(); core::mem::size_of::<_NUMBER>()];",
+            "const _: [(); 0x28] = [(); core::mem::size_of::<_SAMPLE>()];",
+        ]
+        .iter()
+        {
+            assert!(rust.contains(fragment), "{}", fragment);
+        }
+        assert!(!rust.contains("#[repr(C, packed)]"));
+    }
+}
@@ -7,15 +7,15 @@ use prettytable::{cell, row, Table};
 use parse_int::parse;
 
 use crate::{
-    driver_state::DriverState, scan_driver, scan_eprocess, scan_ethread, scan_kernel_module,
+    memory::MemoryReader, scan_driver, scan_eprocess, scan_ethread, scan_kernel_module,
     ssdt_table, traverse_activehead, traverse_handletable, traverse_kiprocesslist,
-    traverse_loadedmodulelist, traverse_unloadeddrivers,
+    traverse_loadedmodulelist, traverse_unloadeddrivers, Symbolizer,
 };
 
-pub fn ssdt(driver: &DriverState, only_hooked: bool) {
-    let loaded = traverse_loadedmodulelist(&driver).unwrap_or(Vec::new());
-    let ssdt = ssdt_table(&driver).unwrap_or(Vec::new());
-    let ntosbase = driver.get_kernel_base();
+pub fn ssdt(driver: &dyn MemoryReader, only_hooked: bool) {
+    let loaded = traverse_loadedmodulelist(driver).unwrap_or(Vec::new());
+    let ssdt = ssdt_table(driver).unwrap_or(Vec::new());
+    let symbolizer = Symbolizer::new(driver).ok();
 
     for (idx, func) in ssdt.iter().enumerate() {
         let owner = loaded.iter().find_map(|r| {
@@ -37,23 +37,12 @@ pub fn ssdt(driver: &DriverState, only_hooked: bool) {
         });
         if owner == Some("ntoskrnl.exe") {
             if !only_hooked {
-                let offset = func - ntosbase.address();
-                let funcname: String = {
-                    driver
-                        .pdb_store
-                        .symbols
-                        .iter()
-                        .find_map(|(name, o)| {
-                            if o.clone() == offset {
-                                Some(name.clone())
-                            } else {
-                                None
-                            }
-                        })
-                        .unwrap_or("(??)".to_string())
+                let funcname = match &symbolizer {
+                    Some(symbolizer) => symbolizer.symbolize(*func),
+                    None => "nt!(??)".to_string(),
                 };
                 println!("SSDT [{}]\t0x{:x}", idx, func);
-                println!("\towned by nt!{}", funcname);
+                println!("\towned by {}", funcname);
             }
         } else if let Some(owner_) = owner {
             println!("SSDT [{}]\t0x{:x}", idx, func);
@@ -65,7 +54,7 @@ pub fn ssdt(driver: &DriverState, only_hooked: bool) {
     }
 }
 
-pub fn psxview(driver: &DriverState) {
+pub fn psxview(driver: &dyn MemoryReader) {
     fn process_in_list(addr: &str, list: &Vec<Value>) -> bool {
         for r in list.iter() {
             if r["address"].as_str().unwrap() == addr {
@@ -93,11 +82,11 @@ pub fn psxview(driver: &DriverState) {
         false
     }
 
-    let process_scan = scan_eprocess(&driver).unwrap_or(Vec::new());
-    let thread_scan = scan_ethread(&driver).unwrap_or(Vec::new());
-    let activehead = traverse_activehead(&driver).unwrap_or(Vec::new());
-    let kiprocesslist = traverse_kiprocesslist(&driver).unwrap_or(Vec::new());
-    let handletable = traverse_handletable(&driver).unwrap_or(Vec::new());
+    let process_scan = scan_eprocess(driver).unwrap_or(Vec::new());
+    let thread_scan = scan_ethread(driver).unwrap_or(Vec::new());
+    let activehead = traverse_activehead(driver).unwrap_or(Vec::new());
+    let kiprocesslist = traverse_kiprocesslist(driver).unwrap_or(Vec::new());
+    let handletable = traverse_handletable(driver).unwrap_or(Vec::new());
 
     let mut unique_process = HashSet::new();
     for list in [&process_scan, &activehead, &kiprocesslist, &handletable].iter() {
@@ -143,8 +132,8 @@ pub fn psxview(driver: &DriverState) {
 
     table.printstd();
 }
-pub fn modscan(driver: &DriverState) {
-    let dd = scan_kernel_module(&driver).unwrap_or(Vec::new());
+pub fn modscan(driver: &dyn MemoryReader) {
+    let dd = scan_kernel_module(driver).unwrap_or(Vec::new());
     let mut table = Table::new();
     table.add_row(row!["Address", "Base name", "Base", "Size", "File"]);
     for d in &dd {
@@ -158,8 +147,8 @@ pub fn modscan(driver: &DriverState) {
     }
     table.printstd();
 }
-pub fn driverscan(driver: &DriverState) {
-    let dd = scan_driver(&driver).unwrap_or(Vec::new());
+pub fn driverscan(driver: &dyn MemoryReader) {
+    let dd = scan_driver(driver).unwrap_or(Vec::new());
     let mut table = Table::new();
     table.add_row(row!["Address", "Device", "Service key", "Start", "Size"]);
     for d in &dd {
@@ -173,8 +162,8 @@ pub fn driverscan(driver: &DriverState) {
     }
     table.printstd();
 }
-pub fn unloadedmodules(driver: &DriverState) {
-    let modules = traverse_unloadeddrivers(&driver).unwrap_or(Vec::new());
+pub fn unloadedmodules(driver: &dyn MemoryReader) {
+    let modules = traverse_unloadeddrivers(driver).unwrap_or(Vec::new());
     let mut table = Table::new();
     table.add_row(row!["Address", "Driver", "Start", "End", "Time"]);
     for m in &modules {
@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::ops::{Deref, DerefMut};
+use std::process;
+use std::sync::{Mutex, Once};
+
+use winapi::shared::ntdef::NTSTATUS;
+
+use crate::driver_state::DriverState;
+use crate::error::LpusError;
+use crate::windows::ServiceCleanup;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Cleanup for the session currently holding the driver, taken out by whoever gets to it first:
+// the session itself or the Ctrl-C handler
+static PENDING_CLEANUP: Mutex<Option<ServiceCleanup>> = Mutex::new(None);
+static CTRLC_HANDLER: Once = Once::new();
+
+// Exit code of a process killed by Ctrl-C
+const CTRLC_EXIT_CODE: i32 = 130;
+
+fn take_pending_cleanup() -> Option<ServiceCleanup> {
+    match PENDING_CLEANUP.lock() {
+        Ok(mut pending) => pending.take(),
+        Err(poisoned) => poisoned.into_inner().take(),
+    }
+}
+
+fn set_pending_cleanup(cleanup: Option<ServiceCleanup>) {
+    match PENDING_CLEANUP.lock() {
+        Ok(mut pending) => *pending = cleanup,
+        Err(poisoned) => *poisoned.into_inner() = cleanup,
+    }
+}
+
+fn install_ctrlc_handler() {
+    CTRLC_HANDLER.call_once(|| {
+        let installed = ctrlc::set_handler(|| {
+            if let Some(cleanup) = take_pending_cleanup() {
+                println!("NtUnloadDriver() -> 0x{:x}", cleanup.run());
+            }
+            process::exit(CTRLC_EXIT_CODE);
+        });
+        if let Err(e) = installed {
+            println!("Cannot install the Ctrl-C handler: {}", e);
+        }
+    });
+}
+
+// Owns the loaded driver: Services\lpus key, NtLoadDriver and the device handle
+// Everything is undone by end(), on drop (so also on `?` and panics) or on Ctrl-C
+pub struct DriverSession {
+    state: DriverState,
+    load_status: NTSTATUS,
+    ended: bool,
+}
+
+impl DriverSession {
+    pub fn start(mut state: DriverState) -> BoxResult<Self> {
+        if !state.is_supported() {
+            return Err(LpusError::UnsupportedVersion(state.windows_ffi.short_version).into());
+        }
+        install_ctrlc_handler();
+        let load_status = state.startup();
+        // From here on a failure still unloads through drop
+        let session = Self {
+            state,
+            load_status,
+            ended: false,
+        };
+        set_pending_cleanup(Some(session.state.windows_ffi.service_cleanup()));
+        if !session.state.windows_ffi.driver_loaded() {
+            return Err(format!(
+                "Cannot open the lpus device, NtLoadDriver() -> 0x{:x}",
+                load_status
+            )
+            .into());
+        }
+        Ok(session)
+    }
+
+    pub fn load_status(&self) -> NTSTATUS {
+        self.load_status
+    }
+
+    pub fn end(mut self) -> NTSTATUS {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> NTSTATUS {
+        self.ended = true;
+        // The Ctrl-C handler already unloaded, the process is about to exit
+        if take_pending_cleanup().is_none() {
+            return 0;
+        }
+        self.state.shutdown()
+    }
+}
+
+impl Deref for DriverSession {
+    type Target = DriverState;
+
+    fn deref(&self) -> &DriverState {
+        &self.state
+    }
+}
+
+impl DerefMut for DriverSession {
+    fn deref_mut(&mut self) -> &mut DriverState {
+        &mut self.state
+    }
+}
+
+impl Drop for DriverSession {
+    fn drop(&mut self) {
+        if !self.ended {
+            println!("NtUnloadDriver() -> 0x{:x}", self.finish());
+        }
+    }
+}
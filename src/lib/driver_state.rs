@@ -1,28 +1,24 @@
-use std::clone::Clone;
-use std::default::Default;
-use std::error::Error;
 // use std::io::{Error, ErrorKind};
-use std::ffi::c_void;
-use std::mem::{size_of, size_of_val};
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
 
-use winapi::shared::minwindef::DWORD;
 use winapi::shared::ntdef::NTSTATUS;
-use winapi::um::winioctl::{
-    CTL_CODE, FILE_ANY_ACCESS, METHOD_IN_DIRECT, /* METHOD_BUFFERED, */ METHOD_NEITHER,
-    METHOD_OUT_DIRECT,
-};
 
 use crate::address::Address;
 use crate::ioctl_protocol::{
-    DerefAddr, HideProcess, InputData, /* OutputData, */ Nothing, OffsetData, ScanPoolData,
+    ioctl_deref, ioctl_query_u64, ioctl_read_many, ioctl_scan_pool, DriverAction, HideProcess,
+    InputData, /* OutputData, */ Nothing, OffsetData,
 };
+use crate::memory::page_cache::{CacheStats, PageCache, DEFAULT_CACHE_PAGES, PAGE_SIZE};
+use crate::memory::{AddressSpace, MemoryReader};
+use crate::overlay::{apply_overlays, build_default_overlays, Overlay};
 use crate::pdb_store::{parse_pdb, PdbStore};
-use crate::utils::mask_cast::MaskCast;
 use crate::windows::{WindowsFFI, WindowsVersion};
 
-type BoxResult<T> = Result<T, Box<dyn Error>>;
+pub use crate::memory::ScannerSignal;
 
-const SIOCTL_TYPE: DWORD = 40000;
+type BoxResult<T> = Result<T, Box<dyn Error>>;
 
 pub fn to_epoch(filetime: u64) -> u64 {
     let windows_epoch_diff: u64 = 11644473600000 * 10000;
@@ -33,62 +29,6 @@ pub fn to_epoch(filetime: u64) -> u64 {
     process_time_epoch
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum ScannerSignal {
-    FoundStruct,        // Found a valid struct, scan for pool in the next chunk
-    SearchNext,         // Keep doing exhausting search
-    StopScan            // Stop the scanning process
-}
-
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum DriverAction {
-    SetupOffset,
-    GetKernelBase,
-    ScanPsActiveHead,
-    ScanPool,
-    ScanPoolRemote,
-    DereferenceAddress,
-    DereferencePhysicalAddress,
-    HideProcess,
-    GetPteBaseAddress,
-}
-
-impl DriverAction {
-    pub fn get_code(&self) -> DWORD {
-        match self {
-            DriverAction::SetupOffset => {
-                CTL_CODE(SIOCTL_TYPE, 0x900, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
-            }
-            DriverAction::GetKernelBase => {
-                CTL_CODE(SIOCTL_TYPE, 0x901, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
-            }
-            DriverAction::ScanPsActiveHead => {
-                CTL_CODE(SIOCTL_TYPE, 0x902, METHOD_NEITHER, FILE_ANY_ACCESS)
-            }
-            DriverAction::ScanPool => {
-                CTL_CODE(SIOCTL_TYPE, 0x903, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
-            }
-            DriverAction::ScanPoolRemote => {
-                CTL_CODE(SIOCTL_TYPE, 0x904, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
-            }
-            DriverAction::DereferenceAddress => {
-                CTL_CODE(SIOCTL_TYPE, 0xA00, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
-            }
-            DriverAction::DereferencePhysicalAddress => {
-                CTL_CODE(SIOCTL_TYPE, 0xA01, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
-            }
-            DriverAction::HideProcess => {
-                CTL_CODE(SIOCTL_TYPE, 0xA02, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
-            }
-            DriverAction::GetPteBaseAddress => {
-                CTL_CODE(SIOCTL_TYPE, 0xB01, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
-            }
-        }
-    }
-}
-
 #[derive(Debug)]
 pub struct EprocessPoolChunk {
     pub pool_addr: u64,
@@ -109,31 +49,71 @@ pub struct DriverState {
     // TODO: Make private, only call methods of DriverState
     pub pdb_store: PdbStore,
     pub windows_ffi: WindowsFFI,
+    cache: PageCache,
+    batch_supported: AtomicBool,
 }
 
 impl DriverState {
-    pub fn new() -> Self {
-        Self {
-            pdb_store: parse_pdb().expect("Cannot get PDB file"),
+    pub fn new() -> BoxResult<Self> {
+        let mut state = Self {
+            pdb_store: parse_pdb()?,
             windows_ffi: WindowsFFI::new(),
-        }
+            cache: PageCache::new(DEFAULT_CACHE_PAGES),
+            batch_supported: AtomicBool::new(true),
+        };
+        state.apply_overlays(&build_default_overlays())?;
+        Ok(state)
+    }
+
+    pub fn apply_overlays(&mut self, overlays: &[Overlay]) -> BoxResult<()> {
+        // Before startup(), the offsets sent to lpus.sys come from the PdbStore
+        let build = self.windows_ffi.get_build_number();
+        apply_overlays(&mut self.pdb_store, overlays, Some(build))
     }
 
     pub fn startup(&mut self) -> NTSTATUS {
+        self.windows_ffi.register_service();
         let s = self.windows_ffi.load_driver();
-        let mut input = InputData {
-            offset_value: OffsetData::new(&self.pdb_store, self.windows_ffi.short_version),
-        };
-        self.windows_ffi.device_io(
+        let mut input = InputData::zeroed();
+        input.offset_value = OffsetData::new(&self.pdb_store, self.windows_ffi.short_version);
+        if let Err(e) = self.windows_ffi.device_io(
             DriverAction::SetupOffset.get_code(),
             &mut input,
             &mut Nothing,
-        );
+        ) {
+            println!("Cannot send the offsets to the driver: {}", e);
+        }
         s
     }
 
-    pub fn shutdown(&self) -> NTSTATUS {
-        self.windows_ffi.unload_driver()
+    pub fn shutdown(&mut self) -> NTSTATUS {
+        self.windows_ffi.flush_recording();
+        self.windows_ffi.close_driver_handle();
+        let s = self.windows_ffi.unload_driver();
+        self.windows_ffi.unregister_service();
+        s
+    }
+
+    pub fn set_cache_capacity(&self, pages: usize) {
+        // 0 sends every read to the driver
+        self.cache.set_capacity(pages);
+    }
+
+    pub fn invalidate_cache(&self) {
+        self.cache.invalidate();
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn record_session(&mut self, path: &Path) -> BoxResult<()> {
+        // Save every IOCTL to `path`, memory::replay::ReplayState reads it back
+        // The cache capacity is saved with it, set it before recording
+        let guid = self.pdb_store.guid.clone();
+        let age = self.pdb_store.age;
+        let cache_pages = self.cache.stats().capacity;
+        self.windows_ffi.start_recording(path, &guid, age, cache_pages)
     }
 
     pub fn connect(&mut self) {
@@ -151,352 +131,92 @@ impl DriverState {
         for i in 0..s.len() {
             name[i] = s_bytes[i];
         }
-        let mut input = InputData {
-            hide_process: HideProcess {
-                name,
-                size: s.len() as u64,
-            },
+        let mut input = InputData::zeroed();
+        input.hide_process = HideProcess {
+            name,
+            size: s.len() as u64,
         };
-        self.windows_ffi.device_io(
+        if let Err(e) = self.windows_ffi.device_io(
             DriverAction::HideProcess.get_code(),
             &mut input,
             &mut Nothing,
-        );
-    }
-
-    pub fn use_old_tag(&self) -> bool {
-        // use old tag to scan, for Window < 8
-        if self.windows_ffi.short_version < WindowsVersion::Windows8 {
-            true
-        } else {
-            false
+        ) {
+            println!("Cannot hide notepad.exe: {}", e);
         }
+        // the process list has changed under us
+        self.cache.invalidate();
     }
+}
 
-    pub fn get_kernel_base(&self) -> Address {
-        let mut ntosbase = 0u64;
-        self.windows_ffi.device_io(
-            DriverAction::GetKernelBase.get_code(),
-            &mut Nothing,
-            &mut ntosbase,
-        );
-        Address::from_base(ntosbase)
+impl MemoryReader for DriverState {
+    fn read_virtual(&self, addr: u64, buf: &mut [u8]) -> usize {
+        //println!("Deref address {:x}", addr);
+        self.cache.read(AddressSpace::Virtual, addr, buf, |addr, buf| {
+            ioctl_deref(&self.windows_ffi, DriverAction::DereferenceAddress, addr, buf)
+        })
     }
 
-    pub fn get_pte_base(&self) -> Address {
-        // Get base address of PTE 
-        let mut pteBase = 0u64;
-        self.windows_ffi.device_io(
-            DriverAction::GetPteBaseAddress.get_code(),
-             &mut Nothing, 
-             &mut pteBase
-        );
-        Address::from_base(pteBase)
+    fn read_physical(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.cache.read(AddressSpace::Physical, addr, buf, |addr, buf| {
+            ioctl_deref(
+                &self.windows_ffi,
+                DriverAction::DereferencePhysicalAddress,
+                addr,
+                buf,
+            )
+        })
+    }
+
+    fn read_many(&self, space: AddressSpace, requests: &[(u64, usize)]) -> Vec<Vec<u8>> {
+        // The batch skips the cache, a failed batch falls back to the cached reads
+        ioctl_read_many(
+            &self.windows_ffi,
+            &self.batch_supported,
+            space,
+            requests,
+            |addr, buf| match space {
+                AddressSpace::Virtual => self.read_virtual(addr, buf),
+                AddressSpace::Physical => self.read_physical(addr, buf),
+            },
+        )
     }
 
-    pub fn scan_pool<F>(
-        &self,
-        tag: &[u8; 4],
-        expected_struct: &str,
-        mut handler: F,
-    ) -> BoxResult<bool>
-    where
-        F: FnMut(Address, &[u8], Address) -> BoxResult<ScannerSignal>, // F(Pool Address, Pool Header Data, Pool Data Address)
-                                                              // TODO: Pool Header as a real struct
-    {
-        // TODO: scan large pool
-        // TODO: make generator, in hold: https://github.com/rust-lang/rust/issues/43122
-        // Making this function a generator will turn the call to a for loop
-        // https://docs.rs/gen-iter/0.2.0/gen_iter/
-        // >> More flexibility in code
-        let pool_header_size = self.pdb_store.get_offset_r("_POOL_HEADER.struct_size")?;
-        let minimum_block_size = self
-            .pdb_store
-            .get_offset_r(&format!("{}.struct_size", expected_struct))?
-            + pool_header_size;
-        let code = DriverAction::ScanPoolRemote.get_code();
-        let ntosbase = self.get_kernel_base();
-        let [start_address, end_address] = self.get_nonpaged_range(&ntosbase)?;
-
-        println!(
-            "kernel base: {}; non-paged pool (start, end): ({}, {}); tag: {:?} {}",
-            ntosbase, start_address, end_address, tag, expected_struct
-        );
-
-        let mut ptr = start_address;
-        while ptr < end_address {
-            let mut next_found = 0u64;
-            let mut input = InputData {
-                scan_range: ScanPoolData::new(&[ptr.address(), end_address.address()], tag),
-            };
-            self.windows_ffi
-                .device_io(code, &mut input, &mut next_found);
-            ptr = Address::from_base(next_found);
-            if ptr >= end_address {
-                break;
-            }
-
-            let pool_addr = Address::from_base(ptr.address());
-            let header: Vec<u8> = self.deref_array(&pool_addr, pool_header_size);
-            let chunk_size = (header[2] as u64) * 16u64;
-
-            if pool_addr.address() + chunk_size > end_address.address() {
-                // the chunk surpasses the non page pool range
-                break;
-            }
-
-            // automatically reject bad chunk
-            if chunk_size < minimum_block_size {
-                ptr += 0x4;
-                continue;
-            }
-
-            let data_addr = Address::from_base(pool_addr.address() + pool_header_size);
-            let handler_status = handler(pool_addr, &header, data_addr).unwrap_or(ScannerSignal::SearchNext);
-            match handler_status {
-                ScannerSignal::FoundStruct => {
-                    ptr += chunk_size;
-                }
-
-                ScannerSignal::SearchNext => {
-                    ptr += 0x4;
-                }
-
-                ScannerSignal::StopScan => {
-                    break;
-                }
+    fn prefetch(&self, space: AddressSpace, ranges: &[(u64, u64)]) {
+        let pages = self.cache.missing_pages(space, ranges);
+        let requests: Vec<(u64, usize)> = pages.iter().map(|&p| (p, PAGE_SIZE as usize)).collect();
+        for (&page, data) in pages.iter().zip(self.read_many(space, &requests)) {
+            if data.len() == PAGE_SIZE as usize {
+                self.cache.insert(space, page, data);
             }
         }
-        Ok(true)
     }
 
-    pub fn address_of(&self, addr: &Address, name: &str) -> BoxResult<u64> {
-        let resolver = |p| self.deref_addr_new(p);
-        let (r, mask, required_len) = self.pdb_store.decompose(&addr, &name)?;
-        Ok(r.get(&resolver))
+    fn get_kernel_base(&self) -> Address {
+        Address::from_base(ioctl_query_u64(&self.windows_ffi, DriverAction::GetKernelBase))
     }
 
-    pub fn decompose<T: Default + MaskCast<u64>>(&self, addr: &Address, name: &str) -> BoxResult<T> {
-        // interface to pdb_store.decompose
-        let resolver = |p| self.deref_addr_new(p);
-        let (addr, mask_handler, required_len) = self.pdb_store.decompose(&addr, &name)?;
-        let r: T = self.deref_addr_new(addr.get(&resolver));
-        if size_of::<T>() as u64 * 8 >= required_len {
-            Ok(T::mask_cast_from(mask_handler(r.mask_cast_to())))
-        } else {
-            Err(format!("Required length is {} while buffer size if {}", required_len, size_of::<T>() * 8).into())
-        }
-        
-        // Ok(r)
+    fn get_pte_base(&self) -> Address {
+        // Get base address of PTE
+        Address::from_base(ioctl_query_u64(
+            &self.windows_ffi,
+            DriverAction::GetPteBaseAddress,
+        ))
     }
 
-    pub fn decompose_physical<T: Default + MaskCast<u64>>(&self, addr: &Address, name: &str) -> BoxResult<T> {
-        // The same as "decompose()", but use physical address
-        let resolver = |p| self.deref_physical_addr(p);
-        let (addr, mask_handler, required_len) = self.pdb_store.decompose(&addr, &name)?;
-        let r: T = self.deref_physical_addr(addr.get(&resolver));
-        if size_of::<T>() as u64 * 8 >= required_len {
-            Ok(T::mask_cast_from(mask_handler(r.mask_cast_to())))
-        } else {
-            Err(format!("Required length is {} while buffer size if {}", required_len, size_of::<T>() * 8, ).into())
-        }
+    fn pdb_store(&self) -> &PdbStore {
+        &self.pdb_store
     }
 
-    pub fn decompose_array<T: Default + Clone>(
-        &self,
-        addr: &Address,
-        name: &str,
-        len: u64,
-    ) -> BoxResult<Vec<T>> {
-        // interface to pdb_store.decompose for array
-        let (addr, mask, required_len) = self.pdb_store.decompose(&addr, &name)?;
-        let r: Vec<T> = self.deref_array(&addr, len);
-        Ok(r)
+    fn windows_version(&self) -> WindowsVersion {
+        self.windows_ffi.short_version
     }
 
-    pub fn deref_addr_new<T: Default>(&self, addr: u64) -> T {
-        let mut r: T = Default::default();
-        if addr != 0 {
-            self.deref_addr(addr, &mut r);
-        }
-        r
-    }
-
-    pub fn deref_physical_addr<T: Default>(&self, addr: u64) -> T {
-        /*
-        Read content at a PHYSICAL ADDRESS
-            - addr: PHYSICAL address of the paging structure
-        */
-        let mut outbuf: T = Default::default();
-        if addr != 0 {
-            let code = DriverAction::DereferencePhysicalAddress.get_code();
-            let size: usize = size_of_val(&outbuf);
-            let mut input = InputData {
-                deref_addr: DerefAddr {
-                    addr,
-                    size: size as u64,
-                },
-            };
-            self.windows_ffi.device_io(code, &mut input, &mut outbuf);
-        }
-        outbuf
-    }
-
-    pub fn deref_array<T: Default + Clone>(&self, addr: &Address, len: u64) -> Vec<T> {
-        let resolver = |p| self.deref_addr_new(p);
-        let mut r: Vec<T> = vec![Default::default(); len as usize];
-        let size_in_byte = (len as usize) * size_of::<T>();
-        self.deref_addr_ptr(addr.get(&resolver), r.as_mut_ptr(), size_in_byte as u64);
-        r
-    }
-
-    pub fn deref_array_physical<T: Default + Clone>(&self, addr: &Address, len: u64) -> Vec<T> {
-        let resolver = |p| self.deref_physical_addr(p);
-        let mut outbuf: Vec<T> = vec![Default::default(); len as usize];
-        let size_in_byte = (len as usize) * size_of::<T>();
-        // self.deref_addr_ptr(addr.get(&resolver), r.as_mut_ptr(), size_in_byte as u64);
-
-        let code = DriverAction::DereferencePhysicalAddress.get_code();
-        let mut input = InputData {
-            deref_addr: DerefAddr {
-                addr: addr.get(&resolver),
-                size: size_in_byte as u64,
-            },
-        };
-        // unsafe {
-        //     self.windows_ffi.device_io(code, &mut input, &mut *outbuf.as_mut_ptr());
-        // }
-
-        self.windows_ffi.device_io_raw(
-            code,
-            &mut input as *mut _ as *mut c_void,
-            size_of_val(&input) as DWORD,
-            outbuf.as_mut_ptr() as *mut c_void,
-            len as DWORD,
-        );
-
-        outbuf
+    fn valid_process_time(&self, filetime: u64) -> bool {
+        self.windows_ffi.valid_process_time(filetime)
     }
 
-    // #[deprecated(note="use deref_addr_new<T>")]
-    pub fn deref_addr<T>(&self, addr: u64, outbuf: &mut T) {
-        let code = DriverAction::DereferenceAddress.get_code();
-        let size: usize = size_of_val(outbuf);
-        let mut input = InputData {
-            deref_addr: DerefAddr {
-                addr,
-                size: size as u64,
-            },
-        };
-        //println!("Deref address {:x}", addr);
-        self.windows_ffi.device_io(code, &mut input, outbuf);
-    }
-
-    // #[deprecated(note="use deref_array<T>")]
-    pub fn deref_addr_ptr<T>(&self, addr: u64, outptr: *mut T, output_len_as_byte: u64) {
-        let code = DriverAction::DereferenceAddress.get_code();
-        let mut input = InputData {
-            deref_addr: DerefAddr {
-                addr,
-                size: output_len_as_byte,
-            },
-        };
-        self.windows_ffi.device_io_raw(
-            code,
-            &mut input as *mut _ as *mut c_void,
-            size_of_val(&input) as DWORD,
-            outptr as *mut c_void,
-            output_len_as_byte as DWORD,
-        );
-    }
-
-    pub fn get_unicode_string(&self, unicode_str_addr: u64) -> BoxResult<String> {
-        if unicode_str_addr == 0 {
-            return Err("Not a valid address".into());
-        }
-
-        let mut strlen = 0u16;
-        let mut capacity = 0u16;
-        let mut bufaddr = 0u64;
-        let buffer_ptr =
-            unicode_str_addr + self.pdb_store.get_offset_r("_UNICODE_STRING.Buffer")?;
-        let capacity_addr = unicode_str_addr
-            + self
-                .pdb_store
-                .get_offset_r("_UNICODE_STRING.MaximumLength")?;
-
-        self.deref_addr(unicode_str_addr, &mut strlen);
-        self.deref_addr(capacity_addr, &mut capacity);
-        self.deref_addr(buffer_ptr, &mut bufaddr);
-
-        if bufaddr == 0 || strlen > capacity || strlen == 0 || strlen % 2 != 0 {
-            return Err("Unicode string is empty".into());
-        }
-
-        let mut buf = vec![0u16; (strlen / 2) as usize];
-        self.deref_addr_ptr(bufaddr, buf.as_mut_ptr(), strlen as u64);
-        // TODO: BUG with deref_array, len is wrong,
-        // >> the size of vector is strlen / 2
-        // >> the size to dereference is strlen
-        // XXX: use Vec<u8> and turn to Vec<u16>
-        // let buf: Vec<u16> = self.deref_array(&Address::from_base(bufaddr), (strlen / 2) as u64);
-
-        Ok(String::from_utf16(&buf)?)
-    }
-
-    pub fn get_nonpaged_range(&self, ntosbase: &Address) -> BoxResult<[Address; 2]> {
-        // TODO: Add support for other Windows version here
-        match self.windows_ffi.short_version {
-            WindowsVersion::WindowsFastRing => {
-                let mistate = ntosbase.clone() + self.pdb_store.get_offset_r("MiState")?;
-                let path_first_va: String = vec![
-                    "_MI_SYSTEM_INFORMATION",
-                    "Hardware",
-                    "SystemNodeNonPagedPool",
-                    "NonPagedPoolFirstVa",
-                ]
-                .join(".");
-                let path_last_va: String = vec![
-                    "_MI_SYSTEM_INFORMATION",
-                    "Hardware",
-                    "SystemNodeNonPagedPool",
-                    "NonPagedPoolLastVa",
-                ]
-                .join(".");
-                let first_va = Address::from_base(self.decompose(&mistate, &path_first_va)?);
-                let last_va = Address::from_base(self.decompose(&mistate, &path_last_va)?);
-                Ok([first_va, last_va])
-            }
-            WindowsVersion::Windows10_2019 | WindowsVersion::Windows10_2018 => {
-                let mistate = ntosbase.clone() + self.pdb_store.get_offset_r("MiState")?;
-                let path_first_va: String = vec![
-                    "_MI_SYSTEM_INFORMATION",
-                    "Hardware",
-                    "SystemNodeInformation",
-                    "NonPagedPoolFirstVa",
-                ]
-                .join(".");
-                let path_last_va: String = vec![
-                    "_MI_SYSTEM_INFORMATION",
-                    "Hardware",
-                    "SystemNodeInformation",
-                    "NonPagedPoolLastVa",
-                ]
-                .join(".");
-                let first_va = Address::from_base(self.decompose(&mistate, &path_first_va)?);
-                let last_va = Address::from_base(self.decompose(&mistate, &path_last_va)?);
-                Ok([first_va, last_va])
-            }
-            WindowsVersion::Windows7 => {
-                let path_first_va =
-                    ntosbase.clone() + self.pdb_store.get_offset_r("MmNonPagedPoolStart")?;
-                let path_last_va =
-                    ntosbase.clone() + self.pdb_store.get_offset_r("MiNonPagedPoolEnd")?;
-                let first_va = Address::from_base(self.deref_addr_new(path_first_va.address()));
-                let last_va = Address::from_base(self.deref_addr_new(path_last_va.address()));
-                Ok([first_va, last_va])
-            }
-            _ => Err("Windows version for nonpaged pool algorithm is not implemented".into()),
-        }
+    fn find_pool_tag(&self, start: u64, end: u64, tag: &[u8; 4]) -> u64 {
+        // The driver walks the range in kernel, much faster than reading page by page
+        ioctl_scan_pool(&self.windows_ffi, start, end, tag)
     }
 }
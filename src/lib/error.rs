@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::version::WindowsVersion;
+
+// Errors callers may want to tell apart, e.g. a page not present from a missing symbol
+// They travel inside BoxResult like any other error, get them back with
+// `err.downcast_ref::<LpusError>()`
+#[derive(Debug, Clone, PartialEq)]
+pub enum LpusError {
+    SymbolNotFound(String),
+    StructNotFound(String),
+    EnumNotFound(String),
+    MemberNotFound { struct_name: String, member: String },
+    // `read` bytes out of `size` could be read at `address`, 0 when nothing was read
+    ReadFailed { address: u64, size: usize, read: usize },
+    InvalidUnicodeString(u64),
+    UnsupportedVersion(WindowsVersion),
+    DownloadFailed { url: String, reason: String },
+    PdbNotFound { name: String, guid: String, age: u32 },
+    // `module!Name` for a module whose PDB is not loaded
+    ModuleNotLoaded(String),
+    // A module by name without extension, not in PsLoadedModuleList
+    ModuleNotFound(String),
+    // `name[index]` past the `count` elements the PDB declares
+    IndexOutOfBounds { name: String, index: u64, count: u64 },
+    // `len` elements asked of an array the PDB declares with `count`
+    ArrayTooShort { name: String, len: u64, count: u64 },
+    // DeviceIoControl failed, `last_error` is GetLastError()
+    DeviceIo { code: u32, last_error: u32 },
+}
+
+pub type LpusResult<T> = Result<T, LpusError>;
+
+impl LpusError {
+    pub fn is_partial_read(&self) -> bool {
+        match self {
+            LpusError::ReadFailed { read, .. } => *read > 0,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for LpusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LpusError::SymbolNotFound(name) => write!(f, "{} is not found in PDB", name),
+            LpusError::StructNotFound(name) => write!(f, "Struct {} is not found in PDB", name),
+            LpusError::EnumNotFound(name) => write!(f, "Enum {} is not found in PDB", name),
+            LpusError::MemberNotFound {
+                struct_name,
+                member,
+            } => write!(f, "No member {} in {}", member, struct_name),
+            LpusError::ReadFailed {
+                address,
+                size,
+                read,
+            } => write!(
+                f,
+                "Read failed at 0x{:x}, {} of {} bytes read",
+                address, read, size
+            ),
+            LpusError::InvalidUnicodeString(address) => {
+                write!(f, "Invalid _UNICODE_STRING at 0x{:x}", address)
+            }
+            LpusError::UnsupportedVersion(version) => {
+                write!(f, "Windows version {:?} is not supported", version)
+            }
+            LpusError::DownloadFailed { url, reason } => {
+                write!(f, "Cannot download {}: {}", url, reason)
+            }
+            LpusError::PdbNotFound { name, guid, age } => {
+                write!(f, "{} {}{:X} is not in the symbol path", name, guid, age)
+            }
+            LpusError::ModuleNotLoaded(module) => {
+                write!(f, "No symbols are loaded for module {}", module)
+            }
+            LpusError::ModuleNotFound(module) => write!(f, "Module {} is not loaded", module),
+            LpusError::IndexOutOfBounds { name, index, count } => write!(
+                f,
+                "Index {} is out of bounds for {} of {} elements",
+                index, name, count
+            ),
+            LpusError::ArrayTooShort { name, len, count } => write!(
+                f,
+                "Cannot read {} elements of {}, it has {}",
+                len, name, count
+            ),
+            LpusError::DeviceIo { code, last_error } => write!(
+                f,
+                "DeviceIoControl 0x{:x} failed: last error {}",
+                code, last_error
+            ),
+        }
+    }
+}
+
+impl Error for LpusError {}
@@ -1,5 +1,22 @@
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ioctl_session::IoctlDevice;
+use crate::memory::AddressSpace;
 use crate::pdb_store::PdbStore;
-use crate::windows::WindowsVersion;
+use crate::version::WindowsVersion;
+
+// The IOCTL codes of lpus.sys, CTL_CODE() of winioctl.h without winapi so a recorded
+// session replays on any platform
+const SIOCTL_TYPE: u32 = 40000;
+const METHOD_IN_DIRECT: u32 = 1;
+const METHOD_OUT_DIRECT: u32 = 2;
+const METHOD_NEITHER: u32 = 3;
+const FILE_ANY_ACCESS: u32 = 0;
+
+const fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
+    (device_type << 16) | (access << 14) | (function << 2) | method
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -152,12 +169,28 @@ pub struct DerefAddr {
     pub size: u64,
 }
 
+// Batched dereference: InputData holds this header and is followed in the same input
+// buffer by `count` DerefAddr. The output starts with the bytes read for each entry
+// (u64), then the data of each entry at its full size, one after another.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DerefMany {
+    pub count: u64,
+    // 0 for virtual addresses, 1 for physical addresses
+    pub physical: u64,
+}
+
+pub const DEREF_MANY_MAX_COUNT: usize = 0x200;
+pub const DEREF_MANY_MAX_SIZE: usize = 0x100000;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct ScanPoolData {
     pub start: u64,
     pub end: u64,
     pub tag: u32,
+    // the padding is sent too, a field keeps it zeroed for the replay to match
+    pub padding: u32,
 }
 
 impl ScanPoolData {
@@ -166,6 +199,7 @@ impl ScanPoolData {
             start: arr[0],
             end: arr[1],
             tag: u32::from_le_bytes(*tag),
+            padding: 0,
         }
     }
 }
@@ -181,10 +215,28 @@ pub struct HideProcess {
 pub union InputData {
     pub offset_value: OffsetData,
     pub deref_addr: DerefAddr,
+    pub deref_many: DerefMany,
     pub scan_range: ScanPoolData,
     pub hide_process: HideProcess,
 }
 
+impl InputData {
+    pub fn zeroed() -> Self {
+        // The unused bytes are sent to the driver too, keep them deterministic
+        // so a recorded session can be matched on replay
+        unsafe { std::mem::zeroed() }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const _ as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct Nothing; // for empty data
@@ -193,3 +245,203 @@ pub struct Nothing; // for empty data
 pub union OutputData {
     pub nothing: Nothing,
 }
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum DriverAction {
+    SetupOffset,
+    GetKernelBase,
+    ScanPsActiveHead,
+    ScanPool,
+    ScanPoolRemote,
+    DereferenceAddress,
+    DereferencePhysicalAddress,
+    HideProcess,
+    DereferenceMany,
+    GetPteBaseAddress,
+}
+
+impl DriverAction {
+    pub fn get_code(&self) -> u32 {
+        match self {
+            DriverAction::SetupOffset => {
+                ctl_code(SIOCTL_TYPE, 0x900, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::GetKernelBase => {
+                ctl_code(SIOCTL_TYPE, 0x901, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::ScanPsActiveHead => {
+                ctl_code(SIOCTL_TYPE, 0x902, METHOD_NEITHER, FILE_ANY_ACCESS)
+            }
+            DriverAction::ScanPool => {
+                ctl_code(SIOCTL_TYPE, 0x903, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::ScanPoolRemote => {
+                ctl_code(SIOCTL_TYPE, 0x904, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::DereferenceAddress => {
+                ctl_code(SIOCTL_TYPE, 0xA00, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::DereferencePhysicalAddress => {
+                ctl_code(SIOCTL_TYPE, 0xA01, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::HideProcess => {
+                ctl_code(SIOCTL_TYPE, 0xA02, METHOD_IN_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::DereferenceMany => {
+                ctl_code(SIOCTL_TYPE, 0xA03, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
+            }
+            DriverAction::GetPteBaseAddress => {
+                ctl_code(SIOCTL_TYPE, 0xB01, METHOD_OUT_DIRECT, FILE_ANY_ACCESS)
+            }
+        }
+    }
+}
+
+// The requests below are shared by DriverState and the replay of a recorded session,
+// both must send the same bytes for a replay to find its answers
+
+pub fn ioctl_deref(
+    device: &dyn IoctlDevice,
+    action: DriverAction,
+    addr: u64,
+    buf: &mut [u8],
+) -> usize {
+    // DereferenceAddress or DereferencePhysicalAddress
+    let mut input = InputData::zeroed();
+    input.deref_addr = DerefAddr {
+        addr,
+        size: buf.len() as u64,
+    };
+    // a failed DeviceIoControl read nothing
+    device
+        .ioctl(action.get_code(), input.as_bytes(), buf)
+        .unwrap_or(0) as usize
+}
+
+pub fn ioctl_deref_many(
+    device: &dyn IoctlDevice,
+    space: AddressSpace,
+    requests: &[(u64, usize)],
+) -> Option<Vec<Vec<u8>>> {
+    // One DereferenceMany, see ioctl_protocol::DerefMany for the layout
+    // None when the driver does not answer it, lpus.sys before the request was added
+    let mut input = InputData::zeroed();
+    input.deref_many = DerefMany {
+        count: requests.len() as u64,
+        physical: (space == AddressSpace::Physical) as u64,
+    };
+    let mut input_bytes = input.as_bytes().to_vec();
+    for &(addr, size) in requests.iter() {
+        input_bytes.extend_from_slice(&addr.to_le_bytes());
+        input_bytes.extend_from_slice(&(size as u64).to_le_bytes());
+    }
+    let lengths_size = requests.len() * 8;
+    let data_size: usize = requests.iter().map(|&(_, size)| size).sum();
+    let mut output = vec![0u8; lengths_size + data_size];
+    let returned = device
+        .ioctl(
+            DriverAction::DereferenceMany.get_code(),
+            &input_bytes,
+            &mut output,
+        )
+        .ok()? as usize;
+    if returned < lengths_size {
+        return None;
+    }
+
+    let mut data_offset = lengths_size;
+    let result = requests
+        .iter()
+        .enumerate()
+        .map(|(i, &(_, size))| {
+            let read = u64::from_le_bytes(output[i * 8..i * 8 + 8].try_into().unwrap()) as usize;
+            let start = data_offset;
+            data_offset += size;
+            // an entry past the bytes returned, or larger than asked, is a failed read
+            match start.checked_add(read) {
+                Some(end) if read <= size && end <= returned => output[start..end].to_vec(),
+                _ => Vec::new(),
+            }
+        })
+        .collect();
+    Some(result)
+}
+
+pub fn ioctl_read_many<F>(
+    device: &dyn IoctlDevice,
+    batch_supported: &AtomicBool,
+    space: AddressSpace,
+    requests: &[(u64, usize)],
+    read_one: F,
+) -> Vec<Vec<u8>>
+where
+    F: Fn(u64, &mut [u8]) -> usize,
+{
+    // Split `requests` into DereferenceMany batches, read one by one with `read_one`
+    // once the driver has refused a batch
+    let fallback = |&(addr, size): &(u64, usize)| {
+        let mut buf = vec![0u8; size];
+        let read = read_one(addr, &mut buf);
+        buf.truncate(read);
+        buf
+    };
+
+    let mut result = Vec::with_capacity(requests.len());
+    let mut start = 0;
+    while start < requests.len() {
+        let mut end = start;
+        let mut size = 0;
+        while end < requests.len()
+            && end - start < DEREF_MANY_MAX_COUNT
+            && size + requests[end].1 <= DEREF_MANY_MAX_SIZE
+        {
+            size += requests[end].1;
+            end += 1;
+        }
+        if end == start {
+            // a single read larger than a batch
+            result.push(fallback(&requests[start]));
+            start += 1;
+            continue;
+        }
+
+        let batch = &requests[start..end];
+        let answer = if batch_supported.load(Ordering::Relaxed) {
+            ioctl_deref_many(device, space, batch)
+        } else {
+            None
+        };
+        match answer {
+            Some(data) => result.extend(data),
+            None => {
+                batch_supported.store(false, Ordering::Relaxed);
+                result.extend(batch.iter().map(fallback));
+            }
+        }
+        start = end;
+    }
+    result
+}
+
+pub fn ioctl_query_u64(device: &dyn IoctlDevice, action: DriverAction) -> u64 {
+    // GetKernelBase or GetPteBaseAddress
+    let mut value = [0u8; 8];
+    device.ioctl(action.get_code(), &[], &mut value).ok();
+    u64::from_le_bytes(value)
+}
+
+pub fn ioctl_scan_pool(device: &dyn IoctlDevice, start: u64, end: u64, tag: &[u8; 4]) -> u64 {
+    let mut input = InputData::zeroed();
+    input.scan_range = ScanPoolData::new(&[start, end], tag);
+    let mut next_found = [0u8; 8];
+    match device.ioctl(
+        DriverAction::ScanPoolRemote.get_code(),
+        input.as_bytes(),
+        &mut next_found,
+    ) {
+        Ok(_) => u64::from_le_bytes(next_found),
+        // a failed scan finds nothing, 0 would restart the search at the start
+        Err(_) => end,
+    }
+}
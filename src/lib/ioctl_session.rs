@@ -0,0 +1,271 @@
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::error::{LpusError, LpusResult};
+use crate::memory::page_cache::DEFAULT_CACHE_PAGES;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Record and replay of the IOCTL traffic with lpus.sys
+// A session file is the magic, a JSON header with the machine details, then one
+// record per DeviceIoControl: code, input bytes, output bytes and bytes returned
+
+const SESSION_MAGIC: &[u8; 8] = b"LPUSIOCT";
+// version 1 has no cache_pages, it was recorded with DEFAULT_CACHE_PAGES
+const SESSION_VERSION: u32 = 2;
+// ERROR_NOT_FOUND, what a replay answers to a request it has not seen
+const NOT_RECORDED: u32 = 1168;
+
+// Anything that answers IOCTLs: the real driver or a recorded session
+pub trait IoctlDevice {
+    // Send `input` with `code`, fill `output` and return the number of bytes returned
+    fn ioctl(&self, code: u32, input: &[u8], output: &mut [u8]) -> LpusResult<u32>;
+}
+
+// The machine details needed to replay a session without the machine
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub build_number: u32,
+    // in milliseconds since 1970, used by valid_process_time()
+    pub boot_time: u64,
+    pub recorded_at: u64,
+    pub pdb_guid: String,
+    pub pdb_age: u32,
+    // the size of DriverState's page cache, the replay needs the same one to send
+    // the same requests
+    pub cache_pages: usize,
+}
+
+impl SessionInfo {
+    fn to_json(&self) -> Value {
+        json!({
+            "version": SESSION_VERSION,
+            "build_number": self.build_number,
+            "boot_time": self.boot_time,
+            "recorded_at": self.recorded_at,
+            "pdb_guid": self.pdb_guid,
+            "pdb_age": self.pdb_age,
+            "cache_pages": self.cache_pages,
+        })
+    }
+
+    fn from_json(v: &Value) -> BoxResult<Self> {
+        let get_u64 = |name: &str| {
+            v[name]
+                .as_u64()
+                .ok_or(format!("Session header has no {}", name))
+        };
+        let version = get_u64("version")?;
+        if version == 0 || version > SESSION_VERSION as u64 {
+            return Err(format!("Unsupported session version {}", version).into());
+        }
+        Ok(Self {
+            build_number: get_u64("build_number")? as u32,
+            boot_time: get_u64("boot_time")?,
+            recorded_at: get_u64("recorded_at")?,
+            pdb_guid: v["pdb_guid"].as_str().unwrap_or("").to_string(),
+            pdb_age: get_u64("pdb_age")? as u32,
+            cache_pages: match version {
+                1 => DEFAULT_CACHE_PAGES,
+                _ => get_u64("cache_pages")? as usize,
+            },
+        })
+    }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> BoxResult<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> BoxResult<u32> {
+    let mut v = [0u8; 4];
+    reader.read_exact(&mut v)?;
+    Ok(u32::from_le_bytes(v))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> BoxResult<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub struct IoctlRecorder {
+    writer: BufWriter<File>,
+}
+
+impl IoctlRecorder {
+    pub fn create(path: &Path, info: &SessionInfo) -> BoxResult<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(SESSION_MAGIC)?;
+        write_bytes(&mut writer, info.to_json().to_string().as_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn record(
+        &mut self,
+        code: u32,
+        input: &[u8],
+        output: &[u8],
+        returned: u32,
+    ) -> BoxResult<()> {
+        self.writer.write_all(&code.to_le_bytes())?;
+        write_bytes(&mut self.writer, input)?;
+        write_bytes(&mut self.writer, output)?;
+        self.writer.write_all(&returned.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> BoxResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// (code, input, output size) -> the (output, bytes returned) recorded for it
+type Responses = HashMap<(u32, Vec<u8>, usize), VecDeque<(Vec<u8>, u32)>>;
+
+// The same request (code, input, output size) can be answered differently over time,
+// the answers are served in recorded order and the last one is kept
+pub struct IoctlReplay {
+    pub info: SessionInfo,
+    responses: Mutex<Responses>,
+}
+
+impl IoctlReplay {
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != SESSION_MAGIC {
+            return Err("Not an lpus IOCTL session file".into());
+        }
+        let header: Value = serde_json::from_slice(&read_bytes(&mut reader)?)?;
+        let info = SessionInfo::from_json(&header)?;
+
+        let mut responses: Responses = HashMap::new();
+        loop {
+            let code = match read_u32(&mut reader) {
+                Ok(code) => code,
+                // end of session
+                Err(_) => break,
+            };
+            let input = read_bytes(&mut reader)?;
+            let output = read_bytes(&mut reader)?;
+            let returned = read_u32(&mut reader)?;
+            responses
+                .entry((code, input, output.len()))
+                .or_default()
+                .push_back((output, returned));
+        }
+        Ok(Self {
+            info,
+            responses: Mutex::new(responses),
+        })
+    }
+}
+
+impl IoctlDevice for IoctlReplay {
+    fn ioctl(&self, code: u32, input: &[u8], output: &mut [u8]) -> LpusResult<u32> {
+        let not_recorded = LpusError::DeviceIo {
+            code,
+            last_error: NOT_RECORDED,
+        };
+        let mut responses = self.responses.lock().map_err(|_| not_recorded.clone())?;
+        let answers = responses
+            .get_mut(&(code, input.to_vec(), output.len()))
+            .ok_or(not_recorded)?;
+        let (recorded, returned) = if answers.len() > 1 {
+            answers.pop_front().unwrap()
+        } else {
+            answers[0].clone()
+        };
+        output.copy_from_slice(&recorded);
+        Ok(returned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn info() -> SessionInfo {
+        SessionInfo {
+            build_number: 19041,
+            boot_time: 1_590_969_600_000,
+            recorded_at: 1_590_973_200_000,
+            pdb_guid: "3844DBB920174967BE7AA4A2C20430FA".to_string(),
+            pdb_age: 2,
+            cache_pages: 0x80,
+        }
+    }
+
+    fn replay(name: &str, records: &[(u32, &[u8], &[u8], u32)]) -> IoctlReplay {
+        let path = std::env::temp_dir().join(format!("lpus-{}-{}.bin", name, std::process::id()));
+        let mut recorder = IoctlRecorder::create(&path, &info()).unwrap();
+        for &(code, input, output, returned) in records {
+            recorder.record(code, input, output, returned).unwrap();
+        }
+        recorder.flush().unwrap();
+        let replay = IoctlReplay::open(&path).unwrap();
+        fs::remove_file(&path).ok();
+        replay
+    }
+
+    #[test]
+    fn round_trip() {
+        let replay = replay(
+            "round-trip",
+            &[
+                (1, b"in", b"first", 5),
+                (1, b"in", b"again", 3),
+                (2, b"", b"12345678", 8),
+            ],
+        );
+        assert_eq!(replay.info.build_number, 19041);
+        assert_eq!(replay.info.pdb_guid, info().pdb_guid);
+        assert_eq!(replay.info.cache_pages, 0x80);
+
+        // answers of the same request in recorded order, then the last one again
+        let mut output = [0u8; 5];
+        assert_eq!(replay.ioctl(1, b"in", &mut output).unwrap(), 5);
+        assert_eq!(&output, b"first");
+        for _ in 0..2 {
+            assert_eq!(replay.ioctl(1, b"in", &mut output).unwrap(), 3);
+            assert_eq!(&output, b"again");
+        }
+        let mut output = [0u8; 8];
+        assert_eq!(replay.ioctl(2, b"", &mut output).unwrap(), 8);
+        assert_eq!(&output, b"12345678");
+    }
+
+    #[test]
+    fn not_recorded() {
+        let replay = replay("not-recorded", &[(1, b"in", b"out", 3)]);
+        let mut output = [0u8; 3];
+        // another code, input or output size
+        for &(code, input) in &[(2, &b"in"[..]), (1, &b"other"[..])] {
+            match replay.ioctl(code, input, &mut output) {
+                Err(LpusError::DeviceIo { last_error, .. }) => assert_eq!(last_error, NOT_RECORDED),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert!(replay.ioctl(1, b"in", &mut [0u8; 4]).is_err());
+    }
+
+    #[test]
+    fn not_a_session() {
+        let path = std::env::temp_dir().join(format!("lpus-bad-{}.bin", std::process::id()));
+        fs::write(&path, b"LPUSSYMC\0\0\0\0").unwrap();
+        assert!(IoctlReplay::open(&path).is_err());
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+
+use crate::overlay::{apply_overlays, default_overlays};
+use crate::pdb_store::{EnumStore, ModuleSymbols, PdbStore, StructStore, SymbolStore};
+use crate::symbol_index::SymbolIndex;
+use crate::type_info::{primitive_size, EnumInfo, StructInfo, TypeInfo, POINTER_SIZE};
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Volatility 3 Intermediate Symbol Format
+// https://github.com/volatilityfoundation/volatility3/blob/develop/volatility3/schemas/schema-6.2.0.json
+const ISF_FORMAT: &str = "6.2.0";
+const KERNEL_PDB_NAME: &str = "ntkrnlmp.pdb";
+const MACHINE_TYPE_AMD64: u64 = 0x8664;
+
+// (pdb PrimitiveKind, ISF base type, signed, ISF kind), the size is primitive_size()
+type Primitive = (&'static str, &'static str, bool, &'static str);
+
+// The first row of an ISF name is the kind it is read back as
+const PRIMITIVES: [Primitive; 34] = [
+    ("Void", "void", false, "void"),
+    ("RChar", "char", true, "char"),
+    ("Char", "char", true, "char"),
+    ("I8", "char", true, "char"),
+    ("UChar", "unsigned char", false, "char"),
+    ("U8", "unsigned char", false, "char"),
+    ("WChar", "wchar", false, "char"),
+    ("RChar16", "wchar", false, "char"),
+    ("RChar32", "char32", false, "char"),
+    ("I16", "short", true, "int"),
+    ("U16", "unsigned short", false, "int"),
+    ("I32", "long", true, "int"),
+    ("HRESULT", "long", true, "int"),
+    ("U32", "unsigned long", false, "int"),
+    ("I64", "long long", true, "int"),
+    ("U64", "unsigned long long", false, "int"),
+    ("I128", "__int128", true, "int"),
+    ("U128", "unsigned __int128", false, "int"),
+    ("F16", "half", true, "float"),
+    ("F32", "float", true, "float"),
+    ("F32PP", "float", true, "float"),
+    ("F48", "float48", true, "float"),
+    ("F64", "double", true, "float"),
+    ("F80", "long double", true, "float"),
+    ("F128", "float128", true, "float"),
+    ("Complex32", "complex32", true, "float"),
+    ("Complex64", "complex64", true, "float"),
+    ("Complex80", "complex80", true, "float"),
+    ("Complex128", "complex128", true, "float"),
+    ("Bool8", "bool", false, "bool"),
+    ("Bool16", "bool16", false, "bool"),
+    ("Bool32", "bool32", false, "bool"),
+    ("Bool64", "bool64", false, "bool"),
+    // an ISF name without a pdb kind of its own
+    ("I32", "int", true, "int"),
+];
+
+fn primitive(pdb_kind: &str) -> Option<&'static Primitive> {
+    PRIMITIVES.iter().find(|p| p.0 == pdb_kind)
+}
+
+fn primitive_from_isf(isf_name: &str) -> Option<&'static str> {
+    match isf_name {
+        "unsigned int" => Some("U32"),
+        "pointer" => Some("U64"),
+        _ => PRIMITIVES.iter().find(|p| p.1 == isf_name).map(|p| p.0),
+    }
+}
+
+struct IsfWriter {
+    base_types: Map<String, Value>,
+    enums: Map<String, Value>,
+}
+
+impl IsfWriter {
+    fn base(&mut self, pdb_kind: &str) -> Value {
+        let (name, signed, kind) = match primitive(pdb_kind) {
+            Some(&(_, name, signed, kind)) => (name, signed, kind),
+            None => ("void", false, "void"),
+        };
+        let size = primitive_size(pdb_kind).unwrap_or(0);
+        self.base_types.insert(
+            name.to_string(),
+            json!({"size": size, "signed": signed, "kind": kind, "endian": "little"}),
+        );
+        json!({"kind": "base", "name": name})
+    }
+
+    fn isf_type(&mut self, t: &TypeInfo) -> Value {
+        match t {
+            TypeInfo::Primitive { name, .. } => self.base(name),
+            TypeInfo::Pointer(pointee) => {
+                json!({"kind": "pointer", "subtype": self.isf_type(pointee)})
+            }
+            TypeInfo::Array { element, count } => {
+                json!({"kind": "array", "count": count, "subtype": self.isf_type(element)})
+            }
+            TypeInfo::Bitfield {
+                underlying,
+                position,
+                length,
+            } => json!({
+                "kind": "bitfield",
+                "bit_position": position,
+                "bit_length": length,
+                "type": self.isf_type(underlying),
+            }),
+            TypeInfo::Struct { name, .. } => json!({"kind": "struct", "name": name}),
+            TypeInfo::Union { name, .. } => json!({"kind": "union", "name": name}),
+            TypeInfo::Enum { name, underlying } => {
+                // the constants come with the EnumStore, an enum missing there has none
+                let base = self.isf_type(underlying);
+                if !self.enums.contains_key(name) {
+                    self.enums.insert(
+                        name.to_string(),
+                        json!({"size": underlying.size(), "base": base["name"], "constants": {}}),
+                    );
+                }
+                json!({"kind": "enum", "name": name})
+            }
+            TypeInfo::Function { .. } => json!({"kind": "function"}),
+            TypeInfo::Unknown(_) => self.base("Void"),
+        }
+    }
+}
+
+pub fn tables_to_isf(
+    symbols: &SymbolStore,
+    structs: &StructStore,
+    enums: &EnumStore,
+    guid: &str,
+    age: u32,
+    database: &str,
+) -> Value {
+    let mut writer = IsfWriter {
+        base_types: Map::new(),
+        enums: Map::new(),
+    };
+    // Volatility needs the pointer size even when nothing names it
+    writer.base_types.insert(
+        "pointer".to_string(),
+        json!({"size": POINTER_SIZE, "signed": false, "kind": "int", "endian": "little"}),
+    );
+
+    let mut user_types = Map::new();
+    let mut struct_names: Vec<&String> = structs.keys().collect();
+    struct_names.sort();
+    for name in struct_names {
+        let info = &structs[name];
+        let mut fields = Map::new();
+        for (member, member_type, offset) in info.sorted_members() {
+            fields.insert(
+                member.to_string(),
+                json!({"offset": offset, "type": writer.isf_type(member_type)}),
+            );
+        }
+        let kind = if info.is_union { "union" } else { "struct" };
+        user_types.insert(
+            name.to_string(),
+            json!({"kind": kind, "size": info.size, "fields": fields}),
+        );
+    }
+
+    let mut enum_names: Vec<&String> = enums.keys().collect();
+    enum_names.sort();
+    for name in enum_names {
+        let info = &enums[name];
+        let base = writer.isf_type(&info.underlying);
+        let constants: Map<String, Value> = info
+            .values
+            .iter()
+            .map(|(constant, value)| (constant.to_string(), json!(value)))
+            .collect();
+        writer.enums.insert(
+            name.to_string(),
+            json!({"size": info.size(), "base": base["name"], "constants": constants}),
+        );
+    }
+
+    let mut isf_symbols = Map::new();
+    let mut symbol_names: Vec<&String> = symbols.keys().collect();
+    symbol_names.sort();
+    for name in symbol_names {
+        isf_symbols.insert(name.to_string(), json!({"address": symbols[name]}));
+    }
+
+    json!({
+        "metadata": {
+            "format": ISF_FORMAT,
+            "producer": {"name": "lpus", "version": env!("CARGO_PKG_VERSION")},
+            "windows": {
+                "pdb": {
+                    "GUID": guid,
+                    "age": age,
+                    "database": database,
+                    "machine_type": MACHINE_TYPE_AMD64,
+                },
+            },
+        },
+        "base_types": writer.base_types,
+        "user_types": user_types,
+        "enums": writer.enums,
+        "symbols": isf_symbols,
+    })
+}
+
+pub fn to_isf(store: &PdbStore) -> Value {
+    tables_to_isf(
+        &store.symbols,
+        &store.structs,
+        &store.enums,
+        &store.guid,
+        store.age,
+        KERNEL_PDB_NAME,
+    )
+}
+
+pub fn write_isf(store: &PdbStore, path: &Path) -> BoxResult<()> {
+    fs::write(path, serde_json::to_string(&to_isf(store))?)?;
+    Ok(())
+}
+
+struct IsfReader<'a> {
+    base_types: &'a Map<String, Value>,
+    user_types: &'a Map<String, Value>,
+    enums: &'a Map<String, Value>,
+}
+
+impl<'a> IsfReader<'a> {
+    fn base(&self, isf_name: &str) -> TypeInfo {
+        let name = primitive_from_isf(isf_name).unwrap_or("Void");
+        match self
+            .base_types
+            .get(isf_name)
+            .and_then(|t| t["size"].as_u64())
+        {
+            // the size in the file wins, "pointer" may be 4 bytes
+            Some(size) => TypeInfo::Primitive {
+                name: name.to_string(),
+                size,
+            },
+            None => TypeInfo::primitive(name),
+        }
+    }
+
+    fn type_info(&self, isf_type: &Value) -> BoxResult<TypeInfo> {
+        let name = isf_type["name"].as_str().unwrap_or("");
+        let user_size = || {
+            self.user_types
+                .get(name)
+                .and_then(|t| t["size"].as_u64())
+                .unwrap_or(0)
+        };
+        Ok(
+            match isf_type["kind"].as_str().ok_or("ISF type without a kind")? {
+                "pointer" => TypeInfo::pointer_to(self.type_info(&isf_type["subtype"])?),
+                "array" => TypeInfo::Array {
+                    element: Box::new(self.type_info(&isf_type["subtype"])?),
+                    count: isf_type["count"].as_u64().unwrap_or(0),
+                },
+                "bitfield" => TypeInfo::Bitfield {
+                    underlying: Box::new(self.type_info(&isf_type["type"])?),
+                    position: isf_type["bit_position"].as_u64().unwrap_or(0),
+                    length: isf_type["bit_length"].as_u64().unwrap_or(0),
+                },
+                "base" => self.base(name),
+                "enum" => {
+                    let base = self.enums.get(name).and_then(|e| e["base"].as_str());
+                    TypeInfo::Enum {
+                        name: name.to_string(),
+                        underlying: Box::new(self.base(base.unwrap_or("long"))),
+                    }
+                }
+                "union" => TypeInfo::Union {
+                    name: name.to_string(),
+                    size: user_size(),
+                },
+                "function" => TypeInfo::Function {
+                    return_type: Box::new(TypeInfo::primitive("Void")),
+                    arguments: Vec::new(),
+                },
+                "struct" | "class" => TypeInfo::Struct {
+                    name: name.to_string(),
+                    size: user_size(),
+                },
+                _ => TypeInfo::Unknown(name.to_string()),
+            },
+        )
+    }
+}
+
+pub fn tables_from_isf(isf: &Value) -> BoxResult<ModuleSymbols> {
+    let empty = Map::new();
+    let reader = IsfReader {
+        base_types: isf["base_types"].as_object().unwrap_or(&empty),
+        user_types: isf["user_types"]
+            .as_object()
+            .ok_or("Not an ISF file: no user_types")?,
+        enums: isf["enums"].as_object().unwrap_or(&empty),
+    };
+
+    let mut structs: StructStore = HashMap::new();
+    for (name, user_type) in reader.user_types {
+        let mut info = StructInfo::new(
+            user_type["size"].as_u64().unwrap_or(0),
+            user_type["kind"] == "union",
+        );
+        if let Some(fields) = user_type["fields"].as_object() {
+            for (member, field) in fields {
+                let offset = field["offset"].as_u64().unwrap_or(0);
+                info.add_member(member, reader.type_info(&field["type"])?, offset);
+            }
+        }
+        structs.insert(name.to_string(), info);
+    }
+
+    let mut enums: EnumStore = HashMap::new();
+    for (name, isf_enum) in reader.enums {
+        let base = isf_enum["base"].as_str().unwrap_or("long");
+        let mut info = EnumInfo::new(reader.base(base));
+        if let Some(constants) = isf_enum["constants"].as_object() {
+            for (constant, value) in constants {
+                info.values
+                    .push((constant.to_string(), value.as_i64().unwrap_or(0)));
+            }
+        }
+        // the constants are a map in the file, by value is how the PDB lists them
+        info.values.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        enums.insert(name.to_string(), info);
+    }
+
+    let mut symbols: SymbolStore = HashMap::new();
+    if let Some(isf_symbols) = isf["symbols"].as_object() {
+        for (name, symbol) in isf_symbols {
+            if let Some(address) = symbol["address"].as_u64() {
+                symbols.insert(name.to_string(), address);
+            }
+        }
+    }
+
+    let pdb = &isf["metadata"]["windows"]["pdb"];
+    Ok(ModuleSymbols {
+        index: SymbolIndex::new(&symbols, Vec::new()),
+        symbols,
+        structs,
+        enums,
+        guid: pdb["GUID"]
+            .as_str()
+            .unwrap_or("")
+            .replace("-", "")
+            .to_uppercase(),
+        age: pdb["age"].as_u64().unwrap_or(0) as u32,
+    })
+}
+
+pub fn parse_isf_file(path: &Path) -> BoxResult<PdbStore> {
+    // An uncompressed ISF, .json.xz files from Volatility have to be unpacked first
+    let isf: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let module = tables_from_isf(&isf)?;
+    println!(
+        "ISF for {}, guid: {}, age: {}\n",
+        isf["metadata"]["windows"]["pdb"]["database"]
+            .as_str()
+            .unwrap_or(KERNEL_PDB_NAME),
+        module.guid,
+        module.age
+    );
+    let mut pdb_store = PdbStore {
+        symbols: module.symbols,
+        index: module.index,
+        structs: module.structs,
+        enums: module.enums,
+        guid: module.guid,
+        age: module.age,
+        modules: HashMap::new(),
+    };
+    // as parse_pdb_file(), an ISF from Volatility has no _UNLOADED_DRIVERS either
+    apply_overlays(&mut pdb_store, &default_overlays(), None)?;
+    Ok(pdb_store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables() -> (SymbolStore, StructStore, EnumStore) {
+        let u32_bits = |position, length| TypeInfo::Bitfield {
+            underlying: Box::new(TypeInfo::primitive("U32")),
+            position,
+            length,
+        };
+        let bytes = TypeInfo::Array {
+            element: Box::new(TypeInfo::primitive("UChar")),
+            count: 8,
+        };
+
+        let mut node = StructInfo::new(0x20, false);
+        let node_type = TypeInfo::Struct {
+            name: "_NODE".to_string(),
+            size: 0x20,
+        };
+        node.add_member("Next", TypeInfo::pointer_to(node_type), 0);
+        node.add_member("Flags", u32_bits(0, 3), 8);
+        node.add_member("Kind", u32_bits(3, 5), 8);
+        let state = TypeInfo::Enum {
+            name: "_STATE".to_string(),
+            underlying: Box::new(TypeInfo::primitive("I32")),
+        };
+        node.add_member("State", state, 0xc);
+        let value_type = TypeInfo::Union {
+            name: "_VALUE".to_string(),
+            size: 8,
+        };
+        node.add_member("Value", value_type, 0x10);
+        node.add_member("Name", bytes.clone(), 0x18);
+
+        let mut value = StructInfo::new(8, true);
+        value.add_member("AsU64", TypeInfo::primitive("U64"), 0);
+        value.add_member("AsBytes", bytes, 0);
+        value.add_member("Ptr", TypeInfo::pointer_to(TypeInfo::primitive("Void")), 0);
+
+        let mut state = EnumInfo::new(TypeInfo::primitive("I32"));
+        // in the order tables_from_isf() sorts them
+        for (name, v) in [("Gone", -1), ("Idle", 0), ("Busy", 1)] {
+            state.values.push((name.to_string(), v));
+        }
+
+        let symbols = [("NodeList", 0x1000), ("NodeCount", 0x1008)]
+            .iter()
+            .map(|(name, rva)| (name.to_string(), *rva))
+            .collect();
+        let structs = vec![("_NODE", node), ("_VALUE", value)]
+            .into_iter()
+            .map(|(name, info)| (name.to_string(), info))
+            .collect();
+        let enums = std::iter::once(("_STATE".to_string(), state)).collect();
+        (symbols, structs, enums)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (symbols, structs, enums) = tables();
+        let guid = "3844DBB920174967BE7AA4A2C20430FA";
+        let isf = tables_to_isf(&symbols, &structs, &enums, guid, 2, KERNEL_PDB_NAME);
+        // through the text, as write_isf() and parse_isf_file() see it
+        let isf: Value = serde_json::from_str(&isf.to_string()).unwrap();
+        let module = tables_from_isf(&isf).unwrap();
+
+        assert_eq!(module.structs, structs);
+        assert_eq!(module.enums, enums);
+        assert_eq!(module.symbols, symbols);
+        assert_eq!((module.guid.as_str(), module.age), (guid, 2));
+        assert_eq!(isf["user_types"]["_VALUE"]["kind"], "union");
+        assert_eq!(
+            isf["user_types"]["_NODE"]["fields"]["Kind"]["type"]["bit_position"],
+            3
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+
+use crate::pdb_store::{PdbStore, StructStore};
+use crate::type_info::TypeInfo;
+
+// Member paths lpus reads: the OffsetData fields (every version's names) and the
+// paths given to decompose(), address_of() and get_offset() in lib.rs, object.rs and
+// memory/mod.rs. A test keeps it in step with them, `X.struct_size` is the size of X
+pub const LPUS_PATHS: &[&str] = &[
+    "_DEVICE_OBJECT.AttachedDevice",
+    "_DEVICE_OBJECT.DeviceType",
+    "_DEVICE_OBJECT.NextDevice",
+    "_DRIVER_OBJECT.DeviceObject",
+    "_DRIVER_OBJECT.DriverExtension.ServiceKeyName",
+    "_DRIVER_OBJECT.DriverInit",
+    "_DRIVER_OBJECT.DriverName",
+    "_DRIVER_OBJECT.DriverSize",
+    "_DRIVER_OBJECT.DriverStart",
+    "_DRIVER_OBJECT.DriverUnload",
+    "_DRIVER_OBJECT.HardwareDatabase",
+    "_DRIVER_OBJECT.MajorFunction",
+    "_DRIVER_OBJECT.Size",
+    "_DRIVER_OBJECT.struct_size",
+    "_EPROCESS.ActiveProcessLinks.Flink",
+    "_EPROCESS.CreateTime",
+    "_EPROCESS.ExitTime",
+    "_EPROCESS.ImageFileName",
+    "_EPROCESS.ImageFilePointer.FileName",
+    "_EPROCESS.InheritedFromUniqueProcessId",
+    "_EPROCESS.Pcb.DirectoryTableBase",
+    "_EPROCESS.ThreadListHead",
+    "_EPROCESS.UniqueProcessId",
+    "_EPROCESS.struct_size",
+    "_ETHREAD.Cid.UniqueProcess",
+    "_ETHREAD.Cid.UniqueThread",
+    "_ETHREAD.CreateTime",
+    "_ETHREAD.CrossThreadFlags",
+    "_ETHREAD.Tcb.Process",
+    "_ETHREAD.Tcb.State",
+    "_ETHREAD.Tcb.WaitReason",
+    "_ETHREAD.ThreadListEntry",
+    "_ETHREAD.ThreadName",
+    "_ETHREAD.struct_size",
+    "_FILE_OBJECT.DeleteAccess",
+    "_FILE_OBJECT.DeviceObject.DriverObject.DriverName",
+    "_FILE_OBJECT.DeviceObject.DriverObject.HardwareDatabase",
+    "_FILE_OBJECT.FileName",
+    "_FILE_OBJECT.ReadAccess",
+    "_FILE_OBJECT.SharedDelete",
+    "_FILE_OBJECT.SharedRead",
+    "_FILE_OBJECT.SharedWrite",
+    "_FILE_OBJECT.Size",
+    "_FILE_OBJECT.Type",
+    "_FILE_OBJECT.WriteAccess",
+    "_FILE_OBJECT.struct_size",
+    "_HANDLE_TABLE.HandleTableList.Flink",
+    "_HANDLE_TABLE.QuotaProcess",
+    "_KPROCESS.ProcessListEntry.Flink",
+    "_LDR_DATA_TABLE_ENTRY.BaseDllName",
+    "_LDR_DATA_TABLE_ENTRY.DllBase",
+    "_LDR_DATA_TABLE_ENTRY.EntryPoint",
+    "_LDR_DATA_TABLE_ENTRY.FullDllName",
+    "_LDR_DATA_TABLE_ENTRY.InInitializationOrderLinks.Flink",
+    "_LDR_DATA_TABLE_ENTRY.InLoadOrderLinks.Flink",
+    "_LDR_DATA_TABLE_ENTRY.InMemoryOrderLinks.Flink",
+    "_LDR_DATA_TABLE_ENTRY.SizeOfImage",
+    "_LIST_ENTRY.Blink",
+    "_LIST_ENTRY.Flink",
+    "_MI_HARDWARE_STATE.SystemNodeInformation",
+    "_MI_HARDWARE_STATE.SystemNodeNonPagedPool",
+    "_MI_SYSTEM_INFORMATION.Hardware",
+    "_MI_SYSTEM_NODE_INFORMATION.NonPagedPoolFirstVa",
+    "_MI_SYSTEM_NODE_INFORMATION.NonPagedPoolLastVa",
+    "_MI_SYSTEM_NODE_NONPAGED_POOL.NonPagedPoolFirstVa",
+    "_MI_SYSTEM_NODE_NONPAGED_POOL.NonPagedPoolLastVa",
+    "_OBJECT_HEADER.struct_size",
+    "_POOL_HEADER.struct_size",
+    "_UNICODE_STRING.Buffer",
+    "_UNICODE_STRING.MaximumLength",
+    "_UNLOADED_DRIVERS.CurrentTime",
+    "_UNLOADED_DRIVERS.EndAddress",
+    "_UNLOADED_DRIVERS.StartAddress",
+    "_UNLOADED_DRIVERS.struct_size",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemberChange {
+    Added,
+    Removed,
+    Moved,
+    Retyped,
+    MovedRetyped,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberDiff {
+    pub name: String,
+    // (type, offset) in each build, None where the member is not there
+    pub old: Option<(TypeInfo, u64)>,
+    pub new: Option<(TypeInfo, u64)>,
+    // lpus reads this member
+    pub used: bool,
+}
+
+fn type_name(type_info: &TypeInfo) -> String {
+    // The type as printed, less the `#<type index>` of anonymous types, which changes
+    // every build: `<unnamed-tag>#4711` -> `<unnamed-tag>`
+    let printed = type_info.to_string();
+    let mut name = String::with_capacity(printed.len());
+    let mut chars = printed.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '#' {
+            while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+impl MemberDiff {
+    pub fn change(&self) -> Option<MemberChange> {
+        // Types are compared as printed: name, array dimensions and bit positions
+        match (&self.old, &self.new) {
+            (None, Some(_)) => Some(MemberChange::Added),
+            (Some(_), None) => Some(MemberChange::Removed),
+            (Some((old_type, old_offset)), Some((new_type, new_offset))) => {
+                let moved = old_offset != new_offset;
+                let retyped = type_name(old_type) != type_name(new_type);
+                match (moved, retyped) {
+                    (true, true) => Some(MemberChange::MovedRetyped),
+                    (true, false) => Some(MemberChange::Moved),
+                    (false, true) => Some(MemberChange::Retyped),
+                    (false, false) => None,
+                }
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Display for MemberDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mark = if self.used { "*" } else { " " };
+        match (self.change(), &self.old, &self.new) {
+            (Some(MemberChange::Added), _, Some((t, offset))) => {
+                write!(f, "{} added   {} +0x{:x} {}", mark, self.name, offset, t)
+            }
+            (Some(MemberChange::Removed), Some((t, offset)), _) => {
+                write!(f, "{} removed {} +0x{:x} {}", mark, self.name, offset, t)
+            }
+            (Some(MemberChange::Moved), Some((_, old)), Some((_, new))) => {
+                write!(
+                    f,
+                    "{} moved   {} +0x{:x} -> +0x{:x}",
+                    mark, self.name, old, new
+                )
+            }
+            (Some(MemberChange::Retyped), Some((old, offset)), Some((new, _))) => write!(
+                f,
+                "{} retyped {} +0x{:x} {} -> {}",
+                mark, self.name, offset, old, new
+            ),
+            (Some(MemberChange::MovedRetyped), Some((old_t, old)), Some((new_t, new))) => write!(
+                f,
+                "{} moved   {} +0x{:x} -> +0x{:x}, {} -> {}",
+                mark, self.name, old, new, old_t, new_t
+            ),
+            _ => write!(f, "{} same    {}", mark, self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StructDiff {
+    pub name: String,
+    // None in the build without the struct
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    // only the members that changed, empty for an added or a removed struct
+    pub members: Vec<MemberDiff>,
+    // lpus reads a member that changed, or the size and it changed
+    pub used: bool,
+}
+
+impl fmt::Display for StructDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let used = if self.used { ", used by lpus" } else { "" };
+        match (self.old_size, self.new_size) {
+            (None, Some(size)) => write!(f, "{} added, 0x{:x} bytes{}", self.name, size, used)?,
+            (Some(_), None) => write!(f, "{} removed{}", self.name, used)?,
+            (Some(old), Some(new)) if old != new => {
+                write!(f, "{} 0x{:x} -> 0x{:x} bytes{}", self.name, old, new, used)?
+            }
+            _ => write!(f, "{}{}", self.name, used)?,
+        }
+        for member in &self.members {
+            write!(f, "\n  {}", member)?;
+        }
+        Ok(())
+    }
+}
+
+// (struct, member) pairs lpus reads, and the structs whose size it reads
+#[derive(Default)]
+struct UsedMembers {
+    members: HashSet<(String, String)>,
+    sizes: HashSet<String>,
+}
+
+impl UsedMembers {
+    fn add_path(&mut self, structs: &StructStore, path: &str) {
+        // Every struct the path goes through: "_FILE_OBJECT.DeviceObject.DriverObject"
+        // reads _FILE_OBJECT.DeviceObject, then _DEVICE_OBJECT.DriverObject
+        let path = path.replace("->", ".");
+        let mut parts = path.split('.');
+        let mut parent = match parts.next() {
+            Some(parent) => parent.to_string(),
+            None => return,
+        };
+        for member in parts {
+            if member == "struct_size" {
+                self.sizes.insert(parent);
+                return;
+            }
+            let member = member.split('[').next().unwrap_or(member);
+            self.members.insert((parent.clone(), member.to_string()));
+            let mut t = match structs
+                .get(&parent)
+                .and_then(|info| info.members.get(member))
+            {
+                Some((t, _)) => t,
+                None => return,
+            };
+            // `.` follows a pointer as decompose() does, and goes into array elements
+            while let Some(inner) = t.pointee().or_else(|| t.array_element().map(|(e, _)| e)) {
+                t = inner;
+            }
+            parent = match t.record_name() {
+                Some(name) => name.to_string(),
+                None => return,
+            };
+        }
+    }
+}
+
+pub fn diff_structs(old: &StructStore, new: &StructStore, paths: &[&str]) -> Vec<StructDiff> {
+    // The structs that differ between two builds, those `paths` read first, then by name.
+    // Anonymous types are named by type index, which changes every build: they are
+    // compared through the parents their members are flattened into
+    let mut used = UsedMembers::default();
+    for path in paths {
+        used.add_path(old, path);
+        used.add_path(new, path);
+    }
+
+    let names: BTreeSet<&String> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|name| !name.contains('#'))
+        .collect();
+    let mut diffs = Vec::new();
+    for name in names {
+        let (old_info, new_info) = (old.get(name), new.get(name));
+        let mut members = Vec::new();
+        if let (Some(old_info), Some(new_info)) = (old_info, new_info) {
+            let member_names: BTreeSet<&String> = old_info
+                .members
+                .keys()
+                .chain(new_info.members.keys())
+                .collect();
+            for member in member_names {
+                let diff = MemberDiff {
+                    name: member.to_string(),
+                    old: old_info.members.get(member).cloned(),
+                    new: new_info.members.get(member).cloned(),
+                    used: used
+                        .members
+                        .contains(&(name.to_string(), member.to_string())),
+                };
+                if diff.change().is_some() {
+                    members.push(diff);
+                }
+            }
+            // as dt lists them, by offset in the new build
+            members.sort_by_key(|m| m.new.as_ref().or(m.old.as_ref()).map_or(0, |(_, o)| *o));
+        }
+
+        let old_size = old_info.map(|info| info.size);
+        let new_size = new_info.map(|info| info.size);
+        if members.is_empty() && old_size == new_size {
+            continue;
+        }
+        let size_used = used.sizes.contains(name.as_str()) && old_size != new_size;
+        let member_used = used.members.iter().any(|(parent, _)| parent == name);
+        let used = size_used
+            || members.iter().any(|m| m.used)
+            || (member_used && (old_info.is_none() || new_info.is_none()));
+        diffs.push(StructDiff {
+            name: name.to_string(),
+            old_size,
+            new_size,
+            members,
+            used,
+        });
+    }
+    diffs.sort_by_key(|diff| !diff.used);
+    diffs
+}
+
+pub fn diff_pdb(old: &PdbStore, new: &PdbStore) -> Vec<StructDiff> {
+    // Kernel structs of two builds, e.g. the ntkrnlmp.pdb of 18362 and of 19041
+    diff_structs(&old.structs, &new.structs, LPUS_PATHS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::type_info::StructInfo;
+
+    // The sources LPUS_PATHS is copied from
+    const SOURCES: &[(&str, &str)] = &[
+        ("lib.rs", include_str!("lib.rs")),
+        ("object.rs", include_str!("object.rs")),
+        ("ioctl_protocol.rs", include_str!("ioctl_protocol.rs")),
+        ("memory/mod.rs", include_str!("memory/mod.rs")),
+    ];
+
+    fn member_paths(source: &str) -> Vec<&str> {
+        // "_STRUCT.Member" literals outside of comments
+        source
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("//"))
+            .flat_map(|line| line.split('"').skip(1).step_by(2))
+            .filter(|literal| {
+                let struct_name = literal.split(['.', '-']).next().unwrap_or("");
+                struct_name.len() > 1
+                    && struct_name.starts_with('_')
+                    && struct_name
+                        .chars()
+                        .all(|c| c == '_' || c.is_ascii_uppercase() || c.is_ascii_digit())
+                    && (literal.contains('.') || literal.contains("->"))
+            })
+            .collect()
+    }
+
+    fn thread_store(anonymous: &str, state: TypeInfo) -> StructStore {
+        // _KTHREAD with a member of an anonymous union and a member of type `state`
+        let union_type = TypeInfo::Union {
+            name: anonymous.to_string(),
+            size: 8,
+        };
+        let mut thread = StructInfo::new(0x10, false);
+        thread.members.insert("u1".to_string(), (union_type, 0));
+        thread.members.insert("State".to_string(), (state, 8));
+        let mut union_info = StructInfo::new(8, true);
+        union_info
+            .members
+            .insert("Value".to_string(), (primitive("U64", 8), 0));
+
+        let mut store = StructStore::new();
+        store.insert("_KTHREAD".to_string(), thread);
+        store.insert(anonymous.to_string(), union_info);
+        store
+    }
+
+    fn primitive(name: &str, size: u64) -> TypeInfo {
+        TypeInfo::Primitive {
+            name: name.to_string(),
+            size,
+        }
+    }
+
+    #[test]
+    fn anonymous_type_index() {
+        // The same layout, the anonymous union got another type index
+        let old = thread_store("<unnamed-tag>#4711", primitive("UChar", 1));
+        let new = thread_store("<unnamed-tag>#5120", primitive("UChar", 1));
+        assert!(diff_structs(&old, &new, LPUS_PATHS).is_empty());
+
+        let new = thread_store("__unnamed#5120", primitive("U32", 4));
+        let diffs = diff_structs(&old, &new, LPUS_PATHS);
+        assert_eq!(diffs.len(), 1);
+        let changes: Vec<_> = diffs[0]
+            .members
+            .iter()
+            .map(|m| (m.name.as_str(), m.change()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("u1", Some(MemberChange::Retyped)),
+                ("State", Some(MemberChange::Retyped))
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_cover_the_sources() {
+        // A path is covered by itself or by a longer path through it, a list walk
+        // reads `.Flink` of the member it is given
+        let mut missing = Vec::new();
+        for (file, source) in SOURCES {
+            let paths = member_paths(source);
+            assert!(!paths.is_empty(), "no member path found in {}", file);
+            for path in paths {
+                let covered = LPUS_PATHS.iter().any(|p| {
+                    p.strip_prefix(path)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+                });
+                if !covered {
+                    missing.push(format!("{} in {}", path, file));
+                }
+            }
+        }
+        assert!(missing.is_empty(), "not in LPUS_PATHS: {:?}", missing);
+    }
+}
@@ -2,13 +2,30 @@ extern crate app_dirs;
 extern crate chrono;
 
 pub mod address;
+pub mod bindings;
 pub mod commands;
+#[cfg(windows)]
+pub mod driver_session;
+#[cfg(windows)]
 pub mod driver_state;
+pub mod error;
 pub mod ioctl_protocol;
+pub mod ioctl_session;
+pub mod isf;
+pub mod layout_diff;
+pub mod memory;
 pub mod object;
+pub mod overlay;
 pub mod pte_scan;
 pub mod pdb_store;
+pub mod pe;
+pub mod symbol_cache;
+pub mod symbol_index;
+pub mod symbol_path;
+pub mod type_info;
 pub mod utils;
+pub mod version;
+#[cfg(windows)]
 pub mod windows;
 
 use app_dirs::AppInfo;
@@ -18,7 +35,11 @@ use std::error::Error;
 use std::str::from_utf8;
 
 use address::Address;
-use driver_state::{DriverState, ScannerSignal};
+use error::LpusError;
+use memory::{MemoryReader, MemoryReaderExt, ScannerSignal};
+use pdb_store::{ModuleSymbols, PdbStore};
+use pe::CodeViewInfo;
+use symbol_path::SymbolPath;
 use object::*;
 
 type BoxResult<T> = Result<T, Box<dyn Error>>;
@@ -81,6 +102,7 @@ pub fn get_irp_name(idx: usize) -> String {
 }
 
 fn get_device_type(typ: u32) -> String {
+    // FILE_DEVICE_* are #defines in wdm.h, the PDB has no enum for them to follow
     match typ {
         0x00000027 => "FILE_DEVICE_8042_PORT",
         0x00000032 => "FILE_DEVICE_ACPI",
@@ -145,8 +167,8 @@ fn get_device_type(typ: u32) -> String {
     .to_string()
 }
 
-pub fn scan_eprocess(driver: &DriverState) -> BoxResult<Vec<Value>> {
-    let mut result: Vec<Value> = Vec::new();
+pub fn scan_eprocess(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
+    let mut found: Vec<Address> = Vec::new();
     let tag = if driver.use_old_tag() {
         b"Pro\xe3"
     } else {
@@ -155,7 +177,7 @@ pub fn scan_eprocess(driver: &DriverState) -> BoxResult<Vec<Value>> {
     driver.scan_pool(tag, "_EPROCESS", |pool_addr, header, data_addr| {
         let chunk_size = (header[2] as u64) * 16u64;
 
-        let eprocess_size = driver.pdb_store.get_offset_r("_EPROCESS.struct_size")?;
+        let eprocess_size = driver.pdb_store().get_offset_r("_EPROCESS.struct_size")?;
 
         let eprocess_valid_start = &data_addr;
         let eprocess_valid_end = (pool_addr.clone() + chunk_size) - eprocess_size;
@@ -163,7 +185,7 @@ pub fn scan_eprocess(driver: &DriverState) -> BoxResult<Vec<Value>> {
 
         while try_eprocess_ptr <= eprocess_valid_end {
             let create_time: u64 = driver.decompose(&try_eprocess_ptr, "_EPROCESS.CreateTime")?;
-            if driver.windows_ffi.valid_process_time(create_time) {
+            if driver.valid_process_time(create_time) {
                 break;
             }
             try_eprocess_ptr += 0x4; // search exhaustively
@@ -172,13 +194,13 @@ pub fn scan_eprocess(driver: &DriverState) -> BoxResult<Vec<Value>> {
             return Ok(ScannerSignal::SearchNext);
         }
 
-        result.push(make_eprocess(driver, &try_eprocess_ptr)?);
+        found.push(try_eprocess_ptr);
         Ok(ScannerSignal::FoundStruct)
     })?;
-    Ok(result)
+    Ok(make_objects(driver, &found, "_EPROCESS", make_eprocess))
 }
 
-pub fn find_eprocess_by_name(driver: &DriverState, expected: &String, find_one: bool) -> BoxResult<Vec<Value>> {
+pub fn find_eprocess_by_name(driver: &dyn MemoryReader, expected: &String, find_one: bool) -> BoxResult<Vec<Value>> {
     // - param: find_one => only return the first process that satisfies the name constraint
 
     let mut result: Vec<Value> = Vec::new();
@@ -191,7 +213,7 @@ pub fn find_eprocess_by_name(driver: &DriverState, expected: &String, find_one:
     driver.scan_pool(tag, "_EPROCESS", |pool_addr, header, data_addr| {
         let chunk_size = (header[2] as u64) * 16u64;
 
-        let eprocess_size = driver.pdb_store.get_offset_r("_EPROCESS.struct_size")?;
+        let eprocess_size = driver.pdb_store().get_offset_r("_EPROCESS.struct_size")?;
 
         let eprocess_valid_start = &data_addr;
         let eprocess_valid_end = (pool_addr.clone() + chunk_size) - eprocess_size;
@@ -199,7 +221,7 @@ pub fn find_eprocess_by_name(driver: &DriverState, expected: &String, find_one:
 
         while try_eprocess_ptr <= eprocess_valid_end {
             let create_time: u64 = driver.decompose(&try_eprocess_ptr, "_EPROCESS.CreateTime")?;
-            if driver.windows_ffi.valid_process_time(create_time) {
+            if driver.valid_process_time(create_time) {
                 break;
             }
             try_eprocess_ptr += 0x4;
@@ -216,6 +238,7 @@ pub fn find_eprocess_by_name(driver: &DriverState, expected: &String, find_one:
         };
 
         if proc_name == *expected {
+            prefetch_objects(driver, std::slice::from_ref(&try_eprocess_ptr), "_EPROCESS");
             result.push(make_eprocess(driver, &try_eprocess_ptr)?);
 
             if find_one {
@@ -228,7 +251,7 @@ pub fn find_eprocess_by_name(driver: &DriverState, expected: &String, find_one:
 
 }
 
-pub fn find_eprocess_by_pid(driver: &DriverState, expected: u64) -> BoxResult<Vec<Value>> {
+pub fn find_eprocess_by_pid(driver: &dyn MemoryReader, expected: u64) -> BoxResult<Vec<Value>> {
     let mut result: Vec<Value> = Vec::new();
     let tag = if driver.use_old_tag() {
         b"Pro\xe3"
@@ -239,7 +262,7 @@ pub fn find_eprocess_by_pid(driver: &DriverState, expected: u64) -> BoxResult<Ve
     driver.scan_pool(tag, "_EPROCESS", |pool_addr, header, data_addr| {
         let chunk_size = (header[2] as u64) * 16u64;
 
-        let eprocess_size = driver.pdb_store.get_offset_r("_EPROCESS.struct_size")?;
+        let eprocess_size = driver.pdb_store().get_offset_r("_EPROCESS.struct_size")?;
 
         let eprocess_valid_start = &data_addr;
         let eprocess_valid_end = (pool_addr.clone() + chunk_size) - eprocess_size;
@@ -247,7 +270,7 @@ pub fn find_eprocess_by_pid(driver: &DriverState, expected: u64) -> BoxResult<Ve
 
         while try_eprocess_ptr <= eprocess_valid_end {
             let create_time: u64 = driver.decompose(&try_eprocess_ptr, "_EPROCESS.CreateTime")?;
-            if driver.windows_ffi.valid_process_time(create_time) {
+            if driver.valid_process_time(create_time) {
                 break;
             }
             try_eprocess_ptr += 0x4;
@@ -259,6 +282,7 @@ pub fn find_eprocess_by_pid(driver: &DriverState, expected: u64) -> BoxResult<Ve
         let pid : u64 = driver.decompose(&try_eprocess_ptr, "_EPROCESS.UniqueProcessId")?;
 
         if pid == expected {
+            prefetch_objects(driver, std::slice::from_ref(&try_eprocess_ptr), "_EPROCESS");
             result.push(make_eprocess(driver, &try_eprocess_ptr)?);
             return Ok(ScannerSignal::StopScan);
         }
@@ -268,7 +292,7 @@ pub fn find_eprocess_by_pid(driver: &DriverState, expected: u64) -> BoxResult<Ve
 
 }
 
-pub fn scan_file(driver: &DriverState) -> BoxResult<Vec<Value>> {
+pub fn scan_file(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
     let mut result: Vec<Value> = Vec::new();
 
     let tag = if driver.use_old_tag() {
@@ -279,7 +303,7 @@ pub fn scan_file(driver: &DriverState) -> BoxResult<Vec<Value>> {
     driver.scan_pool(tag, "_FILE_OBJECT", |pool_addr, header, data_addr| {
         let chunk_size = (header[2] as u64) * 16u64;
 
-        let fob_size = driver.pdb_store.get_offset_r("_FILE_OBJECT.struct_size")?;
+        let fob_size = driver.pdb_store().get_offset_r("_FILE_OBJECT.struct_size")?;
         let valid_end = (pool_addr.clone() + chunk_size) - fob_size;
         let mut try_ptr = data_addr;
 
@@ -347,8 +371,8 @@ pub fn scan_file(driver: &DriverState) -> BoxResult<Vec<Value>> {
     Ok(result)
 }
 
-pub fn scan_ethread(driver: &DriverState) -> BoxResult<Vec<Value>> {
-    let mut result: Vec<Value> = Vec::new();
+pub fn scan_ethread(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
+    let mut found: Vec<Address> = Vec::new();
 
     let tag = if driver.use_old_tag() {
         b"Thr\xe5"
@@ -359,10 +383,10 @@ pub fn scan_ethread(driver: &DriverState) -> BoxResult<Vec<Value>> {
         let chunk_size = (header[2] as u64) * 16u64;
 
         let object_header_size = driver
-            .pdb_store
+            .pdb_store()
             .get_offset_r("_OBJECT_HEADER.struct_size")?;
-        let header_size = driver.pdb_store.get_offset_r("_POOL_HEADER.struct_size")?;
-        let ethread_size = driver.pdb_store.get_offset_r("_ETHREAD.struct_size")?;
+        let header_size = driver.pdb_store().get_offset_r("_POOL_HEADER.struct_size")?;
+        let ethread_size = driver.pdb_store().get_offset_r("_ETHREAD.struct_size")?;
         let ethread_valid_start = &data_addr;
         let ethread_valid_end = (pool_addr.clone() + chunk_size) - ethread_size;
         let mut try_ethread_ptr = ethread_valid_start.clone();
@@ -372,7 +396,7 @@ pub fn scan_ethread(driver: &DriverState) -> BoxResult<Vec<Value>> {
         } else {
             while try_ethread_ptr <= ethread_valid_end {
                 let create_time: u64 = driver.decompose(&try_ethread_ptr, "_ETHREAD.CreateTime")?;
-                if driver.windows_ffi.valid_process_time(create_time) {
+                if driver.valid_process_time(create_time) {
                     break;
                 }
                 try_ethread_ptr += 0x4; // search exhaustively
@@ -382,15 +406,15 @@ pub fn scan_ethread(driver: &DriverState) -> BoxResult<Vec<Value>> {
             }
         }
 
-        result.push(make_ethread(driver, &try_ethread_ptr)?);
+        found.push(try_ethread_ptr);
         Ok(ScannerSignal::FoundStruct)
     })?;
 
-    Ok(result)
+    Ok(make_objects(driver, &found, "_ETHREAD", make_ethread))
 }
 
 // Unstable, do not use
-// pub fn scan_mutant(driver: &DriverState) -> BoxResult<Vec<Value>> {
+// pub fn scan_mutant(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
 //     let mut result: Vec<Value> = Vec::new();
 //
 //     let ntosbase = driver.get_kernel_base();
@@ -402,7 +426,7 @@ pub fn scan_ethread(driver: &DriverState) -> BoxResult<Vec<Value>> {
 //     driver.scan_pool(tag, "_KMUTANT", |pool_addr, header, data_addr| {
 //         let chunk_size = (header[2] as u64) * 16u64;
 //
-//         let kmutant_size = driver.pdb_store.get_offset_r("_KMUTANT.struct_size")?;
+//         let kmutant_size = driver.pdb_store().get_offset_r("_KMUTANT.struct_size")?;
 //
 //         let kmutant_valid_start = data_addr;
 //         let kmutant_valid_end = (pool_addr.clone() + chunk_size) - kmutant_size;
@@ -449,8 +473,8 @@ pub fn scan_ethread(driver: &DriverState) -> BoxResult<Vec<Value>> {
 //     Ok(result)
 // }
 
-pub fn scan_driver(driver: &DriverState) -> BoxResult<Vec<Value>> {
-    let mut result: Vec<Value> = Vec::new();
+pub fn scan_driver(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
+    let mut found: Vec<Address> = Vec::new();
 
     let tag = if driver.use_old_tag() {
         b"Dri\xf6"
@@ -461,7 +485,7 @@ pub fn scan_driver(driver: &DriverState) -> BoxResult<Vec<Value>> {
         let chunk_size = (header[2] as u64) * 16u64;
 
         let dob_size = driver
-            .pdb_store
+            .pdb_store()
             .get_offset_r("_DRIVER_OBJECT.struct_size")?;
         let valid_end = (pool_addr.clone() + chunk_size) - dob_size;
         let mut try_ptr = data_addr;
@@ -477,74 +501,166 @@ pub fn scan_driver(driver: &DriverState) -> BoxResult<Vec<Value>> {
         if try_ptr > valid_end {
             return Ok(ScannerSignal::SearchNext);
         }
-        result.push(make_driver(driver, &try_ptr)?);
+        found.push(try_ptr);
         Ok(ScannerSignal::FoundStruct)
     })?;
 
-    Ok(result)
+    Ok(make_objects(driver, &found, "_DRIVER_OBJECT", make_driver))
 }
 
-pub fn scan_kernel_module(driver: &DriverState) -> BoxResult<Vec<Value>> {
-    let mut result: Vec<Value> = Vec::new();
+pub fn scan_kernel_module(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
+    let mut found: Vec<Address> = Vec::new();
 
     driver.scan_pool(
         b"MmLd",
         "_LDR_DATA_TABLE_ENTRY",
         |_pool_addr, _, data_addr| {
             // By reversing, this structure does not have any header
-            result.push(make_ldr(driver, &data_addr)?);
+            found.push(data_addr);
             Ok(ScannerSignal::FoundStruct)
         },
     )?;
 
-    Ok(result)
+    Ok(make_objects(driver, &found, "_LDR_DATA_TABLE_ENTRY", make_ldr))
 }
 
-pub fn traverse_loadedmodulelist(driver: &DriverState) -> BoxResult<Vec<Value>> {
+pub fn traverse_loadedmodulelist(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
     let ntosbase = driver.get_kernel_base();
-    let module_list_head = ntosbase + driver.pdb_store.get_offset_r("PsLoadedModuleList")?;
+    let module_list_head = ntosbase + driver.pdb_store().get_offset_r("PsLoadedModuleList")?;
 
-    let result = make_list_entry(
+    let entries = make_list_entry(
         driver,
         module_list_head.clone(),
         "_LDR_DATA_TABLE_ENTRY.InLoadOrderLinks",
-    )?
-    .iter()
-    .map(|x| make_ldr(driver, &x).unwrap_or(json!({})))
-    .collect();
+    )?;
+    prefetch_objects(driver, &entries, "_LDR_DATA_TABLE_ENTRY");
+    let result = entries
+        .iter()
+        .map(|x| make_ldr(driver, x).unwrap_or(json!({})))
+        .collect();
 
     Ok(result)
 }
 
-// dx Debugger.Utility.Collections.FromListEntry( *(nt!_LIST_ENTRY*)&(nt!PsActiveProcessHead), "nt!_EPROCESS", "ActiveProcessLinks")
-pub fn traverse_activehead(driver: &DriverState) -> BoxResult<Vec<Value>> {
-    let mut result: Vec<Value> = Vec::new();
-
+pub fn loaded_modules(driver: &dyn MemoryReader) -> BoxResult<Vec<(u64, u64, String)>> {
+    // (base, size, lowercase name without extension) of each module in PsLoadedModuleList
     let ntosbase = driver.get_kernel_base();
-    let process_list_head = ntosbase + driver.pdb_store.get_offset_r("PsActiveProcessHead")?;
-    let eprocess_listentry_offset = driver
-        .pdb_store
-        .get_offset_r("_EPROCESS.ActiveProcessLinks")?;
+    let module_list_head = ntosbase + driver.pdb_store().get_offset_r("PsLoadedModuleList")?;
+    let entries = make_list_entry(
+        driver,
+        module_list_head,
+        "_LDR_DATA_TABLE_ENTRY.InLoadOrderLinks",
+    )?;
+    prefetch_objects(driver, &entries, "_LDR_DATA_TABLE_ENTRY");
+    let mut modules = Vec::new();
+    for entry in entries {
+        let dllbase: u64 = driver.decompose(&entry, "_LDR_DATA_TABLE_ENTRY.DllBase")?;
+        let size: u64 = driver.decompose(&entry, "_LDR_DATA_TABLE_ENTRY.SizeOfImage")?;
+        let basename_ptr = driver.address_of(&entry, "_LDR_DATA_TABLE_ENTRY.BaseDllName")?;
+        let basename = driver.get_unicode_string(basename_ptr).unwrap_or("".to_string());
+        let stem = basename.rsplitn(2, '.').last().unwrap_or("").to_lowercase();
+        modules.push((dllbase, size, stem));
+    }
+    Ok(modules)
+}
 
-    // TODO: make_list_entry
-    let mut ptr: u64 = driver.decompose(&process_list_head, "_LIST_ENTRY.Flink")?;
-    while ptr != process_list_head.address() {
-        let eprocess_ptr = Address::from_base(ptr - eprocess_listentry_offset);
-        result.push(make_eprocess(driver, &eprocess_ptr)?);
-        ptr = driver.decompose(&eprocess_ptr, "_EPROCESS.ActiveProcessLinks.Flink")?;
+pub fn module_base(driver: &dyn MemoryReader, module: &str) -> BoxResult<Address> {
+    // Load base of a module by name without extension, from PsLoadedModuleList
+    if pdb_store::is_kernel_module(module) {
+        return Ok(driver.get_kernel_base());
     }
+    loaded_modules(driver)?
+        .into_iter()
+        .find(|(_, _, stem)| stem.eq_ignore_ascii_case(module))
+        .map(|(dllbase, _, _)| Address::from_base(dllbase))
+        .ok_or_else(|| LpusError::ModuleNotFound(module.to_string()).into())
+}
 
-    Ok(result)
+pub fn module_codeview(driver: &dyn MemoryReader, module: &str) -> BoxResult<CodeViewInfo> {
+    // The PDB of a loaded module, from the debug directory of its image in memory
+    let base = module_base(driver, module)?;
+    pe::codeview_from_memory(driver, base.address())
+}
+
+pub fn fetch_module_symbols(
+    driver: &dyn MemoryReader,
+    module: &str,
+    symbol_path: &SymbolPath,
+) -> BoxResult<ModuleSymbols> {
+    // Symbols of a loaded driver without its file, hand them to PdbStore::add_module
+    let codeview = module_codeview(driver, module)?;
+    let pdb_path = symbol_path.find_pdb(&codeview.pdb_name, &codeview.guid, codeview.age)?;
+    pdb_store::parse_module_pdb(&pdb_path)
+}
+
+pub fn symbol_address(driver: &dyn MemoryReader, name: &str) -> BoxResult<Address> {
+    // Virtual address of "module!Symbol", or of a kernel symbol without the module
+    let offset = driver.pdb_store().get_offset_r(name)?;
+    let base = match pdb_store::split_module(name) {
+        (Some(module), _) => module_base(driver, module)?,
+        (None, _) => driver.get_kernel_base(),
+    };
+    Ok(base + offset)
+}
+
+// The modules in PsLoadedModuleList, read once to name many addresses
+pub struct Symbolizer<'a> {
+    pdb_store: &'a PdbStore,
+    // (base, size, name without extension)
+    modules: Vec<(u64, u64, String)>,
+}
+
+impl<'a> Symbolizer<'a> {
+    pub fn new(driver: &'a dyn MemoryReader) -> BoxResult<Self> {
+        Ok(Self {
+            pdb_store: driver.pdb_store(),
+            modules: loaded_modules(driver)?,
+        })
+    }
+
+    pub fn symbolize(&self, addr: u64) -> String {
+        // "nt!NtCreateFile+0x12", "module+0x1000" for a module without symbols loaded
+        // and the bare address outside of every module
+        let owner = self
+            .modules
+            .iter()
+            .find(|(base, size, _)| addr >= *base && addr < base + size);
+        match owner {
+            Some((base, _, name)) => {
+                let module = if pdb_store::is_kernel_module(name) { "nt" } else { name };
+                match self.pdb_store.symbolize_rva(module, addr - base) {
+                    Ok(Some(symbol)) => symbol,
+                    _ => format!("{}+0x{:x}", module, addr - base),
+                }
+            }
+            None => format!("0x{:x}", addr),
+        }
+    }
+}
+
+pub fn symbolize(driver: &dyn MemoryReader, addr: u64) -> BoxResult<String> {
+    // module!Symbol+0xNN of one address, use a Symbolizer for many
+    Ok(Symbolizer::new(driver)?.symbolize(addr))
+}
+
+// dx Debugger.Utility.Collections.FromListEntry( *(nt!_LIST_ENTRY*)&(nt!PsActiveProcessHead), "nt!_EPROCESS", "ActiveProcessLinks")
+pub fn traverse_activehead(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
+    let ntosbase = driver.get_kernel_base();
+    let process_list_head = ntosbase + driver.pdb_store().get_offset_r("PsActiveProcessHead")?;
+    let processes = make_list_entry(driver, process_list_head, "_EPROCESS.ActiveProcessLinks")?;
+
+    prefetch_objects(driver, &processes, "_EPROCESS");
+    processes.iter().map(|p| make_eprocess(driver, p)).collect()
 }
 
 // TODO: where is afd!
 // dx Debugger.Utility.Collections.FromListEntry( *(nt!_LIST_ENTRY*)&(afd!AfdEndpointListHead), "nt!_EPROCESS", "ActiveProcessLinks")
-// pub fn traverse_afdendpoint(driver: &DriverState) -> BoxResult<Vec<Value>> {
+// pub fn traverse_afdendpoint(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
 //     let mut result: Vec<Value> = Vec::new();
 //
 //     let ntosbase = driver.get_kernel_base();
-//     let process_list_head = ntosbase + driver.pdb_store.get_offset_r("PsActiveProcessHead")?;
-//     let eprocess_listentry_offset = driver.pdb_store.get_offset_r("_EPROCESS.ActiveProcessLinks")?;
+//     let process_list_head = ntosbase + driver.pdb_store().get_offset_r("PsActiveProcessHead")?;
+//     let eprocess_listentry_offset = driver.pdb_store().get_offset_r("_EPROCESS.ActiveProcessLinks")?;
 //
 //     let mut ptr: u64 = driver.decompose(&process_list_head, "_LIST_ENTRY.Flink")?;
 //     while ptr != process_list_head.address() {
@@ -580,35 +696,24 @@ pub fn traverse_activehead(driver: &DriverState) -> BoxResult<Vec<Value>> {
 // }
 
 // dx Debugger.Utility.Collections.FromListEntry( *(nt!_LIST_ENTRY*)&(nt!KiProcessListHead), "nt!_KPROCESS", "ProcessListEntry").Select( p => new {Process = (nt!_EPROCESS*)&p )
-pub fn traverse_kiprocesslist(driver: &DriverState) -> BoxResult<Vec<Value>> {
-    let mut result: Vec<Value> = Vec::new();
-
+pub fn traverse_kiprocesslist(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
     let ntosbase = driver.get_kernel_base();
-    let process_list_head = ntosbase + driver.pdb_store.get_offset_r("KiProcessListHead")?;
-    let eprocess_listentry_offset = driver
-        .pdb_store
-        .get_offset_r("_KPROCESS.ProcessListEntry")?;
-
-    // TODO: make_list_entry
-    let mut ptr: u64 = driver.decompose(&process_list_head, "_LIST_ENTRY.Flink")?;
-    while ptr != process_list_head.address() {
-        let eprocess_ptr = Address::from_base(ptr - eprocess_listentry_offset);
-        result.push(make_eprocess(driver, &eprocess_ptr)?);
-
-        ptr = driver.decompose(&eprocess_ptr, "_KPROCESS.ProcessListEntry.Flink")?;
-    }
+    let process_list_head = ntosbase + driver.pdb_store().get_offset_r("KiProcessListHead")?;
+    // _KPROCESS is the first member of _EPROCESS
+    let processes = make_list_entry(driver, process_list_head, "_KPROCESS.ProcessListEntry")?;
 
-    Ok(result)
+    prefetch_objects(driver, &processes, "_EPROCESS");
+    processes.iter().map(|p| make_eprocess(driver, p)).collect()
 }
 
 // dx Debugger.Utility.Collections.FromListEntry(*(nt!_LIST_ENTRY*)&nt!HandleTableListHead, "nt!_HANDLE_TABLE", "HandleTableList").Where(h => h.QuotaProcess != 0).Select( qp => new {Process= qp.QuotaProcess} )
-pub fn traverse_handletable(driver: &DriverState) -> BoxResult<Vec<Value>> {
-    let mut result: Vec<Value> = Vec::new();
+pub fn traverse_handletable(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
+    let mut processes: Vec<Address> = Vec::new();
 
     let ntosbase = driver.get_kernel_base();
-    let process_list_head = ntosbase + driver.pdb_store.get_offset_r("HandleTableListHead")?;
+    let process_list_head = ntosbase + driver.pdb_store().get_offset_r("HandleTableListHead")?;
     let handle_list_offset = driver
-        .pdb_store
+        .pdb_store()
         .get_offset_r("_HANDLE_TABLE.HandleTableList")?;
 
     let mut ptr: u64 = driver.decompose(&process_list_head, "_LIST_ENTRY.Flink")?;
@@ -617,32 +722,34 @@ pub fn traverse_handletable(driver: &DriverState) -> BoxResult<Vec<Value>> {
         let quota_process: u64 = driver.decompose(&handle_ptr, "_HANDLE_TABLE.QuotaProcess")?;
 
         if quota_process != 0 {
-            let eprocess_ptr = Address::from_base(quota_process);
-            result.push(make_eprocess(driver, &eprocess_ptr)?);
+            processes.push(Address::from_base(quota_process));
         }
 
         ptr = driver.decompose(&handle_ptr, "_HANDLE_TABLE.HandleTableList.Flink")?;
     }
 
-    Ok(result)
+    prefetch_objects(driver, &processes, "_EPROCESS");
+    processes.iter().map(|p| make_eprocess(driver, p)).collect()
 }
 
-pub fn traverse_unloadeddrivers(driver: &DriverState) -> BoxResult<Vec<Value>> {
+pub fn traverse_unloadeddrivers(driver: &dyn MemoryReader) -> BoxResult<Vec<Value>> {
     let mut result: Vec<Value> = Vec::new();
     let ntosbase = driver.get_kernel_base();
-    let unload_array_ptr = ntosbase.clone() + driver.pdb_store.get_offset_r("MmUnloadedDrivers")?;
+    let unload_array_ptr = ntosbase.clone() + driver.pdb_store().get_offset_r("MmUnloadedDrivers")?;
     let num_unload_ptr =
-        ntosbase.clone() + driver.pdb_store.get_offset_r("MmLastUnloadedDriver")?;
+        ntosbase.clone() + driver.pdb_store().get_offset_r("MmLastUnloadedDriver")?;
 
-    let unload_array = driver.deref_addr_new::<u64>(unload_array_ptr.address());
+    let unload_array = driver.try_deref_addr::<u64>(unload_array_ptr.address())?;
     if unload_array == 0 {
         return Err("The unload driver list pointer is null".into());
     }
 
     // by reversing MmLocateUnloadedDriver
-    let num_unload = driver.deref_addr_new::<u32>(num_unload_ptr.address()) as u64;
+    let num_unload = driver.try_deref_addr::<u32>(num_unload_ptr.address())? as u64;
     let bound = if num_unload > 0x32 { 0x32 } else { num_unload };
-    let drivers = (0..bound).map(|i| Address::from_base(unload_array + (i * 0x28)));
+    // the entry size is from overlays/default.json, or an overlay for this build
+    let stride = driver.pdb_store().get_offset_r("_UNLOADED_DRIVERS.struct_size")?;
+    let drivers = (0..bound).map(|i| Address::from_base(unload_array + (i * stride)));
 
     for driver_addr in drivers {
         let name = driver
@@ -667,15 +774,15 @@ pub fn traverse_unloadeddrivers(driver: &DriverState) -> BoxResult<Vec<Value>> {
     Ok(result)
 }
 
-pub fn ssdt_table(driver: &DriverState) -> BoxResult<Vec<u64>> {
+pub fn ssdt_table(driver: &dyn MemoryReader) -> BoxResult<Vec<u64>> {
     // https://github.com/volatilityfoundation/volatility3/blob/master/volatility/framework/plugins/windows/ssdt.py
     let ntosbase = driver.get_kernel_base();
-    let servicetable = ntosbase.clone() + driver.pdb_store.get_offset_r("KiServiceTable")?;
-    let servicelimit_ptr = ntosbase.clone() + driver.pdb_store.get_offset_r("KiServiceLimit")?;
+    let servicetable = ntosbase.clone() + driver.pdb_store().get_offset_r("KiServiceTable")?;
+    let servicelimit_ptr = ntosbase.clone() + driver.pdb_store().get_offset_r("KiServiceLimit")?;
 
-    let servicelimit = driver.deref_addr_new::<u32>(servicelimit_ptr.address()) as u64;
+    let servicelimit = driver.try_deref_addr::<u32>(servicelimit_ptr.address())? as u64;
     let ssdt: Vec<u64> = driver
-        .deref_array::<i32>(&servicetable, servicelimit)
+        .deref_array::<i32>(&servicetable, servicelimit)?
         .iter()
         .map(|entry| {
             // the entry can be negative, we need to do calculation using signed int
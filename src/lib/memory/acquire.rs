@@ -0,0 +1,364 @@
+use chrono::Local;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::crash_dump::{CrashDumpHeader, DumpType};
+use super::{MemoryReader, MemoryReaderExt};
+use crate::address::Address;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+const PAGE_SIZE: u64 = 0x1000;
+const CHUNK_SIZE: u64 = 0x100000;
+// sanity limit for the number of runs read from MmPhysicalMemoryBlock
+const MAX_PHYSICAL_RUNS: u32 = 0x400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcquireFormat {
+    // file offset == physical address, the gaps between ranges are zero
+    Raw,
+    // a full crash dump (PAGEDU64) with the ranges as its run list
+    CrashDump,
+}
+
+// Hash the output while it is written
+struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn write_all(&mut self, buf: &[u8]) -> BoxResult<()> {
+        self.inner.write_all(buf)?;
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write_zeros(&mut self, mut len: u64) -> BoxResult<()> {
+        let zeros = vec![0u8; CHUNK_SIZE as usize];
+        while len > 0 {
+            let n = len.min(CHUNK_SIZE);
+            self.write_all(&zeros[..n as usize])?;
+            len -= n;
+        }
+        Ok(())
+    }
+}
+
+pub fn physical_memory_ranges(driver: &dyn MemoryReader) -> BoxResult<Vec<(u64, u64)>> {
+    // (start, length) of every run in MmPhysicalMemoryBlock, a PHYSICAL_MEMORY_DESCRIPTOR*
+    let ntosbase = driver.get_kernel_base();
+    let pdb = driver.pdb_store();
+    let block = Address::from_ptr(ntosbase + pdb.get_offset_r("MmPhysicalMemoryBlock")?);
+    let number_of_runs: u32 =
+        driver.decompose(&block, "_PHYSICAL_MEMORY_DESCRIPTOR.NumberOfRuns")?;
+    if number_of_runs == 0 || number_of_runs > MAX_PHYSICAL_RUNS {
+        return Err(format!("Invalid number of physical memory runs: {}", number_of_runs).into());
+    }
+    let first_run = driver.address_of(&block, "_PHYSICAL_MEMORY_DESCRIPTOR.Run")?;
+    let run_size = pdb.get_offset_r("_PHYSICAL_MEMORY_RUN.struct_size")?;
+
+    let mut ranges = Vec::new();
+    for i in 0..number_of_runs as u64 {
+        let run = Address::from_base(first_run + i * run_size);
+        let base_page: u64 = driver.decompose(&run, "_PHYSICAL_MEMORY_RUN.BasePage")?;
+        let page_count: u64 = driver.decompose(&run, "_PHYSICAL_MEMORY_RUN.PageCount")?;
+        ranges.push((base_page * PAGE_SIZE, page_count * PAGE_SIZE));
+    }
+    ranges.sort();
+    check_ranges(&ranges)?;
+    Ok(ranges)
+}
+
+fn check_ranges(ranges: &[(u64, u64)]) -> BoxResult<()> {
+    // Sorted runs that do not overlap, the raw layout writes each one at its address
+    let mut end = 0;
+    for &(start, length) in ranges {
+        if start < end {
+            return Err(format!(
+                "Physical memory run at 0x{:x} overlaps the run before it",
+                start
+            )
+            .into());
+        }
+        end = start
+            .checked_add(length)
+            .ok_or_else(|| format!("Physical memory run at 0x{:x} is too long", start))?;
+    }
+    Ok(())
+}
+
+fn copy_range<W: Write>(
+    driver: &dyn MemoryReader,
+    (start, length): (u64, u64),
+    writer: &mut HashWriter<W>,
+    errors: &mut Vec<(u64, u64)>,
+) -> BoxResult<()> {
+    // Unreadable pages are written as zero and recorded in `errors`
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut addr = start;
+    while addr < start + length {
+        let len = (start + length - addr).min(CHUNK_SIZE) as usize;
+        let chunk = &mut buf[..len];
+        if driver.read_physical(addr, chunk) != len {
+            // retry page by page to keep as much as possible
+            for (i, page) in chunk.chunks_mut(PAGE_SIZE as usize).enumerate() {
+                let page_addr = addr + i as u64 * PAGE_SIZE;
+                if driver.read_physical(page_addr, page) == page.len() {
+                    continue;
+                }
+                for b in page.iter_mut() {
+                    *b = 0;
+                }
+                match errors.last_mut() {
+                    Some((error_start, error_length))
+                        if *error_start + *error_length == page_addr =>
+                    {
+                        *error_length += page.len() as u64
+                    }
+                    _ => errors.push((page_addr, page.len() as u64)),
+                }
+            }
+        }
+        writer.write_all(chunk)?;
+        addr += len as u64;
+    }
+    Ok(())
+}
+
+fn write_ranges<W: Write>(
+    driver: &dyn MemoryReader,
+    ranges: &[(u64, u64)],
+    format: AcquireFormat,
+    writer: &mut HashWriter<W>,
+) -> BoxResult<Vec<(u64, u64)>> {
+    // Copy every run after the header, return the unreadable ranges
+    let mut errors = Vec::new();
+    for &(start, length) in ranges.iter() {
+        println!("Acquire 0x{:x} - 0x{:x}", start, start + length);
+        if format == AcquireFormat::Raw && writer.written < start {
+            let gap = start - writer.written;
+            writer.write_zeros(gap)?;
+        }
+        copy_range(driver, (start, length), writer, &mut errors)?;
+    }
+    Ok(errors)
+}
+
+fn crash_dump_header(driver: &dyn MemoryReader, build_number: u32) -> BoxResult<CrashDumpHeader> {
+    let ntosbase = driver.get_kernel_base().address();
+    let pdb = driver.pdb_store();
+    let system_process = Address::from_ptr(Address::from_base(
+        ntosbase + pdb.get_offset_r("PsInitialSystemProcess")?,
+    ));
+    Ok(CrashDumpHeader {
+        dump_type: DumpType::Full,
+        build_number,
+        directory_table_base: driver
+            .decompose(&system_process, "_EPROCESS.Pcb.DirectoryTableBase")?,
+        pfn_database: driver.try_deref_addr(ntosbase + pdb.get_offset_r("MmPfnDatabase")?)?,
+        ps_loaded_module_list: ntosbase + pdb.get_offset_r("PsLoadedModuleList")?,
+        ps_active_process_head: ntosbase + pdb.get_offset_r("PsActiveProcessHead")?,
+        kd_debugger_data_block: ntosbase + pdb.get_offset_r("KdDebuggerDataBlock")?,
+        number_processors: driver
+            .try_deref_addr(ntosbase + pdb.get_offset_r("KeNumberProcessors")?)?,
+        bugcheck_code: 0,
+        bugcheck_parameters: [0; 4],
+    })
+}
+
+pub fn acquire(
+    driver: &dyn MemoryReader,
+    output: &Path,
+    format: AcquireFormat,
+) -> BoxResult<Value> {
+    // Write physical memory to `output` and the metadata to `output`.json, return the metadata
+    let ranges = physical_memory_ranges(driver)?;
+    let ntosbase = driver.get_kernel_base().address();
+    let build_number_ptr = ntosbase + driver.pdb_store().get_offset_r("NtBuildNumber")?;
+    // the high bits mark a checked/free build
+    let build_number = driver.try_deref_addr::<u32>(build_number_ptr)? & 0xffff;
+
+    let mut writer = HashWriter {
+        inner: BufWriter::new(File::create(output)?),
+        hasher: Sha256::new(),
+        written: 0,
+    };
+    let started_at = Local::now();
+
+    if format == AcquireFormat::CrashDump {
+        let header = crash_dump_header(driver, build_number)?;
+        writer.write_all(&header.to_full_dump_header(&ranges)?)?;
+    }
+    let errors = write_ranges(driver, &ranges, format, &mut writer)?;
+    writer.inner.flush()?;
+
+    let to_hex = |v: u64| format!("0x{:x}", v);
+    let metadata = json!({
+        "image": output.display().to_string(),
+        "format": match format {
+            AcquireFormat::Raw => "raw",
+            AcquireFormat::CrashDump => "crash_dump",
+        },
+        "size": writer.written,
+        "sha256": writer.hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        "started_at": started_at.to_rfc3339(),
+        "finished_at": Local::now().to_rfc3339(),
+        "build_number": build_number,
+        "kernel_base": to_hex(ntosbase),
+        "pdb": {
+            "guid": driver.pdb_store().guid,
+            "age": driver.pdb_store().age,
+        },
+        "ranges": ranges.iter().map(|&(start, length)| json!({
+            "start": to_hex(start),
+            "length": to_hex(length),
+        })).collect::<Vec<Value>>(),
+        "errors": errors.iter().map(|&(start, length)| json!({
+            "start": to_hex(start),
+            "length": to_hex(length),
+        })).collect::<Vec<Value>>(),
+    });
+
+    let mut sidecar = output.as_os_str().to_owned();
+    sidecar.push(".json");
+    fs::write(&sidecar, format!("{:#}", metadata))?;
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isf::parse_isf_file;
+    use crate::pdb_store::PdbStore;
+    use crate::version::WindowsVersion;
+
+    // Physical memory where every page holds its page number + 1, `holes` cannot be read
+    struct Memory {
+        size: u64,
+        holes: Vec<u64>,
+        pdb_store: PdbStore,
+    }
+
+    impl Memory {
+        fn new(pages: u64, holes: &[u64]) -> Self {
+            let isf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/win7_isf.json");
+            Self {
+                size: pages * PAGE_SIZE,
+                holes: holes.to_vec(),
+                pdb_store: parse_isf_file(&isf).unwrap(),
+            }
+        }
+    }
+
+    impl MemoryReader for Memory {
+        fn read_virtual(&self, _addr: u64, _buf: &mut [u8]) -> usize {
+            0
+        }
+
+        fn read_physical(&self, addr: u64, buf: &mut [u8]) -> usize {
+            let mut total = 0;
+            while total < buf.len() {
+                let paddr = addr + total as u64;
+                let page = paddr & !(PAGE_SIZE - 1);
+                if paddr >= self.size || self.holes.contains(&page) {
+                    break;
+                }
+                let len = ((page + PAGE_SIZE - paddr) as usize).min(buf.len() - total);
+                for b in buf[total..total + len].iter_mut() {
+                    *b = (page / PAGE_SIZE + 1) as u8;
+                }
+                total += len;
+            }
+            total
+        }
+
+        fn get_kernel_base(&self) -> Address {
+            Address::from_base(0)
+        }
+
+        fn get_pte_base(&self) -> Address {
+            Address::from_base(0)
+        }
+
+        fn pdb_store(&self) -> &PdbStore {
+            &self.pdb_store
+        }
+
+        fn windows_version(&self) -> WindowsVersion {
+            WindowsVersion::Windows7
+        }
+    }
+
+    fn write(memory: &Memory, ranges: &[(u64, u64)]) -> (Vec<u8>, Vec<(u64, u64)>) {
+        let mut writer = HashWriter {
+            inner: Vec::new(),
+            hasher: Sha256::new(),
+            written: 0,
+        };
+        let errors = write_ranges(memory, ranges, AcquireFormat::Raw, &mut writer).unwrap();
+        assert_eq!(writer.written, writer.inner.len() as u64);
+        (writer.inner, errors)
+    }
+
+    fn page(image: &[u8], number: u64) -> &[u8] {
+        &image[(number * PAGE_SIZE) as usize..((number + 1) * PAGE_SIZE) as usize]
+    }
+
+    #[test]
+    fn raw_gaps() {
+        let memory = Memory::new(8, &[]);
+        let (image, errors) = write(&memory, &[(0x1000, 0x2000), (0x5000, 0x1000)]);
+        assert_eq!(image.len(), 0x6000);
+        assert!(errors.is_empty());
+        for number in 0..6 {
+            let expected = match number {
+                1 | 2 | 5 => number as u8 + 1,
+                _ => 0,
+            };
+            assert!(
+                page(&image, number).iter().all(|&b| b == expected),
+                "page {}",
+                number
+            );
+        }
+    }
+
+    #[test]
+    fn error_coalescing() {
+        // the chunk read fails, the pages are retried one by one
+        let memory = Memory::new(8, &[0x2000, 0x3000, 0x5000]);
+        let (image, errors) = write(&memory, &[(0x1000, 0x5000)]);
+        assert_eq!(image.len(), 0x6000);
+        assert_eq!(errors, vec![(0x2000, 0x2000), (0x5000, 0x1000)]);
+        for number in 1..6 {
+            let expected = match number {
+                2 | 3 | 5 => 0,
+                _ => number as u8 + 1,
+            };
+            assert!(
+                page(&image, number).iter().all(|&b| b == expected),
+                "page {}",
+                number
+            );
+        }
+    }
+
+    #[test]
+    fn bad_ranges() {
+        assert!(check_ranges(&[(0x1000, 0x1000), (0x2000, 0x1000)]).is_ok());
+        // overlapping
+        assert!(check_ranges(&[(0x1000, 0x2000), (0x2000, 0x1000)]).is_err());
+        // unsorted, even after an empty run
+        assert!(check_ranges(&[(0x3000, 0x1000), (0x1000, 0x1000)]).is_err());
+        assert!(check_ranges(&[(0x3000, 0), (0x1000, 0x1000)]).is_err());
+        // past the end of the address space
+        assert!(check_ranges(&[(0xffff_ffff_ffff_f000, 0x2000)]).is_err());
+    }
+}
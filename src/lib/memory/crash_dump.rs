@@ -0,0 +1,579 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::raw_image::read_file_at;
+use super::segmented::{Segment, SegmentedFile};
+use super::translate::read_virtual;
+use super::PhysicalMemory;
+use crate::pdb_store::PdbStore;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Windows kernel crash dumps (MEMORY.DMP), 64-bit only
+// Ref: https://github.com/volatilityfoundation/volatility3/blob/develop/volatility3/framework/layers/crash.py
+//      https://codemachine.com/articles/windows_crash_dump_file_format.html (DUMP_HEADER64)
+
+const PAGE_SIZE: u64 = 0x1000;
+// DUMP_HEADER64 is 2 pages, the page data or the summary header follows it
+const HEADER_SIZE: u64 = 0x2000;
+
+const DUMP_SIGNATURE: &[u8; 8] = b"PAGEDU64";
+const SUMMARY_SIGNATURE: &[u8; 4] = b"SDMP";
+const FULL_BITMAP_SIGNATURE: &[u8; 4] = b"FDMP";
+
+// DUMP_HEADER64 offsets
+const MINOR_VERSION_OFFSET: usize = 0xc;
+const DIRECTORY_TABLE_BASE_OFFSET: usize = 0x10;
+const PFN_DATABASE_OFFSET: usize = 0x18;
+const PS_LOADED_MODULE_LIST_OFFSET: usize = 0x20;
+const PS_ACTIVE_PROCESS_HEAD_OFFSET: usize = 0x28;
+const NUMBER_PROCESSORS_OFFSET: usize = 0x34;
+const BUGCHECK_CODE_OFFSET: usize = 0x38;
+const BUGCHECK_PARAMETER_OFFSET: usize = 0x40;
+const KD_DEBUGGER_DATA_BLOCK_OFFSET: usize = 0x80;
+const PHYSICAL_MEMORY_BLOCK_OFFSET: usize = 0x88;
+// PhysicalMemoryBlockBuffer is 0x2c0 bytes, 16 bytes of header then the runs
+const MAX_RUNS: usize = (0x2c0 - 0x10) / 0x10;
+const DUMP_TYPE_OFFSET: usize = 0xf98;
+const REQUIRED_DUMP_SPACE_OFFSET: usize = 0xfa0;
+const MACHINE_IMAGE_TYPE_OFFSET: usize = 0x30;
+const IMAGE_FILE_MACHINE_AMD64: u32 = 0x8664;
+// MajorVersion of a free (retail) build
+const FREE_BUILD: u32 = 0xf;
+
+// SUMMARY_DUMP64 offsets, relative to the end of DUMP_HEADER64
+const SUMMARY_HEADER_SIZE_OFFSET: usize = 0x20;
+const SUMMARY_BITMAP_SIZE_OFFSET: usize = 0x28;
+const SUMMARY_PAGES_OFFSET: usize = 0x30;
+const SUMMARY_BITMAP_OFFSET: usize = 0x38;
+
+// _KDDEBUGGER_DATA64
+const KDBG_OWNER_TAG_OFFSET: u64 = 0x10;
+const KDBG_KERN_BASE_OFFSET: u64 = 0x18;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpType {
+    Full,
+    Kernel,
+    BitmapFull,
+    BitmapKernel,
+}
+
+impl DumpType {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(DumpType::Full),
+            2 => Some(DumpType::Kernel),
+            5 => Some(DumpType::BitmapFull),
+            6 => Some(DumpType::BitmapKernel),
+            _ => None,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            DumpType::Full => 1,
+            DumpType::Kernel => 2,
+            DumpType::BitmapFull => 5,
+            DumpType::BitmapKernel => 6,
+        }
+    }
+}
+
+// The fields of DUMP_HEADER64 that help to find our way in the dump
+#[derive(Debug, Clone)]
+pub struct CrashDumpHeader {
+    pub dump_type: DumpType,
+    pub build_number: u32,
+    pub directory_table_base: u64,
+    pub pfn_database: u64,
+    pub ps_loaded_module_list: u64,
+    pub ps_active_process_head: u64,
+    pub kd_debugger_data_block: u64,
+    pub number_processors: u32,
+    pub bugcheck_code: u32,
+    pub bugcheck_parameters: [u64; 4],
+}
+
+impl CrashDumpHeader {
+    pub fn to_full_dump_header(&self, runs: &[(u64, u64)]) -> BoxResult<Vec<u8>> {
+        // DUMP_HEADER64 of a full dump with `runs` (start, length) stored after it in order
+        // Unused space is filled with "PAGE" like the header written by Windows
+        if runs.len() > MAX_RUNS {
+            return Err(format!("A crash dump holds at most {} memory runs", MAX_RUNS).into());
+        }
+        let mut raw = b"PAGE".repeat(HEADER_SIZE as usize / 4);
+        let mut put =
+            |offset: usize, bytes: &[u8]| raw[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0, DUMP_SIGNATURE);
+        put(8, &FREE_BUILD.to_le_bytes());
+        put(MINOR_VERSION_OFFSET, &self.build_number.to_le_bytes());
+        put(
+            DIRECTORY_TABLE_BASE_OFFSET,
+            &self.directory_table_base.to_le_bytes(),
+        );
+        put(PFN_DATABASE_OFFSET, &self.pfn_database.to_le_bytes());
+        put(
+            PS_LOADED_MODULE_LIST_OFFSET,
+            &self.ps_loaded_module_list.to_le_bytes(),
+        );
+        put(
+            PS_ACTIVE_PROCESS_HEAD_OFFSET,
+            &self.ps_active_process_head.to_le_bytes(),
+        );
+        put(
+            MACHINE_IMAGE_TYPE_OFFSET,
+            &IMAGE_FILE_MACHINE_AMD64.to_le_bytes(),
+        );
+        put(
+            NUMBER_PROCESSORS_OFFSET,
+            &self.number_processors.to_le_bytes(),
+        );
+        put(BUGCHECK_CODE_OFFSET, &self.bugcheck_code.to_le_bytes());
+        for (i, parameter) in self.bugcheck_parameters.iter().enumerate() {
+            put(BUGCHECK_PARAMETER_OFFSET + i * 8, &parameter.to_le_bytes());
+        }
+        put(
+            KD_DEBUGGER_DATA_BLOCK_OFFSET,
+            &self.kd_debugger_data_block.to_le_bytes(),
+        );
+
+        // PHYSICAL_MEMORY_DESCRIPTOR64
+        let total_pages: u64 = runs.iter().map(|(_, length)| length / PAGE_SIZE).sum();
+        put(
+            PHYSICAL_MEMORY_BLOCK_OFFSET,
+            &(runs.len() as u32).to_le_bytes(),
+        );
+        put(PHYSICAL_MEMORY_BLOCK_OFFSET + 4, &0u32.to_le_bytes());
+        put(PHYSICAL_MEMORY_BLOCK_OFFSET + 8, &total_pages.to_le_bytes());
+        for (i, (start, length)) in runs.iter().enumerate() {
+            let run = PHYSICAL_MEMORY_BLOCK_OFFSET + 0x10 + i * 0x10;
+            put(run, &(start / PAGE_SIZE).to_le_bytes());
+            put(run + 8, &(length / PAGE_SIZE).to_le_bytes());
+        }
+
+        put(DUMP_TYPE_OFFSET, &DumpType::Full.to_u32().to_le_bytes());
+        put(
+            REQUIRED_DUMP_SPACE_OFFSET,
+            &(HEADER_SIZE + total_pages * PAGE_SIZE).to_le_bytes(),
+        );
+        Ok(raw)
+    }
+}
+
+pub struct CrashDump {
+    pub header: CrashDumpHeader,
+    memory: SegmentedFile,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(v)
+}
+
+impl CrashDump {
+    pub fn has_signature(magic: &[u8]) -> bool {
+        magic.starts_with(DUMP_SIGNATURE)
+    }
+
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        let file = Mutex::new(File::open(path)?);
+        let mut raw_header = vec![0u8; HEADER_SIZE as usize];
+        if read_file_at(&file, 0, &mut raw_header) != raw_header.len() {
+            return Err("File is too small to be a crash dump".into());
+        }
+        if &raw_header[0..8] != DUMP_SIGNATURE {
+            return Err("Not a 64-bit crash dump, PAGEDU64 signature not found".into());
+        }
+
+        let raw_dump_type = read_u32(&raw_header, DUMP_TYPE_OFFSET);
+        let dump_type = DumpType::from_u32(raw_dump_type).ok_or(format!(
+            "Crash dump type {} has no physical memory",
+            raw_dump_type
+        ))?;
+        let mut bugcheck_parameters = [0u64; 4];
+        for (i, parameter) in bugcheck_parameters.iter_mut().enumerate() {
+            *parameter = read_u64(&raw_header, BUGCHECK_PARAMETER_OFFSET + i * 8);
+        }
+        let header = CrashDumpHeader {
+            dump_type,
+            build_number: read_u32(&raw_header, MINOR_VERSION_OFFSET),
+            directory_table_base: read_u64(&raw_header, DIRECTORY_TABLE_BASE_OFFSET),
+            pfn_database: read_u64(&raw_header, PFN_DATABASE_OFFSET),
+            ps_loaded_module_list: read_u64(&raw_header, PS_LOADED_MODULE_LIST_OFFSET),
+            ps_active_process_head: read_u64(&raw_header, PS_ACTIVE_PROCESS_HEAD_OFFSET),
+            kd_debugger_data_block: read_u64(&raw_header, KD_DEBUGGER_DATA_BLOCK_OFFSET),
+            number_processors: read_u32(&raw_header, NUMBER_PROCESSORS_OFFSET),
+            bugcheck_code: read_u32(&raw_header, BUGCHECK_CODE_OFFSET),
+            bugcheck_parameters,
+        };
+
+        let file_size = match file.lock() {
+            Ok(f) => f.metadata()?.len(),
+            Err(_) => return Err("Crash dump file is poisoned".into()),
+        };
+        let segments = match dump_type {
+            DumpType::Full => Self::parse_run_list(&raw_header, file_size)?,
+            _ => Self::parse_bitmap(&file, &raw_header, file_size)?,
+        };
+        Ok(Self {
+            header,
+            memory: SegmentedFile::new(file, segments),
+        })
+    }
+
+    fn physical_memory_runs(raw_header: &[u8]) -> BoxResult<Vec<(u64, u64)>> {
+        // PHYSICAL_MEMORY_DESCRIPTOR64: (base page, page count) of every run
+        let number_of_runs = read_u32(raw_header, PHYSICAL_MEMORY_BLOCK_OFFSET) as usize;
+        if number_of_runs > MAX_RUNS {
+            return Err(
+                format!("Invalid number of physical memory runs: {}", number_of_runs).into(),
+            );
+        }
+        Ok((0..number_of_runs)
+            .map(|i| {
+                let run = PHYSICAL_MEMORY_BLOCK_OFFSET + 0x10 + i * 0x10;
+                (read_u64(raw_header, run), read_u64(raw_header, run + 8))
+            })
+            .collect())
+    }
+
+    fn parse_run_list(raw_header: &[u8], file_size: u64) -> BoxResult<Vec<Segment>> {
+        // The pages of every run are stored back to back after the header
+        let mut runs = Vec::new();
+        let mut file_offset = HEADER_SIZE;
+        for (base_page, page_count) in Self::physical_memory_runs(raw_header)? {
+            let start = base_page.checked_mul(PAGE_SIZE);
+            let length = page_count.checked_mul(PAGE_SIZE);
+            let (start, length) = match (start, length) {
+                (Some(start), Some(length)) if start.checked_add(length).is_some() => {
+                    (start, length)
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid memory run of {} pages at page 0x{:x}",
+                        page_count, base_page
+                    )
+                    .into())
+                }
+            };
+            let end = match file_offset.checked_add(length) {
+                Some(end) if end <= file_size => end,
+                _ => {
+                    return Err(format!(
+                        "Memory run at page 0x{:x} is past the end of the file",
+                        base_page
+                    )
+                    .into())
+                }
+            };
+            runs.push(Segment {
+                start,
+                length,
+                file_offset,
+            });
+            file_offset = end;
+        }
+        Ok(runs)
+    }
+
+    fn parse_bitmap(
+        file: &Mutex<File>,
+        raw_header: &[u8],
+        file_size: u64,
+    ) -> BoxResult<Vec<Segment>> {
+        // SUMMARY_DUMP64, one bit per physical page, the pages with a bit set
+        // are stored in order after the bitmap
+        let mut summary = [0u8; SUMMARY_BITMAP_OFFSET];
+        if read_file_at(file, HEADER_SIZE, &mut summary) != summary.len() {
+            return Err("Crash dump is truncated before the summary header".into());
+        }
+        if &summary[0..4] != SUMMARY_SIGNATURE && &summary[0..4] != FULL_BITMAP_SIGNATURE {
+            return Err("Summary header signature is neither SDMP nor FDMP".into());
+        }
+        let first_page_offset = read_u64(&summary, SUMMARY_HEADER_SIZE_OFFSET);
+        let bitmap_size = read_u64(&summary, SUMMARY_BITMAP_SIZE_OFFSET);
+        let pages = read_u64(&summary, SUMMARY_PAGES_OFFSET);
+
+        // A corrupt size must not decide how much to allocate: the bitmap is in the file,
+        // and covers no more than the highest page of the header's memory runs (rounded
+        // up to whole 64-bit words)
+        if first_page_offset > file_size {
+            return Err("First page of the summary dump is past the end of the file".into());
+        }
+        if bitmap_size.div_ceil(8) > file_size {
+            return Err(format!(
+                "Page bitmap of {} pages is larger than the file",
+                bitmap_size
+            )
+            .into());
+        }
+        let highest_page = Self::physical_memory_runs(raw_header)?
+            .iter()
+            .map(|(base_page, page_count)| base_page.saturating_add(*page_count))
+            .max()
+            .unwrap_or(0);
+        if highest_page != 0 && bitmap_size > highest_page.div_ceil(64) * 64 {
+            return Err(format!(
+                "Page bitmap of {} pages, the header has {} physical pages",
+                bitmap_size, highest_page
+            )
+            .into());
+        }
+
+        let mut bitmap = vec![0u8; bitmap_size.div_ceil(8) as usize];
+        if read_file_at(
+            file,
+            HEADER_SIZE + SUMMARY_BITMAP_OFFSET as u64,
+            &mut bitmap,
+        ) != bitmap.len()
+        {
+            return Err("Crash dump is truncated inside the page bitmap".into());
+        }
+
+        // Merge consecutive pages into runs to keep the lookup short
+        let mut runs: Vec<Segment> = Vec::new();
+        let mut file_offset = first_page_offset;
+        for pfn in 0..bitmap_size {
+            if bitmap[(pfn / 8) as usize] & (1 << (pfn % 8)) == 0 {
+                continue;
+            }
+            let start = pfn * PAGE_SIZE;
+            match runs.last_mut() {
+                Some(run) if run.start + run.length == start => run.length += PAGE_SIZE,
+                _ => runs.push(Segment {
+                    start,
+                    length: PAGE_SIZE,
+                    file_offset,
+                }),
+            }
+            file_offset += PAGE_SIZE;
+        }
+
+        let stored = (file_offset - first_page_offset) / PAGE_SIZE;
+        if stored != pages {
+            return Err(format!(
+                "Page bitmap has {} pages, the summary header says {}",
+                stored, pages
+            )
+            .into());
+        }
+        Ok(runs)
+    }
+
+    pub fn find_kernel_base(&self, pdb_store: &PdbStore) -> BoxResult<u64> {
+        let dtb = self.header.directory_table_base;
+        let read_u64_virtual = |addr: u64| {
+            let mut buf = [0u8; 8];
+            if read_virtual(self, dtb, addr, &mut buf) != buf.len() {
+                return None;
+            }
+            Some(u64::from_le_bytes(buf))
+        };
+
+        // _KDDEBUGGER_DATA64->KernBase, only usable when the block is not encoded
+        let kdbg = self.header.kd_debugger_data_block;
+        let owner_tag_addr = kdbg.checked_add(KDBG_OWNER_TAG_OFFSET);
+        if let Some(owner_tag) = owner_tag_addr.and_then(read_u64_virtual) {
+            if &owner_tag.to_le_bytes()[0..4] == b"KDBG" {
+                let kern_base_addr = kdbg.checked_add(KDBG_KERN_BASE_OFFSET);
+                if let Some(kernel_base) = kern_base_addr.and_then(read_u64_virtual) {
+                    return Ok(kernel_base);
+                }
+            }
+        }
+
+        // ntoskrnl.exe is always the first entry of PsLoadedModuleList
+        let dll_base_offset = pdb_store.get_offset_r("_LDR_DATA_TABLE_ENTRY.DllBase")?;
+        read_u64_virtual(self.header.ps_loaded_module_list)
+            .and_then(|first_entry| first_entry.checked_add(dll_base_offset))
+            .and_then(read_u64_virtual)
+            .ok_or_else(|| {
+                "Cannot find the kernel base from KdDebuggerDataBlock or PsLoadedModuleList".into()
+            })
+    }
+}
+
+impl PhysicalMemory for CrashDump {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.memory.read(addr, buf)
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        self.memory.ranges()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn dump_file(name: &str, raw: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lpus-{}-{}.dmp", name, std::process::id()));
+        fs::write(&path, raw).unwrap();
+        path
+    }
+
+    fn open(name: &str, raw: &[u8]) -> BoxResult<CrashDump> {
+        let path = dump_file(name, raw);
+        let dump = CrashDump::open(&path);
+        fs::remove_file(&path).ok();
+        dump
+    }
+
+    fn header() -> CrashDumpHeader {
+        CrashDumpHeader {
+            dump_type: DumpType::Full,
+            build_number: 19041,
+            directory_table_base: 0x1aa000,
+            pfn_database: 0xfffffa8000000000,
+            ps_loaded_module_list: 0xfffff80002a4b890,
+            ps_active_process_head: 0xfffff80002a2b940,
+            kd_debugger_data_block: 0xfffff800029f00a0,
+            number_processors: 4,
+            bugcheck_code: 0xe2,
+            bugcheck_parameters: [1, 2, 3, 4],
+        }
+    }
+
+    fn page(fill: u8) -> Vec<u8> {
+        vec![fill; PAGE_SIZE as usize]
+    }
+
+    fn bitmap_dump(signature: &[u8; 4], bitmap_size: u64, present: &[u64]) -> Vec<u8> {
+        // One run of `bitmap_size` pages, the summary header, the bitmap, then the pages
+        // with their bit set, each filled with its page number
+        let mut raw = header()
+            .to_full_dump_header(&[(0, bitmap_size * PAGE_SIZE)])
+            .unwrap();
+        raw[DUMP_TYPE_OFFSET..DUMP_TYPE_OFFSET + 4]
+            .copy_from_slice(&DumpType::BitmapKernel.to_u32().to_le_bytes());
+        let mut bitmap = vec![0u8; bitmap_size.div_ceil(8) as usize];
+        for pfn in present {
+            bitmap[(pfn / 8) as usize] |= 1 << (pfn % 8);
+        }
+        let bitmap_end = HEADER_SIZE + SUMMARY_BITMAP_OFFSET as u64 + bitmap.len() as u64;
+        let first_page_offset = bitmap_end.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let mut summary = vec![0u8; SUMMARY_BITMAP_OFFSET];
+        let mut put = |offset: usize, value: u64| {
+            summary[offset..offset + 8].copy_from_slice(&value.to_le_bytes())
+        };
+        put(SUMMARY_HEADER_SIZE_OFFSET, first_page_offset);
+        put(SUMMARY_BITMAP_SIZE_OFFSET, bitmap_size);
+        put(SUMMARY_PAGES_OFFSET, present.len() as u64);
+        summary[0..4].copy_from_slice(signature);
+        raw.extend(summary);
+        raw.extend(bitmap);
+        raw.resize(first_page_offset as usize, 0);
+        for pfn in present {
+            raw.extend(page(*pfn as u8));
+        }
+        raw
+    }
+
+    #[test]
+    fn full_dump_run_list() {
+        // Pages 0-1 and page 0x10, stored back to back after the header
+        let runs = [(0, 2 * PAGE_SIZE), (0x10 * PAGE_SIZE, PAGE_SIZE)];
+        let mut raw = header().to_full_dump_header(&runs).unwrap();
+        raw.extend(page(0xa1));
+        raw.extend(page(0xa2));
+        raw.extend(page(0xb0));
+        let dump = open("full", &raw).unwrap();
+
+        assert_eq!(dump.header.dump_type, DumpType::Full);
+        assert_eq!(dump.header.build_number, 19041);
+        assert_eq!(dump.header.directory_table_base, 0x1aa000);
+        assert_eq!(dump.header.kd_debugger_data_block, 0xfffff800029f00a0);
+        assert_eq!(dump.header.bugcheck_parameters, [1, 2, 3, 4]);
+        assert_eq!(dump.ranges(), runs.to_vec());
+        let mut buf = [0u8; 2];
+        assert_eq!(dump.read(PAGE_SIZE - 1, &mut buf), 2);
+        assert_eq!(buf, [0xa1, 0xa2]);
+        assert_eq!(dump.read(0x10 * PAGE_SIZE, &mut buf), 2);
+        assert_eq!(buf, [0xb0, 0xb0]);
+        // between the runs
+        assert_eq!(dump.read(2 * PAGE_SIZE, &mut buf), 0);
+    }
+
+    #[test]
+    fn full_dump_bad_run_list() {
+        // The second run has no page in the file
+        let runs = [(0, PAGE_SIZE), (0x10 * PAGE_SIZE, PAGE_SIZE)];
+        let mut raw = header().to_full_dump_header(&runs).unwrap();
+        raw.extend(page(0xa1));
+        let e = open("short-run", &raw).err().expect("run past the file");
+        assert!(e.to_string().contains("past the end"), "{}", e);
+
+        // A page count that overflows once turned into bytes
+        let run = PHYSICAL_MEMORY_BLOCK_OFFSET + 0x10;
+        raw[run + 8..run + 16].copy_from_slice(&(u64::MAX / 0x100).to_le_bytes());
+        let e = open("huge-run", &raw).err().expect("run overflows");
+        assert!(e.to_string().contains("Invalid memory run"), "{}", e);
+    }
+
+    #[test]
+    fn summary_dump_bitmap() {
+        for signature in &[SUMMARY_SIGNATURE, FULL_BITMAP_SIGNATURE] {
+            let raw = bitmap_dump(signature, 20, &[0, 1, 2, 7, 8, 15]);
+            let dump = open("summary", &raw).unwrap();
+
+            assert_eq!(dump.header.dump_type, DumpType::BitmapKernel);
+            assert_eq!(
+                dump.ranges(),
+                vec![
+                    (0, 3 * PAGE_SIZE),
+                    (7 * PAGE_SIZE, 2 * PAGE_SIZE),
+                    (15 * PAGE_SIZE, PAGE_SIZE)
+                ]
+            );
+            let mut buf = [0u8; 2];
+            assert_eq!(dump.read(8 * PAGE_SIZE - 1, &mut buf), 2);
+            assert_eq!(buf, [7, 8]);
+            assert_eq!(dump.read(15 * PAGE_SIZE, &mut buf), 2);
+            assert_eq!(buf, [15, 15]);
+            assert_eq!(dump.read(3 * PAGE_SIZE, &mut buf), 0);
+        }
+    }
+
+    #[test]
+    fn summary_dump_bad_bitmap_size() {
+        let set_bitmap_size = |raw: &mut Vec<u8>, size: u64| {
+            let offset = HEADER_SIZE as usize + SUMMARY_BITMAP_SIZE_OFFSET;
+            raw[offset..offset + 8].copy_from_slice(&size.to_le_bytes());
+        };
+        let mut raw = bitmap_dump(SUMMARY_SIGNATURE, 20, &[0, 1]);
+        set_bitmap_size(&mut raw, 1 << 60);
+        let e = open("huge-bitmap", &raw)
+            .err()
+            .expect("bitmap larger than the file");
+        assert!(e.to_string().contains("larger than the file"), "{}", e);
+
+        // fits in the file, but the header only has 20 pages
+        set_bitmap_size(&mut raw, 200);
+        let e = open("long-bitmap", &raw)
+            .err()
+            .expect("bitmap past the memory runs");
+        assert!(e.to_string().contains("physical pages"), "{}", e);
+
+        let mut past_end = bitmap_dump(SUMMARY_SIGNATURE, 20, &[0, 1]);
+        let offset = HEADER_SIZE as usize + SUMMARY_HEADER_SIZE_OFFSET;
+        past_end[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(open("summary-past-end", &past_end).is_err());
+
+        let mut bad_signature = bitmap_dump(SUMMARY_SIGNATURE, 20, &[3]);
+        bad_signature[HEADER_SIZE as usize..HEADER_SIZE as usize + 4].copy_from_slice(b"XXXX");
+        assert!(open("bad-summary", &bad_signature).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::raw_image::read_file_at;
+use super::segmented::{Segment, SegmentedFile};
+use super::PhysicalMemory;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// ELF64 core file, as written by QEMU dump-guest-memory or virsh dump --memory-only
+// PT_LOAD program headers map guest physical addresses (p_paddr) to file offsets
+// Ref: https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+// e_phnum value meaning the real count is in sh_info of section header 0
+const PN_XNUM: u16 = 0xffff;
+
+const ELF_HEADER_SIZE: usize = 0x40;
+const PROGRAM_HEADER_SIZE: usize = 0x38;
+
+pub struct ElfCore {
+    memory: SegmentedFile,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    let mut v = [0u8; 2];
+    v.copy_from_slice(&buf[offset..offset + 2]);
+    u16::from_le_bytes(v)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(v)
+}
+
+impl ElfCore {
+    pub fn has_signature(magic: &[u8]) -> bool {
+        magic.starts_with(ELF_MAGIC)
+    }
+
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        let file = Mutex::new(File::open(path)?);
+        let size = file
+            .lock()
+            .map_err(|_| "Poisoned file lock")?
+            .metadata()?
+            .len();
+        let mut header = [0u8; ELF_HEADER_SIZE];
+        if read_file_at(&file, 0, &mut header) != header.len() || !Self::has_signature(&header) {
+            return Err("Not an ELF file".into());
+        }
+        if header[4] != ELF_CLASS_64 || header[5] != ELF_DATA_LSB {
+            return Err("Only little endian ELF64 core files are supported".into());
+        }
+        if read_u16(&header, 0x10) != ET_CORE {
+            return Err("ELF file is not a core file".into());
+        }
+
+        let phoff = read_u64(&header, 0x20);
+        let shoff = read_u64(&header, 0x28);
+        let phentsize = read_u16(&header, 0x36) as usize;
+        let mut phnum = read_u16(&header, 0x38) as usize;
+        if phentsize < PROGRAM_HEADER_SIZE {
+            return Err(format!("Invalid program header size 0x{:x}", phentsize).into());
+        }
+        if phnum == PN_XNUM as usize {
+            // Elf64_Shdr.sh_info
+            let mut sh_info = [0u8; 4];
+            if read_file_at(&file, shoff + 0x2c, &mut sh_info) != sh_info.len() {
+                return Err("Cannot read the program header count from section header 0".into());
+            }
+            phnum = u32::from_le_bytes(sh_info) as usize;
+        }
+
+        // e_phnum or sh_info, both come from the file and are checked before allocating
+        let table_size = phentsize
+            .checked_mul(phnum)
+            .filter(|&len| phoff.checked_add(len as u64).is_some_and(|end| end <= size))
+            .ok_or_else(|| format!("{} program headers do not fit in the ELF file", phnum))?;
+        let mut program_headers = vec![0u8; table_size];
+        if read_file_at(&file, phoff, &mut program_headers) != program_headers.len() {
+            return Err("ELF program headers are truncated".into());
+        }
+        let mut segments = Vec::new();
+        for ph in program_headers.chunks(phentsize) {
+            if read_u32(ph, 0) != PT_LOAD {
+                continue;
+            }
+            // only the part backed by the file, p_memsz can be larger
+            segments.push(Segment {
+                start: read_u64(ph, 0x18),
+                length: read_u64(ph, 0x20),
+                file_offset: read_u64(ph, 0x8),
+            });
+        }
+        if segments.is_empty() {
+            return Err("No PT_LOAD segment found in the ELF core".into());
+        }
+        Ok(Self {
+            memory: SegmentedFile::new(file, segments),
+        })
+    }
+}
+
+impl PhysicalMemory for ElfCore {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.memory.read(addr, buf)
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        self.memory.ranges()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const PT_NOTE: u32 = 4;
+
+    fn open(name: &str, raw: &[u8]) -> BoxResult<ElfCore> {
+        let path = std::env::temp_dir().join(format!("lpus-{}-{}.elf", name, std::process::id()));
+        fs::write(&path, raw).unwrap();
+        let core = ElfCore::open(&path);
+        fs::remove_file(&path).ok();
+        core
+    }
+
+    fn core_file(phnum: u16, headers: &[(u32, u64, u64, u64)]) -> Vec<u8> {
+        // The ELF header, the (type, file offset, physical address, file size) program
+        // headers right after it, then zeros up to the end of the last segment
+        let mut raw = vec![0u8; ELF_HEADER_SIZE];
+        raw[..4].copy_from_slice(ELF_MAGIC);
+        raw[4] = ELF_CLASS_64;
+        raw[5] = ELF_DATA_LSB;
+        raw[0x10..0x12].copy_from_slice(&ET_CORE.to_le_bytes());
+        raw[0x20..0x28].copy_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+        raw[0x36..0x38].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        raw[0x38..0x3a].copy_from_slice(&phnum.to_le_bytes());
+        let mut end = 0;
+        for &(p_type, offset, paddr, filesz) in headers {
+            let mut ph = [0u8; PROGRAM_HEADER_SIZE];
+            ph[..4].copy_from_slice(&p_type.to_le_bytes());
+            ph[0x8..0x10].copy_from_slice(&offset.to_le_bytes());
+            ph[0x18..0x20].copy_from_slice(&paddr.to_le_bytes());
+            ph[0x20..0x28].copy_from_slice(&filesz.to_le_bytes());
+            raw.extend_from_slice(&ph);
+            end = end.max(offset + filesz);
+        }
+        raw.resize(raw.len().max(end as usize), 0);
+        raw
+    }
+
+    #[test]
+    fn load_segments() {
+        let headers = [
+            (PT_NOTE, 0x100, 0, 0x100),
+            (PT_LOAD, 0x1000, 0, 0x2000),
+            (PT_LOAD, 0x3000, 0x100000, 0x1000),
+        ];
+        let mut raw = core_file(headers.len() as u16, &headers);
+        raw[0x1000..0x3000].fill(0x11);
+        raw[0x3000..0x4000].fill(0x22);
+        let core = open("load", &raw).unwrap();
+        assert_eq!(core.ranges(), vec![(0, 0x2000), (0x100000, 0x1000)]);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(core.read(0x1ffe, &mut buf), 2);
+        assert_eq!(buf[..2], [0x11, 0x11]);
+        assert_eq!(core.read(0x100000, &mut buf), 4);
+        assert_eq!(buf, [0x22; 4]);
+    }
+
+    #[test]
+    fn program_headers_past_the_end() {
+        // e_phnum says 0xfffe headers, the file has one
+        let raw = core_file(0xfffe, &[(PT_LOAD, 0x1000, 0, 0x1000)]);
+        assert!(open("phnum", &raw).is_err());
+    }
+
+    #[test]
+    fn program_header_count_in_section_header() {
+        // PN_XNUM with a section header 0 whose sh_info is 0xffffffff
+        let mut raw = core_file(PN_XNUM, &[(PT_LOAD, 0x1000, 0, 0x1000)]);
+        raw[0x28..0x30].copy_from_slice(&0x200u64.to_le_bytes());
+        raw[0x22c..0x230].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(open("xnum", &raw).is_err());
+
+        raw[0x22c..0x230].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(open("xnum", &raw).unwrap().ranges(), vec![(0, 0x1000)]);
+    }
+
+    #[test]
+    fn not_a_core_file() {
+        let mut raw = core_file(1, &[(PT_LOAD, 0x1000, 0, 0x1000)]);
+        // ET_EXEC
+        raw[0x10] = 2;
+        assert!(open("exec", &raw).is_err());
+        assert!(open("short", &raw[..0x20]).is_err());
+    }
+}
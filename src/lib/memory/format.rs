@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::crash_dump::CrashDump;
+use super::elf_core::ElfCore;
+use super::hiberfil::Hiberfil;
+use super::image_state::ImageState;
+use super::lime::LimeImage;
+use super::raw_image::{read_file_at, RawImage};
+use super::vmware::VmwareImage;
+use super::PhysicalMemory;
+use crate::pdb_store::PdbStore;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+const MAGIC_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    CrashDump,
+    Hiberfil,
+    Lime,
+    ElfCore,
+    Vmware,
+}
+
+impl ImageFormat {
+    pub fn detect(path: &Path) -> BoxResult<Self> {
+        // From the magic bytes, a .vmem has none and is known by the state file next to it
+        let mut magic = [0u8; MAGIC_SIZE];
+        let len = read_file_at(&Mutex::new(File::open(path)?), 0, &mut magic);
+        let magic = &magic[..len];
+        let format = if CrashDump::has_signature(magic) {
+            ImageFormat::CrashDump
+        } else if Hiberfil::has_signature(magic) {
+            ImageFormat::Hiberfil
+        } else if LimeImage::has_signature(magic) {
+            ImageFormat::Lime
+        } else if ElfCore::has_signature(magic) {
+            ImageFormat::ElfCore
+        } else if VmwareImage::has_signature(magic)
+            || (path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("vmem"))
+                && VmwareImage::find_state_file(path).is_some())
+        {
+            ImageFormat::Vmware
+        } else {
+            ImageFormat::Raw
+        };
+        Ok(format)
+    }
+}
+
+pub fn open_image(
+    path: &Path,
+    pdb_store: PdbStore,
+    dtb_and_base: Option<(u64, u64)>,
+) -> BoxResult<ImageState> {
+    // Open any supported format, the DTB and kernel base come from `dtb_and_base`,
+    // then from the crash dump header, then from the low stub
+    let format = ImageFormat::detect(path)?;
+    if format == ImageFormat::CrashDump {
+        let dump = CrashDump::open(path)?;
+        let (dtb, kernel_base) = match dtb_and_base {
+            Some(v) => v,
+            None => (
+                dump.header.directory_table_base,
+                dump.find_kernel_base(&pdb_store)?,
+            ),
+        };
+        return ImageState::from_dtb(pdb_store, Box::new(dump), dtb, kernel_base);
+    }
+
+    let physical: Box<dyn PhysicalMemory> = match format {
+        ImageFormat::Hiberfil => Box::new(Hiberfil::open(path)?),
+        ImageFormat::Lime => Box::new(LimeImage::open(path)?),
+        ImageFormat::ElfCore => Box::new(ElfCore::open(path)?),
+        ImageFormat::Vmware => Box::new(VmwareImage::open(path)?),
+        _ => Box::new(RawImage::open(path)?),
+    };
+    match dtb_and_base {
+        Some((dtb, kernel_base)) => ImageState::from_dtb(pdb_store, physical, dtb, kernel_base),
+        None => ImageState::new(pdb_store, physical),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn detect(name: &str, raw: &[u8]) -> ImageFormat {
+        let path = std::env::temp_dir().join(format!("lpus-{}-{}", name, std::process::id()));
+        fs::write(&path, raw).unwrap();
+        let format = ImageFormat::detect(&path).unwrap();
+        fs::remove_file(&path).ok();
+        format
+    }
+
+    #[test]
+    fn magic_bytes() {
+        assert_eq!(detect("dmp", b"PAGEDU64"), ImageFormat::CrashDump);
+        assert_eq!(detect("hiberfil", b"HIBR\0\0\0\0"), ImageFormat::Hiberfil);
+        assert_eq!(detect("hiberfil", b"wake"), ImageFormat::Hiberfil);
+        assert_eq!(detect("lime", b"EMiL\x01\0\0\0"), ImageFormat::Lime);
+        assert_eq!(
+            detect("elf", b"\x7fELF\x02\x01\x01\0"),
+            ImageFormat::ElfCore
+        );
+        assert_eq!(detect("vmss", b"\xd0\xbe\xd2\xbe"), ImageFormat::Vmware);
+        // only 64-bit crash dumps are recognized
+        assert_eq!(detect("raw", b"PAGEDUMP"), ImageFormat::Raw);
+        assert_eq!(detect("short", b"EL"), ImageFormat::Raw);
+        assert_eq!(detect("empty", b""), ImageFormat::Raw);
+    }
+
+    #[test]
+    fn vmem_with_state_file() {
+        // A .vmem is only VMware with a .vmss or .vmsn next to it
+        let base = std::env::temp_dir().join(format!("lpus-vmem-{}", std::process::id()));
+        let (vmem, vmss) = (base.with_extension("vmem"), base.with_extension("vmss"));
+        fs::write(&vmem, [0u8; 16]).unwrap();
+        assert_eq!(ImageFormat::detect(&vmem).unwrap(), ImageFormat::Raw);
+        fs::write(&vmss, [0u8; 16]).unwrap();
+        assert_eq!(ImageFormat::detect(&vmem).unwrap(), ImageFormat::Vmware);
+        fs::remove_file(&vmem).ok();
+        fs::remove_file(&vmss).ok();
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::raw_image::read_file_at;
+use super::xpress::{decompress_huffman, decompress_lz77};
+use super::PhysicalMemory;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Hibernation file (hiberfil.sys), 64-bit only
+// Ref: https://github.com/volatilityfoundation/volatility/blob/master/volatility/plugins/addrspaces/hibernate.py (Windows 7)
+//      https://github.com/volatilityfoundation/volatility3/blob/develop/volatility3/framework/layers/hib.py (Windows 8+)
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// PO_MEMORY_IMAGE
+const SIGNATURES: [&[u8; 4]; 4] = [b"hibr", b"HIBR", b"wake", b"RSTR"];
+const LEGACY_FIRST_TABLE_PAGE_OFFSET: usize = 0x68;
+const FIRST_BOOT_RESTORE_PAGE_OFFSET: usize = 0x68;
+const FIRST_KERNEL_RESTORE_PAGE_OFFSET: usize = 0x70;
+
+// Windows 7: a restoration table page (_PO_MEMORY_RANGE_ARRAY) lists page ranges,
+// the pages follow it in "\x81\x81xpress" blocks, then the next table page
+const XPRESS_SIGNATURE: &[u8; 8] = b"\x81\x81xpress";
+const XPRESS_HEADER_SIZE: u64 = 0x20;
+const RANGE_ARRAY_NEXT_TABLE_OFFSET: usize = 0x8;
+const RANGE_ARRAY_ENTRY_COUNT_OFFSET: usize = 0x14;
+const RANGE_ARRAY_ENTRY_SIZE: usize = 0x20;
+const RANGE_START_PAGE_OFFSET: usize = 0x8;
+const RANGE_END_PAGE_OFFSET: usize = 0x10;
+
+// Windows 8+: the pages are stored in compression sets, a 32-bit header with up to
+// 16 page descriptors followed by the compressed data of all their pages
+const MAX_SET_DESCRIPTORS: u32 = 16;
+const SET_DESCRIPTOR_SIZE: u64 = 8;
+
+#[derive(Clone, Copy)]
+enum Compression {
+    None,
+    Lz77,
+    Huffman,
+}
+
+// A compressed run of pages in the file
+struct Block {
+    file_offset: u64,
+    compressed_size: u64,
+    pages: u64,
+    compression: Compression,
+}
+
+// Where a physical page lives: the block and its index inside the decompressed block
+#[derive(Clone, Copy)]
+struct PageLocation {
+    block: usize,
+    index: u64,
+}
+
+pub struct Hiberfil {
+    file: Mutex<File>,
+    blocks: Vec<Block>,
+    pages: BTreeMap<u64, PageLocation>,
+    // Blocks are read sequentially by the scanners, keep the last one decompressed
+    last_block: Mutex<Option<(usize, Vec<u8>)>>,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(v)
+}
+
+fn page_offset(page: u64) -> BoxResult<u64> {
+    // The page numbers come from the file, a corrupted one must not wrap around
+    page.checked_mul(PAGE_SIZE)
+        .ok_or_else(|| format!("Invalid page number 0x{:x} in the hibernation file", page).into())
+}
+
+impl Hiberfil {
+    pub fn has_signature(magic: &[u8]) -> bool {
+        SIGNATURES.iter().any(|&s| magic.starts_with(s))
+    }
+
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        let file = Mutex::new(File::open(path)?);
+        let mut header = vec![0u8; PAGE_SIZE as usize];
+        if read_file_at(&file, 0, &mut header) != header.len() {
+            return Err("File is too small to be a hibernation file".into());
+        }
+        if !Self::has_signature(&header) {
+            return Err("PO_MEMORY_IMAGE signature not found, the hibernation file may have been wiped on resume".into());
+        }
+
+        let mut hiberfil = Self {
+            file,
+            blocks: Vec::new(),
+            pages: BTreeMap::new(),
+            last_block: Mutex::new(None),
+        };
+        let first_table_page = read_u64(&header, LEGACY_FIRST_TABLE_PAGE_OFFSET);
+        if hiberfil.has_xpress_block(page_offset(first_table_page.saturating_add(1))?) {
+            hiberfil.parse_range_tables(first_table_page)?;
+        } else {
+            let first_boot_page = read_u64(&header, FIRST_BOOT_RESTORE_PAGE_OFFSET);
+            let first_kernel_page = read_u64(&header, FIRST_KERNEL_RESTORE_PAGE_OFFSET);
+            hiberfil.parse_compression_sets(
+                page_offset(first_boot_page)?,
+                page_offset(first_kernel_page)?,
+            )?;
+            hiberfil.parse_compression_sets(page_offset(first_kernel_page)?, u64::MAX)?;
+        }
+        if hiberfil.pages.is_empty() {
+            return Err("No page found in the hibernation file".into());
+        }
+        Ok(hiberfil)
+    }
+
+    fn has_xpress_block(&self, offset: u64) -> bool {
+        let mut signature = [0u8; 8];
+        read_file_at(&self.file, offset, &mut signature) == signature.len()
+            && &signature == XPRESS_SIGNATURE
+    }
+
+    fn insert_page(&mut self, pfn: u64, location: PageLocation) -> BoxResult<()> {
+        // ranges() turns the page numbers back into addresses
+        page_offset(pfn)?;
+        self.pages.insert(pfn, location);
+        Ok(())
+    }
+
+    fn parse_xpress_block(&mut self, offset: u64) -> BoxResult<u64> {
+        // Add the block at `offset`, return the offset of the next one
+        let mut header = [0u8; XPRESS_HEADER_SIZE as usize];
+        if read_file_at(&self.file, offset, &mut header) != header.len()
+            || &header[0..8] != XPRESS_SIGNATURE
+        {
+            return Err(format!("Xpress block not found at 0x{:x}", offset).into());
+        }
+        let info = read_u32(&header, 8) as u64;
+        let pages = (info & 0xff) + 1;
+        // compressed size is 8 bytes aligned
+        let compressed_size = ((info >> 10) + 1 + 7) & !7;
+        let compression = if compressed_size == pages * PAGE_SIZE {
+            Compression::None
+        } else {
+            Compression::Lz77
+        };
+        self.blocks.push(Block {
+            file_offset: offset + XPRESS_HEADER_SIZE,
+            compressed_size,
+            pages,
+            compression,
+        });
+        Ok(offset + XPRESS_HEADER_SIZE + compressed_size)
+    }
+
+    fn parse_range_tables(&mut self, first_table_page: u64) -> BoxResult<()> {
+        let mut table_page = first_table_page;
+        let mut table = vec![0u8; PAGE_SIZE as usize];
+        let mut visited = HashSet::new();
+        while table_page != 0 {
+            // a corrupted next table page could send us around in circles
+            if !visited.insert(table_page) {
+                return Err(
+                    format!("Restoration table page 0x{:x} is listed twice", table_page).into(),
+                );
+            }
+            if read_file_at(&self.file, page_offset(table_page)?, &mut table) != table.len() {
+                return Err(
+                    format!("Restoration table page 0x{:x} is truncated", table_page).into(),
+                );
+            }
+            let entry_count = read_u32(&table, RANGE_ARRAY_ENTRY_COUNT_OFFSET) as usize;
+            if (entry_count + 1) * RANGE_ARRAY_ENTRY_SIZE > table.len() {
+                return Err(format!("Invalid restoration table at page 0x{:x}", table_page).into());
+            }
+
+            // The pages of the ranges fill the xpress blocks after the table in order
+            let mut next_block = page_offset(table_page + 1)?;
+            let mut index_in_block = 0;
+            let mut block_pages = 0;
+            for i in 0..entry_count {
+                let entry = (i + 1) * RANGE_ARRAY_ENTRY_SIZE;
+                let start_page = read_u64(&table, entry + RANGE_START_PAGE_OFFSET);
+                let end_page = read_u64(&table, entry + RANGE_END_PAGE_OFFSET);
+                for pfn in start_page..end_page {
+                    if index_in_block == block_pages {
+                        next_block = self.parse_xpress_block(next_block)?;
+                        block_pages = self.blocks.last().map(|b| b.pages).unwrap_or(0);
+                        index_in_block = 0;
+                    }
+                    self.insert_page(
+                        pfn,
+                        PageLocation {
+                            block: self.blocks.len() - 1,
+                            index: index_in_block,
+                        },
+                    )?;
+                    index_in_block += 1;
+                }
+            }
+            table_page = read_u64(&table, RANGE_ARRAY_NEXT_TABLE_OFFSET);
+        }
+        Ok(())
+    }
+
+    fn parse_compression_sets(&mut self, start: u64, end: u64) -> BoxResult<()> {
+        // Read sets until the first invalid header, which ends the restore area
+        let mut offset = start;
+        while offset < end {
+            let mut raw = [0u8; 4];
+            if read_file_at(&self.file, offset, &mut raw) != raw.len() {
+                break;
+            }
+            let set_header = u32::from_le_bytes(raw);
+            let descriptor_count = set_header & 0xff;
+            if descriptor_count == 0 || descriptor_count > MAX_SET_DESCRIPTORS {
+                break;
+            }
+            let compressed_size = ((set_header >> 8) & 0x3f_ffff) as u64;
+            let huffman = set_header >> 31 == 1;
+
+            let mut descriptors =
+                vec![0u8; (descriptor_count as u64 * SET_DESCRIPTOR_SIZE) as usize];
+            if read_file_at(&self.file, offset + 4, &mut descriptors) != descriptors.len() {
+                break;
+            }
+            let block = self.blocks.len();
+            let mut pages = 0;
+            for descriptor in descriptors.chunks(SET_DESCRIPTOR_SIZE as usize) {
+                let descriptor = read_u64(descriptor, 0);
+                // low 4 bits are the page count minus one, the rest is the first page number
+                let page_count = (descriptor & 0xf) + 1;
+                let first_page = descriptor >> 4;
+                for pfn in first_page..first_page + page_count {
+                    self.insert_page(
+                        pfn,
+                        PageLocation {
+                            block,
+                            index: pages,
+                        },
+                    )?;
+                    pages += 1;
+                }
+            }
+            let compression = if compressed_size == pages * PAGE_SIZE {
+                Compression::None
+            } else if huffman {
+                Compression::Huffman
+            } else {
+                Compression::Lz77
+            };
+            let data_offset = offset + 4 + descriptors.len() as u64;
+            self.blocks.push(Block {
+                file_offset: data_offset,
+                compressed_size,
+                pages,
+                compression,
+            });
+            offset = data_offset + compressed_size;
+        }
+        Ok(())
+    }
+
+    fn read_page(&self, pfn: u64, buf: &mut [u8]) -> usize {
+        // Copy the start of page `pfn` into `buf`, decompressing its block if needed
+        let location = match self.pages.get(&pfn) {
+            Some(l) => *l,
+            None => return 0,
+        };
+        let mut last_block = match self.last_block.lock() {
+            Ok(b) => b,
+            Err(_) => return 0,
+        };
+        let cached = matches!(*last_block, Some((block, _)) if block == location.block);
+        if !cached {
+            let block = &self.blocks[location.block];
+            let mut compressed = vec![0u8; block.compressed_size as usize];
+            if read_file_at(&self.file, block.file_offset, &mut compressed) != compressed.len() {
+                return 0;
+            }
+            let size = (block.pages * PAGE_SIZE) as usize;
+            let data = match block.compression {
+                Compression::None => Ok(compressed),
+                Compression::Lz77 => decompress_lz77(&compressed, size),
+                Compression::Huffman => decompress_huffman(&compressed, size),
+            };
+            match data {
+                Ok(data) => *last_block = Some((location.block, data)),
+                Err(_) => return 0,
+            }
+        }
+
+        let data = match &*last_block {
+            Some((_, data)) => data,
+            None => return 0,
+        };
+        let start = (location.index * PAGE_SIZE) as usize;
+        let len = buf.len().min(data.len().saturating_sub(start));
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        len
+    }
+}
+
+impl PhysicalMemory for Hiberfil {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        // Read page by page, stop at the first page that is not in the file
+        let mut total = 0;
+        while total < buf.len() {
+            let paddr = match addr.checked_add(total as u64) {
+                Some(paddr) => paddr,
+                None => break,
+            };
+            let offset = (paddr % PAGE_SIZE) as usize;
+            let len = (PAGE_SIZE as usize - offset).min(buf.len() - total);
+            let mut page = [0u8; PAGE_SIZE as usize];
+            if self.read_page(paddr / PAGE_SIZE, &mut page[..offset + len]) != offset + len {
+                break;
+            }
+            buf[total..total + len].copy_from_slice(&page[offset..offset + len]);
+            total += len;
+        }
+        total
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for &pfn in self.pages.keys() {
+            let start = pfn * PAGE_SIZE;
+            match ranges.last_mut() {
+                Some((range_start, length)) if range_start.checked_add(*length) == Some(start) => {
+                    *length += PAGE_SIZE
+                }
+                _ => ranges.push((start, PAGE_SIZE)),
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn open(name: &str, raw: &[u8]) -> BoxResult<Hiberfil> {
+        let path = std::env::temp_dir().join(format!("lpus-{}-{}.sys", name, std::process::id()));
+        fs::write(&path, raw).unwrap();
+        let hiberfil = Hiberfil::open(&path);
+        fs::remove_file(&path).ok();
+        hiberfil
+    }
+
+    fn put(raw: &mut [u8], offset: usize, value: u64) {
+        raw[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn header(signature: &[u8; 4], first_page: u64, second_page: u64) -> Vec<u8> {
+        let mut raw = vec![0u8; PAGE_SIZE as usize];
+        raw[0..4].copy_from_slice(signature);
+        put(&mut raw, FIRST_BOOT_RESTORE_PAGE_OFFSET, first_page);
+        put(&mut raw, FIRST_KERNEL_RESTORE_PAGE_OFFSET, second_page);
+        raw
+    }
+
+    fn lz77_fill(byte: u8, size: u64) -> Vec<u8> {
+        // A literal then a match of offset 1 repeating it, with the 16-bit length
+        let length = (size - 4) as u16;
+        let mut data = vec![0x00, 0x00, 0x00, 0x40, byte, 0x07, 0x00, 0x0f, 0xff];
+        data.extend_from_slice(&length.to_le_bytes());
+        data
+    }
+
+    fn range_table(next_table: u64, ranges: &[(u64, u64)]) -> Vec<u8> {
+        let mut table = vec![0u8; PAGE_SIZE as usize];
+        put(&mut table, RANGE_ARRAY_NEXT_TABLE_OFFSET, next_table);
+        table[RANGE_ARRAY_ENTRY_COUNT_OFFSET..RANGE_ARRAY_ENTRY_COUNT_OFFSET + 4]
+            .copy_from_slice(&(ranges.len() as u32).to_le_bytes());
+        for (i, &(start, end)) in ranges.iter().enumerate() {
+            let entry = (i + 1) * RANGE_ARRAY_ENTRY_SIZE;
+            put(&mut table, entry + RANGE_START_PAGE_OFFSET, start);
+            put(&mut table, entry + RANGE_END_PAGE_OFFSET, end);
+        }
+        table
+    }
+
+    fn xpress_block(pages: u64, data: &[u8]) -> Vec<u8> {
+        // The size field is the size minus one, the data is padded to 8 bytes
+        let padded = (data.len() as u64).div_ceil(8) * 8;
+        let info = ((padded - 1) << 10) | (pages - 1);
+        let mut block = vec![0u8; XPRESS_HEADER_SIZE as usize];
+        block[0..8].copy_from_slice(XPRESS_SIGNATURE);
+        block[8..12].copy_from_slice(&(info as u32).to_le_bytes());
+        block.extend_from_slice(data);
+        block.resize(XPRESS_HEADER_SIZE as usize + padded as usize, 0);
+        block
+    }
+
+    fn compression_set(descriptors: &[(u64, u64)], huffman: bool, data: &[u8]) -> Vec<u8> {
+        // `descriptors` are (first page, page count)
+        let mut set_header = descriptors.len() as u32 | (data.len() as u32) << 8;
+        if huffman {
+            set_header |= 1 << 31;
+        }
+        let mut set = set_header.to_le_bytes().to_vec();
+        for &(first_page, count) in descriptors {
+            set.extend_from_slice(&((first_page << 4) | (count - 1)).to_le_bytes());
+        }
+        set.extend_from_slice(data);
+        set
+    }
+
+    fn read(hiberfil: &Hiberfil, addr: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        let read = hiberfil.read(addr, &mut buf);
+        buf.truncate(read);
+        buf
+    }
+
+    #[test]
+    fn win7_range_table() {
+        // Page 0x10 stored as is, pages 0x20-0x21 in one Xpress LZ77 block
+        let mut raw = header(b"hibr", 1, 0);
+        raw.extend(range_table(0, &[(0x10, 0x11), (0x20, 0x22)]));
+        raw.extend(xpress_block(1, &[0x11; PAGE_SIZE as usize]));
+        raw.extend(xpress_block(2, &lz77_fill(0x22, 2 * PAGE_SIZE)));
+
+        let hiberfil = open("win7", &raw).unwrap();
+        assert_eq!(
+            hiberfil.ranges(),
+            vec![(0x10000, 0x1000), (0x20000, 0x2000)]
+        );
+        assert_eq!(read(&hiberfil, 0x10ff0, 0x10), vec![0x11; 0x10]);
+        assert_eq!(read(&hiberfil, 0x20ff0, 0x20), vec![0x22; 0x20]);
+        // stops at the first page that is not in the file
+        assert_eq!(read(&hiberfil, 0x21ff0, 0x20), vec![0x22; 0x10]);
+    }
+
+    #[test]
+    fn win7_range_table_loop() {
+        // The table page is its own next table
+        let mut raw = header(b"hibr", 1, 0);
+        raw.extend(range_table(1, &[(0x10, 0x11)]));
+        raw.extend(xpress_block(1, &[0x11; PAGE_SIZE as usize]));
+        assert!(open("win7-loop", &raw).is_err());
+    }
+
+    #[test]
+    fn win8_compression_sets() {
+        // A boot set stored as is in page 1, a kernel LZ77 set from page 3
+        let mut raw = header(b"HIBR", 1, 3);
+        raw.extend(compression_set(
+            &[(0x30, 1)],
+            false,
+            &[0x33; PAGE_SIZE as usize],
+        ));
+        raw.resize(3 * PAGE_SIZE as usize, 0);
+        raw.extend(compression_set(
+            &[(0x40, 2), (0x50, 1)],
+            false,
+            &lz77_fill(0x44, 0x3000),
+        ));
+        raw.extend([0u8; 4]);
+
+        let hiberfil = open("win8", &raw).unwrap();
+        assert_eq!(
+            hiberfil.ranges(),
+            vec![(0x30000, 0x1000), (0x40000, 0x2000), (0x50000, 0x1000)]
+        );
+        assert_eq!(read(&hiberfil, 0x30000, 0x1000), vec![0x33; 0x1000]);
+        assert_eq!(read(&hiberfil, 0x41ff8, 0x8), vec![0x44; 0x8]);
+        assert_eq!(read(&hiberfil, 0x50000, 0x1000), vec![0x44; 0x1000]);
+    }
+
+    #[test]
+    fn invalid_page_number() {
+        // The kernel restore page is past the end of the address space
+        let raw = header(b"HIBR", 1, u64::MAX / 0x100);
+        assert!(open("win8-page", &raw).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::error::Error;
+
+use super::translate::{pte_base_from_index, read_virtual, self_ref_index};
+use super::{MemoryReader, MemoryReaderExt, PhysicalMemory};
+use crate::address::Address;
+use crate::overlay::{apply_overlays, build_default_overlays, Overlay};
+use crate::pdb_store::PdbStore;
+use crate::pe::image_name_from_memory;
+use crate::version::WindowsVersion;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+const PAGE_SIZE: u64 = 0x1000;
+const LOW_STUB_END: u64 = 0x100000;
+// how far below LmTarget we look for the ntoskrnl.exe image
+const KERNEL_SEARCH_LIMIT: u64 = 0x4000000;
+
+// Offline analysis of a captured physical memory image, the counterpart of DriverState
+// Kernel virtual addresses are translated in software through the kernel DTB
+pub struct ImageState {
+    pub pdb_store: PdbStore,
+    physical: Box<dyn PhysicalMemory>,
+    dtb: u64,
+    kernel_base: u64,
+    pte_base: u64,
+    short_version: WindowsVersion,
+}
+
+impl ImageState {
+    pub fn new(pdb_store: PdbStore, physical: Box<dyn PhysicalMemory>) -> BoxResult<Self> {
+        // Find the kernel DTB and base from the low stub (PROCESSOR_START_BLOCK) below 1MB
+        // Ref: https://github.com/ufrisk/MemProcFS/blob/master/vmm/vmmwininit.c (VmmWinInit_DTB_FindValidate_X64_LowStub)
+        let (dtb, lm_target) = find_low_stub(physical.as_ref())
+            .ok_or("Cannot find the low stub, give the DTB and kernel base by hand")?;
+        let mut state = Self::build(pdb_store, physical, dtb, 0)?;
+        state.kernel_base = state.find_kernel_base(lm_target).ok_or(
+            "Cannot find ntoskrnl.exe below the low stub target, give the kernel base by hand",
+        )?;
+        state.short_version = state.read_version()?;
+        state.apply_overlays(&build_default_overlays())?;
+        Ok(state)
+    }
+
+    pub fn from_dtb(
+        pdb_store: PdbStore,
+        physical: Box<dyn PhysicalMemory>,
+        dtb: u64,
+        kernel_base: u64,
+    ) -> BoxResult<Self> {
+        let mut state = Self::build(pdb_store, physical, dtb, kernel_base)?;
+        state.short_version = state.read_version()?;
+        state.apply_overlays(&build_default_overlays())?;
+        Ok(state)
+    }
+
+    fn build(
+        pdb_store: PdbStore,
+        physical: Box<dyn PhysicalMemory>,
+        dtb: u64,
+        kernel_base: u64,
+    ) -> BoxResult<Self> {
+        let index = self_ref_index(physical.as_ref(), dtb)
+            .ok_or(format!("0x{:x} is not a valid kernel DTB", dtb))?;
+        Ok(Self {
+            pdb_store,
+            physical,
+            dtb,
+            kernel_base,
+            pte_base: pte_base_from_index(index),
+            short_version: WindowsVersion::WindowsUnknown,
+        })
+    }
+
+    pub fn get_dtb(&self) -> u64 {
+        self.dtb
+    }
+
+    pub fn physical(&self) -> &dyn PhysicalMemory {
+        self.physical.as_ref()
+    }
+
+    pub fn get_build_number(&self) -> BoxResult<u32> {
+        let ptr = self.kernel_base + self.pdb_store.get_offset_r("NtBuildNumber")?;
+        let build_number: u32 = self.try_deref_addr(ptr)?;
+        // the high bits mark a checked/free build
+        Ok(build_number & 0xffff)
+    }
+
+    pub fn apply_overlays(&mut self, overlays: &[Overlay]) -> BoxResult<()> {
+        // Those for the build in the image as well as those for every build
+        let build = self.get_build_number()?;
+        apply_overlays(&mut self.pdb_store, overlays, Some(build))
+    }
+
+    fn read_version(&self) -> BoxResult<WindowsVersion> {
+        Ok(WindowsVersion::from_build_number(self.get_build_number()?))
+    }
+
+    fn find_kernel_base(&self, lm_target: u64) -> Option<u64> {
+        let mut base = lm_target & !(PAGE_SIZE - 1);
+        let limit = lm_target.saturating_sub(KERNEL_SEARCH_LIMIT);
+        while base > limit {
+            if let Ok((name, size)) = image_name_from_memory(self, base) {
+                let image_end = base.saturating_add(size as u64);
+                if name.eq_ignore_ascii_case("ntoskrnl.exe") && lm_target < image_end {
+                    return Some(base);
+                }
+            }
+            base -= PAGE_SIZE;
+        }
+        None
+    }
+}
+
+fn find_low_stub(mem: &dyn PhysicalMemory) -> Option<(u64, u64)> {
+    let mut page = vec![0u8; PAGE_SIZE as usize];
+    let read_u64 = |b: &[u8], o: usize| {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(&b[o..o + 8]);
+        u64::from_le_bytes(v)
+    };
+    for addr in (PAGE_SIZE..LOW_STUB_END).step_by(PAGE_SIZE as usize) {
+        if mem.read(addr, &mut page) != page.len() {
+            continue;
+        }
+        // PROCESSOR_START_BLOCK->Jmp
+        if read_u64(&page, 0) & 0xffff_ffff_ffff_00ff != 0x0000_0001_0006_00e9 {
+            continue;
+        }
+        // PROCESSOR_START_BLOCK->LmTarget, a kernel address
+        let lm_target = read_u64(&page, 0x70);
+        if lm_target & 0xffff_f800_0000_0003 != 0xffff_f800_0000_0000 {
+            continue;
+        }
+        // PROCESSOR_START_BLOCK->ProcessorState.SpecialRegisters.Cr3
+        let cr3 = read_u64(&page, 0xa0);
+        if cr3 & 0xffff_ff00_0000_0fff != 0 {
+            continue;
+        }
+        return Some((cr3, lm_target));
+    }
+    None
+}
+
+impl MemoryReader for ImageState {
+    fn read_virtual(&self, addr: u64, buf: &mut [u8]) -> usize {
+        read_virtual(self.physical.as_ref(), self.dtb, addr, buf)
+    }
+
+    fn read_physical(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.physical.read(addr, buf)
+    }
+
+    fn get_kernel_base(&self) -> Address {
+        Address::from_base(self.kernel_base)
+    }
+
+    fn get_pte_base(&self) -> Address {
+        Address::from_base(self.pte_base)
+    }
+
+    fn pdb_store(&self) -> &PdbStore {
+        &self.pdb_store
+    }
+
+    fn windows_version(&self) -> WindowsVersion {
+        self.short_version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isf::parse_isf_file;
+    use std::path::Path;
+
+    struct Memory(Vec<u8>);
+
+    impl Memory {
+        fn put(&mut self, addr: u64, bytes: &[u8]) {
+            let addr = addr as usize;
+            self.0[addr..addr + bytes.len()].copy_from_slice(bytes);
+        }
+
+        fn put_u64(&mut self, addr: u64, value: u64) {
+            self.put(addr, &value.to_le_bytes());
+        }
+    }
+
+    impl PhysicalMemory for Memory {
+        fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+            let start = (addr as usize).min(self.0.len());
+            let len = buf.len().min(self.0.len() - start);
+            buf[..len].copy_from_slice(&self.0[start..start + len]);
+            len
+        }
+
+        fn ranges(&self) -> Vec<(u64, u64)> {
+            vec![(0, self.0.len() as u64)]
+        }
+    }
+
+    const DTB: u64 = 0x1000;
+    const KERNEL_BASE: u64 = 0xffff_f800_0000_0000;
+    const BUILD_NUMBER_RVA: u64 = 0x2000;
+
+    fn kernel_image() -> Memory {
+        // The page tables map ntoskrnl.exe at KERNEL_BASE: its headers, a page in
+        // transition with the export directory, the page of NtBuildNumber, and a 2MB
+        // page after it. The low stub at 0x8000 points into the image.
+        let mut mem = Memory(vec![0u8; 0x40_1000]);
+        mem.put_u64(DTB + 0x1ed * 8, DTB | 1);
+        mem.put_u64(DTB + 0x1f0 * 8, 0x2000 | 1);
+        mem.put_u64(0x2000, 0x3000 | 1);
+        mem.put_u64(0x3000, 0x4000 | 1);
+        mem.put_u64(0x3000 + 8, 0x20_0000 | 0x80 | 1);
+        mem.put_u64(0x4000, 0x10000 | 1);
+        mem.put_u64(0x4000 + 8, 0x11000 | (1 << 11));
+        mem.put_u64(0x4000 + 16, 0x12000 | 1);
+
+        mem.put_u64(0x8000, 0x0000_0001_0006_00e9);
+        mem.put_u64(0x8070, KERNEL_BASE + 0x1800);
+        mem.put_u64(0x80a0, DTB);
+
+        // DOS header, PE header, a PE32+ optional header with 16 directories, the
+        // export directory at RVA 0x1000 and its name at 0x1100
+        mem.put(0x10000, b"MZ");
+        mem.put(0x1003c, &0x40u32.to_le_bytes());
+        mem.put(0x10040, b"PE\0\0");
+        mem.put(0x10040 + 4 + 16, &0xf0u16.to_le_bytes());
+        mem.put(0x10058, &0x20bu16.to_le_bytes());
+        mem.put(0x10058 + 56, &0x3000u32.to_le_bytes());
+        mem.put(0x10058 + 108, &16u32.to_le_bytes());
+        mem.put(0x10058 + 112, &0x1000u32.to_le_bytes());
+        mem.put(0x10058 + 116, &0x28u32.to_le_bytes());
+        mem.put(0x1100c, &0x1100u32.to_le_bytes());
+        mem.put(0x11100, b"ntoskrnl.exe\0");
+        // 7601 of a free build
+        mem.put(0x12000, &0xf000_1db1u32.to_le_bytes());
+        mem.put(0x20_0010, &[0x5a; 4]);
+        mem
+    }
+
+    fn pdb_store() -> PdbStore {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/win7_isf.json");
+        let mut pdb_store = parse_isf_file(&fixture).unwrap();
+        let overlay = Overlay {
+            min_build: None,
+            max_build: None,
+            structs: vec![],
+            symbols: vec![("NtBuildNumber".to_string(), BUILD_NUMBER_RVA)],
+        };
+        apply_overlays(&mut pdb_store, &[overlay], None).unwrap();
+        pdb_store
+    }
+
+    #[test]
+    fn low_stub_and_kernel_image() {
+        let state = ImageState::new(pdb_store(), Box::new(kernel_image())).unwrap();
+        assert_eq!(state.get_dtb(), DTB);
+        assert_eq!(state.get_kernel_base().address(), KERNEL_BASE);
+        assert_eq!(state.get_pte_base().address(), 0xffff_f680_0000_0000);
+        assert_eq!(state.get_build_number().unwrap(), 7601);
+        assert_eq!(state.windows_version(), WindowsVersion::Windows7);
+
+        // through the 2MB page
+        let value: u32 = state.try_deref_addr(KERNEL_BASE + 0x20_0010).unwrap();
+        assert_eq!(value, 0x5a5a_5a5a);
+    }
+
+    #[test]
+    fn dtb_given_by_hand() {
+        let state =
+            ImageState::from_dtb(pdb_store(), Box::new(kernel_image()), DTB, KERNEL_BASE).unwrap();
+        assert_eq!(state.get_build_number().unwrap(), 7601);
+        // not a DTB: no entry maps the table to itself
+        assert!(ImageState::from_dtb(pdb_store(), Box::new(kernel_image()), 0x2000, 0).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::raw_image::read_file_at;
+use super::segmented::{Segment, SegmentedFile};
+use super::PhysicalMemory;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// LiME (Linux Memory Extractor) format, a header before every range
+// Ref: https://github.com/504ensicsLabs/LiME/blob/master/doc/README.md (Spec)
+
+const LIME_MAGIC: u32 = 0x4c69_4d45;
+const LIME_VERSION: u32 = 1;
+const LIME_HEADER_SIZE: u64 = 0x20;
+
+pub struct LimeImage {
+    memory: SegmentedFile,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(v)
+}
+
+impl LimeImage {
+    pub fn has_signature(magic: &[u8]) -> bool {
+        magic.len() >= 4 && read_u32(magic, 0) == LIME_MAGIC
+    }
+
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        let file = Mutex::new(File::open(path)?);
+        let size = file
+            .lock()
+            .map_err(|_| "Poisoned file lock")?
+            .metadata()?
+            .len();
+
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        while offset + LIME_HEADER_SIZE <= size {
+            let mut header = [0u8; LIME_HEADER_SIZE as usize];
+            if read_file_at(&file, offset, &mut header) != header.len() {
+                break;
+            }
+            if read_u32(&header, 0) != LIME_MAGIC {
+                return Err(format!("LiME header not found at 0x{:x}", offset).into());
+            }
+            if read_u32(&header, 4) != LIME_VERSION {
+                return Err(format!("Unsupported LiME version {}", read_u32(&header, 4)).into());
+            }
+            // the end address is inclusive
+            let start = read_u64(&header, 8);
+            let end = read_u64(&header, 0x10);
+            if end < start {
+                return Err(format!("Invalid LiME range at 0x{:x}", offset).into());
+            }
+            let file_offset = offset + LIME_HEADER_SIZE;
+            // the range must be in the file, a corrupted header must not wrap around
+            let next = (end - start)
+                .checked_add(1)
+                .and_then(|length| file_offset.checked_add(length));
+            let length = match next {
+                Some(next) if next <= size => next - file_offset,
+                _ => {
+                    return Err(
+                        format!("LiME range at 0x{:x} is past the end of the file", offset).into(),
+                    )
+                }
+            };
+            segments.push(Segment {
+                start,
+                length,
+                file_offset,
+            });
+            offset = file_offset + length;
+        }
+        if segments.is_empty() {
+            return Err("No memory range found in the LiME image".into());
+        }
+        Ok(Self {
+            memory: SegmentedFile::new(file, segments),
+        })
+    }
+}
+
+impl PhysicalMemory for LimeImage {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.memory.read(addr, buf)
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        self.memory.ranges()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn open(name: &str, raw: &[u8]) -> BoxResult<LimeImage> {
+        let path = std::env::temp_dir().join(format!("lpus-{}-{}.lime", name, std::process::id()));
+        fs::write(&path, raw).unwrap();
+        let image = LimeImage::open(&path);
+        fs::remove_file(&path).ok();
+        image
+    }
+
+    fn range(raw: &mut Vec<u8>, start: u64, end: u64, fill: u8) {
+        // A header, then the bytes of [start, end]
+        let mut header = [0u8; LIME_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&LIME_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&LIME_VERSION.to_le_bytes());
+        header[8..0x10].copy_from_slice(&start.to_le_bytes());
+        header[0x10..0x18].copy_from_slice(&end.to_le_bytes());
+        raw.extend_from_slice(&header);
+        raw.resize(raw.len() + (end - start + 1) as usize, fill);
+    }
+
+    #[test]
+    fn ranges() {
+        let mut raw = Vec::new();
+        range(&mut raw, 0, 0xfff, 0x11);
+        range(&mut raw, 0x100000, 0x101fff, 0x22);
+        let image = open("ranges", &raw).unwrap();
+        assert_eq!(image.ranges(), vec![(0, 0x1000), (0x100000, 0x2000)]);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(image.read(0xffe, &mut buf), 2);
+        assert_eq!(buf[..2], [0x11, 0x11]);
+        assert_eq!(image.read(0x101ffc, &mut buf), 4);
+        assert_eq!(buf, [0x22; 4]);
+    }
+
+    #[test]
+    fn bad_headers() {
+        let mut raw = Vec::new();
+        range(&mut raw, 0, 0xfff, 0);
+        let mut bad_magic = raw.clone();
+        bad_magic.extend_from_slice(&[0xff; LIME_HEADER_SIZE as usize]);
+        assert!(open("magic", &bad_magic).is_err());
+
+        let mut bad_version = raw.clone();
+        bad_version[4] = 2;
+        assert!(open("version", &bad_version).is_err());
+
+        // the whole address space, its length does not fit in 64 bits
+        let mut whole = raw.clone();
+        whole[0x10..0x18].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(open("whole", &whole).is_err());
+
+        // a range longer than the rest of the file
+        let mut truncated = raw.clone();
+        truncated.truncate(truncated.len() - 1);
+        assert!(open("truncated", &truncated).is_err());
+
+        // end before start
+        let mut bad_range = raw;
+        bad_range[0x10..0x18].copy_from_slice(&0u64.to_le_bytes());
+        bad_range[8..0x10].copy_from_slice(&0x1000u64.to_le_bytes());
+        assert!(open("range", &bad_range).is_err());
+        assert!(open("empty", &[]).is_err());
+    }
+}
@@ -0,0 +1,670 @@
+pub mod acquire;
+pub mod crash_dump;
+pub mod elf_core;
+pub mod format;
+pub mod hiberfil;
+pub mod image_state;
+pub mod lime;
+pub mod page_cache;
+pub mod raw_image;
+pub mod replay;
+pub mod segmented;
+pub mod translate;
+pub mod vmware;
+pub mod xpress;
+
+use std::error::Error;
+use std::mem::size_of;
+use std::panic;
+use std::slice;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::address::Address;
+use crate::error::{LpusError, LpusResult};
+use crate::pdb_store::{qualify, split_module, PdbStore};
+use crate::type_info::TypeInfo;
+use crate::utils::mask_cast::MaskCast;
+use crate::version::WindowsVersion;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+const PAGE_SIZE: u64 = 0x1000;
+const POOL_ALIGNMENT: u64 = 0x10;
+const POOL_TAG_OFFSET: u64 = 0x4;
+// The tag search over the nonpaged range runs on this many slices at once. A constant and
+// not the CPU count so a recorded session sends the same IOCTLs on every machine
+const POOL_SCAN_SLICES: u64 = 16;
+// FILETIME of 1970-01-01
+const WINDOWS_EPOCH_DIFF: u64 = 11644473600000 * 10000;
+// The boot time from the tick count is not exact, allow processes a bit older
+const BOOT_TIME_PENALTY_MS: u64 = 10 * 3600 * 1000;
+
+pub fn process_time_in_range(filetime: u64, boot_time_ms: u64, now_ms: u64) -> bool {
+    // `filetime` is after boot (0 if unknown) and not in the future
+    if filetime < WINDOWS_EPOCH_DIFF {
+        return false;
+    }
+    let process_time_epoch = (filetime - WINDOWS_EPOCH_DIFF) / 10000; // in milisecond
+    process_time_epoch >= boot_time_ms.saturating_sub(BOOT_TIME_PENALTY_MS)
+        && process_time_epoch <= now_ms
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressSpace {
+    Virtual,
+    Physical,
+}
+
+// Everything the scanners need from a source of kernel memory.
+// DriverState reads through the lpus.sys IOCTLs, other implementations can read
+// from captured images or fixtures without any driver loaded.
+pub trait MemoryReader: Sync {
+    // Read kernel virtual memory at `addr` into `buf`, return the number of bytes read
+    fn read_virtual(&self, addr: u64, buf: &mut [u8]) -> usize;
+
+    // Read physical memory at `addr` into `buf`, return the number of bytes read
+    fn read_physical(&self, addr: u64, buf: &mut [u8]) -> usize;
+
+    fn get_kernel_base(&self) -> Address;
+
+    fn get_pte_base(&self) -> Address;
+
+    fn pdb_store(&self) -> &PdbStore;
+
+    fn windows_version(&self) -> WindowsVersion;
+
+    fn use_old_tag(&self) -> bool {
+        // use old tag to scan, for Window < 8
+        self.windows_version() < WindowsVersion::Windows8
+    }
+
+    fn valid_process_time(&self, filetime: u64) -> bool {
+        // Without the boot time, only reject times before 1970 or in the future
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        process_time_in_range(filetime, 0, now_ms)
+    }
+
+    fn read_many(&self, space: AddressSpace, requests: &[(u64, usize)]) -> Vec<Vec<u8>> {
+        // One buffer for each (address, size), cut short where the read failed
+        requests
+            .iter()
+            .map(|&(addr, size)| {
+                let mut buf = vec![0u8; size];
+                let read = match space {
+                    AddressSpace::Virtual => self.read_virtual(addr, &mut buf),
+                    AddressSpace::Physical => self.read_physical(addr, &mut buf),
+                };
+                buf.truncate(read);
+                buf
+            })
+            .collect()
+    }
+
+    fn prefetch(&self, _space: AddressSpace, _ranges: &[(u64, u64)]) {
+        // A hint that the (address, length) ranges are read soon, readers with a cache
+        // can fetch them in one go
+    }
+
+    fn find_pool_tag(&self, start: u64, end: u64, tag: &[u8; 4]) -> u64 {
+        // Return the address of the next pool header in [start, end) with the tag
+        // or `end` if there is none, the same contract as the ScanPoolRemote IOCTL
+        let mut page = start & !(PAGE_SIZE - 1);
+        let mut buf = vec![0u8; PAGE_SIZE as usize];
+        while page < end {
+            if self.read_virtual(page, &mut buf) == buf.len() {
+                let mut offset = start.saturating_sub(page);
+                offset = (offset + POOL_ALIGNMENT - 1) & !(POOL_ALIGNMENT - 1);
+                while offset + POOL_TAG_OFFSET + 4 <= PAGE_SIZE && page + offset < end {
+                    let o = (offset + POOL_TAG_OFFSET) as usize;
+                    if &buf[o..o + 4] == tag {
+                        return page + offset;
+                    }
+                    offset += POOL_ALIGNMENT;
+                }
+            }
+            page = match page.checked_add(PAGE_SIZE) {
+                Some(p) => p,
+                None => break,
+            };
+        }
+        end
+    }
+}
+
+// A physical address space, e.g. a memory image on disk
+// ImageState turns one into a MemoryReader by translating virtual addresses
+pub trait PhysicalMemory: Send + Sync {
+    // Read physical memory at `addr` into `buf`, return the number of bytes read
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize;
+
+    // (start, length) of each physical range present in the source
+    fn ranges(&self) -> Vec<(u64, u64)>;
+}
+
+// Typed reads and PDB aware helpers on top of any MemoryReader
+pub trait MemoryReaderExt: MemoryReader {
+    fn deref_addr<T>(&self, addr: u64, outbuf: &mut T) {
+        let buf = unsafe { slice::from_raw_parts_mut(outbuf as *mut T as *mut u8, size_of::<T>()) };
+        self.read_virtual(addr, buf);
+    }
+
+    fn deref_addr_ptr<T>(&self, addr: u64, outptr: *mut T, output_len_as_byte: u64) {
+        let buf = unsafe { slice::from_raw_parts_mut(outptr as *mut u8, output_len_as_byte as usize) };
+        self.read_virtual(addr, buf);
+    }
+
+    fn try_read_virtual(&self, addr: u64, buf: &mut [u8]) -> LpusResult<()> {
+        // read_virtual() that fails unless every byte is read
+        let read = self.read_virtual(addr, buf);
+        if read == buf.len() {
+            Ok(())
+        } else {
+            Err(LpusError::ReadFailed { address: addr, size: buf.len(), read })
+        }
+    }
+
+    fn try_read_physical(&self, addr: u64, buf: &mut [u8]) -> LpusResult<()> {
+        let read = self.read_physical(addr, buf);
+        if read == buf.len() {
+            Ok(())
+        } else {
+            Err(LpusError::ReadFailed { address: addr, size: buf.len(), read })
+        }
+    }
+
+    fn try_deref_addr<T: Default>(&self, addr: u64) -> LpusResult<T> {
+        // deref_addr_new() that reports a failed read instead of giving zero
+        let mut r: T = Default::default();
+        let buf = unsafe { slice::from_raw_parts_mut(&mut r as *mut T as *mut u8, size_of::<T>()) };
+        self.try_read_virtual(addr, buf)?;
+        Ok(r)
+    }
+
+    fn try_deref_physical_addr<T: Default>(&self, addr: u64) -> LpusResult<T> {
+        let mut r: T = Default::default();
+        let buf = unsafe { slice::from_raw_parts_mut(&mut r as *mut T as *mut u8, size_of::<T>()) };
+        self.try_read_physical(addr, buf)?;
+        Ok(r)
+    }
+
+    fn deref_addr_new<T: Default>(&self, addr: u64) -> T {
+        // Zero, or the bytes read, when the read fails, try_deref_addr() reports it
+        let mut r: T = Default::default();
+        if addr != 0 {
+            self.deref_addr(addr, &mut r);
+        }
+        r
+    }
+
+    fn deref_physical_addr<T: Default>(&self, addr: u64) -> T {
+        /*
+        Read content at a PHYSICAL ADDRESS
+            - addr: PHYSICAL address of the paging structure
+        */
+        let mut outbuf: T = Default::default();
+        if addr != 0 {
+            let buf = unsafe {
+                slice::from_raw_parts_mut(&mut outbuf as *mut T as *mut u8, size_of::<T>())
+            };
+            self.read_physical(addr, buf);
+        }
+        outbuf
+    }
+
+    fn deref_array<T: Default + Clone>(&self, addr: &Address, len: u64) -> LpusResult<Vec<T>> {
+        let resolver = |p| self.try_deref_addr(p);
+        let mut r: Vec<T> = vec![Default::default(); len as usize];
+        let size_in_byte = (len as usize) * size_of::<T>();
+        let buf = unsafe { slice::from_raw_parts_mut(r.as_mut_ptr() as *mut u8, size_in_byte) };
+        self.try_read_virtual(addr.try_get(&resolver)?, buf)?;
+        Ok(r)
+    }
+
+    fn deref_array_physical<T: Default + Clone>(
+        &self,
+        addr: &Address,
+        len: u64,
+    ) -> LpusResult<Vec<T>> {
+        let resolver = |p| self.try_deref_physical_addr(p);
+        let mut outbuf: Vec<T> = vec![Default::default(); len as usize];
+        let size_in_byte = (len as usize) * size_of::<T>();
+        let buf = unsafe { slice::from_raw_parts_mut(outbuf.as_mut_ptr() as *mut u8, size_in_byte) };
+        self.try_read_physical(addr.try_get(&resolver)?, buf)?;
+        Ok(outbuf)
+    }
+
+    fn address_of(&self, addr: &Address, name: &str) -> BoxResult<u64> {
+        let resolver = |p| self.try_deref_addr(p);
+        let (r, _mask, _required_len) = self.pdb_store().decompose(&addr, &name)?;
+        Ok(r.try_get(&resolver)?)
+    }
+
+    fn decompose<T: Default + MaskCast<u64>>(&self, addr: &Address, name: &str) -> BoxResult<T> {
+        // interface to pdb_store.decompose
+        let resolver = |p| self.try_deref_addr(p);
+        let (addr, mask_handler, required_len) = self.pdb_store().decompose(&addr, &name)?;
+        let r: T = self.try_deref_addr(addr.try_get(&resolver)?)?;
+        if size_of::<T>() as u64 * 8 >= required_len {
+            Ok(T::mask_cast_from(mask_handler(r.mask_cast_to())))
+        } else {
+            Err(format!("Required length is {} while buffer size if {}", required_len, size_of::<T>() * 8).into())
+        }
+    }
+
+    fn decompose_physical<T: Default + MaskCast<u64>>(&self, addr: &Address, name: &str) -> BoxResult<T> {
+        // The same as "decompose()", but use physical address
+        let resolver = |p| self.try_deref_physical_addr(p);
+        let (addr, mask_handler, required_len) = self.pdb_store().decompose(&addr, &name)?;
+        let r: T = self.try_deref_physical_addr(addr.try_get(&resolver)?)?;
+        if size_of::<T>() as u64 * 8 >= required_len {
+            Ok(T::mask_cast_from(mask_handler(r.mask_cast_to())))
+        } else {
+            Err(format!("Required length is {} while buffer size if {}", required_len, size_of::<T>() * 8, ).into())
+        }
+    }
+
+    fn decompose_enum(
+        &self,
+        addr: &Address,
+        name: &str,
+        enum_name: Option<&str>,
+    ) -> BoxResult<Option<String>> {
+        // The enumerator a member holds, None for a value the build does not name
+        // An enum member names its type, an integer holding one (_KTHREAD.State) is told
+        let value: u64 = self.decompose(addr, name)?;
+        let pdb_store = self.pdb_store();
+        let enum_name = match enum_name {
+            Some(enum_name) => enum_name.to_string(),
+            None => {
+                // The type of a member in `module!_STRUCT.Member` is in the same module
+                let module = split_module(name).0;
+                match pdb_store.type_of(name)? {
+                    TypeInfo::Bitfield { underlying, .. } => match *underlying {
+                        TypeInfo::Enum { name: type_name, .. } => qualify(module, &type_name),
+                        t => return Err(format!("{} is {}, not an enum", name, t).into()),
+                    },
+                    TypeInfo::Enum { name: type_name, .. } => qualify(module, &type_name),
+                    t => return Err(format!("{} is {}, not an enum", name, t).into()),
+                }
+            }
+        };
+        Ok(pdb_store
+            .enum_name(&enum_name, value)?
+            .map(|enumerator| enumerator.to_string()))
+    }
+
+    fn decompose_array<T: Default + Clone>(
+        &self,
+        addr: &Address,
+        name: &str,
+        len: u64,
+    ) -> BoxResult<Vec<T>> {
+        // interface to pdb_store.decompose for array
+        // `len` is checked against the array the PDB declares, counted in elements of T
+        if let Some((element_size, count)) = self.pdb_store().array_info(name)? {
+            let capacity = element_size * count / (size_of::<T>() as u64).max(1);
+            if len > capacity {
+                return Err(LpusError::ArrayTooShort {
+                    name: name.to_string(),
+                    len,
+                    count: capacity,
+                }
+                .into());
+            }
+        }
+        let resolver = |p| self.try_deref_addr(p);
+        let (addr, _mask, _required_len) = self.pdb_store().decompose(&addr, &name)?;
+        let mut r: Vec<T> = vec![Default::default(); len as usize];
+        let size_in_byte = (len as usize) * size_of::<T>();
+        let buf = unsafe { slice::from_raw_parts_mut(r.as_mut_ptr() as *mut u8, size_in_byte) };
+        self.try_read_virtual(addr.try_get(&resolver)?, buf)?;
+        Ok(r)
+    }
+
+    fn get_unicode_string(&self, unicode_str_addr: u64) -> BoxResult<String> {
+        if unicode_str_addr == 0 {
+            return Err(LpusError::InvalidUnicodeString(unicode_str_addr).into());
+        }
+
+        let buffer_ptr =
+            unicode_str_addr + self.pdb_store().get_offset_r("_UNICODE_STRING.Buffer")?;
+        let capacity_addr = unicode_str_addr
+            + self
+                .pdb_store()
+                .get_offset_r("_UNICODE_STRING.MaximumLength")?;
+
+        let strlen: u16 = self.try_deref_addr(unicode_str_addr)?;
+        let capacity: u16 = self.try_deref_addr(capacity_addr)?;
+        let bufaddr: u64 = self.try_deref_addr(buffer_ptr)?;
+
+        if bufaddr == 0 || strlen > capacity || strlen == 0 || strlen % 2 != 0 {
+            return Err(LpusError::InvalidUnicodeString(unicode_str_addr).into());
+        }
+
+        let mut buf = vec![0u16; (strlen / 2) as usize];
+        let bytes = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, strlen as usize) };
+        self.try_read_virtual(bufaddr, bytes)?;
+        // TODO: BUG with deref_array, len is wrong,
+        // >> the size of vector is strlen / 2
+        // >> the size to dereference is strlen
+        // XXX: use Vec<u8> and turn to Vec<u16>
+        // let buf: Vec<u16> = self.deref_array(&Address::from_base(bufaddr), (strlen / 2) as u64);
+
+        String::from_utf16(&buf).map_err(|_| LpusError::InvalidUnicodeString(unicode_str_addr).into())
+    }
+
+    fn get_nonpaged_range(&self, ntosbase: &Address) -> BoxResult<[Address; 2]> {
+        // TODO: Add support for other Windows version here
+        match self.windows_version() {
+            WindowsVersion::WindowsFastRing => {
+                let mistate = ntosbase.clone() + self.pdb_store().get_offset_r("MiState")?;
+                let path_first_va: String = vec![
+                    "_MI_SYSTEM_INFORMATION",
+                    "Hardware",
+                    "SystemNodeNonPagedPool",
+                    "NonPagedPoolFirstVa",
+                ]
+                .join(".");
+                let path_last_va: String = vec![
+                    "_MI_SYSTEM_INFORMATION",
+                    "Hardware",
+                    "SystemNodeNonPagedPool",
+                    "NonPagedPoolLastVa",
+                ]
+                .join(".");
+                let first_va = Address::from_base(self.decompose(&mistate, &path_first_va)?);
+                let last_va = Address::from_base(self.decompose(&mistate, &path_last_va)?);
+                Ok([first_va, last_va])
+            }
+            WindowsVersion::Windows10_2019 | WindowsVersion::Windows10_2018 => {
+                let mistate = ntosbase.clone() + self.pdb_store().get_offset_r("MiState")?;
+                let path_first_va: String = vec![
+                    "_MI_SYSTEM_INFORMATION",
+                    "Hardware",
+                    "SystemNodeInformation",
+                    "NonPagedPoolFirstVa",
+                ]
+                .join(".");
+                let path_last_va: String = vec![
+                    "_MI_SYSTEM_INFORMATION",
+                    "Hardware",
+                    "SystemNodeInformation",
+                    "NonPagedPoolLastVa",
+                ]
+                .join(".");
+                let first_va = Address::from_base(self.decompose(&mistate, &path_first_va)?);
+                let last_va = Address::from_base(self.decompose(&mistate, &path_last_va)?);
+                Ok([first_va, last_va])
+            }
+            WindowsVersion::Windows7 => {
+                let path_first_va =
+                    ntosbase.clone() + self.pdb_store().get_offset_r("MmNonPagedPoolStart")?;
+                let path_last_va =
+                    ntosbase.clone() + self.pdb_store().get_offset_r("MiNonPagedPoolEnd")?;
+                let first_va = Address::from_base(self.try_deref_addr(path_first_va.address())?);
+                let last_va = Address::from_base(self.try_deref_addr(path_last_va.address())?);
+                Ok([first_va, last_va])
+            }
+            // the nonpaged pool algorithm is not implemented for this version
+            version => Err(LpusError::UnsupportedVersion(version).into()),
+        }
+    }
+
+    fn scan_pool<F>(
+        &self,
+        tag: &[u8; 4],
+        expected_struct: &str,
+        mut handler: F,
+    ) -> BoxResult<bool>
+    where
+        F: FnMut(Address, &[u8], Address) -> BoxResult<ScannerSignal>, // F(Pool Address, Pool Header Data, Pool Data Address)
+                                                              // TODO: Pool Header as a real struct
+    {
+        // TODO: scan large pool
+        // TODO: make generator, in hold: https://github.com/rust-lang/rust/issues/43122
+        // Making this function a generator will turn the call to a for loop
+        // https://docs.rs/gen-iter/0.2.0/gen_iter/
+        // >> More flexibility in code
+        let pool_header_size = self.pdb_store().get_offset_r("_POOL_HEADER.struct_size")?;
+        let minimum_block_size = self
+            .pdb_store()
+            .get_offset_r(&format!("{}.struct_size", expected_struct))?
+            + pool_header_size;
+        let ntosbase = self.get_kernel_base();
+        let [start_address, end_address] = self.get_nonpaged_range(&ntosbase)?;
+
+        println!(
+            "kernel base: {}; non-paged pool (start, end): ({}, {}); tag: {:?} {}",
+            ntosbase, start_address, end_address, tag, expected_struct
+        );
+
+        // Every tagged pool header in the range, searched slice by slice in parallel
+        let candidates = find_pool_tags_parallel(
+            self,
+            start_address.address(),
+            end_address.address(),
+            tag,
+            pool_header_size,
+        );
+
+        // The handler still sees the pools in address order, a pool inside a chunk
+        // the handler took is skipped even when the chunk runs into the next slice
+        let mut ptr = start_address.address();
+        for next_found in candidates {
+            if next_found < ptr {
+                continue;
+            }
+
+            let pool_addr = Address::from_base(next_found);
+            let header: Vec<u8> = match self.deref_array(&pool_addr, pool_header_size) {
+                Ok(header) => header,
+                Err(_) => continue,
+            };
+            let chunk_size = (header[2] as u64) * 16u64;
+
+            if pool_addr.address() + chunk_size > end_address.address() {
+                // the chunk surpasses the non page pool range
+                break;
+            }
+
+            // automatically reject bad chunk
+            if chunk_size < minimum_block_size {
+                ptr = next_found + 0x4;
+                continue;
+            }
+
+            let data_addr = Address::from_base(pool_addr.address() + pool_header_size);
+            let handler_status = handler(pool_addr, &header, data_addr).unwrap_or(ScannerSignal::SearchNext);
+            match handler_status {
+                ScannerSignal::FoundStruct => {
+                    ptr = next_found + chunk_size;
+                }
+
+                ScannerSignal::SearchNext => {
+                    ptr = next_found + 0x4;
+                }
+
+                ScannerSignal::StopScan => {
+                    break;
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: MemoryReader + ?Sized> MemoryReaderExt for R {}
+
+fn find_pool_tags<R: MemoryReader + ?Sized>(
+    reader: &R,
+    start: u64,
+    end: u64,
+    limit: u64,
+    tag: &[u8; 4],
+) -> Vec<u64> {
+    // Tagged pool headers in [start, end), the search may look up to `limit` so a header
+    // right before `end` is found whole, anything found past `end` belongs to the next slice
+    let mut found = Vec::new();
+    let mut ptr = start;
+    while ptr < end {
+        let next_found = reader.find_pool_tag(ptr, limit, tag);
+        if next_found >= end {
+            break;
+        }
+        found.push(next_found);
+        ptr = next_found + 0x4;
+    }
+    found
+}
+
+fn find_pool_tags_parallel<R: MemoryReader + ?Sized>(
+    reader: &R,
+    start: u64,
+    end: u64,
+    tag: &[u8; 4],
+    overlap: u64,
+) -> Vec<u64> {
+    if start >= end {
+        return Vec::new();
+    }
+    let slice_size = ((end - start) / POOL_SCAN_SLICES + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let slice_size = slice_size.max(PAGE_SIZE);
+    let slices: Vec<(u64, u64)> = (0..POOL_SCAN_SLICES)
+        .map(|i| start.saturating_add(i * slice_size))
+        .take_while(|&s| s < end)
+        .map(|s| (s, s.saturating_add(slice_size).min(end)))
+        .collect();
+
+    // Each slice comes back in order and the slices are joined in order, the result is the
+    // same as one search over the whole range
+    thread::scope(|scope| {
+        let workers: Vec<_> = slices
+            .iter()
+            .map(|&(s, e)| {
+                let limit = e.saturating_add(overlap).min(end);
+                scope.spawn(move || find_pool_tags(reader, s, e, limit, tag))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ScannerSignal {
+    FoundStruct,        // Found a valid struct, scan for pool in the next chunk
+    SearchNext,         // Keep doing exhausting search
+    StopScan            // Stop the scanning process
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isf::parse_isf_file;
+    use std::path::Path;
+
+    const BASE: u64 = 0xffff_fa80_0000_0000;
+    const TAG: &[u8; 4] = b"Proc";
+
+    // Kernel memory in a buffer at BASE, a page of it cannot be read
+    struct Memory {
+        data: Vec<u8>,
+        hole: u64,
+        pdb_store: PdbStore,
+    }
+
+    impl Memory {
+        fn new(pages: u64, hole: u64) -> Self {
+            let isf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/win7_isf.json");
+            Self {
+                data: vec![0u8; (pages * PAGE_SIZE) as usize],
+                hole,
+                pdb_store: parse_isf_file(&isf).unwrap(),
+            }
+        }
+
+        fn tag_header(&mut self, addr: u64) {
+            let o = (addr - BASE + POOL_TAG_OFFSET) as usize;
+            self.data[o..o + 4].copy_from_slice(TAG);
+        }
+    }
+
+    impl MemoryReader for Memory {
+        fn read_virtual(&self, addr: u64, buf: &mut [u8]) -> usize {
+            let mut total = 0;
+            while total < buf.len() {
+                let vaddr = addr + total as u64;
+                let page = vaddr & !(PAGE_SIZE - 1);
+                if vaddr < BASE || vaddr - BASE >= self.data.len() as u64 || page == self.hole {
+                    break;
+                }
+                let o = (vaddr - BASE) as usize;
+                let len = ((page + PAGE_SIZE - vaddr) as usize).min(buf.len() - total);
+                buf[total..total + len].copy_from_slice(&self.data[o..o + len]);
+                total += len;
+            }
+            total
+        }
+
+        fn read_physical(&self, _addr: u64, _buf: &mut [u8]) -> usize {
+            0
+        }
+
+        fn get_kernel_base(&self) -> Address {
+            Address::from_base(0)
+        }
+
+        fn get_pte_base(&self) -> Address {
+            Address::from_base(0)
+        }
+
+        fn pdb_store(&self) -> &PdbStore {
+            &self.pdb_store
+        }
+
+        fn windows_version(&self) -> WindowsVersion {
+            WindowsVersion::Windows7
+        }
+    }
+
+    #[test]
+    fn pool_tags_parallel() {
+        // 64 pages are 16 slices of 4 pages, the headers sit around the slice edges
+        let slice = 4 * PAGE_SIZE;
+        let hole = BASE + 9 * PAGE_SIZE;
+        let mut mem = Memory::new(64, hole);
+        let headers = [
+            BASE,
+            BASE + slice - POOL_ALIGNMENT,
+            BASE + slice,
+            BASE + 2 * slice - 2 * POOL_ALIGNMENT,
+            BASE + 2 * slice + PAGE_SIZE - POOL_ALIGNMENT,
+            hole + 0x100,
+            BASE + 15 * slice - POOL_ALIGNMENT,
+            BASE + 16 * slice - POOL_ALIGNMENT,
+        ];
+        for &header in &headers {
+            mem.tag_header(header);
+        }
+
+        let end = BASE + 16 * slice;
+        let expected: Vec<u64> = headers.iter().cloned().filter(|&h| h & !0xfff != hole).collect();
+        let single = find_pool_tags(&mem, BASE, end, end, TAG);
+        assert_eq!(single, expected);
+        assert_eq!(find_pool_tags_parallel(&mem, BASE, end, TAG, 0x10), single);
+
+        // a range that does not start or end on a slice edge
+        let (start, end) = (BASE + 0x10, BASE + 2 * slice);
+        assert_eq!(
+            find_pool_tags_parallel(&mem, start, end, TAG, 0x10),
+            find_pool_tags(&mem, start, end, end, TAG)
+        );
+    }
+}
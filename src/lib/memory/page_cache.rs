@@ -0,0 +1,276 @@
+use lru::LruCache;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use super::AddressSpace;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+// 16MB of pages
+pub const DEFAULT_CACHE_PAGES: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub pages: usize,
+    pub capacity: usize,
+}
+
+struct CacheInner {
+    pages: LruCache<(AddressSpace, u64), Vec<u8>>,
+    hits: u64,
+    misses: u64,
+}
+
+// LRU cache of whole pages in front of a slow reader, e.g. one DeviceIoControl per read
+// Small reads are served from the cached page, a miss reads the whole page once.
+// Reads of a page or more go straight to the reader, they gain nothing from the cache
+// and would push out the small structures we keep coming back to.
+// Live memory keeps changing, invalidate() when stale data matters.
+pub struct PageCache {
+    inner: Mutex<CacheInner>,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        // capacity is in pages, 0 disables the cache
+        Self {
+            inner: Mutex::new(CacheInner {
+                pages: LruCache::new(capacity),
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.pages.resize(capacity);
+        }
+    }
+
+    pub fn invalidate(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.pages.clear();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        match self.inner.lock() {
+            Ok(inner) => CacheStats {
+                hits: inner.hits,
+                misses: inner.misses,
+                pages: inner.pages.len(),
+                capacity: inner.pages.cap(),
+            },
+            Err(_) => Default::default(),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.hits = 0;
+            inner.misses = 0;
+        }
+    }
+
+    fn lookup(&self, space: AddressSpace, page: u64, offset: usize, out: &mut [u8]) -> bool {
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return false,
+        };
+        let hit = match inner.pages.get(&(space, page)) {
+            Some(data) => {
+                out.copy_from_slice(&data[offset..offset + out.len()]);
+                true
+            }
+            None => false,
+        };
+        if hit {
+            inner.hits += 1;
+        } else {
+            inner.misses += 1;
+        }
+        hit
+    }
+
+    pub fn insert(&self, space: AddressSpace, page: u64, data: Vec<u8>) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if inner.pages.cap() > 0 {
+                inner.pages.put((space, page), data);
+            }
+        }
+    }
+
+    pub fn missing_pages(&self, space: AddressSpace, ranges: &[(u64, u64)]) -> Vec<u64> {
+        // Pages of `ranges` not in the cache, nothing when the cache is disabled
+        let inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return Vec::new(),
+        };
+        if inner.pages.cap() == 0 {
+            return Vec::new();
+        }
+        // in the order of `ranges`, a recorded session replays the same batch
+        let mut pages = Vec::new();
+        let mut seen = HashSet::new();
+        for &(addr, len) in ranges.iter() {
+            let mut page = addr & !(PAGE_SIZE - 1);
+            while page < addr.saturating_add(len) {
+                if !inner.pages.contains(&(space, page)) && seen.insert(page) {
+                    pages.push(page);
+                }
+                page = match page.checked_add(PAGE_SIZE) {
+                    Some(p) => p,
+                    None => break,
+                };
+            }
+        }
+        pages
+    }
+
+    pub fn read<F>(&self, space: AddressSpace, addr: u64, buf: &mut [u8], read: F) -> usize
+    where
+        F: Fn(u64, &mut [u8]) -> usize,
+    {
+        // Return the number of bytes read like `read`, stop at the first failed page
+        if buf.len() as u64 >= PAGE_SIZE || self.stats().capacity == 0 {
+            return read(addr, buf);
+        }
+
+        let mut done = 0usize;
+        while done < buf.len() {
+            let current = addr.wrapping_add(done as u64);
+            let page = current & !(PAGE_SIZE - 1);
+            let offset = (current - page) as usize;
+            let len = (buf.len() - done).min(PAGE_SIZE as usize - offset);
+            let out = &mut buf[done..done + len];
+
+            if !self.lookup(space, page, offset, out) {
+                let mut data = vec![0u8; PAGE_SIZE as usize];
+                if read(page, &mut data) == data.len() {
+                    out.copy_from_slice(&data[offset..offset + len]);
+                    self.insert(space, page, data);
+                } else {
+                    // Part of the page may still be readable, do not cache it
+                    let n = read(current, out);
+                    if n != len {
+                        return done + n;
+                    }
+                }
+            }
+            done += len;
+        }
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // Pages filled with their page number, reads past `end` fail
+    struct Source {
+        end: u64,
+        reads: RefCell<Vec<(u64, usize)>>,
+    }
+
+    impl Source {
+        fn new(end: u64) -> Self {
+            Self {
+                end,
+                reads: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+            self.reads.borrow_mut().push((addr, buf.len()));
+            let len = (self.end.saturating_sub(addr) as usize).min(buf.len());
+            for (i, b) in buf[..len].iter_mut().enumerate() {
+                *b = ((addr + i as u64) / PAGE_SIZE) as u8;
+            }
+            len
+        }
+
+        fn read_count(&self) -> usize {
+            self.reads.borrow().len()
+        }
+    }
+
+    fn read(cache: &PageCache, source: &Source, addr: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        let n = cache.read(AddressSpace::Virtual, addr, &mut buf, |a, b| {
+            source.read(a, b)
+        });
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = PageCache::new(2);
+        let source = Source::new(0x10000);
+        read(&cache, &source, 0x1000, 8);
+        read(&cache, &source, 0x2000, 8);
+        // page 0x1000 is the most recent again, 0x3000 pushes out 0x2000
+        read(&cache, &source, 0x1010, 8);
+        read(&cache, &source, 0x3000, 8);
+        assert_eq!(source.read_count(), 3);
+
+        assert_eq!(read(&cache, &source, 0x1020, 4), vec![1; 4]);
+        assert_eq!(source.read_count(), 3);
+        assert_eq!(read(&cache, &source, 0x2020, 4), vec![2; 4]);
+        assert_eq!(source.read_count(), 4);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 4));
+        assert_eq!((stats.pages, stats.capacity), (2, 2));
+    }
+
+    #[test]
+    fn straddling_read() {
+        // 8 bytes over a page boundary read both pages whole, once
+        let cache = PageCache::new(16);
+        let source = Source::new(0x10000);
+        assert_eq!(
+            read(&cache, &source, 0x1ffc, 8),
+            vec![1, 1, 1, 1, 2, 2, 2, 2]
+        );
+        assert_eq!(
+            *source.reads.borrow(),
+            vec![(0x1000, 0x1000), (0x2000, 0x1000)]
+        );
+        assert_eq!(read(&cache, &source, 0x1ffe, 4), vec![1, 1, 2, 2]);
+        assert_eq!(source.read_count(), 2);
+
+        // a page or more skips the cache
+        assert_eq!(read(&cache, &source, 0x1000, 0x1000).len(), 0x1000);
+        assert_eq!(source.read_count(), 3);
+    }
+
+    #[test]
+    fn short_read() {
+        // The page at 0x2000 is only readable up to 0x2800, what can be read of it
+        // is returned but not cached
+        let cache = PageCache::new(16);
+        let source = Source::new(0x2800);
+        assert_eq!(
+            read(&cache, &source, 0x1ffc, 8),
+            vec![1, 1, 1, 1, 2, 2, 2, 2]
+        );
+        assert_eq!(read(&cache, &source, 0x27fc, 8), vec![2; 4]);
+        assert_eq!(cache.stats().pages, 1);
+        assert_eq!(
+            cache.missing_pages(AddressSpace::Virtual, &[(0x1000, 0x3000), (0x2000, 8)]),
+            vec![0x2000, 0x3000]
+        );
+
+        // a disabled cache goes straight to the source
+        cache.set_capacity(0);
+        assert_eq!(read(&cache, &source, 0x27fc, 8), vec![2; 4]);
+        assert!(cache
+            .missing_pages(AddressSpace::Virtual, &[(0x1000, 0x1000)])
+            .is_empty());
+    }
+}
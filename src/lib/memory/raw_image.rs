@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::PhysicalMemory;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// A raw (.raw/.mem/.dd) physical memory image, file offset == physical address
+pub struct RawImage {
+    file: Mutex<File>,
+    size: u64,
+}
+
+impl RawImage {
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            file: Mutex::new(file),
+            size,
+        })
+    }
+}
+
+pub fn read_file_at(file: &Mutex<File>, offset: u64, buf: &mut [u8]) -> usize {
+    // Read as much as possible of `buf` from `offset`, stop at the end of file
+    let mut file = match file.lock() {
+        Ok(f) => f,
+        Err(_) => return 0,
+    };
+    if file.seek(SeekFrom::Start(offset)).is_err() {
+        return 0;
+    }
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => total += n,
+        }
+    }
+    total
+}
+
+impl PhysicalMemory for RawImage {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        if addr >= self.size {
+            return 0;
+        }
+        read_file_at(&self.file, addr, buf)
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        vec![(0, self.size)]
+    }
+}
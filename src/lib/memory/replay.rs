@@ -0,0 +1,229 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+
+use super::page_cache::{PageCache, PAGE_SIZE};
+use super::{process_time_in_range, AddressSpace, MemoryReader};
+use crate::address::Address;
+use crate::ioctl_protocol::{
+    ioctl_deref, ioctl_query_u64, ioctl_read_many, ioctl_scan_pool, DriverAction,
+};
+use crate::ioctl_session::IoctlReplay;
+use crate::overlay::{apply_overlays, build_default_overlays, Overlay};
+use crate::pdb_store::PdbStore;
+use crate::version::WindowsVersion;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Replay of a session recorded with DriverState::record_session
+// The scanners get the answers lpus.sys gave on the recorded machine, no driver is needed
+pub struct ReplayState {
+    pub pdb_store: PdbStore,
+    session: IoctlReplay,
+    short_version: WindowsVersion,
+    // The recording went through DriverState's cache, small reads only exist in the
+    // session as whole pages, and only the same capacity evicts the same pages
+    cache: PageCache,
+    batch_supported: AtomicBool,
+}
+
+impl ReplayState {
+    pub fn open(path: &Path, pdb_store: PdbStore) -> BoxResult<Self> {
+        let session = IoctlReplay::open(path)?;
+        let info = &session.info;
+        if !info.pdb_guid.is_empty()
+            && (info.pdb_guid != pdb_store.guid || info.pdb_age != pdb_store.age)
+        {
+            return Err(format!(
+                "Session was recorded with PDB {}{:X}, not {}{:X}",
+                info.pdb_guid, info.pdb_age, pdb_store.guid, pdb_store.age
+            )
+            .into());
+        }
+        let short_version = WindowsVersion::from_build_number(info.build_number);
+        let cache = PageCache::new(info.cache_pages);
+        let mut state = Self {
+            pdb_store,
+            session,
+            short_version,
+            cache,
+            batch_supported: AtomicBool::new(true),
+        };
+        state.apply_overlays(&build_default_overlays())?;
+        Ok(state)
+    }
+
+    pub fn get_build_number(&self) -> u32 {
+        self.session.info.build_number
+    }
+
+    pub fn apply_overlays(&mut self, overlays: &[Overlay]) -> BoxResult<()> {
+        // The build of the recorded machine picks the ranged overlays
+        let build = self.get_build_number();
+        apply_overlays(&mut self.pdb_store, overlays, Some(build))
+    }
+}
+
+impl MemoryReader for ReplayState {
+    fn read_virtual(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.cache
+            .read(AddressSpace::Virtual, addr, buf, |addr, buf| {
+                ioctl_deref(&self.session, DriverAction::DereferenceAddress, addr, buf)
+            })
+    }
+
+    fn read_physical(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.cache
+            .read(AddressSpace::Physical, addr, buf, |addr, buf| {
+                ioctl_deref(
+                    &self.session,
+                    DriverAction::DereferencePhysicalAddress,
+                    addr,
+                    buf,
+                )
+            })
+    }
+
+    fn read_many(&self, space: AddressSpace, requests: &[(u64, usize)]) -> Vec<Vec<u8>> {
+        ioctl_read_many(
+            &self.session,
+            &self.batch_supported,
+            space,
+            requests,
+            |addr, buf| match space {
+                AddressSpace::Virtual => self.read_virtual(addr, buf),
+                AddressSpace::Physical => self.read_physical(addr, buf),
+            },
+        )
+    }
+
+    fn prefetch(&self, space: AddressSpace, ranges: &[(u64, u64)]) {
+        // The same requests as DriverState::prefetch
+        let pages = self.cache.missing_pages(space, ranges);
+        let requests: Vec<(u64, usize)> = pages.iter().map(|&p| (p, PAGE_SIZE as usize)).collect();
+        for (&page, data) in pages.iter().zip(self.read_many(space, &requests)) {
+            if data.len() == PAGE_SIZE as usize {
+                self.cache.insert(space, page, data);
+            }
+        }
+    }
+
+    fn get_kernel_base(&self) -> Address {
+        Address::from_base(ioctl_query_u64(&self.session, DriverAction::GetKernelBase))
+    }
+
+    fn get_pte_base(&self) -> Address {
+        Address::from_base(ioctl_query_u64(
+            &self.session,
+            DriverAction::GetPteBaseAddress,
+        ))
+    }
+
+    fn pdb_store(&self) -> &PdbStore {
+        &self.pdb_store
+    }
+
+    fn windows_version(&self) -> WindowsVersion {
+        self.short_version
+    }
+
+    fn valid_process_time(&self, filetime: u64) -> bool {
+        // Judge against the recorded machine clock, not ours
+        let info = &self.session.info;
+        process_time_in_range(filetime, info.boot_time, info.recorded_at)
+    }
+
+    fn find_pool_tag(&self, start: u64, end: u64, tag: &[u8; 4]) -> u64 {
+        ioctl_scan_pool(&self.session, start, end, tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::LpusError;
+    use crate::isf::parse_isf_file;
+    use crate::memory::MemoryReaderExt;
+    use crate::memory::page_cache::DEFAULT_CACHE_PAGES;
+    use crate::scan_eprocess;
+    use serde_json::Value;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A Windows 7 session with three processes in the nonpaged pool, the ISF has
+    // the few structs, enums and symbols scan_eprocess() needs
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn remove_local_times(value: &mut Value) {
+        // rfc2822 is in the local time zone, "unix" has the same time
+        match value {
+            Value::Object(map) => {
+                map.remove("rfc2822");
+                map.values_mut().for_each(remove_local_times);
+            }
+            Value::Array(values) => values.iter_mut().for_each(remove_local_times),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn scan_eprocess_from_session() {
+        let pdb_store = parse_isf_file(&fixture("win7_isf.json")).unwrap();
+        let state = ReplayState::open(&fixture("win7_session.bin"), pdb_store).unwrap();
+        assert_eq!(state.get_build_number(), 7601);
+        // recorded before the session kept the cache size
+        assert_eq!(state.session.info.cache_pages, DEFAULT_CACHE_PAGES);
+
+        let mut result = Value::Array(scan_eprocess(&state).unwrap());
+        let expected = fs::read_to_string(fixture("win7_eprocess.json")).unwrap();
+        let mut expected: Value = serde_json::from_str(&expected).unwrap();
+        remove_local_times(&mut result);
+        remove_local_times(&mut expected);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn threads_without_enums() {
+        let mut pdb_store = parse_isf_file(&fixture("win7_isf.json")).unwrap();
+        pdb_store.enums.clear();
+        let state = ReplayState::open(&fixture("win7_session.bin"), pdb_store).unwrap();
+        let result = scan_eprocess(&state).unwrap();
+        let threads: Vec<&Value> = result
+            .iter()
+            .flat_map(|p| p["threads"].as_array().unwrap())
+            .collect();
+        assert_eq!(threads.len(), 3);
+        assert!(threads
+            .iter()
+            .all(|t| t["state"] == "Unknown" && t["wait_reason"] == "Unknown"));
+    }
+
+    #[test]
+    fn failed_pointer_read() {
+        // A pointer hop to memory the session never read fails where it was read
+        let pdb_store = parse_isf_file(&fixture("win7_isf.json")).unwrap();
+        let state = ReplayState::open(&fixture("win7_session.bin"), pdb_store).unwrap();
+        let missing = 0xfffff80000100000;
+        let read_failed =
+            |e: &LpusError| matches!(e, LpusError::ReadFailed { address, .. } if *address == missing);
+
+        let ptr = Address::from_ptr(Address::from_base(missing));
+        let err = state.decompose::<u64>(&ptr, "_LIST_ENTRY.Blink").unwrap_err();
+        assert!(err.downcast_ref::<LpusError>().is_some_and(read_failed));
+        let err = state.address_of(&ptr, "_LIST_ENTRY.Blink").unwrap_err();
+        assert!(err.downcast_ref::<LpusError>().is_some_and(read_failed));
+        let err = state.deref_array::<u8>(&Address::from_base(missing), 16).unwrap_err();
+        assert!(read_failed(&err));
+    }
+
+    #[test]
+    fn session_of_another_pdb() {
+        let mut pdb_store = parse_isf_file(&fixture("win7_isf.json")).unwrap();
+        pdb_store.age += 1;
+        assert!(ReplayState::open(&fixture("win7_session.bin"), pdb_store).is_err());
+    }
+}
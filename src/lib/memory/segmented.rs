@@ -0,0 +1,79 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::sync::Mutex;
+
+use super::raw_image::read_file_at;
+use super::PhysicalMemory;
+
+// A run of physical memory stored contiguously in the file
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub start: u64,
+    pub length: u64,
+    pub file_offset: u64,
+}
+
+// Physical memory split into segments at known file offsets, the common layout
+// of crash dumps, LiME, ELF cores and VMware snapshots
+pub struct SegmentedFile {
+    file: Mutex<File>,
+    segments: Vec<Segment>,
+}
+
+impl SegmentedFile {
+    pub fn new(file: Mutex<File>, mut segments: Vec<Segment>) -> Self {
+        segments.retain(|s| s.length != 0);
+        segments.sort_by_key(|s| s.start);
+        Self { file, segments }
+    }
+
+    fn find_segment(&self, addr: u64) -> Option<&Segment> {
+        let index = self
+            .segments
+            .binary_search_by(|s| {
+                // a segment can end at the top of the address space, compare without adding
+                if s.start > addr {
+                    Ordering::Greater
+                } else if addr - s.start >= s.length {
+                    Ordering::Less
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .ok()?;
+        Some(&self.segments[index])
+    }
+}
+
+impl PhysicalMemory for SegmentedFile {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        // Stop at the first gap between segments, like a raw image stops at the end of file
+        let mut total = 0;
+        while total < buf.len() {
+            let paddr = match addr.checked_add(total as u64) {
+                Some(paddr) => paddr,
+                None => break,
+            };
+            let segment = match self.find_segment(paddr) {
+                Some(s) => s,
+                None => break,
+            };
+            let offset = paddr - segment.start;
+            let file_offset = match segment.file_offset.checked_add(offset) {
+                Some(file_offset) => file_offset,
+                None => break,
+            };
+            let len = (segment.length - offset).min((buf.len() - total) as u64) as usize;
+            let n = read_file_at(&self.file, file_offset, &mut buf[total..total + len]);
+            total += n;
+            if n != len {
+                break;
+            }
+        }
+        total
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        self.segments.iter().map(|s| (s.start, s.length)).collect()
+    }
+}
@@ -0,0 +1,201 @@
+use super::PhysicalMemory;
+
+// Software x64 4-level paging, the same walk as old-bin/translate-addr.rs but over
+// any physical memory source instead of the driver
+// Ref: Intel SDM Vol. 3A, 4.5 4-Level Paging
+
+const PAGE_SIZE: u64 = 0x1000;
+const ENTRY_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const LARGE_1GB_ADDRESS_MASK: u64 = 0x000f_ffff_c000_0000;
+const LARGE_2MB_ADDRESS_MASK: u64 = 0x000f_ffff_ffe0_0000;
+
+const VALID_BIT: u64 = 1 << 0;
+const LARGE_PAGE_BIT: u64 = 1 << 7;
+// Windows software bits of an invalid PTE, a transition page is still in physical memory
+const PROTOTYPE_BIT: u64 = 1 << 10;
+const TRANSITION_BIT: u64 = 1 << 11;
+
+fn read_entry(mem: &dyn PhysicalMemory, table: u64, index: u64) -> Option<u64> {
+    let mut buf = [0u8; 8];
+    let entry_addr = (table & ENTRY_ADDRESS_MASK) | (index << 3);
+    if mem.read(entry_addr, &mut buf) != buf.len() {
+        return None;
+    }
+    Some(u64::from_le_bytes(buf))
+}
+
+fn is_valid_entry(entry: u64) -> bool {
+    entry & VALID_BIT != 0
+}
+
+pub fn translate(mem: &dyn PhysicalMemory, dtb: u64, vaddr: u64) -> Option<u64> {
+    // Return the physical address of `vaddr` in the address space of `dtb`
+    // None when the page is not in physical memory
+    let pml4e = read_entry(mem, dtb, (vaddr >> 39) & 0x1ff)?;
+    if !is_valid_entry(pml4e) {
+        return None;
+    }
+
+    let pdpte = read_entry(mem, pml4e, (vaddr >> 30) & 0x1ff)?;
+    if !is_valid_entry(pdpte) {
+        return None;
+    }
+    if pdpte & LARGE_PAGE_BIT != 0 {
+        return Some((pdpte & LARGE_1GB_ADDRESS_MASK) | (vaddr & 0x3fff_ffff));
+    }
+
+    let pde = read_entry(mem, pdpte, (vaddr >> 21) & 0x1ff)?;
+    if !is_valid_entry(pde) {
+        return None;
+    }
+    if pde & LARGE_PAGE_BIT != 0 {
+        return Some((pde & LARGE_2MB_ADDRESS_MASK) | (vaddr & 0x1f_ffff));
+    }
+
+    let pte = read_entry(mem, pde, (vaddr >> 12) & 0x1ff)?;
+    if is_valid_entry(pte) || (pte & TRANSITION_BIT != 0 && pte & PROTOTYPE_BIT == 0) {
+        return Some((pte & ENTRY_ADDRESS_MASK) | (vaddr & 0xfff));
+    }
+    None
+}
+
+pub fn read_virtual(mem: &dyn PhysicalMemory, dtb: u64, addr: u64, buf: &mut [u8]) -> usize {
+    // Read page by page, stop at the first page that is not in physical memory
+    let mut total = 0;
+    while total < buf.len() {
+        let vaddr = addr.wrapping_add(total as u64);
+        let in_page = (PAGE_SIZE - (vaddr & (PAGE_SIZE - 1))) as usize;
+        let len = in_page.min(buf.len() - total);
+        let paddr = match translate(mem, dtb, vaddr) {
+            Some(p) => p,
+            None => break,
+        };
+        let n = mem.read(paddr, &mut buf[total..total + len]);
+        total += n;
+        if n != len {
+            break;
+        }
+    }
+    total
+}
+
+pub fn self_ref_index(mem: &dyn PhysicalMemory, dtb: u64) -> Option<u64> {
+    // Windows maps the PML4 into itself at one entry of the kernel half
+    // The index is random since Windows 10 1607, it decides where the PTEs are mapped
+    (256..512).find(|&index| match read_entry(mem, dtb, index) {
+        Some(entry) => {
+            is_valid_entry(entry) && entry & ENTRY_ADDRESS_MASK == dtb & ENTRY_ADDRESS_MASK
+        }
+        None => false,
+    })
+}
+
+pub fn pte_base_from_index(index: u64) -> u64 {
+    // Canonical address of the page table self map
+    0xffff_0000_0000_0000 | (index << 39)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Physical memory in a buffer, the page tables are written in it by hand
+    struct Memory(Vec<u8>);
+
+    impl Memory {
+        fn put(&mut self, addr: u64, value: u64) {
+            let addr = addr as usize;
+            self.0[addr..addr + 8].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn entry(&mut self, table: u64, index: u64, value: u64) {
+            self.put(table + index * 8, value);
+        }
+    }
+
+    impl PhysicalMemory for Memory {
+        fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+            let start = (addr as usize).min(self.0.len());
+            let len = buf.len().min(self.0.len() - start);
+            buf[..len].copy_from_slice(&self.0[start..start + len]);
+            len
+        }
+
+        fn ranges(&self) -> Vec<(u64, u64)> {
+            vec![(0, self.0.len() as u64)]
+        }
+    }
+
+    const DTB: u64 = 0x1000;
+    const PDPT: u64 = 0x2000;
+    const PD: u64 = 0x3000;
+    const PT: u64 = 0x4000;
+    // PML4 index 0x1f0, then index 0 in the other tables
+    const BASE: u64 = 0xffff_f800_0000_0000;
+
+    fn page_tables() -> Memory {
+        let mut mem = Memory(vec![0u8; 0x10000]);
+        mem.entry(DTB, 0x1ed, DTB | VALID_BIT);
+        mem.entry(DTB, 0x1f0, PDPT | VALID_BIT);
+        mem.entry(PDPT, 0, PD | VALID_BIT);
+        mem.entry(PD, 0, PT | VALID_BIT);
+        mem
+    }
+
+    #[test]
+    fn four_level() {
+        let mut mem = page_tables();
+        mem.entry(PT, 0, 0x8000 | VALID_BIT);
+        mem.entry(PT, 1, 0x9000 | VALID_BIT);
+        mem.put(0x8ff8, 0x1111_1111_1111_1111);
+        mem.put(0x9000, 0x2222_2222_2222_2222);
+
+        assert_eq!(translate(&mem, DTB, BASE + 0x123), Some(0x8123));
+        assert_eq!(translate(&mem, DTB, BASE + 0x1456), Some(0x9456));
+        // not present, and a PML4 entry that is not valid
+        assert_eq!(translate(&mem, DTB, BASE + 0x2000), None);
+        assert_eq!(translate(&mem, DTB, 0xffff_f880_0000_0000), None);
+
+        // a read across two pages far apart in physical memory
+        let mut buf = [0u8; 16];
+        assert_eq!(read_virtual(&mem, DTB, BASE + 0xff8, &mut buf), 16);
+        assert_eq!(buf[..8], [0x11; 8]);
+        assert_eq!(buf[8..], [0x22; 8]);
+        // stops at the page that is not present
+        assert_eq!(read_virtual(&mem, DTB, BASE + 0x1ff8, &mut buf), 8);
+    }
+
+    #[test]
+    fn large_pages() {
+        let mut mem = page_tables();
+        // 2MB page at 0x600000 for BASE + 0x200000, 1GB page at 0x80000000 for BASE + 1GB
+        mem.entry(PD, 1, 0x60_0000 | LARGE_PAGE_BIT | VALID_BIT);
+        mem.entry(PDPT, 1, 0x8000_0000 | LARGE_PAGE_BIT | VALID_BIT);
+
+        assert_eq!(translate(&mem, DTB, BASE + 0x2f_f123), Some(0x6f_f123));
+        assert_eq!(
+            translate(&mem, DTB, BASE + 0x4000_0000 + 0x1234_5678),
+            Some(0x9234_5678)
+        );
+    }
+
+    #[test]
+    fn transition_pages() {
+        let mut mem = page_tables();
+        // still in memory, a prototype PTE, and a page that is out
+        mem.entry(PT, 0, 0x8000 | TRANSITION_BIT);
+        mem.entry(PT, 1, 0x9000 | TRANSITION_BIT | PROTOTYPE_BIT);
+        mem.entry(PT, 2, 0xa000);
+
+        assert_eq!(translate(&mem, DTB, BASE + 0x10), Some(0x8010));
+        assert_eq!(translate(&mem, DTB, BASE + 0x1010), None);
+        assert_eq!(translate(&mem, DTB, BASE + 0x2010), None);
+    }
+
+    #[test]
+    fn self_map() {
+        let mem = page_tables();
+        assert_eq!(self_ref_index(&mem, DTB), Some(0x1ed));
+        assert_eq!(pte_base_from_index(0x1ed), 0xffff_f680_0000_0000);
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::raw_image::read_file_at;
+use super::segmented::{Segment, SegmentedFile};
+use super::PhysicalMemory;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// VMware suspend state (.vmss) and snapshot (.vmsn) files, with the .vmem next to them
+// The "memory" group of the state file maps guest physical pages to .vmem pages,
+// the .vmem alone is not enough for guests with memory above the PCI hole
+// Ref: https://github.com/volatilityfoundation/volatility3/blob/develop/volatility3/framework/layers/vmware.py
+
+const PAGE_SIZE: u64 = 0x1000;
+const STATE_MAGICS: [u32; 4] = [0xbed2_bed0, 0xbad1_bad1, 0xbed2_bed2, 0xbed3_bed3];
+const STATE_HEADER_SIZE: u64 = 0xc;
+const GROUP_SIZE: u64 = 0x50;
+const GROUP_NAME_SIZE: usize = 0x40;
+const MAX_GROUPS: u32 = 0x100;
+// low 6 bits of the tag flags, a large tag has its sizes after the indices
+const LARGE_TAG_SIZES: [u8; 2] = [62, 63];
+
+// Where the data of a tag is in the state file
+struct Tag {
+    offset: u64,
+    size: u64,
+}
+
+pub struct VmwareImage {
+    memory: SegmentedFile,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(v)
+}
+
+fn read_exact(file: &Mutex<File>, offset: u64, len: usize) -> BoxResult<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    if read_file_at(file, offset, &mut buf) != len {
+        return Err(format!("VMware state file is truncated at 0x{:x}", offset).into());
+    }
+    Ok(buf)
+}
+
+fn file_size(file: &Mutex<File>) -> BoxResult<u64> {
+    Ok(file
+        .lock()
+        .map_err(|_| "Poisoned file lock")?
+        .metadata()?
+        .len())
+}
+
+fn read_tags(
+    file: &Mutex<File>,
+    mut offset: u64,
+    large_size: usize,
+) -> BoxResult<HashMap<(String, Vec<u32>), Tag>> {
+    let size_of_file = file_size(file)?;
+    let mut tags = HashMap::new();
+    loop {
+        let flags = read_exact(file, offset, 1)?[0];
+        offset += 1;
+        if flags == 0 {
+            break;
+        }
+        let name_len = read_exact(file, offset, 1)?[0] as usize;
+        offset += 1;
+        let name = String::from_utf8_lossy(&read_exact(file, offset, name_len)?).to_string();
+        offset += name_len as u64;
+
+        let index_count = ((flags >> 6) & 3) as usize;
+        let raw_indices = read_exact(file, offset, index_count * 4)?;
+        let indices: Vec<u32> = (0..index_count)
+            .map(|i| read_u32(&raw_indices, i * 4))
+            .collect();
+        offset += (index_count * 4) as u64;
+
+        let mut size = (flags & 0x3f) as u64;
+        if LARGE_TAG_SIZES.contains(&(flags & 0x3f)) {
+            // data size, in-memory size, then a 16-bit padding length and the padding
+            let raw = read_exact(file, offset, large_size * 2 + 2)?;
+            size = match large_size {
+                4 => read_u32(&raw, 0) as u64,
+                _ => read_u64(&raw, 0),
+            };
+            let padding = u16::from_le_bytes([raw[large_size * 2], raw[large_size * 2 + 1]]) as u64;
+            offset += (large_size * 2 + 2) as u64 + padding;
+        }
+        // the data of the tag must be in the file, a corrupted size must not wrap around
+        let end = match offset.checked_add(size) {
+            Some(end) if end <= size_of_file => end,
+            _ => return Err(format!("VMware tag {} is past the end of the file", name).into()),
+        };
+        tags.insert((name, indices), Tag { offset, size });
+        offset = end;
+    }
+    Ok(tags)
+}
+
+fn read_tag_value(file: &Mutex<File>, tag: &Tag) -> BoxResult<u64> {
+    // small integer tags are 4 or 8 bytes
+    let mut value = [0u8; 8];
+    let len = (tag.size as usize).min(value.len());
+    value[..len].copy_from_slice(&read_exact(file, tag.offset, len)?);
+    Ok(u64::from_le_bytes(value))
+}
+
+impl VmwareImage {
+    pub fn has_signature(magic: &[u8]) -> bool {
+        magic.len() >= 4 && STATE_MAGICS.contains(&read_u32(magic, 0))
+    }
+
+    pub fn find_state_file(vmem: &Path) -> Option<PathBuf> {
+        // The .vmss or .vmsn with the same name as the .vmem
+        ["vmss", "vmsn"]
+            .iter()
+            .map(|extension| vmem.with_extension(extension))
+            .find(|path| path.is_file())
+    }
+
+    pub fn open(path: &Path) -> BoxResult<Self> {
+        // Accept either the state file or the .vmem
+        let mut magic = [0u8; 4];
+        let is_state = read_file_at(&Mutex::new(File::open(path)?), 0, &mut magic) == magic.len()
+            && Self::has_signature(&magic);
+        let (state_path, vmem_path) = if is_state {
+            (path.to_path_buf(), path.with_extension("vmem"))
+        } else {
+            let state_path =
+                Self::find_state_file(path).ok_or("No .vmss or .vmsn found next to the .vmem")?;
+            (state_path, path.to_path_buf())
+        };
+
+        let state = Mutex::new(File::open(&state_path)?);
+        let header = read_exact(&state, 0, STATE_HEADER_SIZE as usize)?;
+        let magic = read_u32(&header, 0);
+        if !STATE_MAGICS.contains(&magic) {
+            return Err(format!("{} is not a VMware state file", state_path.display()).into());
+        }
+        let large_size = if magic & 0xf == 0 { 4 } else { 8 };
+        let group_count = read_u32(&header, 8);
+        if group_count > MAX_GROUPS {
+            return Err(format!("Invalid VMware group count {}", group_count).into());
+        }
+
+        let mut memory_tags = None;
+        for i in 0..group_count as u64 {
+            let group = read_exact(
+                &state,
+                STATE_HEADER_SIZE + i * GROUP_SIZE,
+                GROUP_SIZE as usize,
+            )?;
+            let name = group[..GROUP_NAME_SIZE]
+                .split(|&c| c == 0)
+                .next()
+                .unwrap_or(&[]);
+            if name == b"memory" {
+                memory_tags = Some(read_tags(
+                    &state,
+                    read_u64(&group, GROUP_NAME_SIZE),
+                    large_size,
+                )?);
+                break;
+            }
+        }
+        let tags = memory_tags.ok_or("No memory group in the VMware state file")?;
+
+        let region_count = match tags.get(&("regionsCount".to_string(), vec![])) {
+            Some(tag) => read_tag_value(&state, tag)?,
+            None => 0,
+        };
+        if region_count > 0 {
+            // regionPPN: guest page, regionPageNum: page in the .vmem, regionSize: page count
+            let vmem = Mutex::new(File::open(&vmem_path)?);
+            let vmem_size = file_size(&vmem)?;
+            let mut segments = Vec::new();
+            for i in 0..region_count as u32 {
+                let value = |name: &str| -> BoxResult<u64> {
+                    let tag = tags.get(&(name.to_string(), vec![i])).ok_or(format!(
+                        "Missing {}[{}] in the VMware memory group",
+                        name, i
+                    ))?;
+                    let pages = read_tag_value(&state, tag)?;
+                    pages.checked_mul(PAGE_SIZE).ok_or_else(|| {
+                        format!("Invalid {}[{}] in the VMware memory group", name, i).into()
+                    })
+                };
+                let segment = Segment {
+                    start: value("regionPPN")?,
+                    length: value("regionSize")?,
+                    file_offset: value("regionPageNum")?,
+                };
+                let in_vmem = segment
+                    .file_offset
+                    .checked_add(segment.length)
+                    .is_some_and(|end| end <= vmem_size);
+                if !in_vmem || segment.start.checked_add(segment.length).is_none() {
+                    return Err(
+                        format!("VMware memory region {} is past the end of the .vmem", i).into(),
+                    );
+                }
+                segments.push(segment);
+            }
+            return Ok(Self {
+                memory: SegmentedFile::new(vmem, segments),
+            });
+        }
+
+        // Older snapshots keep the memory inside the state file, or in a flat .vmem
+        if let Some(tag) = tags.get(&("Memory".to_string(), vec![0, 0])) {
+            let segments = vec![Segment {
+                start: 0,
+                length: tag.size,
+                file_offset: tag.offset,
+            }];
+            return Ok(Self {
+                memory: SegmentedFile::new(state, segments),
+            });
+        }
+        let vmem = File::open(&vmem_path)?;
+        let segments = vec![Segment {
+            start: 0,
+            length: vmem.metadata()?.len(),
+            file_offset: 0,
+        }];
+        Ok(Self {
+            memory: SegmentedFile::new(Mutex::new(vmem), segments),
+        })
+    }
+}
+
+impl PhysicalMemory for VmwareImage {
+    fn read(&self, addr: u64, buf: &mut [u8]) -> usize {
+        self.memory.read(addr, buf)
+    }
+
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        self.memory.ranges()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn tag(raw: &mut Vec<u8>, name: &str, indices: &[u32], value: u32) {
+        // A 4-byte tag, the index count in the top 2 bits of the flags
+        raw.push(((indices.len() as u8) << 6) | 4);
+        raw.push(name.len() as u8);
+        raw.extend_from_slice(name.as_bytes());
+        for index in indices {
+            raw.extend_from_slice(&index.to_le_bytes());
+        }
+        raw.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn state_file(groups: &[&str], regions: &[(u32, u32, u32)]) -> Vec<u8> {
+        // The header, the groups, then the tags of "memory": regionsCount and the
+        // (regionPPN, regionPageNum, regionSize) of each region
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&STATE_MAGICS[0].to_le_bytes());
+        raw.extend_from_slice(&0u32.to_le_bytes());
+        raw.extend_from_slice(&(groups.len() as u32).to_le_bytes());
+        let tags_offset = STATE_HEADER_SIZE + groups.len() as u64 * GROUP_SIZE;
+        for name in groups {
+            let mut group = [0u8; GROUP_SIZE as usize];
+            group[..name.len()].copy_from_slice(name.as_bytes());
+            group[GROUP_NAME_SIZE..GROUP_NAME_SIZE + 8].copy_from_slice(&tags_offset.to_le_bytes());
+            raw.extend_from_slice(&group);
+        }
+        tag(&mut raw, "regionsCount", &[], regions.len() as u32);
+        for (i, &(ppn, page_num, size)) in regions.iter().enumerate() {
+            tag(&mut raw, "regionPPN", &[i as u32], ppn);
+            tag(&mut raw, "regionPageNum", &[i as u32], page_num);
+            tag(&mut raw, "regionSize", &[i as u32], size);
+        }
+        raw.push(0);
+        raw
+    }
+
+    fn files(name: &str, state: &[u8], vmem: &[u8]) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("lpus-{}-{}", name, std::process::id()));
+        let (state_path, vmem_path) = (base.with_extension("vmss"), base.with_extension("vmem"));
+        fs::write(&state_path, state).unwrap();
+        fs::write(&vmem_path, vmem).unwrap();
+        (state_path, vmem_path)
+    }
+
+    #[test]
+    fn regions() {
+        // guest page 0 is .vmem page 0, guest page 0x100 is .vmem page 1
+        let state = state_file(&["cpu", "memory"], &[(0, 0, 1), (0x100, 1, 1)]);
+        let mut vmem = vec![0x11u8; PAGE_SIZE as usize];
+        vmem.resize(2 * PAGE_SIZE as usize, 0x22);
+        let (state_path, vmem_path) = files("regions", &state, &vmem);
+
+        for path in &[&state_path, &vmem_path] {
+            let image = VmwareImage::open(path).unwrap();
+            assert_eq!(
+                image.ranges(),
+                vec![(0, PAGE_SIZE), (0x100 * PAGE_SIZE, PAGE_SIZE)]
+            );
+            let mut buf = [0u8; 4];
+            assert_eq!(image.read(0x100 * PAGE_SIZE, &mut buf), 4);
+            assert_eq!(buf, [0x22; 4]);
+            assert_eq!(image.read(PAGE_SIZE, &mut buf), 0);
+        }
+        fs::remove_file(&state_path).ok();
+        fs::remove_file(&vmem_path).ok();
+    }
+
+    #[test]
+    fn bad_state_files() {
+        let vmem = vec![0u8; PAGE_SIZE as usize];
+        let (state_path, vmem_path) = files("nomemory", &state_file(&["cpu"], &[]), &vmem);
+        assert!(VmwareImage::open(&state_path).is_err());
+
+        // regionsCount of 1 without its region tags
+        let mut state = state_file(&["memory"], &[]);
+        let count = state.len() - 5;
+        state[count..count + 4].copy_from_slice(&1u32.to_le_bytes());
+        fs::write(&state_path, &state).unwrap();
+        assert!(VmwareImage::open(&state_path).is_err());
+
+        // a region past the end of the .vmem
+        let past_vmem = state_file(&["memory"], &[(0, 0, 2)]);
+        fs::write(&state_path, &past_vmem).unwrap();
+        assert!(VmwareImage::open(&state_path).is_err());
+
+        // a tag longer than the state file
+        let mut truncated = state_file(&["memory"], &[]);
+        truncated.truncate(truncated.len() - 3);
+        fs::write(&state_path, &truncated).unwrap();
+        assert!(VmwareImage::open(&state_path).is_err());
+
+        // a group count past MAX_GROUPS
+        state[8..12].copy_from_slice(&(MAX_GROUPS + 1).to_le_bytes());
+        fs::write(&state_path, &state).unwrap();
+        assert!(VmwareImage::open(&state_path).is_err());
+        fs::remove_file(&state_path).ok();
+        fs::remove_file(&vmem_path).ok();
+    }
+}
@@ -0,0 +1,311 @@
+use std::error::Error;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Xpress decompression used by hibernation files
+// Ref: [MS-XCA] 2.2.4 Plain LZ77 Decompression, 2.2.4 LZ77+Huffman Decompression
+// https://docs.microsoft.com/en-us/openspecs/windows_protocols/ms-xca/
+
+const HUFFMAN_TABLE_SIZE: usize = 256;
+const HUFFMAN_SYMBOLS: usize = 512;
+const HUFFMAN_MAX_BITS: u32 = 15;
+const HUFFMAN_CHUNK_SIZE: usize = 0x10000;
+
+struct Input<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Input<'a> {
+    fn is_end(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn u8(&mut self) -> BoxResult<u8> {
+        let v = *self
+            .data
+            .get(self.position)
+            .ok_or("Xpress input is truncated")?;
+        self.position += 1;
+        Ok(v)
+    }
+
+    fn u16(&mut self) -> BoxResult<u16> {
+        Ok(self.u8()? as u16 | (self.u8()? as u16) << 8)
+    }
+
+    fn u32(&mut self) -> BoxResult<u32> {
+        Ok(self.u16()? as u32 | (self.u16()? as u32) << 16)
+    }
+}
+
+fn copy_match(
+    output: &mut Vec<u8>,
+    output_size: usize,
+    offset: usize,
+    length: usize,
+) -> BoxResult<()> {
+    if offset > output.len() {
+        return Err("Xpress match points before the start of output".into());
+    }
+    // A corrupt length can be up to 4GB, nothing past output_size is kept
+    let length = length.min(output_size.saturating_sub(output.len()));
+    // The source can overlap the bytes being written, copy one by one
+    let start = output.len() - offset;
+    for i in 0..length {
+        let b = output[start + i];
+        output.push(b);
+    }
+    Ok(())
+}
+
+pub fn decompress_lz77(input: &[u8], output_size: usize) -> BoxResult<Vec<u8>> {
+    let mut input = Input {
+        data: input,
+        position: 0,
+    };
+    let mut output = Vec::with_capacity(output_size);
+    let mut flags = 0u32;
+    let mut flag_count = 0;
+    let mut last_length_half_byte: Option<usize> = None;
+
+    while output.len() < output_size {
+        if flag_count == 0 {
+            flags = input.u32()?;
+            flag_count = 32;
+        }
+        flag_count -= 1;
+
+        if flags & (1 << flag_count) == 0 {
+            output.push(input.u8()?);
+            continue;
+        }
+        if input.is_end() {
+            break;
+        }
+
+        let match_bytes = input.u16()? as usize;
+        let mut length = match_bytes % 8;
+        let offset = match_bytes / 8 + 1;
+        if length == 7 {
+            // Two lengths share one byte, the low nibble first
+            length = match last_length_half_byte.take() {
+                None => {
+                    last_length_half_byte = Some(input.position);
+                    (input.u8()? % 16) as usize
+                }
+                Some(position) => (input.data[position] / 16) as usize,
+            };
+            if length == 15 {
+                length = input.u8()? as usize;
+                if length == 255 {
+                    length = input.u16()? as usize;
+                    if length == 0 {
+                        length = input.u32()? as usize;
+                    }
+                    if length < 15 + 7 {
+                        return Err("Invalid Xpress match length".into());
+                    }
+                    length -= 15 + 7;
+                }
+                length += 15;
+            }
+            length += 7;
+        }
+        length += 3;
+        copy_match(&mut output, output_size, offset, length)?;
+    }
+
+    output.truncate(output_size);
+    Ok(output)
+}
+
+fn build_decoding_table(lengths: &[u8]) -> BoxResult<Vec<u16>> {
+    // Canonical Huffman codes ordered by (bit length, symbol), indexed by the next 15 bits
+    let mut table = vec![0u16; 1 << HUFFMAN_MAX_BITS];
+    let mut position = 0usize;
+    for bit_length in 1..=HUFFMAN_MAX_BITS {
+        let entries = 1usize << (HUFFMAN_MAX_BITS - bit_length);
+        for (symbol, _) in lengths
+            .iter()
+            .enumerate()
+            .filter(|(_, &l)| l as u32 == bit_length)
+        {
+            if position + entries > table.len() {
+                return Err("Invalid Xpress Huffman table".into());
+            }
+            for entry in table[position..position + entries].iter_mut() {
+                *entry = symbol as u16;
+            }
+            position += entries;
+        }
+    }
+    Ok(table)
+}
+
+pub fn decompress_huffman(input: &[u8], output_size: usize) -> BoxResult<Vec<u8>> {
+    let mut input = Input {
+        data: input,
+        position: 0,
+    };
+    let mut output = Vec::with_capacity(output_size);
+
+    while output.len() < output_size {
+        // Every 64KB of output starts with a new table of 512 4-bit code lengths
+        let mut lengths = [0u8; HUFFMAN_SYMBOLS];
+        for i in 0..HUFFMAN_TABLE_SIZE {
+            let b = input.u8()?;
+            lengths[2 * i] = b & 0xf;
+            lengths[2 * i + 1] = b >> 4;
+        }
+        let table = build_decoding_table(&lengths)?;
+
+        // The end of the stream is padded with zero bits
+        let mut next_bits = (input.u16()? as u32) << 16 | input.u16()? as u32;
+        let mut extra_bit_count: i32 = 16;
+        let chunk_end = (output.len() + HUFFMAN_CHUNK_SIZE).min(output_size);
+
+        while output.len() < chunk_end {
+            let symbol = table[(next_bits >> (32 - HUFFMAN_MAX_BITS)) as usize] as usize;
+            let bit_length = lengths[symbol] as u32;
+            if bit_length == 0 {
+                return Err("Invalid Xpress Huffman code".into());
+            }
+            next_bits <<= bit_length;
+            extra_bit_count -= bit_length as i32;
+            if extra_bit_count < 0 {
+                next_bits |= (input.u16().unwrap_or(0) as u32) << (-extra_bit_count);
+                extra_bit_count += 16;
+            }
+
+            if symbol < 256 {
+                output.push(symbol as u8);
+                continue;
+            }
+            if symbol == 256 && input.is_end() {
+                break;
+            }
+
+            let symbol = symbol - 256;
+            let mut length = symbol % 16;
+            let offset_bit_length = (symbol / 16) as u32;
+            if length == 15 {
+                length = input.u8()? as usize;
+                if length == 255 {
+                    length = input.u16()? as usize;
+                    if length < 15 {
+                        return Err("Invalid Xpress Huffman match length".into());
+                    }
+                    length -= 15;
+                }
+                length += 15;
+            }
+            length += 3;
+
+            let mut offset = 1usize << offset_bit_length;
+            if offset_bit_length > 0 {
+                offset += (next_bits >> (32 - offset_bit_length)) as usize;
+                next_bits <<= offset_bit_length;
+                extra_bit_count -= offset_bit_length as i32;
+                if extra_bit_count < 0 {
+                    next_bits |= (input.u16().unwrap_or(0) as u32) << (-extra_bit_count);
+                    extra_bit_count += 16;
+                }
+            }
+            copy_match(&mut output, output_size, offset, length)?;
+        }
+
+        if input.is_end() {
+            break;
+        }
+    }
+
+    output.truncate(output_size);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn huffman_block(code_lengths: &[(usize, u8)], stream: &[u8]) -> Vec<u8> {
+        // The 256 bytes of 4-bit code lengths, then the bit stream
+        let mut block = vec![0u8; HUFFMAN_TABLE_SIZE];
+        for &(symbol, length) in code_lengths {
+            block[symbol / 2] |= length << (4 * (symbol % 2));
+        }
+        block.extend_from_slice(stream);
+        block
+    }
+
+    #[test]
+    fn lz77_literals() {
+        // the plain LZ77 example of [MS-XCA]
+        let mut input = vec![0x3f, 0x00, 0x00, 0x00];
+        input.extend_from_slice(b"abcdefghijklmnopqrstuvwxyz");
+        let output = decompress_lz77(&input, 26).unwrap();
+        assert_eq!(output, b"abcdefghijklmnopqrstuvwxyz");
+    }
+
+    #[test]
+    fn lz77_match() {
+        // the plain LZ77 example of [MS-XCA], "abc" 100 times
+        let input = [
+            0xff, 0xff, 0xff, 0x1f, 0x61, 0x62, 0x63, 0x17, 0x00, 0x0f, 0xff, 0x26, 0x01,
+        ];
+        let output = decompress_lz77(&input, 300).unwrap();
+        assert_eq!(output, b"abc".repeat(100));
+    }
+
+    #[test]
+    fn lz77_match_past_output_size() {
+        // 'a' then a match of offset 1 and a 32-bit length of about 4GB
+        let input = [
+            0x00, 0x00, 0x00, 0x40, 0x61, 0x07, 0x00, 0x0f, 0xff, 0x00, 0x00, 0xf0, 0xff, 0xff,
+            0xff,
+        ];
+        let output = decompress_lz77(&input, 16).unwrap();
+        assert_eq!(output, [0x61; 16]);
+        assert!(output.capacity() < 0x10000);
+    }
+
+    #[test]
+    fn lz77_match_before_output() {
+        // a match of offset 1 with nothing decompressed yet
+        let input = [0x00, 0x00, 0x00, 0x80, 0x00, 0x00];
+        assert!(decompress_lz77(&input, 3).is_err());
+    }
+
+    #[test]
+    fn huffman_literals() {
+        // 26 literals with 5-bit codes, 'a' = 00000 to 'z' = 11001
+        let lengths: Vec<_> = (b'a'..=b'z').map(|c| (c as usize, 5)).collect();
+        let stream = [
+            0x44, 0x00, 0x14, 0x32, 0x42, 0xc7, 0xb6, 0x54, 0xcf, 0x35, 0x65, 0x84, 0x56, 0x3a,
+            0xc6, 0xd7, 0x00, 0x40, 0x00, 0x00,
+        ];
+        let output = decompress_huffman(&huffman_block(&lengths, &stream), 26).unwrap();
+        assert_eq!(output, b"abcdefghijklmnopqrstuvwxyz");
+    }
+
+    #[test]
+    fn huffman_match() {
+        // "abcdefgh" then a match of length 4 (symbol 256 + 2 * 16 + 1) and offset
+        // 4 + 2 (2 offset bits)
+        let mut lengths: Vec<_> = (b'a'..=b'h').map(|c| (c as usize, 4)).collect();
+        lengths.push((256 + 2 * 16 + 1, 4));
+        let stream = [0x23, 0x01, 0x67, 0x45, 0x00, 0x88, 0x00, 0x00];
+        let output = decompress_huffman(&huffman_block(&lengths, &stream), 12).unwrap();
+        assert_eq!(output, b"abcdefghcdef");
+    }
+
+    #[test]
+    fn huffman_long_match() {
+        // "abc" then a match of offset 3 (1 offset bit) and length 297, the length
+        // is past the 255 of the extra byte and follows in 16 bits
+        let lengths = [(0x61, 2), (0x62, 2), (0x63, 2), (256 + 16 + 15, 2)];
+        let stream = [0x80, 0x1b, 0x00, 0x00, 0xff, 0x26, 0x01];
+        let output = decompress_huffman(&huffman_block(&lengths, &stream), 300).unwrap();
+        assert_eq!(output, b"abc".repeat(100));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::address::Address;
-use crate::driver_state::DriverState;
+use crate::memory::{AddressSpace, MemoryReader, MemoryReaderExt};
 use crate::{get_device_type, to_epoch};
 use serde_json::{json, Value};
 use std::error::Error;
@@ -7,14 +7,14 @@ use std::str::from_utf8;
 
 type BoxResult<T> = Result<T, Box<dyn Error>>;
 
-pub fn make_list_entry(d: &DriverState, a: Address, next: &str) -> BoxResult<Vec<Address>> {
+pub fn make_list_entry(d: &dyn MemoryReader, a: Address, next: &str) -> BoxResult<Vec<Address>> {
     // `a` is the address to the _LIST_ENTRY
     // `next` is the _LIST_ENTRY field in the object
     // return a list of address for object
     let mut result: Vec<Address> = Vec::new();
-    let list_offset = d.pdb_store.get_offset_r(next)?;
+    let list_offset = d.pdb_store().get_offset_r(next)?;
 
-    let mut ptr: u64 = d.deref_addr_new(a.address());
+    let mut ptr: u64 = d.try_deref_addr(a.address())?;
     while ptr != a.address() {
         let obj_ptr = Address::from_base(ptr - list_offset);
         ptr = d.decompose(&obj_ptr, &format!("{}.Flink", next))?;
@@ -23,7 +23,32 @@ pub fn make_list_entry(d: &DriverState, a: Address, next: &str) -> BoxResult<Vec
     Ok(result)
 }
 
-pub fn make_eprocess(d: &DriverState, a: &Address) -> BoxResult<Value> {
+pub fn prefetch_objects(d: &dyn MemoryReader, objects: &[Address], struct_name: &str) {
+    // Fetch every object in one batch before decompose() reads them member by member
+    let size = match d.pdb_store().get_offset(&format!("{}.struct_size", struct_name)) {
+        Some(size) => size,
+        None => return,
+    };
+    let ranges: Vec<(u64, u64)> = objects.iter().map(|a| (a.address(), size)).collect();
+    d.prefetch(AddressSpace::Virtual, &ranges);
+}
+
+pub fn make_objects<F>(
+    d: &dyn MemoryReader,
+    objects: &[Address],
+    struct_name: &str,
+    make: F,
+) -> Vec<Value>
+where
+    F: Fn(&dyn MemoryReader, &Address) -> BoxResult<Value>,
+{
+    // Objects found by a scan, fetched in one batch then built one by one
+    // An object that cannot be built is left out, as the scan would skip its pool
+    prefetch_objects(d, objects, struct_name);
+    objects.iter().filter_map(|a| make(d, a).ok()).collect()
+}
+
+pub fn make_eprocess(d: &dyn MemoryReader, a: &Address) -> BoxResult<Value> {
     let createtime: u64 = d.decompose(a, "_EPROCESS.CreateTime")?;
     let exittime: u64 = d.decompose(a, "_EPROCESS.ExitTime")?;
     let pid: u64 = d.decompose(a, "_EPROCESS.UniqueProcessId")?;
@@ -43,12 +68,14 @@ pub fn make_eprocess(d: &DriverState, a: &Address) -> BoxResult<Value> {
     let binary_path = d.get_unicode_string(filename_ptr).unwrap_or("".to_string());
 
     let thread_head = d.address_of(a, "_EPROCESS.ThreadListHead")?;
-    let threads: Vec<Value> = make_list_entry(
+    let thread_addrs = make_list_entry(
         d,
         Address::from_base(thread_head),
         "_ETHREAD.ThreadListEntry",
     )
-    .unwrap_or(Vec::new())
+    .unwrap_or_default();
+    prefetch_objects(d, &thread_addrs, "_ETHREAD");
+    let threads: Vec<Value> = thread_addrs
     .iter()
     .map(|thread_addr| {
         make_ethread(d, thread_addr).unwrap_or(json!({})) // unlikely
@@ -79,66 +106,26 @@ pub fn make_eprocess(d: &DriverState, a: &Address) -> BoxResult<Value> {
     }))
 }
 
-pub fn make_ethread(d: &DriverState, a: &Address) -> BoxResult<Value> {
+fn enum_name_or_unknown(d: &dyn MemoryReader, enum_name: &str, value: u64) -> String {
+    match d.pdb_store().enum_name(enum_name, value) {
+        Ok(Some(enumerator)) => enumerator.to_string(),
+        _ => "Unknown".to_string(),
+    }
+}
+
+pub fn make_ethread(d: &dyn MemoryReader, a: &Address) -> BoxResult<Value> {
     // let createtime: u64 = d.decompose(a, "_ETHREAD.CreateTime")?;
     // let exittime: u64 = d.decompose(a, "_ETHREAD.ExitTime")?;
     let pid: u64 = d.decompose(a, "_ETHREAD.Cid.UniqueProcess")?;
     let tid: u64 = d.decompose(a, "_ETHREAD.Cid.UniqueThread")?;
     let eprocess: u64 = d.decompose(a, "_ETHREAD.Tcb.Process")?;
     let flags: u32 = d.decompose(a, "_ETHREAD.CrossThreadFlags")?;
-    let state = match d.decompose::<u8>(a, "_ETHREAD.Tcb.State")? {
-        0 => "Initialized",
-        1 => "Ready",
-        2 => "Running",
-        3 => "Standby",
-        4 => "Terminated",
-        5 => "Waiting",
-        6 => "Transition",
-        7 => "DeferredReady",
-        8 => "GateWait",
-        _ => "Unknown",
-    };
-    let wait = match d.decompose::<u8>(a, "_ETHREAD.Tcb.WaitReason")? {
-        0 => "Executive",
-        1 => "FreePage",
-        2 => "PageIn",
-        3 => "PoolAllocation",
-        4 => "DelayExecution",
-        5 => "Suspended",
-        6 => "UserRequest",
-        7 => "WrExecutive",
-        8 => "WrFreePage",
-        9 => "WrPageIn",
-        10 => "WrPoolAllocation",
-        11 => "WrDelayExecution",
-        12 => "WrSuspended",
-        13 => "WrUserRequest",
-        14 => "WrEventPair",
-        15 => "WrQueue",
-        16 => "WrLpcReceive",
-        17 => "WrLpcReply",
-        18 => "WrVirtualMemory",
-        19 => "WrPageOut",
-        20 => "WrRendezvous",
-        21 => "Spare2",
-        22 => "Spare3",
-        23 => "Spare4",
-        24 => "Spare5",
-        25 => "Spare6",
-        26 => "WrKernel",
-        27 => "WrResource",
-        28 => "WrPushLock",
-        29 => "WrMutex",
-        30 => "WrQuantumEnd",
-        31 => "WrDispatchInt",
-        32 => "WrPreempted",
-        33 => "WrYieldExecution",
-        34 => "WrFastMutex",
-        35 => "WrGuardedMutex",
-        36 => "WrRundown",
-        37 => "MaximumWaitReason",
-        _ => "Unknown",
-    };
+    // State and WaitReason are UChar members, the names come from the enums of this build
+    // A store without the enums, e.g. from an ISF, still gives the thread
+    let state: u64 = d.decompose(a, "_ETHREAD.Tcb.State")?;
+    let wait: u64 = d.decompose(a, "_ETHREAD.Tcb.WaitReason")?;
+    let state = enum_name_or_unknown(d, "_KTHREAD_STATE", state);
+    let wait = enum_name_or_unknown(d, "_KWAIT_REASON", wait);
     let name_ptr: u64 = d.address_of(a, "_ETHREAD.ThreadName").unwrap_or(0); // ThreadName is after Windows 10 Anniversary
 
     let thread_name = if let Ok(name) = d.get_unicode_string(name_ptr) {
@@ -182,7 +169,7 @@ pub fn make_ethread(d: &DriverState, a: &Address) -> BoxResult<Value> {
     }))
 }
 
-pub fn make_driver(d: &DriverState, a: &Address) -> BoxResult<Value> {
+pub fn make_driver(d: &dyn MemoryReader, a: &Address) -> BoxResult<Value> {
     let devicename_ptr = d.address_of(a, "_DRIVER_OBJECT.DriverName")?;
     let servicekey_ptr = d.address_of(a, "_DRIVER_OBJECT.DriverExtension.ServiceKeyName")?;
     let hardware_ptr: u64 = d.decompose(a, "_DRIVER_OBJECT.HardwareDatabase")?;
@@ -250,7 +237,7 @@ pub fn make_driver(d: &DriverState, a: &Address) -> BoxResult<Value> {
     }))
 }
 
-pub fn make_ldr(d: &DriverState, a: &Address) -> BoxResult<Value> {
+pub fn make_ldr(d: &dyn MemoryReader, a: &Address) -> BoxResult<Value> {
     let dllbase: u64 = d.decompose(a, "_LDR_DATA_TABLE_ENTRY.DllBase")?;
     let entry: u64 = d.decompose(a, "_LDR_DATA_TABLE_ENTRY.EntryPoint")?;
     let size: u64 = d.decompose(a, "_LDR_DATA_TABLE_ENTRY.SizeOfImage")?;
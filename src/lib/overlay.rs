@@ -0,0 +1,411 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use parse_int::parse;
+use serde_json::Value;
+
+use crate::pdb_store::PdbStore;
+use crate::type_info::{primitive_size, StructInfo, TypeInfo};
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// Structs the kernel PDB does not have, applied to every PdbStore lpus parses
+const DEFAULT_OVERLAYS: &str = include_str!("../../overlays/default.json");
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayMember {
+    pub name: String,
+    // as TypeInfo prints it: "U32", "Void*", "_LIST_ENTRY", "UChar[16]"
+    pub type_name: String,
+    pub offset: u64,
+    // (bit position, bit length) of a bitfield
+    pub bits: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayStruct {
+    pub name: String,
+    // None keeps the size of the struct being extended
+    pub size: Option<u64>,
+    pub is_union: bool,
+    // add members to the struct from the PDB instead of replacing it
+    pub extend: bool,
+    pub members: Vec<OverlayMember>,
+}
+
+// Structs and symbols for the kernel builds in [min_build, max_build]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlay {
+    pub min_build: Option<u32>,
+    pub max_build: Option<u32>,
+    pub structs: Vec<OverlayStruct>,
+    // name -> RVA
+    pub symbols: Vec<(String, u64)>,
+}
+
+impl Overlay {
+    pub fn applies_to(&self, build: Option<u32>) -> bool {
+        // Without a range an overlay is for every build, with one it waits for the
+        // build number of the system being read
+        match build {
+            None => self.min_build.is_none() && self.max_build.is_none(),
+            Some(build) => {
+                self.min_build.is_none_or(|min| build >= min)
+                    && self.max_build.is_none_or(|max| build <= max)
+            }
+        }
+    }
+}
+
+fn number(value: &Value, what: &str) -> BoxResult<u64> {
+    // 40 or "0x28"
+    match value {
+        Value::Number(n) => n
+            .as_u64()
+            .ok_or_else(|| format!("{} is not a positive integer", what).into()),
+        Value::String(s) => {
+            parse::<u64>(s.trim()).map_err(|_| format!("{} is not a number: {}", what, s).into())
+        }
+        Value::Null => Err(format!("{} is missing", what).into()),
+        _ => Err(format!("{} is not a number", what).into()),
+    }
+}
+
+fn optional_number(value: &Value, what: &str) -> BoxResult<Option<u64>> {
+    match value {
+        Value::Null => Ok(None),
+        value => number(value, what).map(Some),
+    }
+}
+
+fn optional_build(value: &Value, what: &str) -> BoxResult<Option<u32>> {
+    // Build numbers are 16 bits, anything past a u32 is a typo
+    match optional_number(value, what)? {
+        Some(build) => u32::try_from(build)
+            .map(Some)
+            .map_err(|_| format!("{} is not a build number: {}", what, build).into()),
+        None => Ok(None),
+    }
+}
+
+fn parse_member(struct_name: &str, name: &str, value: &Value) -> BoxResult<OverlayMember> {
+    let what = format!("{}.{}", struct_name, name);
+    let type_name = value["type"]
+        .as_str()
+        .ok_or_else(|| format!("{} has no type", what))?;
+    let bits = match &value["bits"] {
+        Value::Null => None,
+        Value::Array(bits) if bits.len() == 2 => Some((
+            number(&bits[0], &format!("{} bit position", what))?,
+            number(&bits[1], &format!("{} bit length", what))?,
+        )),
+        _ => return Err(format!("{} bits is not [position, length]", what).into()),
+    };
+    Ok(OverlayMember {
+        name: name.to_string(),
+        type_name: type_name.to_string(),
+        offset: number(&value["offset"], &format!("{} offset", what))?,
+        bits,
+    })
+}
+
+fn parse_struct(name: &str, value: &Value) -> BoxResult<OverlayStruct> {
+    let extend = value["extend"].as_bool().unwrap_or(false);
+    let size = optional_number(&value["size"], &format!("{} size", name))?;
+    if size.is_none() && !extend {
+        return Err(format!("{} needs a size, or extend = true", name).into());
+    }
+    let mut members = Vec::new();
+    if let Some(entries) = value["members"].as_object() {
+        for (member, entry) in entries {
+            members.push(parse_member(name, member, entry)?);
+        }
+    }
+    Ok(OverlayStruct {
+        name: name.to_string(),
+        size,
+        is_union: value["union"].as_bool().unwrap_or(false),
+        extend,
+        members,
+    })
+}
+
+pub fn parse_overlays(value: &Value) -> BoxResult<Vec<Overlay>> {
+    // { "overlay": [ { "min_build": 19041, "structs": {...}, "symbols": {...} } ] }
+    // Keys lpus does not know, e.g. "source", are left for the reader
+    let entries = value["overlay"]
+        .as_array()
+        .ok_or("An overlay file is a list of `overlay` tables")?;
+    let mut overlays = Vec::new();
+    for entry in entries {
+        let mut structs = Vec::new();
+        if let Some(defs) = entry["structs"].as_object() {
+            for (name, def) in defs {
+                structs.push(parse_struct(name, def)?);
+            }
+        }
+        let mut symbols = Vec::new();
+        if let Some(defs) = entry["symbols"].as_object() {
+            for (name, rva) in defs {
+                symbols.push((name.to_string(), number(rva, name)?));
+            }
+        }
+        overlays.push(Overlay {
+            min_build: optional_build(&entry["min_build"], "min_build")?,
+            max_build: optional_build(&entry["max_build"], "max_build")?,
+            structs,
+            symbols,
+        });
+    }
+    Ok(overlays)
+}
+
+pub fn parse_overlay_file(path: &Path) -> BoxResult<Vec<Overlay>> {
+    // .toml or JSON, the same tables in either
+    let text = fs::read_to_string(path)?;
+    let value = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => serde_json::to_value(text.parse::<toml::Value>()?)?,
+        _ => serde_json::from_str(&text)?,
+    };
+    parse_overlays(&value).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+pub fn default_overlays() -> Vec<Overlay> {
+    // overlays/default.json is built in, an error in it is a bug
+    let value: Value =
+        serde_json::from_str(DEFAULT_OVERLAYS).expect("Invalid overlays/default.json");
+    parse_overlays(&value).expect("Invalid overlays/default.json")
+}
+
+pub fn build_default_overlays() -> Vec<Overlay> {
+    // The default overlays with a build range, parsing the PDB or ISF applied the others
+    default_overlays()
+        .into_iter()
+        .filter(|overlay| !overlay.applies_to(None))
+        .collect()
+}
+
+fn parse_type(pdb_store: &PdbStore, type_name: &str) -> BoxResult<TypeInfo> {
+    // A base type followed by `*` and `[count]`, as TypeInfo prints them:
+    // "_LIST_ENTRY*[4]" is 4 pointers, "UChar[2][3]" 2 arrays of 3
+    let bad_type = || format!("Bad type {}", type_name);
+    let type_name = type_name.trim();
+    let end = type_name.find(['*', '[']).unwrap_or(type_name.len());
+    let (base, mut rest) = (type_name[..end].trim(), &type_name[end..]);
+
+    let mut t = if primitive_size(base).is_some() {
+        TypeInfo::primitive(base)
+    } else if let Some(info) = pdb_store.structs.get(base) {
+        let (name, size) = (base.to_string(), info.size);
+        if info.is_union {
+            TypeInfo::Union { name, size }
+        } else {
+            TypeInfo::Struct { name, size }
+        }
+    } else if let Some(info) = pdb_store.enums.get(base) {
+        TypeInfo::Enum {
+            name: base.to_string(),
+            underlying: Box::new(info.underlying.clone()),
+        }
+    } else if rest.starts_with('*') {
+        // only pointed to, the layout is not needed
+        TypeInfo::Struct {
+            name: base.to_string(),
+            size: 0,
+        }
+    } else {
+        return Err(format!("Unknown type {}", base).into());
+    };
+
+    while !rest.is_empty() {
+        if rest.starts_with('*') {
+            t = TypeInfo::pointer_to(t);
+            rest = rest[1..].trim_start();
+            continue;
+        }
+        // the dimensions of one array, the outer first
+        let mut counts = Vec::new();
+        while rest.starts_with('[') {
+            let close = rest.find(']').ok_or_else(bad_type)?;
+            counts.push(parse::<u64>(rest[1..close].trim()).map_err(|_| bad_type())?);
+            rest = rest[close + 1..].trim_start();
+        }
+        if counts.is_empty() {
+            return Err(bad_type().into());
+        }
+        for count in counts.into_iter().rev() {
+            t = TypeInfo::Array {
+                element: Box::new(t),
+                count,
+            };
+        }
+    }
+    Ok(t)
+}
+
+pub fn apply_overlays(
+    pdb_store: &mut PdbStore,
+    overlays: &[Overlay],
+    build: Option<u32>,
+) -> BoxResult<()> {
+    // Overlays for `build` (None: those without a range) in order, a later one
+    // overrides an earlier one and all of them override the PDB
+    for overlay in overlays.iter().filter(|o| o.applies_to(build)) {
+        // every struct gets its size first, members can then be of each other's types
+        for def in &overlay.structs {
+            let info = match pdb_store.structs.remove(&def.name) {
+                Some(mut info) if def.extend => {
+                    info.size = def.size.unwrap_or(info.size);
+                    info
+                }
+                _ => {
+                    let size = def
+                        .size
+                        .ok_or_else(|| format!("{} is not in the PDB to extend", def.name))?;
+                    StructInfo::new(size, def.is_union)
+                }
+            };
+            pdb_store.structs.insert(def.name.clone(), info);
+        }
+        for def in &overlay.structs {
+            for member in &def.members {
+                let mut t = parse_type(pdb_store, &member.type_name)
+                    .map_err(|e| format!("{}.{}: {}", def.name, member.name, e))?;
+                if let Some((position, length)) = member.bits {
+                    t = TypeInfo::Bitfield {
+                        underlying: Box::new(t),
+                        position,
+                        length,
+                    };
+                }
+                if let Some(info) = pdb_store.structs.get_mut(&def.name) {
+                    info.add_member(&member.name, t, member.offset);
+                }
+            }
+        }
+        for (name, rva) in &overlay.symbols {
+            pdb_store.symbols.insert(name.to_string(), *rva);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isf::parse_isf_file;
+    use serde_json::json;
+
+    fn store() -> PdbStore {
+        let isf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/win7_isf.json");
+        parse_isf_file(&isf).unwrap()
+    }
+
+    fn overlays(value: Value) -> Vec<Overlay> {
+        parse_overlays(&json!({ "overlay": [value] })).unwrap()
+    }
+
+    #[test]
+    fn build_range() {
+        let ranged = overlays(json!({ "min_build": 9200, "max_build": "0x4a61" }));
+        assert_eq!(
+            (ranged[0].min_build, ranged[0].max_build),
+            (Some(9200), Some(19041))
+        );
+        assert!(!ranged[0].applies_to(None));
+        assert!(!ranged[0].applies_to(Some(7601)));
+        assert!(ranged[0].applies_to(Some(9200)));
+        assert!(ranged[0].applies_to(Some(19041)));
+        assert!(!ranged[0].applies_to(Some(22000)));
+
+        let open_ended = overlays(json!({ "min_build": 22000 }));
+        assert!(open_ended[0].applies_to(Some(26100)));
+        assert!(!open_ended[0].applies_to(Some(19041)));
+
+        let unranged = overlays(json!({}));
+        assert!(unranged[0].applies_to(None));
+        assert!(unranged[0].applies_to(Some(7601)));
+
+        for build in [json!(4294967296u64), json!("0x1_0000_0000"), json!(-1)] {
+            let value = json!({ "overlay": [{ "max_build": build }] });
+            assert!(parse_overlays(&value).is_err(), "{}", build);
+        }
+    }
+
+    #[test]
+    fn apply_for_build() {
+        let ranged = overlays(json!({
+            "min_build": 9200,
+            "symbols": { "MiNewSymbol": "0x1000" },
+        }));
+        let mut pdb_store = store();
+        apply_overlays(&mut pdb_store, &ranged, None).unwrap();
+        apply_overlays(&mut pdb_store, &ranged, Some(7601)).unwrap();
+        assert_eq!(pdb_store.get_offset("MiNewSymbol"), None);
+        apply_overlays(&mut pdb_store, &ranged, Some(9200)).unwrap();
+        assert_eq!(pdb_store.get_offset("MiNewSymbol"), Some(0x1000));
+    }
+
+    #[test]
+    fn override_and_extend() {
+        let mut pdb_store = store();
+        let replaced = overlays(json!({
+            "structs": { "_CLIENT_ID": {
+                "size": 8,
+                "members": { "Id": { "type": "U32", "offset": 4 } },
+            }},
+        }));
+        apply_overlays(&mut pdb_store, &replaced, None).unwrap();
+        let info = &pdb_store.structs["_CLIENT_ID"];
+        assert_eq!(info.size, 8);
+        assert_eq!(info.members.keys().collect::<Vec<_>>(), vec!["Id"]);
+
+        let mut pdb_store = store();
+        let extended = overlays(json!({
+            "structs": { "_CLIENT_ID": {
+                "extend": true,
+                "members": { "Flags": { "type": "U32", "offset": 8, "bits": [3, 2] } },
+            }},
+        }));
+        apply_overlays(&mut pdb_store, &extended, None).unwrap();
+        let info = &pdb_store.structs["_CLIENT_ID"];
+        assert_eq!(info.size, 16);
+        assert_eq!(info.members.len(), 3);
+        assert_eq!(info.members["UniqueThread"].1, 8);
+        assert_eq!(info.members["Flags"].0.bitfield(), Some((3, 2)));
+
+        // a later overlay overrides an earlier one
+        let mut pdb_store = store();
+        let both = [replaced[0].clone(), extended[0].clone()];
+        apply_overlays(&mut pdb_store, &both, None).unwrap();
+        let info = &pdb_store.structs["_CLIENT_ID"];
+        assert_eq!(info.size, 8);
+        let mut names: Vec<_> = info.members.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["Flags", "Id"]);
+
+        // nothing to extend
+        let missing = overlays(json!({ "structs": { "_MISSING": { "extend": true } } }));
+        assert!(apply_overlays(&mut pdb_store, &missing, None).is_err());
+    }
+
+    #[test]
+    fn default_overlays_apply() {
+        let defaults = default_overlays();
+        assert!(!defaults.is_empty());
+        assert!(build_default_overlays().iter().all(|o| !o.applies_to(None)));
+
+        let mut pdb_store = store();
+        apply_overlays(&mut pdb_store, &defaults, None).unwrap();
+        let info = &pdb_store.structs["_UNLOADED_DRIVERS"];
+        assert_eq!(info.size, 0x28);
+        assert_eq!(info.members["Name"].1, 0);
+        assert_eq!(info.members["CurrentTime"].1, 0x20);
+        assert_eq!(
+            pdb_store.get_offset("_UNLOADED_DRIVERS.EndAddress"),
+            Some(0x18)
+        );
+    }
+}
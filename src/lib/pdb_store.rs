@@ -1,135 +1,378 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
+#[cfg(windows)]
 use std::io;
-use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use app_dirs::{app_dir, AppDataType};
 use pdb::{
-    ClassType, FallibleIterator, ModifierType, Rva, SymbolData, TypeData, TypeFinder, TypeIndex,
-    PDB,
+    ClassType, FallibleIterator, Indirection, MemberType, Rva, SymbolData, TypeData, TypeFinder,
+    TypeIndex, UnionType, Variant, PDB,
 };
 
 use crate::address::Address;
+use crate::error::{LpusError, LpusResult};
+use crate::overlay::{apply_overlays, default_overlays};
+use crate::pe::{codeview_from_file, CodeViewInfo};
+use crate::symbol_cache::{cache_path, load_symbol_cache, save_symbol_cache};
+use crate::symbol_index::SymbolIndex;
+use crate::symbol_path::SymbolPath;
+use crate::type_info::{EnumInfo, StructInfo, TypeInfo};
 use crate::utils::mask_cast::*;
 use crate::APP_INFO;
 
 const KERNEL_PDB_NAME: &str = "ntkrnlmp.pdb";
 const NTOSKRNL_PATH: &str = "C:\\Windows\\System32\\ntoskrnl.exe";
-const PDB_SERVER_PATH: &str = "http://msdl.microsoft.com/download/symbols";
+pub(crate) const PDB_SERVER_PATH: &str = "http://msdl.microsoft.com/download/symbols";
 
 type BoxResult<T> = Result<T, Box<dyn Error>>;
 
-type SymbolStore = HashMap<String, u64>;
-type StructStore = HashMap<String, HashMap<String, (String, u64)>>;
+pub type SymbolStore = HashMap<String, u64>;
+pub type StructStore = HashMap<String, StructInfo>;
+pub type EnumStore = HashMap<String, EnumInfo>;
+
+// Names the kernel answers to in `module!Name`, a name without `!` is also the kernel's
+const KERNEL_MODULE_NAMES: [&str; 3] = ["nt", "ntoskrnl", "ntkrnlmp"];
+// Anonymous structs in anonymous unions in ... are flattened this deep
+const MAX_ANONYMOUS_DEPTH: usize = 4;
+
+// Symbols and types of a PDB other than the kernel's, e.g. tcpip.pdb
+pub struct ModuleSymbols {
+    pub symbols: SymbolStore,
+    pub index: SymbolIndex,
+    pub structs: StructStore,
+    pub enums: EnumStore,
+    pub guid: String,
+    pub age: u32,
+}
 
 pub struct PdbStore {
     pub symbols: SymbolStore,
+    // the symbols and functions by address, for symbolize()
+    pub index: SymbolIndex,
     pub structs: StructStore,
+    pub enums: EnumStore,
+    // as used by the symbol server, uppercase hex without dashes
+    pub guid: String,
+    pub age: u32,
+    // other modules by lowercase name without extension: tcpip, win32kfull, afd
+    pub modules: HashMap<String, ModuleSymbols>,
+}
+
+// "tcpip!_TCB.Foo" -> (Some("tcpip"), "_TCB.Foo")
+pub fn split_module(name: &str) -> (Option<&str>, &str) {
+    match name.find('!') {
+        Some(i) => (Some(&name[..i]), &name[i + 1..]),
+        None => (None, name),
+    }
+}
+
+pub fn is_kernel_module(module: &str) -> bool {
+    KERNEL_MODULE_NAMES.contains(&module.to_lowercase().as_str())
+}
+
+pub(crate) fn qualify(module: Option<&str>, name: &str) -> String {
+    match module {
+        Some(module) => format!("{}!{}", module, name),
+        None => name.to_string(),
+    }
+}
+
+// One step of a member path: `.Member` or `->Member`, then its `[index]`es
+struct PathStep<'a> {
+    arrow: bool,
+    member: &'a str,
+    indices: Vec<u64>,
+}
+
+// What a member path comes down to, done in order from the struct address
+enum PathOp {
+    Add(u64),
+    Deref,
+}
+
+fn parse_path(full_name: &str) -> BoxResult<(&str, Vec<PathStep<'_>>)> {
+    // "_EPROCESS.Peb->Ldr" -> ("_EPROCESS", [.Peb, ->Ldr])
+    let bad_path = || format!("Cannot parse member path {}", full_name);
+    let start = match (full_name.find('.'), full_name.find("->")) {
+        (Some(dot), Some(arrow)) => dot.min(arrow),
+        (Some(i), None) | (None, Some(i)) => i,
+        (None, None) => return Err("Not decomposable".into()),
+    };
+    let struct_name = &full_name[..start];
+    let mut rest = &full_name[start..];
+    let mut steps = Vec::new();
+    while !rest.is_empty() {
+        let arrow = rest.starts_with("->");
+        rest = if let Some(after) = rest.strip_prefix("->") {
+            after
+        } else if let Some(after) = rest.strip_prefix('.') {
+            after
+        } else {
+            return Err(bad_path().into());
+        };
+        let end = rest.find(['.', '-', '[']).unwrap_or(rest.len());
+        let member = &rest[..end];
+        if member.is_empty() {
+            return Err(bad_path().into());
+        }
+        rest = &rest[end..];
+        let mut indices = Vec::new();
+        while rest.starts_with('[') {
+            let close = rest.find(']').ok_or_else(bad_path)?;
+            indices.push(rest[1..close].trim().parse().map_err(|_| bad_path())?);
+            rest = &rest[close + 1..];
+        }
+        steps.push(PathStep {
+            arrow,
+            member,
+            indices,
+        });
+    }
+    Ok((struct_name, steps))
 }
 
 impl PdbStore {
+    pub(crate) fn tables(
+        &self,
+        module: Option<&str>,
+    ) -> LpusResult<(&SymbolStore, &StructStore)> {
+        match module {
+            Some(module) if !is_kernel_module(module) => self
+                .modules
+                .get(&module.to_lowercase())
+                .map(|m| (&m.symbols, &m.structs))
+                .ok_or_else(|| LpusError::ModuleNotLoaded(module.to_string())),
+            _ => Ok((&self.symbols, &self.structs)),
+        }
+    }
+
+    pub(crate) fn enum_table(&self, module: Option<&str>) -> LpusResult<&EnumStore> {
+        match module {
+            Some(module) if !is_kernel_module(module) => self
+                .modules
+                .get(&module.to_lowercase())
+                .map(|m| &m.enums)
+                .ok_or_else(|| LpusError::ModuleNotLoaded(module.to_string())),
+            _ => Ok(&self.enums),
+        }
+    }
+
+    pub fn get_enum(&self, name: &str) -> LpusResult<&EnumInfo> {
+        // "_KWAIT_REASON" or "module!_ENUM"
+        let (module, bare_name) = split_module(name);
+        self.enum_table(module)?
+            .get(bare_name)
+            .ok_or_else(|| LpusError::EnumNotFound(name.to_string()))
+    }
+
+    pub fn enum_name(&self, name: &str, value: u64) -> LpusResult<Option<&str>> {
+        // The enumerator of `name` with this value, None for a value the build does not name
+        Ok(self.get_enum(name)?.name_of(value))
+    }
+
+    pub fn symbolize_rva(&self, module: &str, rva: u64) -> LpusResult<Option<String>> {
+        // "module!Symbol+0x10" for an RVA in a module, None when no symbol is before it
+        let index = if is_kernel_module(module) {
+            &self.index
+        } else {
+            &self
+                .modules
+                .get(&module.to_lowercase())
+                .ok_or_else(|| LpusError::ModuleNotLoaded(module.to_string()))?
+                .index
+        };
+        Ok(index
+            .symbolize(rva)
+            .map(|symbol| format!("{}!{}", module, symbol)))
+    }
+
+    pub fn add_module(&mut self, name: &str, module: ModuleSymbols) {
+        self.modules.insert(name.to_lowercase(), module);
+    }
+
+    pub fn load_module_pdb(&mut self, name: &str, pdb_path: &Path) -> BoxResult<()> {
+        let module = parse_module_pdb(pdb_path)?;
+        self.add_module(name, module);
+        Ok(())
+    }
+
+    pub fn load_module_pe(
+        &mut self,
+        pe_path: &Path,
+        symbol_path: &SymbolPath,
+    ) -> BoxResult<String> {
+        // The module is named after the file, tcpip.sys -> tcpip
+        let name = pe_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| format!("No module name in {}", pe_path.display()))?
+            .to_lowercase();
+        let codeview = codeview_from_file(pe_path)?;
+        let pdb_path = symbol_path.find_pdb(&codeview.pdb_name, &codeview.guid, codeview.age)?;
+        self.load_module_pdb(&name, &pdb_path)?;
+        Ok(name)
+    }
+
     pub fn get_offset_r(&self, name: &str) -> BoxResult<u64> {
         self.get_offset(name)
-            .ok_or(format!("{} is not found in PDB", name).into())
+            .ok_or_else(|| self.not_found(name).into())
+    }
+
+    pub fn not_found(&self, name: &str) -> LpusError {
+        // The error for a name missing from the PDB: "Symbol" or "Struct.Member"
+        // with an optional "module!" in front
+        let (module, bare_name) = split_module(name);
+        let structs = match self.tables(module) {
+            Ok((_, structs)) => structs,
+            Err(e) => return e,
+        };
+        let parts: Vec<&str> = bare_name.splitn(2, '.').collect();
+        match parts.as_slice() {
+            [struct_name, member] if structs.contains_key(*struct_name) => {
+                LpusError::MemberNotFound {
+                    struct_name: qualify(module, struct_name),
+                    member: member.to_string(),
+                }
+            }
+            [struct_name, _] => LpusError::StructNotFound(qualify(module, struct_name)),
+            _ => LpusError::SymbolNotFound(name.to_string()),
+        }
     }
     #[allow(dead_code)]
     pub fn get_offset(&self, name: &str) -> Option<u64> {
+        // "Struct.struct_size" is the size of the struct
+        let (module, name) = split_module(name);
+        let (symbols, structs) = self.tables(module).ok()?;
         if name.contains(".") {
             let v: Vec<&str> = name.split_terminator('.').collect();
-            match self.structs.get(v[0]) {
-                Some(member_info) => match member_info.get(v[1]) {
-                    Some((_memtype, offset)) => Some(*offset),
-                    None => None,
-                },
-                None => None,
+            let info = structs.get(v[0])?;
+            match v[1] {
+                "struct_size" => Some(info.size),
+                member => info.members.get(member).map(|(_, offset)| *offset),
             }
         } else {
-            match self.symbols.get(name) {
-                Some(offset) => Some(*offset),
-                None => None,
+            symbols.get(name).copied()
+        }
+    }
+
+    fn resolve_path(&self, full_name: &str) -> BoxResult<(Vec<PathOp>, TypeInfo)> {
+        // The offsets and dereferences to reach the end of a member path, and its type
+        // A "module!" prefix looks the types up in that module's PDB, the whole way down
+        let (module, full_name) = split_module(full_name);
+        let (_, structs) = self.tables(module)?;
+        let (struct_name, steps) = parse_path(full_name)?;
+
+        let mut ops = Vec::new();
+        let mut current: Option<TypeInfo> = None;
+        for step in &steps {
+            // A non-struct type is looked up by its name and reported as not found
+            let key = |t: &TypeInfo| t.record_name().map_or_else(|| t.to_string(), str::to_string);
+            let parent = match (&current, step.arrow) {
+                (None, false) => struct_name.to_string(),
+                // `.` after a pointer member follows it as well, as it always has
+                (Some(TypeInfo::Pointer(pointee)), _) => {
+                    ops.push(PathOp::Deref);
+                    key(pointee)
+                }
+                (Some(t), false) => key(t),
+                (t, true) => {
+                    let t = t.as_ref().map_or_else(|| struct_name.to_string(), key);
+                    return Err(format!("{} is not a pointer, cannot use ->{}", t, step.member)
+                        .into());
+                }
+            };
+            let info = structs
+                .get(&parent)
+                .ok_or_else(|| LpusError::StructNotFound(qualify(module, &parent)))?;
+            let (memtype, offset) =
+                info.members
+                    .get(step.member)
+                    .ok_or_else(|| LpusError::MemberNotFound {
+                        struct_name: qualify(module, &parent),
+                        member: step.member.to_string(),
+                    })?;
+            ops.push(PathOp::Add(*offset));
+            let mut memtype = memtype;
+
+            for index in &step.indices {
+                let (element, count) = memtype
+                    .array_element()
+                    .ok_or_else(|| format!("{}.{} is not an array", parent, step.member))?;
+                if *index >= count {
+                    return Err(LpusError::IndexOutOfBounds {
+                        name: qualify(module, &format!("{}.{}", parent, step.member)),
+                        index: *index,
+                        count,
+                    }
+                    .into());
+                }
+                ops.push(PathOp::Add(index * element.size()));
+                memtype = element;
             }
+            current = Some(memtype.clone());
         }
+        let member_type = current.ok_or("Not decomposable")?;
+        Ok((ops, member_type))
+    }
+
+    pub fn type_of(&self, full_name: &str) -> BoxResult<TypeInfo> {
+        // The type at the end of a member path, UChar[15] for _EPROCESS.ImageFileName
+        Ok(self.resolve_path(full_name)?.1)
+    }
+
+    pub fn array_info(&self, full_name: &str) -> BoxResult<Option<(u64, u64)>> {
+        // (element size, element count) of the array at the end of a member path
+        let member_type = self.type_of(full_name)?;
+        Ok(member_type
+            .array_element()
+            .map(|(element, count)| (element.size(), count)))
     }
 
     #[allow(dead_code)]
     pub fn addr_decompose(&self, addr: u64, full_name: &str) -> BoxResult<u64> {
-        if !full_name.contains(".") {
-            return Err("Not decomposable".into());
-        }
-
-        let mut name_part: Vec<&str> = full_name.split_terminator('.').collect();
-        let mut next: Vec<_> = name_part.drain(2..).collect();
-        match self.structs.get(name_part[0]) {
-            Some(member_info) => match member_info.get(name_part[1]) {
-                Some((memtype, offset)) => {
-                    if next.len() != 0 {
-                        if memtype.contains("*") {
-                            return Err(format!(
-                                "Cannot dereference pointer at {} {}",
-                                memtype, name_part[1]
-                            )
-                            .into());
-                        }
-                        next.insert(0, memtype);
-                        self.addr_decompose(addr + *offset, &next.join("."))
-                    } else {
-                        Ok(addr + *offset)
-                    }
+        let (ops, _) = self.resolve_path(full_name)?;
+        let mut addr = addr;
+        for op in ops {
+            match op {
+                PathOp::Add(offset) => addr += offset,
+                PathOp::Deref => {
+                    return Err(format!("Cannot dereference pointer in {}", full_name).into())
                 }
-                None => Err(format!("Not found member {}", name_part[1]).into()),
-            },
-            None => Err(format!("Struct {} not found", name_part[0]).into()),
+            }
         }
+        Ok(addr)
     }
 
     pub fn decompose(&self, source: &Address, full_name: &str) -> BoxResult<(Address, Box<dyn Fn(u64) -> u64>, u64)> {
         // Get the Address object for a field inside a struct
+        // The path may index arrays and follow pointers: _EPROCESS.Peb->Ldr, _X.Array[3]
         // If the field is a bit field:
         //  - The second value in the return tuple is a mask to get the exact bit(s)
         //  - The third value is the required length in BIT of the data type (e.g if we need 1 bit is at pos 17, the required len is 17)
-
-        if !full_name.contains(".") {
-            return Err("Not decomposable".into());
+        let (ops, memtype) = self.resolve_path(full_name)?;
+        let mut addr = source.clone();
+        for op in ops {
+            addr = match op {
+                PathOp::Add(offset) => addr + offset,
+                PathOp::Deref => Address::from_ptr(addr),
+            };
         }
 
-        let mut name_part: Vec<&str> = full_name.split_terminator('.').collect();
-        let mut next: Vec<_> = name_part.drain(2..).collect();
-        let member_info = self
-            .structs
-            .get(name_part[0])
-            .ok_or(format!("No struct {}", name_part[0]))?;
-        let (memtype, offset) = member_info
-            .get(name_part[1])
-            .ok_or(format!("No member {} in {}", name_part[1], name_part[0]))?;
-
-        if next.len() == 0 {
-            // Default mask, getting every bits.
-            let mut mask_handler = get_bit_mask_handler(0, 64);
-            let mut required_len = 0;
-            // ":" is my own sperator for bitfield type
-            if memtype.contains(":") {
-                let bit_parts: Vec<&str> = memtype.split(":").collect();
-                let bit_pos: u64 = bit_parts[1].parse().unwrap();
-                let bit_len: u64 = bit_parts[2].parse().unwrap();
-                mask_handler = get_bit_mask_handler(bit_pos, bit_len);
-                required_len = bit_pos + bit_len;
-                //println!("Pos: {}, Len: {}", bit_pos, bit_len);
-            }
-
-            return Ok((source.clone() + *offset, mask_handler, required_len));
-        }
-        if memtype.contains("*") {
-            let mut t = memtype.clone(); // remove *
-            t.pop();
-            next.insert(0, &t);
-            let p = Address::from_ptr(source.clone() + *offset);
-            self.decompose(&p, &next.join("."))
-        } else {
-            next.insert(0, memtype);
-            self.decompose(&(source.clone() + *offset), &next.join("."))
+        // Default mask, getting every bits.
+        let mut mask_handler = get_bit_mask_handler(0, 64);
+        let mut required_len = 0;
+        if let Some((bit_pos, bit_len)) = memtype.bitfield() {
+            mask_handler = get_bit_mask_handler(bit_pos, bit_len);
+            required_len = bit_pos + bit_len;
+        } else if memtype.is_scalar() && (1..=8).contains(&memtype.size()) {
+            // A primitive, pointer or enum is masked to its own size and needs a T that holds it
+            // Reading a U32 into a u64 does not take the next member along
+            mask_handler = get_bit_mask_handler(0, memtype.size() * 8);
+            required_len = memtype.size() * 8;
         }
+        Ok((addr, mask_handler, required_len))
     }
 
     #[allow(dead_code)]
@@ -221,48 +464,47 @@ impl PdbStore {
         need_structs.insert("_RTL_BITMAP_EX", vec![]); // windows insider, 2020
 
         for &symbol in &need_symbols {
-            match self.symbols.get(symbol) {
-                Some(offset) => println!("0x{:x} {}", offset, symbol),
-                None => {}
+            if let Some(offset) = self.symbols.get(symbol) {
+                println!("0x{:x} {}", offset, symbol);
             }
         }
 
         for (&struct_name, members) in &need_structs {
-            match self.structs.get(struct_name) {
-                Some(member_info) => {
-                    for &member in members {
-                        match member_info.get(member) {
-                            Some((memtype, offset)) => {
-                                println!("0x{:x} {} {}.{}", offset, memtype, struct_name, member)
-                            }
-                            None => {}
-                        }
+            if let Some(info) = self.structs.get(struct_name) {
+                for &member in members {
+                    if member == "struct_size" {
+                        println!("0x{:x} {}.{}", info.size, struct_name, member);
+                        continue;
+                    }
+                    if let Some((memtype, offset)) = info.members.get(member) {
+                        println!("0x{:x} {} {}.{}", offset, memtype, struct_name, member);
                     }
                 }
-                None => {}
             }
         }
     }
 
     pub fn dt(&self, struct_name: &str) -> BoxResult<()> {
-        let member_info = self
-            .structs
-            .get(struct_name)
-            .ok_or(format!("no struct named {}", struct_name))?;
-        let (_, struct_size) = member_info.get("struct_size").ok_or("")?;
-        println!("// 0x{:x} bytes", struct_size);
-        println!("struct {} {{", struct_name);
-
-        // Vec<(offset, type, name)>
-        let mut members: Vec<(u64, String, String)> = Vec::new();
-        for (name, (t, offset)) in member_info.iter() {
-            if name != "struct_size" {
-                members.push((*offset, t.to_string(), name.to_string()));
+        let (module, bare_name) = split_module(struct_name);
+        let (_, structs) = self.tables(module)?;
+        if !structs.contains_key(bare_name) {
+            if let Ok(info) = self.get_enum(struct_name) {
+                println!("enum {} {{", struct_name);
+                for (name, value) in &info.values {
+                    println!("  {} = 0x{:x};", name, value);
+                }
+                println!("}} // {}", struct_name);
+                return Ok(());
             }
         }
-        members.sort_by(|(o1, _, _), (o2, _, _)| o1.partial_cmp(o2).unwrap());
-
-        for (offset, memtype, member) in members.iter() {
+        let info = structs
+            .get(bare_name)
+            .ok_or_else(|| LpusError::StructNotFound(struct_name.to_string()))?;
+        let kind = if info.is_union { "union" } else { "struct" };
+        println!("// 0x{:x} bytes", info.size);
+        println!("{} {} {{", kind, struct_name);
+
+        for (member, memtype, offset) in info.sorted_members() {
             println!("  +0x{:x} {} {};", offset, memtype, member);
         }
 
@@ -271,197 +513,136 @@ impl PdbStore {
     }
 }
 
-fn get_type_as_str(type_finder: &TypeFinder, typ: &TypeIndex) -> String {
-    match type_finder.find(*typ).unwrap().parse().unwrap() {
-        TypeData::Class(ct) => format!("{}", ct.name.to_string()),
-        TypeData::Primitive(pt) => format!("{:?}", pt.kind),
-        TypeData::Pointer(pt) => format!("{}*", get_type_as_str(type_finder, &pt.underlying_type)),
-        TypeData::StaticMember(st) => {
-            format!("static {}", get_type_as_str(type_finder, &st.field_type))
+fn type_key(name: &str, index: TypeIndex) -> String {
+    // Anonymous types all have the same name, tell them apart with the type index.
+    // Unnamed types of a named member are called e.g. _MMPFN::<unnamed-type-u4> and unique
+    if name == "<unnamed-tag>" || name.starts_with("__unnamed") {
+        format!("{}#{}", name, index)
+    } else {
+        name.to_string()
+    }
+}
+
+fn get_fields<'t>(type_finder: &TypeFinder<'t>, fields: TypeIndex) -> Vec<TypeData<'t>> {
+    // The fields of a field list, following the continuation of a long list
+    let mut all_fields = Vec::new();
+    let mut next = Some(fields);
+    while let Some(index) = next {
+        let list = match type_finder.find(index).and_then(|t| t.parse()) {
+            Ok(TypeData::FieldList(list)) => list,
+            _ => break,
+        };
+        all_fields.extend(list.fields);
+        next = list.continuation;
+    }
+    all_fields
+}
+
+fn get_members<'t>(type_finder: &TypeFinder<'t>, fields: TypeIndex) -> Vec<MemberType<'t>> {
+    // The data members of a struct or a union
+    let mut members = Vec::new();
+    for field in get_fields(type_finder, fields) {
+        if let TypeData::Member(member) = field {
+            members.push(member);
         }
+    }
+    members
+}
+
+fn variant_value(value: &Variant) -> i64 {
+    match *value {
+        Variant::U8(v) => v as i64,
+        Variant::U16(v) => v as i64,
+        Variant::U32(v) => v as i64,
+        Variant::U64(v) => v as i64,
+        Variant::I8(v) => v as i64,
+        Variant::I16(v) => v as i64,
+        Variant::I32(v) => v as i64,
+        Variant::I64(v) => v,
+    }
+}
+
+fn get_type_info(
+    type_finder: &TypeFinder,
+    sizes: &HashMap<String, u64>,
+    typ: &TypeIndex,
+) -> TypeInfo {
+    // `sizes` has the struct and union sizes by key, a member refers to a forward reference
+    let type_data = match type_finder.find(*typ).and_then(|t| t.parse()) {
+        Ok(type_data) => type_data,
+        Err(_) => return TypeInfo::Unknown("UNKNOWN".to_string()),
+    };
+    let record = |name: String, size: u64| {
+        let size = sizes.get(&name).cloned().unwrap_or(size);
+        (name, size)
+    };
+    match type_data {
+        TypeData::Class(ct) => {
+            let (name, size) = record(type_key(&ct.name.to_string(), *typ), ct.size as u64);
+            TypeInfo::Struct { name, size }
+        }
+        TypeData::Union(ut) => {
+            let (name, size) = record(type_key(&ut.name.to_string(), *typ), ut.size as u64);
+            TypeInfo::Union { name, size }
+        }
+        TypeData::Enumeration(et) => TypeInfo::Enum {
+            name: type_key(&et.name.to_string(), *typ),
+            underlying: Box::new(get_type_info(type_finder, sizes, &et.underlying_type)),
+        },
+        TypeData::Primitive(pt) => {
+            let primitive = TypeInfo::primitive(&format!("{:?}", pt.kind));
+            match pt.indirection {
+                // e.g. a PVOID member is a primitive with a pointer indirection
+                Indirection::None => primitive,
+                _ => TypeInfo::pointer_to(primitive),
+            }
+        }
+        TypeData::Pointer(pt) => {
+            TypeInfo::pointer_to(get_type_info(type_finder, sizes, &pt.underlying_type))
+        }
+        TypeData::Modifier(mt) => get_type_info(type_finder, sizes, &mt.underlying_type),
         TypeData::Array(at) => {
-            format!(
-                "{}{:?}",
-                get_type_as_str(type_finder, &at.element_type),
-                /* get_type_as_str(type_finder, &at.indexing_type), */ at.dimensions
-            )
+            // The dimensions are sizes in bytes, from the inner dimension out
+            let mut t = get_type_info(type_finder, sizes, &at.element_type);
+            for dim in at.dimensions {
+                let count = (dim as u64).checked_div(t.size()).unwrap_or(0);
+                t = TypeInfo::Array {
+                    element: Box::new(t),
+                    count,
+                };
+            }
+            t
         }
-        // TypeData::Enumeration(et) => {
-        //     format!("enumeration")
-        // },
-        // TypeData::Enumerate(et) => {
-        //     format!("enumerate")
-        // },
-        // TypeData::MemberFunction(mft) => {
-        //     format!("member function")
-        // },
-        // TypeData::OverloadedMethod(ovmt) => {
-        //     format!("overloaded method")
-        // },
-        // TypeData::Nested(nt) => {
-        //     format!("nested")
-        // },
-        // TypeData::BaseClass(bct) => {
-        //     format!("base class")
-        // },
-        // TypeData::VirtualBaseClass(vbct) => {
-        //     format!("virtual base class")
-        // },
-        // TypeData::VirtualFunctionTablePointer(vftpt) => {
-        //     format!("virtual function table pointer")
-        // },
+        TypeData::Bitfield(bft) => TypeInfo::Bitfield {
+            underlying: Box::new(get_type_info(type_finder, sizes, &bft.underlying_type)),
+            position: bft.position as u64,
+            length: bft.length as u64,
+        },
         TypeData::Procedure(pt) => {
-            let rettype = match pt.return_type {
-                Some(rt) => get_type_as_str(type_finder, &rt),
-                _ => "UNKNOWN".to_string(),
+            let return_type = match pt.return_type {
+                Some(rt) => get_type_info(type_finder, sizes, &rt),
+                None => TypeInfo::primitive("Void"),
             };
-            format!(
-                "{}({})",
-                rettype,
-                get_type_as_str(type_finder, &pt.argument_list)
-            )
+            let arguments = match type_finder.find(pt.argument_list).and_then(|t| t.parse()) {
+                Ok(TypeData::ArgumentList(list)) => list
+                    .arguments
+                    .iter()
+                    .map(|arg| get_type_info(type_finder, sizes, arg))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            TypeInfo::Function {
+                return_type: Box::new(return_type),
+                arguments,
+            }
         }
-        TypeData::Modifier(mt) => match mt {
-            ModifierType {
-                constant: true,
-                volatile: true,
-                unaligned: true,
-                ..
-            } => format!(
-                "const volatile unaligned {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-            ModifierType {
-                constant: true,
-                volatile: true,
-                unaligned: false,
-                ..
-            } => format!(
-                "const volatile {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-            ModifierType {
-                constant: true,
-                volatile: false,
-                unaligned: true,
-                ..
-            } => format!(
-                "const unaligned {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-            ModifierType {
-                constant: false,
-                volatile: true,
-                unaligned: true,
-                ..
-            } => format!(
-                "volatile unaligned {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-            ModifierType {
-                constant: true,
-                volatile: false,
-                unaligned: false,
-                ..
-            } => format!(
-                "const {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-            ModifierType {
-                constant: false,
-                volatile: true,
-                unaligned: false,
-                ..
-            } => format!(
-                "volatile {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-            ModifierType {
-                constant: false,
-                volatile: false,
-                unaligned: true,
-                ..
-            } => format!(
-                "unaligned {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-            _ => format!(
-                "modifier {}",
-                get_type_as_str(type_finder, &mt.underlying_type)
-            ),
-        },
-        // TypeData::Union(ut) => {
-        //     format!("union")
-        // },
-        TypeData::Bitfield(bft) => {
-            format!("{}:{}:{}", get_type_as_str(type_finder, &bft.underlying_type), bft.position, bft.length)
-        },
-        TypeData::FieldList(_flt) => format!("fieldlist"),
-        // TypeData::ArgumentList(alt) => {
-        //     format!("arglist")
-        // },
-        // TypeData::MethodList(mlt) => {
-        //     format!("methodlist")
-        // },
         unk => match unk.name() {
-            Some(s) => format!("{}", s.to_string()),
-            _ => "UNNOWN".to_string(),
+            Some(s) => TypeInfo::Unknown(s.to_string().into()),
+            _ => TypeInfo::Unknown("UNKNOWN".to_string()),
         },
     }
 }
 
-fn get_guid_age(exe_file: &str) -> BoxResult<(String, u32)> {
-    // TODO: Check file existance
-    let mut file = File::open(exe_file)?;
-
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-
-    let mut buffiter = buffer.chunks(4);
-    while buffiter.next().unwrap() != [0x52, 0x53, 0x44, 0x53] {
-        // signature == RSDS
-    }
-
-    // next 16 bytes is guid in raw bytes
-    let raw_guid: Vec<u8> = vec![
-        buffiter.next().unwrap(),
-        buffiter.next().unwrap(),
-        buffiter.next().unwrap(),
-        buffiter.next().unwrap(),
-    ]
-    .concat();
-
-    // guid to hex string
-    let guid = (vec![
-        raw_guid[3],
-        raw_guid[2],
-        raw_guid[1],
-        raw_guid[0],
-        raw_guid[5],
-        raw_guid[4],
-        raw_guid[7],
-        raw_guid[6],
-        raw_guid[8],
-        raw_guid[9],
-        raw_guid[10],
-        raw_guid[11],
-        raw_guid[12],
-        raw_guid[13],
-        raw_guid[14],
-        raw_guid[15],
-    ]
-    .iter()
-    .map(|b| format!("{:02X}", b))
-    .collect::<Vec<String>>())
-    .join("");
-
-    // next 4 bytes is age, in little endian
-    let raw_age = buffiter.next().unwrap();
-    let age = u32::from_le_bytes([raw_age[0], raw_age[1], raw_age[2], raw_age[3]]);
-
-    Ok((guid, age))
-}
-
 fn pdb_exists(pdbname: &str, guid: &str, age: u32) -> BoxResult<(bool, PathBuf)> {
     // Use a folder at %APPDATA% to save pdb files
     // %APPDATA%\nganhkhoaa\lpus
@@ -480,30 +661,126 @@ fn pdb_exists(pdbname: &str, guid: &str, age: u32) -> BoxResult<(bool, PathBuf)>
     Ok((pdb_location.exists(), pdb_location))
 }
 
-fn download_pdb(pdbname: &str, guid: &str, age: u32, outfile: &PathBuf) -> BoxResult<()> {
+pub(crate) fn download_pdb(
+    server: &str,
+    pdbname: &str,
+    guid: &str,
+    age: u32,
+    outfile: &Path,
+) -> BoxResult<()> {
     let downloadurl = format!(
         "{}/{}/{}{:X}/{}",
-        PDB_SERVER_PATH, pdbname, guid, age, pdbname
+        server.trim_end_matches('/'), pdbname, guid, age, pdbname
     );
     println!("{}", downloadurl);
 
-    let mut resp = reqwest::blocking::get(&downloadurl)?;
-    let mut out = File::create(outfile)?;
-    io::copy(&mut resp, &mut out)?;
+    let download_failed = |reason: String| LpusError::DownloadFailed {
+        url: downloadurl.clone(),
+        reason,
+    };
+    fetch_pdb(&downloadurl, outfile).map_err(|reason| download_failed(reason).into())
+}
+
+#[cfg(windows)]
+fn fetch_pdb(url: &str, outfile: &Path) -> Result<(), String> {
+    let mut resp = reqwest::blocking::get(url).map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        // do not save the error page as the PDB
+        return Err(resp.status().to_string());
+    }
+    let mut out = File::create(outfile).map_err(|e| e.to_string())?;
+    io::copy(&mut resp, &mut out).map_err(|e| e.to_string())?;
     Ok(())
 }
 
+#[cfg(not(windows))]
+fn fetch_pdb(_url: &str, _outfile: &Path) -> Result<(), String> {
+    // The offline readers take their PDB from the symbol path or an ISF file
+    Err("downloading is only built on Windows, put the PDB in the symbol path".to_string())
+}
+
 pub fn parse_pdb() -> BoxResult<PdbStore> {
     // TODO: Resolve pdb name
     // ntoskrnl.exe -> ntkrnlmp.pdb
     // tcpip.sys -> tcpip.pdb ?????
     // There may be more pdb files in the future
-    let (guid, age) = get_guid_age(NTOSKRNL_PATH)?;
+    // _NT_SYMBOL_PATH, when set, is used instead of the lpus folder and msdl
+    if let Some(symbol_path) = SymbolPath::from_env() {
+        return parse_pdb_for_pe(Path::new(NTOSKRNL_PATH), &symbol_path?);
+    }
+    let CodeViewInfo { guid, age, .. } = codeview_from_file(Path::new(NTOSKRNL_PATH))?;
     let (exists, pdb_path) = pdb_exists(KERNEL_PDB_NAME, &guid, age)?;
     if !exists {
         println!("PDB not found, download into {:?}", pdb_path);
-        download_pdb(KERNEL_PDB_NAME, &guid, age, &pdb_path)?;
+        download_pdb(PDB_SERVER_PATH, KERNEL_PDB_NAME, &guid, age, &pdb_path)?;
+    }
+    parse_pdb_file(&pdb_path)
+}
+
+pub fn parse_pdb_for_pe(pe_path: &Path, symbol_path: &SymbolPath) -> BoxResult<PdbStore> {
+    // The PDB named in the PE debug record, e.g. ntoskrnl.exe from a memory image's system
+    let codeview = codeview_from_file(pe_path)?;
+    let pdb_path = symbol_path.find_pdb(&codeview.pdb_name, &codeview.guid, codeview.age)?;
+    parse_pdb_file(&pdb_path)
+}
+
+pub fn parse_pdb_file(pdb_path: &Path) -> BoxResult<PdbStore> {
+    // Parse a PDB file already on disk, e.g. the ntkrnlmp.pdb matching a memory image
+    let ModuleSymbols {
+        symbols,
+        index,
+        structs,
+        enums,
+        guid,
+        age,
+    } = parse_module_pdb(pdb_path)?;
+
+    let mut pdb_store = PdbStore {
+        symbols,
+        index,
+        structs,
+        enums,
+        guid,
+        age,
+        modules: HashMap::new(),
+    };
+    // Structs the PDB does not have, e.g. _UNLOADED_DRIVERS; those for a range of builds
+    // are applied once the reader knows the build
+    apply_overlays(&mut pdb_store, &default_overlays(), None)?;
+    Ok(pdb_store)
+}
+
+pub fn parse_module_pdb(pdb_path: &Path) -> BoxResult<ModuleSymbols> {
+    // Public symbols as RVAs and the struct layouts of any PDB, from the symbol cache
+    // next to it when there is one for its GUID and age
+    let (guid, age) = pdb_guid_age(pdb_path)?;
+    let cache = cache_path(pdb_path, &guid, age);
+    match load_symbol_cache(&cache, &guid, age) {
+        Ok(module) => {
+            println!("Symbol cache {:?}, guid: {}, age: {}\n", cache, guid, age);
+            return Ok(module);
+        }
+        Err(_) if !cache.exists() => {}
+        Err(e) => println!("Symbol cache {:?} is not used: {}", cache, e),
+    }
+    let module = parse_pdb_streams(pdb_path)?;
+    // a symbol store can be read-only, lpus then parses the PDB every time
+    if let Err(e) = save_symbol_cache(&cache, &module) {
+        println!("Cannot write the symbol cache {:?}: {}", cache, e);
     }
+    Ok(module)
+}
+
+fn pdb_guid_age(pdb_path: &Path) -> BoxResult<(String, u32)> {
+    // Only the PDB info and DBI streams, not the types
+    let mut pdb = PDB::open(File::open(pdb_path)?)?;
+    let guid = pdb.pdb_information()?.guid;
+    let age = pdb.debug_information()?.age().unwrap_or(0);
+    Ok((guid.to_string().replace("-", "").to_uppercase(), age))
+}
+
+fn parse_pdb_streams(pdb_path: &Path) -> BoxResult<ModuleSymbols> {
+    // Walks the whole type stream twice, seconds for ntkrnlmp.pdb
     let f = File::open(pdb_path)?;
     let mut pdb = PDB::open(f)?;
 
@@ -518,9 +795,20 @@ pub fn parse_pdb() -> BoxResult<PdbStore> {
 
     let type_information = pdb.type_information()?;
     let mut type_finder = type_information.type_finder();
+    // Sizes of the structs and unions by key, a forward reference has size 0
+    let mut sizes: HashMap<String, u64> = HashMap::new();
     let mut iter = type_information.iter();
-    while let Some(_typ) = iter.next().unwrap() {
+    while let Some(typ) = iter.next().unwrap() {
         type_finder.update(&iter);
+        match typ.parse() {
+            Ok(TypeData::Class(ct)) if !ct.properties.forward_reference() => {
+                sizes.insert(type_key(&ct.name.to_string(), typ.type_index()), ct.size as u64);
+            }
+            Ok(TypeData::Union(ut)) if !ut.properties.forward_reference() => {
+                sizes.insert(type_key(&ut.name.to_string(), typ.type_index()), ut.size as u64);
+            }
+            _ => {}
+        }
     }
 
     let mut symbol_extracted: SymbolStore = HashMap::new();
@@ -531,61 +819,113 @@ pub fn parse_pdb() -> BoxResult<PdbStore> {
         match symbol.parse() {
             Ok(SymbolData::PublicSymbol(data)) => {
                 let name = symbol.name().unwrap().to_string();
-                let Rva(rva) = data.offset.to_rva(&addr_map).unwrap_or_default();
-                symbol_extracted.insert(format!("{}", name), rva as u64);
+                // outside of every section there is no RVA, it is not indexed at 0
+                if let Some(Rva(rva)) = data.offset.to_rva(&addr_map) {
+                    symbol_extracted.insert(format!("{}", name), rva as u64);
+                }
             }
             _ => {}
         }
     }
 
+    // Functions with their length, only a private PDB has them in its module streams
+    let mut functions: Vec<(String, u64, u64)> = Vec::new();
+    let mut modules = dbi.modules()?;
+    while let Some(module) = modules.next()? {
+        let module_info = match pdb.module_info(&module) {
+            Ok(Some(module_info)) => module_info,
+            _ => continue,
+        };
+        let mut module_symbols = module_info.symbols()?;
+        while let Some(symbol) = module_symbols.next()? {
+            if let Ok(SymbolData::Procedure(procedure)) = symbol.parse() {
+                if let (Some(Rva(rva)), Ok(name)) =
+                    (procedure.offset.to_rva(&addr_map), symbol.name())
+                {
+                    let name = name.to_string().into();
+                    functions.push((name, rva as u64, procedure.len as u64));
+                }
+            }
+        }
+    }
+
     let mut struct_extracted: StructStore = HashMap::new();
+    let mut enum_extracted: EnumStore = HashMap::new();
+    // (struct, type of a member without a name, its offset), flattened in afterwards
+    let mut anonymous: Vec<(String, TypeInfo, u64)> = Vec::new();
     iter = type_information.iter();
     while let Some(typ) = iter.next().unwrap() {
-        match typ.parse() {
+        // Structs and unions are both recorded, the members of a union share offset 0
+        let (name, fields, size, is_union) = match typ.parse() {
             Ok(TypeData::Class(ClassType {
                 name,
                 fields: Some(fields),
                 size,
                 ..
-            })) => {
-                let mut struct_fields = HashMap::new();
-                struct_fields.insert("struct_size".to_string(), ("U32".to_string(), size as u64));
-                match type_finder.find(fields).unwrap().parse().unwrap() {
-                    TypeData::FieldList(list) => {
-                        for field in list.fields {
-                            if let TypeData::Member(member) = field {
-                                let mem_typ = get_type_as_str(&type_finder, &member.field_type);
-                                struct_fields.insert(
-                                    format!("{}", member.name),
-                                    (mem_typ, member.offset as u64),
-                                );
-                            }
-                        }
+            })) => (name, fields, size as u64, false),
+            Ok(TypeData::Union(UnionType {
+                name,
+                fields,
+                size,
+                properties,
+                ..
+            })) if !properties.forward_reference() => (name, fields, size as u64, true),
+            Ok(TypeData::Enumeration(et)) if !et.properties.forward_reference() => {
+                let underlying = get_type_info(&type_finder, &sizes, &et.underlying_type);
+                let mut info = EnumInfo::new(underlying);
+                for field in get_fields(&type_finder, et.fields) {
+                    if let TypeData::Enumerate(enumerate) = field {
+                        let value = variant_value(&enumerate.value);
+                        info.values.push((enumerate.name.to_string().into(), value));
                     }
-                    _ => {}
                 }
-                struct_extracted.insert(format!("{}", name), struct_fields);
+                enum_extracted.insert(type_key(&et.name.to_string(), typ.type_index()), info);
+                continue;
             }
-            _ => {}
+            _ => continue,
+        };
+        let key = type_key(&name.to_string(), typ.type_index());
+        let mut info = StructInfo::new(size, is_union);
+        for member in get_members(&type_finder, fields) {
+            let mem_typ = get_type_info(&type_finder, &sizes, &member.field_type);
+            if member.name.as_bytes().is_empty() {
+                anonymous.push((key.clone(), mem_typ, member.offset as u64));
+                continue;
+            }
+            info.add_member(&member.name.to_string(), mem_typ, member.offset as u64);
         }
+        struct_extracted.insert(key, info);
     }
 
-    {
-        // https://github.com/Zer0Mem0ry/ntoskrnl/blob/master/Include/mm.h#L1107
-        let mut unload_driver_member = HashMap::new();
-        unload_driver_member.insert("struct_size".to_string(), ("U32".to_string(), 0x30));
-        unload_driver_member.insert("Name".to_string(), ("_UNICODE_STRING".to_string(), 0));
-        unload_driver_member.insert("StartAddress".to_string(), ("PVOID".to_string(), 0x10));
-        unload_driver_member.insert("EndAddress".to_string(), ("PVOID".to_string(), 0x18));
-        unload_driver_member.insert(
-            "CurrentTime".to_string(),
-            ("_LARGE_INTEGER".to_string(), 0x20),
-        );
-        struct_extracted.insert("_UNLOADED_DRIVERS".to_string(), unload_driver_member);
+    // Members of an anonymous struct or union are reached from the parent, as WinDbg
+    // shows them. Repeated for anonymous members nested in anonymous members
+    for _ in 0..MAX_ANONYMOUS_DEPTH {
+        for (parent, member_type, base) in &anonymous {
+            let inner: Vec<(String, (TypeInfo, u64))> = match member_type
+                .record_name()
+                .and_then(|name| struct_extracted.get(name))
+            {
+                Some(info) => info
+                    .members
+                    .iter()
+                    .map(|(name, (t, offset))| (name.clone(), (t.clone(), base + offset)))
+                    .collect(),
+                None => continue,
+            };
+            if let Some(parent_info) = struct_extracted.get_mut(parent) {
+                for (name, member) in inner {
+                    parent_info.members.entry(name).or_insert(member);
+                }
+            }
+        }
     }
 
-    Ok(PdbStore {
+    Ok(ModuleSymbols {
+        index: SymbolIndex::new(&symbol_extracted, functions),
         symbols: symbol_extracted,
         structs: struct_extracted,
+        enums: enum_extracted,
+        guid: info.guid.to_string().replace("-", "").to_uppercase(),
+        age: dbi.age().unwrap_or(0),
     })
 }
@@ -0,0 +1,398 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::address::Address;
+use crate::memory::{MemoryReader, MemoryReaderExt};
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// https://docs.microsoft.com/en-us/windows/win32/debug/pe-format
+const DOS_SIGNATURE: &[u8] = b"MZ";
+const PE_SIGNATURE: &[u8] = b"PE\0\0";
+const E_LFANEW_OFFSET: u64 = 0x3c;
+const COFF_HEADER_SIZE: u64 = 20;
+const OPTIONAL_HEADER_MAGIC_PE32: u16 = 0x10b;
+const OPTIONAL_HEADER_MAGIC_PE32_PLUS: u16 = 0x20b;
+const SIZE_OF_IMAGE_OFFSET: usize = 56;
+const SECTION_HEADER_SIZE: u64 = 40;
+const EXPORT_DIRECTORY_INDEX: u32 = 0;
+const EXPORT_NAME_OFFSET: u64 = 0xc;
+const MAX_EXPORT_NAME_SIZE: usize = 32;
+const DEBUG_DIRECTORY_INDEX: u32 = 6;
+const DEBUG_DIRECTORY_ENTRY_SIZE: u64 = 28;
+const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+// Limits on sizes read from the headers, a broken image can claim anything
+const MAX_DEBUG_DIRECTORY_SIZE: u64 = DEBUG_DIRECTORY_ENTRY_SIZE * 64;
+// A CodeView record is a signature, a GUID or a timestamp, the age and a path
+const MAX_CODEVIEW_SIZE: u32 = 0x1000;
+
+// The PDB a PE image was built with, as the symbol server files it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeViewInfo {
+    pub pdb_name: String,
+    // uppercase hex without dashes
+    pub guid: String,
+    pub age: u32,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> BoxResult<u16> {
+    buf.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "Truncated PE header".into())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> BoxResult<u32> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "Truncated PE header".into())
+}
+
+fn guid_string(raw: &[u8]) -> String {
+    // The first three fields of a GUID are little endian, the last 8 bytes are as is
+    let order = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+    order.iter().map(|&i| format!("{:02X}", raw[i])).collect()
+}
+
+fn parse_codeview_record(record: &[u8]) -> BoxResult<CodeViewInfo> {
+    // RSDS: GUID, age, path. NB10 (older linkers): offset, timestamp, age, path
+    let (guid, age, path) = match record.get(0..4) {
+        Some(b"RSDS") if record.len() >= 24 => (
+            guid_string(&record[4..20]),
+            u32_at(record, 20)?,
+            &record[24..],
+        ),
+        Some(b"NB10") if record.len() >= 16 => (
+            format!("{:08X}", u32_at(record, 8)?),
+            u32_at(record, 12)?,
+            &record[16..],
+        ),
+        _ => return Err("Unknown CodeView record".into()),
+    };
+    // only the file name of the path the PDB was built to is looked up
+    let path: Vec<u8> = path.iter().take_while(|&&b| b != 0).cloned().collect();
+    let path = String::from_utf8_lossy(&path).to_string();
+    match path.rsplit(['\\', '/']).next() {
+        Some(name) if !name.is_empty() => Ok(CodeViewInfo {
+            pdb_name: name.to_string(),
+            guid,
+            age,
+        }),
+        _ => Err("No PDB name in the CodeView record".into()),
+    }
+}
+
+// The headers every lookup in the image starts from
+struct PeHeaders {
+    optional_header: Vec<u8>,
+    // offset of the data directories in the optional header
+    directories: usize,
+    sections: Vec<u8>,
+}
+
+impl PeHeaders {
+    fn directory(&self, index: u32) -> BoxResult<Option<(u32, u32)>> {
+        // (RVA, size) of a data directory, None when the image has none
+        let number_of_directories = u32_at(&self.optional_header, self.directories - 4)?;
+        if number_of_directories <= index {
+            return Ok(None);
+        }
+        let entry = self.directories + index as usize * 8;
+        let rva = u32_at(&self.optional_header, entry)?;
+        let size = u32_at(&self.optional_header, entry + 4)?;
+        if rva == 0 || size == 0 {
+            return Ok(None);
+        }
+        Ok(Some((rva, size)))
+    }
+}
+
+// Reads a PE image through `read(offset, len)`: file offsets for a file on disk,
+// RVAs for an image loaded in memory (`mapped`)
+struct PeReader<F: FnMut(u64, usize) -> BoxResult<Vec<u8>>> {
+    read: F,
+    mapped: bool,
+}
+
+impl<F: FnMut(u64, usize) -> BoxResult<Vec<u8>>> PeReader<F> {
+    fn read(&mut self, offset: u64, len: usize) -> BoxResult<Vec<u8>> {
+        let buf = (self.read)(offset, len)?;
+        if buf.len() < len {
+            return Err(format!("Truncated PE image at 0x{:x}", offset).into());
+        }
+        Ok(buf)
+    }
+
+    fn rva_to_offset(&self, rva: u32, headers: &PeHeaders) -> BoxResult<u64> {
+        // A loaded image is laid out by RVA, a file by the sections' raw data
+        if self.mapped {
+            return Ok(rva as u64);
+        }
+        for section in headers.sections.chunks(SECTION_HEADER_SIZE as usize) {
+            let virtual_size = u32_at(section, 8)?;
+            let virtual_address = u32_at(section, 12)?;
+            let raw_size = u32_at(section, 16)?;
+            let raw_pointer = u32_at(section, 20)?;
+            let size = virtual_size.max(raw_size);
+            if rva >= virtual_address && rva < virtual_address.saturating_add(size) {
+                return Ok((rva - virtual_address) as u64 + raw_pointer as u64);
+            }
+        }
+        Err(format!("RVA 0x{:x} is in no section", rva).into())
+    }
+
+    fn headers(&mut self) -> BoxResult<PeHeaders> {
+        let dos_header = self.read(0, 0x40)?;
+        if &dos_header[0..2] != DOS_SIGNATURE {
+            return Err("Not a PE image: no MZ signature".into());
+        }
+        let e_lfanew = u32_at(&dos_header, E_LFANEW_OFFSET as usize)? as u64;
+
+        let headers = self.read(e_lfanew, (4 + COFF_HEADER_SIZE) as usize)?;
+        if &headers[0..4] != PE_SIGNATURE {
+            return Err("Not a PE image: no PE signature".into());
+        }
+        let number_of_sections = u16_at(&headers, 4 + 2)? as u64;
+        let optional_header_size = u16_at(&headers, 4 + 16)? as u64;
+
+        let optional_header_offset = e_lfanew + 4 + COFF_HEADER_SIZE;
+        let optional_header = self.read(optional_header_offset, optional_header_size as usize)?;
+        // The data directories follow the fields that differ between 32 and 64 bit
+        let directories = match u16_at(&optional_header, 0)? {
+            OPTIONAL_HEADER_MAGIC_PE32 => 96,
+            OPTIONAL_HEADER_MAGIC_PE32_PLUS => 112,
+            magic => return Err(format!("Unknown optional header magic 0x{:x}", magic).into()),
+        };
+        let sections = self.read(
+            optional_header_offset + optional_header_size,
+            (number_of_sections * SECTION_HEADER_SIZE) as usize,
+        )?;
+        Ok(PeHeaders {
+            optional_header,
+            directories,
+            sections,
+        })
+    }
+
+    fn image_name(&mut self) -> BoxResult<(String, u32)> {
+        // The name in the export directory and SizeOfImage, the same for PE32 and PE32+
+        let headers = self.headers()?;
+        let size_of_image = u32_at(&headers.optional_header, SIZE_OF_IMAGE_OFFSET)?;
+        let (export_rva, _) = headers
+            .directory(EXPORT_DIRECTORY_INDEX)?
+            .ok_or("No export directory in the PE image")?;
+        let export_offset = self.rva_to_offset(export_rva, &headers)?;
+        let name_rva = u32_at(&self.read(export_offset + EXPORT_NAME_OFFSET, 4)?, 0)?;
+        let name_offset = self.rva_to_offset(name_rva, &headers)?;
+        let name = self.read(name_offset, MAX_EXPORT_NAME_SIZE)?;
+        let name = name.split(|&c| c == 0).next().unwrap_or(&[]);
+        Ok((String::from_utf8_lossy(name).to_string(), size_of_image))
+    }
+
+    fn codeview(&mut self) -> BoxResult<CodeViewInfo> {
+        let headers = self.headers()?;
+        let (debug_rva, debug_size) = headers
+            .directory(DEBUG_DIRECTORY_INDEX)?
+            .ok_or("No debug directory in the PE image")?;
+        let debug_size = (debug_size as u64).min(MAX_DEBUG_DIRECTORY_SIZE);
+        let debug_offset = self.rva_to_offset(debug_rva, &headers)?;
+        let debug_directory = self.read(debug_offset, debug_size as usize)?;
+        for entry in debug_directory.chunks_exact(DEBUG_DIRECTORY_ENTRY_SIZE as usize) {
+            if u32_at(entry, 12)? != IMAGE_DEBUG_TYPE_CODEVIEW {
+                continue;
+            }
+            let size = u32_at(entry, 16)?.min(MAX_CODEVIEW_SIZE);
+            // AddressOfRawData in memory, PointerToRawData in the file
+            let offset = if self.mapped {
+                u32_at(entry, 20)? as u64
+            } else {
+                u32_at(entry, 24)? as u64
+            };
+            if offset == 0 {
+                continue;
+            }
+            let record = self.read(offset, size as usize)?;
+            return parse_codeview_record(&record);
+        }
+        Err("No CodeView entry in the debug directory".into())
+    }
+}
+
+pub fn codeview_from_bytes(image: &[u8], mapped: bool) -> BoxResult<CodeViewInfo> {
+    // A whole PE file (`mapped` false) or a dump of a loaded image (`mapped` true)
+    let read = |offset: u64, len: usize| -> BoxResult<Vec<u8>> {
+        let start = (offset as usize).min(image.len());
+        let end = start.saturating_add(len).min(image.len());
+        Ok(image[start..end].to_vec())
+    };
+    PeReader { read, mapped }.codeview()
+}
+
+pub fn codeview_from_file(path: &Path) -> BoxResult<CodeViewInfo> {
+    // Only the headers and the debug record are read, not the whole file
+    let mut file = File::open(path)?;
+    let read = |offset: u64, len: usize| -> BoxResult<Vec<u8>> {
+        let mut buf = Vec::with_capacity(len);
+        file.seek(SeekFrom::Start(offset))?;
+        (&mut file).take(len as u64).read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    PeReader {
+        read,
+        mapped: false,
+    }
+    .codeview()
+    .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+pub fn codeview_from_memory(driver: &dyn MemoryReader, dllbase: u64) -> BoxResult<CodeViewInfo> {
+    // A module loaded at `dllbase`, e.g. the DllBase of its _LDR_DATA_TABLE_ENTRY
+    let read = |offset: u64, len: usize| -> BoxResult<Vec<u8>> {
+        Ok(driver.deref_array::<u8>(&Address::from_base(dllbase + offset), len as u64)?)
+    };
+    PeReader { read, mapped: true }
+        .codeview()
+        .map_err(|e| format!("Image at 0x{:x}: {}", dllbase, e).into())
+}
+
+pub fn image_name_from_memory(driver: &dyn MemoryReader, base: u64) -> BoxResult<(String, u32)> {
+    // The export name and SizeOfImage of the image loaded at `base`, e.g. ntoskrnl.exe
+    let read = |offset: u64, len: usize| -> BoxResult<Vec<u8>> {
+        Ok(driver.deref_array::<u8>(&Address::from_base(base + offset), len as u64)?)
+    };
+    PeReader { read, mapped: true }.image_name()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONAL_HEADER: usize = 0x58;
+    const DEBUG_DIRECTORY: usize = OPTIONAL_HEADER + 112 + DEBUG_DIRECTORY_INDEX as usize * 8;
+    // .rdata is at RVA 0x2000 and at 0x400 in the file
+    const SECTION_RVA: u32 = 0x2000;
+    const SECTION_RAW: u32 = 0x400;
+    const GUID: &str = "3844DBB920174967BE7AA4A2C20430FA";
+
+    fn put(image: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if image.len() < offset + bytes.len() {
+            image.resize(offset + bytes.len(), 0);
+        }
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn image(record: &[u8], mapped: bool) -> Vec<u8> {
+        // A PE32+ with one section holding the debug directory then the CodeView record
+        let mut image = Vec::new();
+        put(&mut image, 0, DOS_SIGNATURE);
+        put(&mut image, E_LFANEW_OFFSET as usize, &0x40u32.to_le_bytes());
+        put(&mut image, 0x40, PE_SIGNATURE);
+        put(&mut image, 0x40 + 4 + 2, &1u16.to_le_bytes());
+        put(&mut image, 0x40 + 4 + 16, &0xf0u16.to_le_bytes());
+        put(
+            &mut image,
+            OPTIONAL_HEADER,
+            &OPTIONAL_HEADER_MAGIC_PE32_PLUS.to_le_bytes(),
+        );
+        put(&mut image, OPTIONAL_HEADER + 108, &16u32.to_le_bytes());
+        put(&mut image, DEBUG_DIRECTORY, &SECTION_RVA.to_le_bytes());
+        put(
+            &mut image,
+            DEBUG_DIRECTORY + 4,
+            &(DEBUG_DIRECTORY_ENTRY_SIZE as u32).to_le_bytes(),
+        );
+
+        let section = OPTIONAL_HEADER + 0xf0;
+        put(&mut image, section, b".rdata\0\0");
+        put(&mut image, section + 8, &0x1000u32.to_le_bytes());
+        put(&mut image, section + 12, &SECTION_RVA.to_le_bytes());
+        put(&mut image, section + 16, &0x200u32.to_le_bytes());
+        put(&mut image, section + 20, &SECTION_RAW.to_le_bytes());
+
+        let base = if mapped { SECTION_RVA } else { SECTION_RAW } as usize;
+        let mut entry = vec![0u8; DEBUG_DIRECTORY_ENTRY_SIZE as usize];
+        put(&mut entry, 12, &IMAGE_DEBUG_TYPE_CODEVIEW.to_le_bytes());
+        put(&mut entry, 16, &(record.len() as u32).to_le_bytes());
+        put(&mut entry, 20, &(SECTION_RVA + 0x40).to_le_bytes());
+        put(&mut entry, 24, &(SECTION_RAW + 0x40).to_le_bytes());
+        put(&mut image, base, &entry);
+        put(&mut image, base + 0x40, record);
+        image
+    }
+
+    fn rsds() -> Vec<u8> {
+        let mut record = b"RSDS".to_vec();
+        record.extend_from_slice(&[
+            0xb9, 0xdb, 0x44, 0x38, 0x17, 0x20, 0x67, 0x49, 0xbe, 0x7a, 0xa4, 0xa2, 0xc2, 0x04,
+            0x30, 0xfa,
+        ]);
+        record.extend_from_slice(&2u32.to_le_bytes());
+        record.extend_from_slice(b"d:\\os\\obj\\amd64fre\\ntkrnlmp.pdb\0");
+        record
+    }
+
+    #[test]
+    fn rsds_record() {
+        let expected = CodeViewInfo {
+            pdb_name: "ntkrnlmp.pdb".to_string(),
+            guid: GUID.to_string(),
+            age: 2,
+        };
+        for &mapped in [true, false].iter() {
+            let image = image(&rsds(), mapped);
+            assert_eq!(codeview_from_bytes(&image, mapped).unwrap(), expected);
+            // the other layout reads the wrong place
+            assert!(codeview_from_bytes(&image, !mapped).is_err());
+        }
+    }
+
+    #[test]
+    fn nb10_record() {
+        let mut record = b"NB10".to_vec();
+        record.extend_from_slice(&0u32.to_le_bytes());
+        record.extend_from_slice(&0x4ce7_951au32.to_le_bytes());
+        record.extend_from_slice(&1u32.to_le_bytes());
+        record.extend_from_slice(b"tcpip.pdb\0");
+        let expected = CodeViewInfo {
+            pdb_name: "tcpip.pdb".to_string(),
+            guid: "4CE7951A".to_string(),
+            age: 1,
+        };
+        let image = image(&record, false);
+        assert_eq!(codeview_from_bytes(&image, false).unwrap(), expected);
+    }
+
+    #[test]
+    fn truncated_image() {
+        let image = image(&rsds(), false);
+        // in the DOS header, the PE header, the optional header, the section table,
+        // the debug directory and the CodeView record
+        for &len in [0x30, 0x50, 0x100, 0x160, 0x410, 0x450].iter() {
+            assert!(
+                codeview_from_bytes(&image[..len], false).is_err(),
+                "0x{:x}",
+                len
+            );
+        }
+        let mut bad_signature = image.clone();
+        put(&mut bad_signature, 0x40, b"NE\0\0");
+        assert!(codeview_from_bytes(&bad_signature, false).is_err());
+        let mut bad_record = image;
+        put(&mut bad_record, SECTION_RAW as usize + 0x40, b"XXXX");
+        assert!(codeview_from_bytes(&bad_record, false).is_err());
+    }
+
+    #[test]
+    fn no_debug_directory() {
+        let mut image = image(&rsds(), true);
+        put(&mut image, DEBUG_DIRECTORY, &[0; 8]);
+        let error = codeview_from_bytes(&image, true).unwrap_err();
+        assert_eq!(error.to_string(), "No debug directory in the PE image");
+
+        // fewer data directories than the debug directory index
+        let mut image = self::image(&rsds(), true);
+        put(&mut image, OPTIONAL_HEADER + 108, &4u32.to_le_bytes());
+        assert!(codeview_from_bytes(&image, true).is_err());
+    }
+}
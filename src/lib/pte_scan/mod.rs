@@ -5,20 +5,20 @@ use std::error::Error;
 
 use paging_structs::*;
 use paging_traverse::*;
-use crate::driver_state::DriverState;
+use crate::memory::MemoryReader;
 
 type BoxResult<T> = Result<T, Box<dyn Error>>;
 
 
-pub fn is_rwx_page(driver: &DriverState, pte: &PTE) -> bool {
-    return pte.is_executable(&driver).unwrap() && pte.is_writable(&driver).unwrap()
+pub fn is_rwx_page(driver: &dyn MemoryReader, pte: &PTE) -> bool {
+    return pte.is_executable(driver).unwrap() && pte.is_writable(driver).unwrap()
 }
 
-pub fn is_shared_exec_page(driver: &DriverState, pte: &PTE, pfn_entry: &MMPFN) -> bool {
+pub fn is_shared_exec_page(driver: &dyn MemoryReader, pte: &PTE, pfn_entry: &MMPFN) -> bool {
     return pte.is_executable(driver).unwrap() && !pfn_entry.is_shared_mem(driver).unwrap();
 }
 
-pub fn scan_rwx_pages(driver: &DriverState, cr3: u64) -> BoxResult<Vec<PTE>>{
+pub fn scan_rwx_pages(driver: &dyn MemoryReader, cr3: u64) -> BoxResult<Vec<PTE>>{
     let mut result: Vec<PTE> = Vec::new(); 
     let pte_table = list_all_pte(driver, cr3);
 
@@ -32,7 +32,7 @@ pub fn scan_rwx_pages(driver: &DriverState, cr3: u64) -> BoxResult<Vec<PTE>>{
     Ok(result)
 }
 
-pub fn scan_private_exec_pages(driver: &DriverState, cr3: u64) -> BoxResult<Vec<PTE>>{
+pub fn scan_private_exec_pages(driver: &dyn MemoryReader, cr3: u64) -> BoxResult<Vec<PTE>>{
     let mut result: Vec<PTE> = Vec::new();
     let pte_table = list_all_pte(driver, cr3);
     for pte in pte_table {
@@ -50,7 +50,7 @@ pub fn scan_private_exec_pages(driver: &DriverState, cr3: u64) -> BoxResult<Vec<
     Ok(result)
 }
 
-pub fn scan_injected_pages(driver: &DriverState, cr3: u64) -> BoxResult<Vec<PTE>> {
+pub fn scan_injected_pages(driver: &dyn MemoryReader, cr3: u64) -> BoxResult<Vec<PTE>> {
     // Scanning using both criteria
     let mut result: Vec<PTE> = Vec::new();
     let pte_table = list_all_pte(driver, cr3);
@@ -1,8 +1,7 @@
 use std::error::Error;
 use  std::convert::From;
 use crate::address::Address;
-use crate::driver_state::*;
-use crate::utils::mask_cast::get_bit_mask_handler;
+use crate::memory::{MemoryReader, MemoryReaderExt};
 
 // Ref: https://back.engineering/23/08/2020/
 // Ref: https://blog.efiens.com/post/luibo/address-translation-revisited/
@@ -75,7 +74,7 @@ pub struct PTE {
 
 impl PTE {
 
-    pub fn from_addr(driver: &DriverState, addr: u64) -> Self {
+    pub fn from_addr(driver: &dyn MemoryReader, addr: u64) -> Self {
         let addr_obj = Address::from_base(addr);
         // let is_hardware: u64 = driver.decompose_physical(&addr_obj, "_MMPTE_HARDWARE.Valid").unwrap();
         // if is_hardware != 0 {
@@ -93,20 +92,20 @@ impl PTE {
         // }
         // return Self{state: PageState::PAGEFILE, address: addr_obj};
         let pte_value = driver.deref_physical_addr(addr);
-        let (offset, hardware_handler, _) = driver.pdb_store.decompose(&addr_obj, "_MMPTE_HARDWARE.Valid").unwrap();
+        let (offset, hardware_handler, _) = driver.pdb_store().decompose(&addr_obj, "_MMPTE_HARDWARE.Valid").unwrap();
         assert!(offset.address() == addr_obj.address(), "Fault in decomposing PTE");
         
         let is_hardware = hardware_handler(pte_value);
         if is_hardware != 0 {
             return Self{state: PageState::HARDWARE, address: addr_obj, value: pte_value};
         }
-        let (_, prototype_handler, _) = driver.pdb_store.decompose(&addr_obj, "_MMPTE_PROTOTYPE.Prototype").unwrap();
+        let (_, prototype_handler, _) = driver.pdb_store().decompose(&addr_obj, "_MMPTE_PROTOTYPE.Prototype").unwrap();
         let is_prototype = prototype_handler(pte_value);
         if is_prototype != 0 {
             return Self{state: PageState::PROTOTYPE, address: addr_obj, value: pte_value};
         }
 
-        let (_, trans_handler, _) = driver.pdb_store.decompose(&addr_obj, "_MMPTE_TRANSITION.Transition").unwrap();
+        let (_, trans_handler, _) = driver.pdb_store().decompose(&addr_obj, "_MMPTE_TRANSITION.Transition").unwrap();
         let is_transition = trans_handler(pte_value);
         if is_transition != 0 {
             return Self{state: PageState::TRANSITION, address: addr_obj, value: pte_value};
@@ -115,21 +114,28 @@ impl PTE {
 
     }
 
-    pub fn from_value(driver: &DriverState, pte_value: u64) -> Self {
+    pub fn from_entry(driver: &dyn MemoryReader, addr: u64, pte_value: u64) -> Self {
+        // An entry already read, e.g. from a whole table
+        let mut pte = Self::from_value(driver, pte_value);
+        pte.address = Address::from_base(addr);
+        pte
+    }
+
+    pub fn from_value(driver: &dyn MemoryReader, pte_value: u64) -> Self {
         let addr_obj = Address::from_base(0);
-        let (offset, hardware_handler, _) = driver.pdb_store.decompose(&addr_obj, "_MMPTE_HARDWARE.Valid").unwrap();
+        let (offset, hardware_handler, _) = driver.pdb_store().decompose(&addr_obj, "_MMPTE_HARDWARE.Valid").unwrap();
         
         let is_hardware = hardware_handler(pte_value);
         if is_hardware != 0 {
             return Self{state: PageState::HARDWARE, address: addr_obj, value: pte_value};
         }
-        let (_, prototype_handler, _) = driver.pdb_store.decompose(&addr_obj, "_MMPTE_PROTOTYPE.Prototype").unwrap();
+        let (_, prototype_handler, _) = driver.pdb_store().decompose(&addr_obj, "_MMPTE_PROTOTYPE.Prototype").unwrap();
         let is_prototype = prototype_handler(pte_value);
         if is_prototype != 0 {
             return Self{state: PageState::PROTOTYPE, address: addr_obj, value: pte_value};
         }
 
-        let (_, trans_handler, _) = driver.pdb_store.decompose(&addr_obj, "_MMPTE_TRANSITION.Transition").unwrap();
+        let (_, trans_handler, _) = driver.pdb_store().decompose(&addr_obj, "_MMPTE_TRANSITION.Transition").unwrap();
         let is_transition = trans_handler(pte_value);
         if is_transition != 0 {
             return Self{state: PageState::TRANSITION, address: addr_obj, value: pte_value};
@@ -137,8 +143,8 @@ impl PTE {
         return Self{state: PageState::PAGEFILE, address: addr_obj, value: pte_value};
     }
 
-    pub fn get_pte_field(&self, driver: &DriverState, name: &str) -> u64 {
-        let (addr, handler, len) = driver.pdb_store.decompose(&self.address, name).unwrap();
+    pub fn get_pte_field(&self, driver: &dyn MemoryReader, name: &str) -> u64 {
+        let (addr, handler, len) = driver.pdb_store().decompose(&self.address, name).unwrap();
         handler(self.value)
     }
 
@@ -146,12 +152,12 @@ impl PTE {
         return self.state == PageState::HARDWARE;
     }
 
-    // pub fn test_present_exact(&self, driver: &DriverState) -> bool {
+    // pub fn test_present_exact(&self, driver: &dyn MemoryReader) -> bool {
     //     let is_hardware: u64 = driver.decompose_physical(&self.address, "_MMPTE_HARDWARE.Valid").unwrap();
     //     return is_hardware != 0;
     // }
 
-    pub fn get_pfn(&self, driver: &DriverState) -> BoxResult<u64> {
+    pub fn get_pfn(&self, driver: &dyn MemoryReader) -> BoxResult<u64> {
         if self.state == PageState::HARDWARE {
             // let pfn: u64 = driver.decompose_physical(&self.address, "_MMPTE_HARDWARE.PageFrameNumber").unwrap();
             let pfn = self.get_pte_field(driver, "_MMPTE_HARDWARE.PageFrameNumber");
@@ -165,7 +171,7 @@ impl PTE {
         }
     }
 
-    pub fn is_executable(&self, driver: &DriverState) -> BoxResult<bool> {
+    pub fn is_executable(&self, driver: &dyn MemoryReader) -> BoxResult<bool> {
         // Following: https://github.com/f-block/volatility-plugins/blob/main/ptenum.py
         if self.state == PageState::HARDWARE {
             // let nx_bit: u64 = driver.decompose_physical(&self.address, "_MMPTE_HARDWARE.NoExecute")?;
@@ -215,7 +221,7 @@ impl PTE {
         return Err("Executable page test is not implemented for this state".into())
     }
 
-    pub fn is_writable(&self, driver: &DriverState) -> BoxResult<bool> {
+    pub fn is_writable(&self, driver: &dyn MemoryReader) -> BoxResult<bool> {
         // Get the write access right similar to the way we get the exec right
         if self.state == PageState::HARDWARE {
             // let write_bit: u64 = driver.decompose_physical(&self.address, "_MMPTE_HARDWARE.Write").unwrap();
@@ -274,7 +280,7 @@ impl PTE {
         return Err("Writable page test is not implemented for this state".into())
     }
 
-    pub fn is_large_page(&self, driver: &DriverState) -> BoxResult<bool> {
+    pub fn is_large_page(&self, driver: &dyn MemoryReader) -> BoxResult<bool> {
         if self.state == PageState::HARDWARE {
             // let large_page_bit: u64 = driver.decompose_physical(&self.address, "_MMPTE_HARDWARE.LargePage").unwrap();
             let large_page_bit = self.get_pte_field(driver, "_MMPTE_HARDWARE.LargePage");
@@ -292,22 +298,17 @@ pub struct MMPFN {
 }
 
 impl MMPFN {
-    pub fn new(driver: &DriverState, index: u64) -> Self {
+    pub fn new(driver: &dyn MemoryReader, index: u64) -> Self {
         let kernel_base = driver.get_kernel_base();
-        let pfn_symbol = kernel_base + driver.pdb_store.get_offset_r("MmPfnDatabase").unwrap();
+        let pfn_symbol = kernel_base + driver.pdb_store().get_offset_r("MmPfnDatabase").unwrap();
         let pfn_db_base: u64 = driver.deref_addr_new(pfn_symbol.address());
-        let pfn_entry_size = driver.pdb_store.get_offset_r("_MMPFN.struct_size").unwrap();
+        let pfn_entry_size = driver.pdb_store().get_offset_r("_MMPFN.struct_size").unwrap();
         let entry_address = pfn_db_base + index * pfn_entry_size; 
         Self { index: index, address: Address::from_base(entry_address) }
     }
 
-    pub fn is_shared_mem(&self, driver: &DriverState) -> BoxResult<bool> {
-        // Getting _MMPFN.u4.PrototypePte
-        // TODO: add union support for decompose 
-        let u4_union: u64 = driver.decompose(&self.address, "_MMPFN.u4")?;
-        // According to windbg
-        let handler = get_bit_mask_handler(57, 1);
-        let prototype_pte_bit = handler(u4_union);
+    pub fn is_shared_mem(&self, driver: &dyn MemoryReader) -> BoxResult<bool> {
+        let prototype_pte_bit: u64 = driver.decompose(&self.address, "_MMPFN.u4.PrototypePte")?;
         Ok(prototype_pte_bit != 0)
     }
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
-use crate::driver_state::DriverState;
+use crate::memory::{AddressSpace, MemoryReader};
 use super::paging_structs::*;
+use std::convert::TryInto;
 use std::error::Error;
 
 
@@ -9,24 +10,45 @@ type BoxResult<T> = Result<T, Box<dyn Error>>;
 // https://learn.microsoft.com/en-us/windows-hardware/drivers/gettingstarted/virtual-address-spaces
 const HIGHEST_USER_ADDRESS : u64 = 0x7FFFFFFFFFFF;
 static mut PTE_BASE : u64 = 0;
+const TABLE_SIZE: usize = 0x1000;
 
-pub fn startup(driver_state: &DriverState) {
+pub fn startup(driver_state: &dyn MemoryReader) {
 
 }
 
-pub fn list_all_pml4e(driver_state: &DriverState, cr3: u64) -> Vec<PTE> {   
+fn read_tables(driver_state: &dyn MemoryReader, tables: &[u64]) -> Vec<Vec<PTE>> {
+    /* Read every paging table at the physical addresses `tables` in one batch */
+    // Reading one entry at a time was the bottleneck, an unreadable entry is zero as before
+    let requests: Vec<(u64, usize)> = tables.iter().map(|&table| (table, TABLE_SIZE)).collect();
+    driver_state
+        .read_many(AddressSpace::Physical, &requests)
+        .into_iter()
+        .zip(tables)
+        .map(|(mut data, &table)| {
+            data.resize(TABLE_SIZE, 0);
+            data.chunks_exact(8)
+                .enumerate()
+                .map(|(index, entry)| {
+                    let value = u64::from_le_bytes(entry.try_into().unwrap());
+                    PTE::from_entry(driver_state, table | ((index as u64) << 3), value)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn list_all_pml4e(driver_state: &dyn MemoryReader, cr3: u64) -> Vec<PTE> {   
     /* Return a list of all presenting PML4 entries*/
     let mut pml4e_list : Vec<PTE> = Vec::new();
-    for index in 0..512 {
-        let vaddr = index << 39;
+    let table = read_tables(driver_state, &[cr3 & 0xffffffffff000]).remove(0);
+    for (index, new_entry) in table.into_iter().enumerate() {
+        let vaddr = (index as u64) << 39;
         
         // Only loop through usermode pages
         if vaddr > HIGHEST_USER_ADDRESS {
             break;
         }
 
-        let entry_addr = (cr3 & 0xffffffffff000) | (index  << 3);
-        let new_entry = PTE::from_addr(driver_state, entry_addr);
         if new_entry.is_present() {
             // println!("[*] PML4 entry number {:?}: {:?}", index, new_entry);
             pml4e_list.push(new_entry);
@@ -35,23 +57,20 @@ pub fn list_all_pml4e(driver_state: &DriverState, cr3: u64) -> Vec<PTE> {
     return pml4e_list;
 }
 
-pub fn list_all_pdpte(driver_state: &DriverState, cr3: u64) -> Vec<PTE> {
+pub fn list_all_pdpte(driver_state: &dyn MemoryReader, cr3: u64) -> Vec<PTE> {
     /* Return a list of all presenting PDPTE */
 
     let pml4e_list = list_all_pml4e(driver_state, cr3);
+    // We don't need to check against HIGHEST_USER_ADDRESS here
+    // Since HIGHEST_USER_ADDRESS is paged align, if the top of the page is in userland then the whole page will be too
+    // The check for if the top of the page is in userland is already in list_pml4e
+    // ptenum still perform the check for some reason
+    let tables: Vec<u64> = pml4e_list.iter()
+        .map(|pml4e| pml4e.get_pfn(driver_state).unwrap() << 12)
+        .collect();
     let mut pdpte_list : Vec<PTE> = Vec::new();
-    for pml4e in pml4e_list {
-        for index in 0..512 {
-            // We don't need to check against HIGHEST_USER_ADDRESS here
-            // Since HIGHEST_USER_ADDRESS is paged align, if the top of the page is in userland then the whole page will be too
-            // The check for if the top of the page is in userland is already in list_pml4e
-            // ptenum still perform the check for some reason
-
-            // TODO: Read the whole PDPT instead of each entry one by one (IO is slow!)
-
-            // println!("Deref address {:x}", (pml4e.pfn.value() << 12) | (index << 3));
-            let entry_addr = (pml4e.get_pfn(driver_state).unwrap() << 12) | (index << 3);
-            let new_entry = PTE::from_addr(driver_state, entry_addr);
+    for table in read_tables(driver_state, &tables) {
+        for new_entry in table {
             if new_entry.is_present() {
                 // println!("[*] PDPT entry number {:?}: {:?}", index, new_entry);
                 pdpte_list.push(new_entry);
@@ -61,22 +80,24 @@ pub fn list_all_pdpte(driver_state: &DriverState, cr3: u64) -> Vec<PTE> {
     return pdpte_list;
 }
 
-pub fn list_all_pde(driver_state: &DriverState, cr3: u64) -> Vec<PTE> {
+pub fn list_all_pde(driver_state: &dyn MemoryReader, cr3: u64) -> Vec<PTE> {
     /* Return a list of all presenting PDE*/
     // Handle both PDE and PDPTE for large pages (1gb pages)
 
     let pdpte_list = list_all_pdpte(driver_state, cr3);
     let mut pde_list : Vec<PTE> = Vec::new();
+    let mut tables: Vec<u64> = Vec::new();
     for pdpte in pdpte_list {
         if pdpte.is_large_page(driver_state).unwrap_or(false) {
             // Return this value so it can be handled along with all other normal pages
             pde_list.push(pdpte);
             continue;
         }
+        tables.push(pdpte.get_pfn(driver_state).unwrap() << 12);
+    }
 
-        for index in 0..512 {
-            let entry_addr = (pdpte.get_pfn(driver_state).unwrap() << 12) | (index << 3);
-            let new_entry = PTE::from_addr(driver_state, entry_addr);
+    for table in read_tables(driver_state, &tables) {
+        for new_entry in table {
             if new_entry.is_present() {
                 // println!("[*] PDE entry number {:?}: {:?}", index, new_entry);
                 pde_list.push(new_entry);
@@ -86,9 +107,10 @@ pub fn list_all_pde(driver_state: &DriverState, cr3: u64) -> Vec<PTE> {
     return pde_list;
 }
 
-pub fn list_all_pte(driver_state: &DriverState, cr3: u64) -> Vec<PTE>{
+pub fn list_all_pte(driver_state: &dyn MemoryReader, cr3: u64) -> Vec<PTE>{
     let pde_list = list_all_pde(driver_state, cr3);
     let mut pte_list: Vec<PTE> = Vec::new();
+    let mut tables: Vec<u64> = Vec::new();
     for pde in pde_list {
         if pde.is_large_page(driver_state).unwrap_or(false) {
             // Return this value so it can be handled along with all other normal pages
@@ -96,14 +118,12 @@ pub fn list_all_pte(driver_state: &DriverState, cr3: u64) -> Vec<PTE>{
             pte_list.push(pde);
             continue;
         }
+        tables.push(pde.get_pfn(driver_state).unwrap() << 12);
+    }
 
-        for index in 0..512 {
-            let entry_addr = (pde.get_pfn(driver_state).unwrap() << 12) | (index << 3);
-            let new_entry = PTE::from_addr(driver_state, entry_addr);
-            // println!("[*] PTE entry number {:?}: {:?}", index, data);
-            // pte_list.push((data, pte_addr));
-            pte_list.push(new_entry);
-        }
+    for table in read_tables(driver_state, &tables) {
+        // println!("[*] PTE entry number {:?}: {:?}", index, data);
+        pte_list.extend(table);
     }
     return pte_list;
 }
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+
+use crate::pdb_store::ModuleSymbols;
+use crate::symbol_index::{IndexedSymbol, SymbolIndex};
+use crate::type_info::{EnumInfo, StructInfo, TypeInfo};
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// What parse_module_pdb() extracts from a PDB, saved next to it so the type stream is
+// walked once per PDB. A cache file is the magic, the version, the GUID and age of the
+// PDB, a table of every string, then the symbols, the index, the structs and the enums
+// with strings as indexes into the table
+
+const CACHE_MAGIC: &[u8; 8] = b"LPUSSYMC";
+// Bump when the format changes or parse_module_pdb() extracts something else,
+// older caches are then parsed again from their PDB
+const CACHE_VERSION: u32 = 2;
+const CACHE_EXTENSION: &str = "lpuscache";
+
+fn truncated() -> Box<dyn Error> {
+    "Truncated symbol cache".into()
+}
+
+pub fn cache_path(pdb_path: &Path, guid: &str, age: u32) -> PathBuf {
+    // ntkrnlmp.pdb -> ntkrnlmp.<GUID><AGE>.lpuscache, a PDB replaced in a plain
+    // directory does not pick up the cache of the one before
+    pdb_path.with_extension(format!("{}{:X}.{}", guid, age, CACHE_EXTENSION))
+}
+
+// Strings are written once, the tables refer to them by index
+#[derive(Default)]
+struct CacheWriter {
+    strings: Vec<String>,
+    string_ids: HashMap<String, u32>,
+    body: Vec<u8>,
+}
+
+impl CacheWriter {
+    fn u8(&mut self, v: u8) {
+        self.body.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.body.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.body.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        let id = match self.string_ids.get(s) {
+            Some(id) => *id,
+            None => {
+                let id = self.strings.len() as u32;
+                self.strings.push(s.to_string());
+                self.string_ids.insert(s.to_string(), id);
+                id
+            }
+        };
+        self.u32(id);
+    }
+
+    fn type_info(&mut self, t: &TypeInfo) {
+        match t {
+            TypeInfo::Primitive { name, size } => {
+                self.u8(0);
+                self.string(name);
+                self.u64(*size);
+            }
+            TypeInfo::Pointer(pointee) => {
+                self.u8(1);
+                self.type_info(pointee);
+            }
+            TypeInfo::Array { element, count } => {
+                self.u8(2);
+                self.type_info(element);
+                self.u64(*count);
+            }
+            TypeInfo::Bitfield {
+                underlying,
+                position,
+                length,
+            } => {
+                self.u8(3);
+                self.type_info(underlying);
+                self.u64(*position);
+                self.u64(*length);
+            }
+            TypeInfo::Struct { name, size } => {
+                self.u8(4);
+                self.string(name);
+                self.u64(*size);
+            }
+            TypeInfo::Union { name, size } => {
+                self.u8(5);
+                self.string(name);
+                self.u64(*size);
+            }
+            TypeInfo::Enum { name, underlying } => {
+                self.u8(6);
+                self.string(name);
+                self.type_info(underlying);
+            }
+            TypeInfo::Function {
+                return_type,
+                arguments,
+            } => {
+                self.u8(7);
+                self.type_info(return_type);
+                self.u32(arguments.len() as u32);
+                for argument in arguments {
+                    self.type_info(argument);
+                }
+            }
+            TypeInfo::Unknown(name) => {
+                self.u8(8);
+                self.string(name);
+            }
+        }
+    }
+
+    fn module(&mut self, module: &ModuleSymbols) {
+        self.u32(module.symbols.len() as u32);
+        for (name, rva) in &module.symbols {
+            self.string(name);
+            self.u64(*rva);
+        }
+        self.u32(module.index.len() as u32);
+        for entry in module.index.entries() {
+            self.string(&entry.name);
+            self.u64(entry.rva);
+            // u64::MAX for a public symbol without a length
+            self.u64(entry.len.unwrap_or(u64::MAX));
+        }
+        self.u32(module.structs.len() as u32);
+        for (name, info) in &module.structs {
+            self.string(name);
+            self.u64(info.size);
+            self.u8(info.is_union as u8);
+            self.u32(info.members.len() as u32);
+            for (member, (t, offset)) in &info.members {
+                self.string(member);
+                self.type_info(t);
+                self.u64(*offset);
+            }
+        }
+        self.u32(module.enums.len() as u32);
+        for (name, info) in &module.enums {
+            self.string(name);
+            self.type_info(&info.underlying);
+            self.u32(info.values.len() as u32);
+            for (enumerator, value) in &info.values {
+                self.string(enumerator);
+                self.u64(*value as u64);
+            }
+        }
+    }
+
+    fn finish(self, guid: &str, age: u32) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.body.len() + self.strings.len() * 24);
+        out.extend_from_slice(CACHE_MAGIC);
+        out.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+        write_raw_string(&mut out, guid);
+        out.extend_from_slice(&age.to_le_bytes());
+        out.extend_from_slice(&(self.strings.len() as u32).to_le_bytes());
+        for s in &self.strings {
+            write_raw_string(&mut out, s);
+        }
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+fn write_raw_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct CacheReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    strings: Vec<String>,
+}
+
+impl<'a> CacheReader<'a> {
+    fn bytes(&mut self, len: usize) -> BoxResult<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or_else(truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or_else(truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> BoxResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> BoxResult<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> BoxResult<u64> {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    fn raw_string(&mut self) -> BoxResult<String> {
+        let len = self.u32()? as usize;
+        Ok(std::str::from_utf8(self.bytes(len)?)?.to_string())
+    }
+
+    fn string(&mut self) -> BoxResult<String> {
+        let id = self.u32()? as usize;
+        self.strings
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("Bad string {} in the symbol cache", id).into())
+    }
+
+    fn type_info(&mut self) -> BoxResult<TypeInfo> {
+        Ok(match self.u8()? {
+            0 => TypeInfo::Primitive {
+                name: self.string()?,
+                size: self.u64()?,
+            },
+            1 => TypeInfo::Pointer(Box::new(self.type_info()?)),
+            2 => TypeInfo::Array {
+                element: Box::new(self.type_info()?),
+                count: self.u64()?,
+            },
+            3 => TypeInfo::Bitfield {
+                underlying: Box::new(self.type_info()?),
+                position: self.u64()?,
+                length: self.u64()?,
+            },
+            4 => TypeInfo::Struct {
+                name: self.string()?,
+                size: self.u64()?,
+            },
+            5 => TypeInfo::Union {
+                name: self.string()?,
+                size: self.u64()?,
+            },
+            6 => TypeInfo::Enum {
+                name: self.string()?,
+                underlying: Box::new(self.type_info()?),
+            },
+            7 => {
+                let return_type = Box::new(self.type_info()?);
+                let count = self.u32()?;
+                let mut arguments = Vec::new();
+                for _ in 0..count {
+                    arguments.push(self.type_info()?);
+                }
+                TypeInfo::Function {
+                    return_type,
+                    arguments,
+                }
+            }
+            8 => TypeInfo::Unknown(self.string()?),
+            tag => return Err(format!("Bad type tag {} in the symbol cache", tag).into()),
+        })
+    }
+
+    fn module(&mut self, guid: String, age: u32) -> BoxResult<ModuleSymbols> {
+        let mut symbols = HashMap::new();
+        for _ in 0..self.u32()? {
+            let name = self.string()?;
+            symbols.insert(name, self.u64()?);
+        }
+        let mut entries = Vec::new();
+        for _ in 0..self.u32()? {
+            let name = self.string()?;
+            let rva = self.u64()?;
+            let len = Some(self.u64()?).filter(|&len| len != u64::MAX);
+            entries.push(IndexedSymbol { rva, len, name });
+        }
+        let mut structs = HashMap::new();
+        for _ in 0..self.u32()? {
+            let name = self.string()?;
+            let mut info = StructInfo::new(self.u64()?, self.u8()? != 0);
+            for _ in 0..self.u32()? {
+                let member = self.string()?;
+                let t = self.type_info()?;
+                info.add_member(&member, t, self.u64()?);
+            }
+            structs.insert(name, info);
+        }
+        let mut enums = HashMap::new();
+        for _ in 0..self.u32()? {
+            let name = self.string()?;
+            let mut info = EnumInfo::new(self.type_info()?);
+            for _ in 0..self.u32()? {
+                let enumerator = self.string()?;
+                info.values.push((enumerator, self.u64()? as i64));
+            }
+            enums.insert(name, info);
+        }
+        Ok(ModuleSymbols {
+            symbols,
+            index: SymbolIndex::from_entries(entries),
+            structs,
+            enums,
+            guid,
+            age,
+        })
+    }
+}
+
+pub fn load_symbol_cache(path: &Path, guid: &str, age: u32) -> BoxResult<ModuleSymbols> {
+    // The symbols of the PDB with `guid` and `age`, an error for a cache of another
+    // PDB or another version
+    let file = File::open(path)?;
+    // Safety: caches are written to a temporary file and renamed into place, never
+    // changed in place
+    let map = unsafe { Mmap::map(&file)? };
+    let mut reader = CacheReader {
+        data: &map,
+        pos: 0,
+        strings: Vec::new(),
+    };
+    if reader.bytes(CACHE_MAGIC.len())? != CACHE_MAGIC {
+        return Err("Not an lpus symbol cache".into());
+    }
+    let version = reader.u32()?;
+    if version != CACHE_VERSION {
+        return Err(format!("Symbol cache version {}, not {}", version, CACHE_VERSION).into());
+    }
+    let (cached_guid, cached_age) = (reader.raw_string()?, reader.u32()?);
+    if cached_guid != guid || cached_age != age {
+        return Err(format!(
+            "Symbol cache is for {}{:X}, not {}{:X}",
+            cached_guid, cached_age, guid, age
+        )
+        .into());
+    }
+    let mut strings = Vec::new();
+    for _ in 0..reader.u32()? {
+        strings.push(reader.raw_string()?);
+    }
+    reader.strings = strings;
+    reader.module(cached_guid, cached_age)
+}
+
+pub fn save_symbol_cache(path: &Path, module: &ModuleSymbols) -> BoxResult<()> {
+    // Written whole then renamed, a run reading the cache meanwhile sees the old one
+    let mut writer = CacheWriter::default();
+    writer.module(module);
+    // one temporary file per process, two runs saving the same cache do not mix writes
+    let tmp = path.with_extension(format!("{}.{}.tmp", CACHE_EXTENSION, std::process::id()));
+    fs::write(&tmp, writer.finish(&module.guid, module.age))?;
+    fs::rename(&tmp, path).map_err(|e| {
+        fs::remove_file(&tmp).ok();
+        e.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isf::parse_isf_file;
+
+    const GUID: &str = "3844DBB920174967BE7AA4A2C20430FA";
+
+    fn module() -> ModuleSymbols {
+        let isf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/win7_isf.json");
+        let store = parse_isf_file(&isf).unwrap();
+        let functions = vec![("KiSystemCall64".to_string(), 0x1000, 0x80)];
+        ModuleSymbols {
+            index: SymbolIndex::new(&store.symbols, functions),
+            symbols: store.symbols,
+            structs: store.structs,
+            enums: store.enums,
+            guid: GUID.to_string(),
+            age: 2,
+        }
+    }
+
+    fn temp_cache(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "lpus-{}-{}.{}",
+            name,
+            std::process::id(),
+            CACHE_EXTENSION
+        ))
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_cache("round-trip");
+        let saved = module();
+        save_symbol_cache(&path, &saved).unwrap();
+        let loaded = load_symbol_cache(&path, GUID, 2).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(!saved.structs.is_empty() && !saved.enums.is_empty());
+        assert_eq!(loaded.symbols, saved.symbols);
+        assert_eq!(loaded.index, saved.index);
+        assert_eq!(loaded.structs, saved.structs);
+        assert_eq!(loaded.enums, saved.enums);
+        assert_eq!((loaded.guid, loaded.age), (saved.guid, saved.age));
+    }
+
+    #[test]
+    fn mismatch() {
+        let path = temp_cache("mismatch");
+        save_symbol_cache(&path, &module()).unwrap();
+        assert!(load_symbol_cache(&path, GUID, 2).is_ok());
+        assert!(load_symbol_cache(&path, GUID, 3).is_err());
+        assert!(load_symbol_cache(&path, "00000000000000000000000000000000", 2).is_err());
+
+        // a cache of an older format
+        let mut data = fs::read(&path).unwrap();
+        let version = CACHE_MAGIC.len();
+        data[version..version + 4].copy_from_slice(&(CACHE_VERSION - 1).to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        let result = load_symbol_cache(&path, GUID, 2);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashSet;
+
+use crate::pdb_store::SymbolStore;
+
+// A function with its length from the module streams of a private PDB,
+// or a public symbol that only has a start
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedSymbol {
+    pub rva: u64,
+    pub len: Option<u64>,
+    pub name: String,
+}
+
+// Symbols sorted by RVA for address -> Symbol+offset lookups
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolIndex {
+    entries: Vec<IndexedSymbol>,
+}
+
+impl SymbolIndex {
+    pub fn new(symbols: &SymbolStore, functions: Vec<(String, u64, u64)>) -> Self {
+        // `functions` are (name, rva, length), a public symbol at the start of one is
+        // the same function and is left out
+        let starts: HashSet<u64> = functions.iter().map(|(_, rva, _)| *rva).collect();
+        let mut entries: Vec<IndexedSymbol> = functions
+            .into_iter()
+            .map(|(name, rva, len)| IndexedSymbol {
+                rva,
+                len: Some(len),
+                name,
+            })
+            .collect();
+        entries.extend(symbols.iter().filter(|(_, rva)| !starts.contains(rva)).map(
+            |(name, rva)| IndexedSymbol {
+                rva: *rva,
+                len: None,
+                name: name.to_string(),
+            },
+        ));
+        // by name as well, two names at one address always pick the same one
+        entries.sort_by(|a, b| (a.rva, &a.name).cmp(&(b.rva, &b.name)));
+        Self { entries }
+    }
+
+    pub(crate) fn from_entries(entries: Vec<IndexedSymbol>) -> Self {
+        // Entries in the order entries() gives them, e.g. from the symbol cache
+        Self { entries }
+    }
+
+    pub fn entries(&self) -> &[IndexedSymbol] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn lookup(&self, rva: u64) -> Option<(&str, u64)> {
+        // The symbol at or before `rva` and the offset into it
+        // Past the end of a function with a length is not in it: padding or data
+        let after = self.entries.partition_point(|e| e.rva <= rva);
+        let symbol = self.entries[..after].last()?;
+        let offset = rva - symbol.rva;
+        match symbol.len {
+            Some(len) if offset >= len && offset > 0 => None,
+            _ => Some((symbol.name.as_str(), offset)),
+        }
+    }
+
+    pub fn symbolize(&self, rva: u64) -> Option<String> {
+        // "Symbol" or "Symbol+0x1f"
+        self.lookup(rva).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+0x{:x}", name, offset),
+        })
+    }
+}
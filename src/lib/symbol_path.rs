@@ -0,0 +1,300 @@
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use app_dirs::{app_dir, AppDataType};
+use pdb::PDB;
+
+use crate::error::LpusError;
+use crate::pdb_store::{download_pdb, PDB_SERVER_PATH};
+use crate::APP_INFO;
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
+
+// One element of a symbol path, `srv*a*b*http://...`, `cache*a` or a plain directory
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolPathEntry {
+    // A folder holding name.pdb directly or in the name.pdb/GUIDAGE/name.pdb layout
+    Directory(PathBuf),
+    // Downstream stores searched in order, a miss is downloaded from `url` into the first one
+    Server {
+        stores: Vec<PathBuf>,
+        url: Option<String>,
+    },
+}
+
+// A symbol path in the _NT_SYMBOL_PATH syntax used by WinDbg and DbgHelp
+// https://docs.microsoft.com/en-us/windows-hardware/drivers/debugger/advanced-symsrv-use
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolPath {
+    pub entries: Vec<SymbolPathEntry>,
+}
+
+fn is_url(s: &str) -> bool {
+    let lower = s.to_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+pub fn default_store() -> BoxResult<PathBuf> {
+    // %APPDATA%\nganhkhoa\lpus\symbols, in the symbol server layout
+    Ok(app_dir(AppDataType::UserData, &APP_INFO, "symbols")?)
+}
+
+// ntkrnlmp.pdb/GUIDAGE/ntkrnlmp.pdb, the age in hex as on the symbol server
+pub fn store_relative_path(pdbname: &str, guid: &str, age: u32) -> PathBuf {
+    let mut path = PathBuf::from(pdbname);
+    path.push(format!("{}{:X}", guid, age));
+    path.push(pdbname);
+    path
+}
+
+fn pdb_matches(path: &Path, guid: &str, age: u32) -> bool {
+    // A PDB in a plain directory can be from any build, check it before use
+    let matches = || -> BoxResult<bool> {
+        let mut pdb = PDB::open(File::open(path)?)?;
+        let info = pdb.pdb_information()?;
+        let dbi = pdb.debug_information()?;
+        let file_guid = info.guid.to_string().replace("-", "").to_uppercase();
+        Ok(file_guid == guid && dbi.age().unwrap_or(info.age) == age)
+    };
+    path.is_file() && matches().unwrap_or(false)
+}
+
+impl SymbolPath {
+    pub fn parse(symbol_path: &str) -> BoxResult<Self> {
+        let mut entries = Vec::new();
+        // A `cache*dir` element also keeps what the servers after it return
+        let mut caches: Vec<PathBuf> = Vec::new();
+        for element in symbol_path
+            .split(';')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let parts: Vec<&str> = element.split('*').collect();
+            match parts[0].to_lowercase().as_str() {
+                "cache" => {
+                    let store = match parts.get(1) {
+                        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+                        _ => default_store()?,
+                    };
+                    caches.push(store.clone());
+                    entries.push(SymbolPathEntry::Server {
+                        stores: vec![store],
+                        url: None,
+                    });
+                }
+                kind @ "srv" | kind @ "symsrv" => {
+                    // symsrv*symsrv.dll*store*url names the dll first
+                    let skip = if kind == "symsrv" { 2 } else { 1 };
+                    let mut rest: Vec<&str> = parts.iter().skip(skip).cloned().collect();
+                    let url = match rest.last() {
+                        Some(last) if is_url(last) => rest.pop().map(str::to_string),
+                        _ => None,
+                    };
+                    let mut stores = caches.clone();
+                    for store in rest {
+                        // srv**url uses the default downstream store
+                        if store.is_empty() {
+                            stores.push(default_store()?);
+                        } else {
+                            stores.push(PathBuf::from(store));
+                        }
+                    }
+                    if stores.is_empty() && url.is_some() {
+                        stores.push(default_store()?);
+                    }
+                    entries.push(SymbolPathEntry::Server { stores, url });
+                }
+                _ => entries.push(SymbolPathEntry::Directory(PathBuf::from(element))),
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn from_env() -> Option<BoxResult<Self>> {
+        // _NT_SYMBOL_PATH, or None when it is not set
+        match env::var("_NT_SYMBOL_PATH") {
+            Ok(value) if !value.trim().is_empty() => Some(Self::parse(&value)),
+            _ => None,
+        }
+    }
+
+    pub fn microsoft() -> BoxResult<Self> {
+        // srv*<default store>*http://msdl.microsoft.com/download/symbols
+        Ok(Self {
+            entries: vec![SymbolPathEntry::Server {
+                stores: vec![default_store()?],
+                url: Some(PDB_SERVER_PATH.to_string()),
+            }],
+        })
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.entries.iter().all(|entry| match entry {
+            SymbolPathEntry::Server { url, .. } => url.is_none(),
+            SymbolPathEntry::Directory(_) => true,
+        })
+    }
+
+    fn find_local(&self, pdbname: &str, guid: &str, age: u32) -> Option<PathBuf> {
+        let relative = store_relative_path(pdbname, guid, age);
+        for entry in &self.entries {
+            match entry {
+                SymbolPathEntry::Directory(dir) => {
+                    let in_store = dir.join(&relative);
+                    if in_store.is_file() {
+                        return Some(in_store);
+                    }
+                    let flat = dir.join(pdbname);
+                    if pdb_matches(&flat, guid, age) {
+                        return Some(flat);
+                    }
+                }
+                SymbolPathEntry::Server { stores, .. } => {
+                    if let Some(found) = stores
+                        .iter()
+                        .map(|store| store.join(&relative))
+                        .find(|path| path.is_file())
+                    {
+                        return Some(found);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    pub fn find_pdb(&self, pdbname: &str, guid: &str, age: u32) -> BoxResult<PathBuf> {
+        // Every local copy is tried before anything is downloaded
+        if let Some(found) = self.find_local(pdbname, guid, age) {
+            return Ok(found);
+        }
+
+        let relative = store_relative_path(pdbname, guid, age);
+        let mut last_error: Option<Box<dyn Error>> = None;
+        for entry in &self.entries {
+            if let SymbolPathEntry::Server {
+                stores,
+                url: Some(url),
+            } = entry
+            {
+                let outfile = stores[0].join(&relative);
+                if let Some(dir) = outfile.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                println!("PDB not found, download into {:?}", outfile);
+                match download_pdb(url, pdbname, guid, age, &outfile) {
+                    Ok(()) => return Ok(outfile),
+                    Err(e) => {
+                        // do not leave a partial file for the next lookup to find
+                        fs::remove_file(&outfile).ok();
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            LpusError::PdbNotFound {
+                name: pdbname.to_string(),
+                guid: guid.to_string(),
+                age,
+            }
+            .into()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://msdl.microsoft.com/download/symbols";
+
+    fn server(stores: &[&str], url: Option<&str>) -> SymbolPathEntry {
+        SymbolPathEntry::Server {
+            stores: stores.iter().map(PathBuf::from).collect(),
+            url: url.map(str::to_string),
+        }
+    }
+
+    fn parse(symbol_path: &str) -> Vec<SymbolPathEntry> {
+        SymbolPath::parse(symbol_path).unwrap().entries
+    }
+
+    #[test]
+    fn server_stores() {
+        let path = format!("srv*C:\\symbols*{}", URL);
+        assert_eq!(parse(&path), vec![server(&["C:\\symbols"], Some(URL))]);
+
+        // two downstream stores, the first one gets the downloads
+        let path = format!("SRV*C:\\local*\\\\share\\symbols*{}", URL);
+        let stores = ["C:\\local", "\\\\share\\symbols"];
+        assert_eq!(parse(&path), vec![server(&stores, Some(URL))]);
+
+        let path = format!("symsrv*symsrv.dll*C:\\symbols*{}", URL);
+        assert_eq!(parse(&path), vec![server(&["C:\\symbols"], Some(URL))]);
+
+        // a store without a server
+        assert_eq!(
+            parse("srv*C:\\symbols"),
+            vec![server(&["C:\\symbols"], None)]
+        );
+    }
+
+    #[test]
+    fn default_store_for_empty() {
+        let default = default_store().unwrap();
+        let default = default.to_str().unwrap();
+        assert_eq!(
+            parse(&format!("srv**{}", URL)),
+            vec![server(&[default], Some(URL))]
+        );
+        assert_eq!(
+            parse(&format!("srv*{}", URL)),
+            vec![server(&[default], Some(URL))]
+        );
+        assert_eq!(parse("cache*"), vec![server(&[default], None)]);
+    }
+
+    #[test]
+    fn cache_then_server() {
+        let path = format!("cache*C:\\cache;srv*C:\\symbols*{}", URL);
+        assert_eq!(
+            parse(&path),
+            vec![
+                server(&["C:\\cache"], None),
+                server(&["C:\\cache", "C:\\symbols"], Some(URL)),
+            ]
+        );
+        // a server before the cache does not use it
+        let path = format!("srv*C:\\symbols*{};cache*C:\\cache", URL);
+        assert_eq!(
+            parse(&path),
+            vec![
+                server(&["C:\\symbols"], Some(URL)),
+                server(&["C:\\cache"], None),
+            ]
+        );
+    }
+
+    #[test]
+    fn directories() {
+        let path = format!(" C:\\pdbs ; ;D:\\build\\out;srv*C:\\symbols*{}", URL);
+        let symbol_path = SymbolPath::parse(&path).unwrap();
+        assert_eq!(
+            symbol_path.entries,
+            vec![
+                SymbolPathEntry::Directory(PathBuf::from("C:\\pdbs")),
+                SymbolPathEntry::Directory(PathBuf::from("D:\\build\\out")),
+                server(&["C:\\symbols"], Some(URL)),
+            ]
+        );
+        assert!(!symbol_path.is_offline());
+        assert!(SymbolPath::parse("C:\\pdbs;cache*C:\\cache")
+            .unwrap()
+            .is_offline());
+        assert!(parse("").is_empty());
+    }
+}
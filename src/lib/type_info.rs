@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::fmt;
+
+pub const POINTER_SIZE: u64 = 8;
+
+pub fn primitive_size(kind: &str) -> Option<u64> {
+    // Sizes of the pdb PrimitiveKind names, as Debug prints them
+    match kind {
+        "Void" => Some(0),
+        "RChar" | "Char" | "UChar" | "I8" | "U8" | "Bool8" => Some(1),
+        "WChar" | "RChar16" | "I16" | "U16" | "F16" | "Bool16" => Some(2),
+        "RChar32" | "I32" | "U32" | "HRESULT" | "F32" | "F32PP" | "Bool32" => Some(4),
+        "F48" => Some(6),
+        "I64" | "U64" | "F64" | "Complex32" | "Bool64" => Some(8),
+        "F80" => Some(10),
+        "I128" | "U128" | "F128" | "Complex64" => Some(16),
+        "Complex80" => Some(20),
+        "Complex128" => Some(32),
+        _ => None,
+    }
+}
+
+// The type of a struct member as the PDB describes it
+// const, volatile and unaligned are dropped, they do not change the layout
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeInfo {
+    // a pdb PrimitiveKind: U32, UChar, Void...
+    Primitive {
+        name: String,
+        size: u64,
+    },
+    Pointer(Box<TypeInfo>),
+    // float[2][3] is an array of 2 arrays of 3 floats
+    Array {
+        element: Box<TypeInfo>,
+        count: u64,
+    },
+    Bitfield {
+        underlying: Box<TypeInfo>,
+        position: u64,
+        length: u64,
+    },
+    // structs and unions are found by name in the StructStore
+    Struct {
+        name: String,
+        size: u64,
+    },
+    Union {
+        name: String,
+        size: u64,
+    },
+    Enum {
+        name: String,
+        underlying: Box<TypeInfo>,
+    },
+    // what a function pointer points to
+    Function {
+        return_type: Box<TypeInfo>,
+        arguments: Vec<TypeInfo>,
+    },
+    // anything else by its name, e.g. a C++ member function
+    Unknown(String),
+}
+
+impl TypeInfo {
+    pub fn primitive(name: &str) -> Self {
+        Self::Primitive {
+            name: name.to_string(),
+            size: primitive_size(name).unwrap_or(0),
+        }
+    }
+
+    pub fn pointer_to(pointee: TypeInfo) -> Self {
+        Self::Pointer(Box::new(pointee))
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::Primitive { size, .. } | Self::Struct { size, .. } | Self::Union { size, .. } => {
+                *size
+            }
+            Self::Pointer(_) => POINTER_SIZE,
+            Self::Array { element, count } => element.size() * count,
+            Self::Bitfield { underlying, .. } | Self::Enum { underlying, .. } => underlying.size(),
+            Self::Function { .. } | Self::Unknown(_) => 0,
+        }
+    }
+
+    pub fn pointee(&self) -> Option<&TypeInfo> {
+        match self {
+            Self::Pointer(pointee) => Some(pointee),
+            _ => None,
+        }
+    }
+
+    pub fn array_element(&self) -> Option<(&TypeInfo, u64)> {
+        // (element type, element count) of the outer dimension
+        match self {
+            Self::Array { element, count } => Some((element, *count)),
+            _ => None,
+        }
+    }
+
+    pub fn bitfield(&self) -> Option<(u64, u64)> {
+        // (bit position, bit length)
+        match self {
+            Self::Bitfield {
+                position, length, ..
+            } => Some((*position, *length)),
+            _ => None,
+        }
+    }
+
+    pub fn record_name(&self) -> Option<&str> {
+        // The StructStore key of a struct or a union
+        match self {
+            Self::Struct { name, .. } | Self::Union { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn is_scalar(&self) -> bool {
+        // Read as one integer: a primitive, a pointer or an enum
+        matches!(
+            self,
+            Self::Primitive { .. } | Self::Pointer(_) | Self::Enum { .. }
+        )
+    }
+}
+
+impl fmt::Display for TypeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Primitive { name, .. }
+            | Self::Struct { name, .. }
+            | Self::Union { name, .. }
+            | Self::Enum { name, .. }
+            | Self::Unknown(name) => write!(f, "{}", name),
+            Self::Pointer(pointee) => write!(f, "{}*", pointee),
+            Self::Array { .. } => {
+                // UChar[2][3], the outer dimension first as in C
+                let mut t = self;
+                let mut dims = String::new();
+                while let Some((element, count)) = t.array_element() {
+                    dims.push_str(&format!("[{}]", count));
+                    t = element;
+                }
+                write!(f, "{}{}", t, dims)
+            }
+            Self::Bitfield {
+                underlying,
+                position,
+                length,
+            } => write!(f, "{} Pos {}, {} Bit", underlying, position, length),
+            Self::Function {
+                return_type,
+                arguments,
+            } => {
+                let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", return_type, arguments.join(", "))
+            }
+        }
+    }
+}
+
+// A struct or a union with its members by name: (type, offset)
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructInfo {
+    pub size: u64,
+    pub is_union: bool,
+    pub members: HashMap<String, (TypeInfo, u64)>,
+}
+
+impl StructInfo {
+    pub fn new(size: u64, is_union: bool) -> Self {
+        Self {
+            size,
+            is_union,
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn add_member(&mut self, name: &str, member_type: TypeInfo, offset: u64) {
+        self.members.insert(name.to_string(), (member_type, offset));
+    }
+
+    pub fn sorted_members(&self) -> Vec<(&String, &TypeInfo, u64)> {
+        // By offset, bit position, then name for the members of a union
+        let mut members: Vec<(&String, &TypeInfo, u64)> = self
+            .members
+            .iter()
+            .map(|(name, (t, offset))| (name, t, *offset))
+            .collect();
+        let bit = |t: &TypeInfo| t.bitfield().map_or(0, |(position, _)| position);
+        members.sort_by(|a, b| (a.2, bit(a.1), a.0).cmp(&(b.2, bit(b.1), b.0)));
+        members
+    }
+}
+
+// An enum with its enumerators in PDB order
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnumInfo {
+    pub underlying: TypeInfo,
+    pub values: Vec<(String, i64)>,
+}
+
+impl EnumInfo {
+    pub fn new(underlying: TypeInfo) -> Self {
+        Self {
+            underlying,
+            values: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.underlying.size()
+    }
+
+    pub fn name_of(&self, value: u64) -> Option<&str> {
+        // `value` as read from memory, a negative enumerator matches in the enum's size
+        let bits = self.size() * 8;
+        let mask = if bits == 0 || bits >= 64 {
+            u64::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        self.values
+            .iter()
+            .find(|(_, v)| *v as u64 & mask == value & mask)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<i64> {
+        self.values.iter().find(|(n, _)| n == name).map(|(_, v)| *v)
+    }
+}
@@ -0,0 +1,52 @@
+// The Windows releases lpus tells apart, from the NtBuildNumber of the kernel
+// Kept out of windows.rs so the offline readers have it on any platform
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum WindowsVersion {
+    Windows7,
+    Windows8,
+    Windows10Legacy,
+    Windows10_2015,
+    Windows10_2016,
+    Windows10_2017,
+    Windows10_2018,
+    Windows10_2019,
+    Windows10_2020,
+    WindowsFastRing,
+    WindowsUnknown,
+}
+
+impl WindowsVersion {
+    pub fn not_supported(self) -> bool {
+        match self {
+            WindowsVersion::Windows10Legacy
+            | WindowsVersion::Windows10_2015
+            | WindowsVersion::Windows10_2016
+            | WindowsVersion::Windows10_2017
+            | WindowsVersion::Windows8
+            | WindowsVersion::WindowsUnknown => true,
+            _ => false,
+        }
+    }
+    pub fn is_supported(self) -> bool {
+        !self.not_supported()
+    }
+    pub fn from_build_number(build_number: u32) -> Self {
+        match build_number {
+            // 2600 => WindowsVersion::WindowsXP,
+            // 6000 | 6001 | 6002 => WindowsVersion::WindowsVista,
+            7600 | 7601 => WindowsVersion::Windows7,
+            9200 | 9600 => WindowsVersion::Windows8,
+            10240 => WindowsVersion::Windows10Legacy,
+            10586 => WindowsVersion::Windows10_2015,
+            14393 => WindowsVersion::Windows10_2016,
+            15063 | 16299 => WindowsVersion::Windows10_2017,
+            17134 | 17763 => WindowsVersion::Windows10_2018,
+            18363 | 18362 => WindowsVersion::Windows10_2019,
+            19041 => WindowsVersion::Windows10_2020,
+            x if x >= 19536 => WindowsVersion::WindowsFastRing,
+            _ => WindowsVersion::WindowsUnknown,
+        }
+    }
+}
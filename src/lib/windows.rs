@@ -1,15 +1,24 @@
+use std::error::Error;
 use std::ffi::{c_void, CString};
-use std::mem::{size_of_val, transmute};
+use std::mem::{size_of_val, transmute, zeroed};
+use std::path::Path;
 use std::ptr::null_mut;
+use std::slice;
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use app_dirs::{app_dir, AppDataType};
 use widestring::U16CString;
 
+use crate::error::{LpusError, LpusResult};
+use crate::ioctl_session::{IoctlDevice, IoctlRecorder, SessionInfo};
+use crate::memory::process_time_in_range;
+pub use crate::version::WindowsVersion;
 use crate::APP_INFO;
 
-use winapi::shared::minwindef::{DWORD, HKEY, HMODULE};
+use winapi::shared::minwindef::{DWORD, HKEY, HMODULE, TRUE};
 use winapi::shared::ntdef::*;
+use winapi::shared::winerror::ERROR_IO_PENDING;
 use winapi::um::winnt::{
     FILE_ATTRIBUTE_NORMAL, GENERIC_READ, GENERIC_WRITE, KEY_WRITE, LUID_AND_ATTRIBUTES,
     OSVERSIONINFOW, PRTL_OSVERSIONINFOW, REG_DWORD, REG_OPTION_NON_VOLATILE, REG_SZ,
@@ -19,52 +28,53 @@ use winapi::um::winnt::{
 use winapi::um::errhandlingapi::GetLastError;
 use winapi::um::fileapi::{CreateFileA, CREATE_ALWAYS};
 use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
-use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::ioapiset::{DeviceIoControl, GetOverlappedResult};
 use winapi::um::libloaderapi::{GetProcAddress, LoadLibraryA};
+use winapi::um::minwinbase::OVERLAPPED;
 use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
 use winapi::um::securitybaseapi::AdjustTokenPrivileges;
+use winapi::um::synchapi::CreateEventA;
 use winapi::um::sysinfoapi::GetTickCount64;
-use winapi::um::winbase::LookupPrivilegeValueA;
-use winapi::um::winreg::{RegCloseKey, RegCreateKeyExA, RegSetValueExA, HKEY_LOCAL_MACHINE};
+use winapi::um::winbase::{LookupPrivilegeValueA, FILE_FLAG_OVERLAPPED};
+use winapi::um::winreg::{
+    RegCloseKey, RegCreateKeyExA, RegDeleteKeyA, RegSetValueExA, HKEY_LOCAL_MACHINE,
+};
+
+type BoxResult<T> = Result<T, Box<dyn Error>>;
 
 const STR_DRIVER_REGISTRY_PATH: &str =
     "\\Registry\\Machine\\System\\CurrentControlSet\\Services\\lpus";
+const STR_SERVICE_KEY: &str = "System\\CurrentControlSet\\Services\\lpus";
 
-#[allow(dead_code)]
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
-pub enum WindowsVersion {
-    Windows7,
-    Windows8,
-    Windows10Legacy,
-    Windows10_2015,
-    Windows10_2016,
-    Windows10_2017,
-    Windows10_2018,
-    Windows10_2019,
-    Windows10_2020,
-    WindowsFastRing,
-    WindowsUnknown,
+#[derive(Copy, Clone)]
+pub struct ServiceCleanup {
+    driver_handle: usize,
+    nt_unload_driver: extern "system" fn(PUNICODE_STRING) -> NTSTATUS,
+    rtl_init_unicode_str: extern "system" fn(PUNICODE_STRING, PCWSTR),
 }
 
-impl WindowsVersion {
-    pub fn not_supported(self) -> bool {
-        match self {
-            WindowsVersion::Windows10Legacy
-            | WindowsVersion::Windows10_2015
-            | WindowsVersion::Windows10_2016
-            | WindowsVersion::Windows10_2017
-            | WindowsVersion::Windows8
-            | WindowsVersion::WindowsUnknown => true,
-            _ => false,
+impl ServiceCleanup {
+    // Close the device, NtUnloadDriver and remove the Services\lpus key
+    pub fn run(&self) -> NTSTATUS {
+        let handle = self.driver_handle as HANDLE;
+        if handle != INVALID_HANDLE_VALUE {
+            unsafe {
+                CloseHandle(handle);
+            }
         }
-    }
-    pub fn is_supported(self) -> bool {
-        !self.not_supported()
+        let str_driver_reg = U16CString::from_str(STR_DRIVER_REGISTRY_PATH).unwrap();
+        let mut str_driver_reg_unicode = UNICODE_STRING::default();
+        (self.rtl_init_unicode_str)(&mut str_driver_reg_unicode, str_driver_reg.as_ptr());
+        let status = (self.nt_unload_driver)(&mut str_driver_reg_unicode);
+        let str_registry_path = CString::new(STR_SERVICE_KEY).unwrap();
+        unsafe {
+            RegDeleteKeyA(HKEY_LOCAL_MACHINE, str_registry_path.as_ptr());
+        }
+        status
     }
 }
 
 #[allow(dead_code)]
-#[derive(Copy, Clone)]
 pub struct WindowsFFI {
     pub version_info: OSVERSIONINFOW,
     pub short_version: WindowsVersion,
@@ -74,8 +84,15 @@ pub struct WindowsFFI {
     nt_unload_driver: extern "system" fn(PUNICODE_STRING) -> NTSTATUS,
     rtl_init_unicode_str: extern "system" fn(PUNICODE_STRING, PCWSTR),
     rtl_get_version: extern "system" fn(PRTL_OSVERSIONINFOW) -> NTSTATUS,
+    recorder: Option<Mutex<IoctlRecorder>>,
 }
 
+// The handles are plain kernel handles, usable from any thread. The device is opened for
+// overlapped I/O so IOCTLs from several threads are not serialized on the file object, and
+// the recorder is behind a Mutex
+unsafe impl Send for WindowsFFI {}
+unsafe impl Sync for WindowsFFI {}
+
 impl WindowsFFI {
     pub fn new() -> Self {
         let str_ntdll = CString::new("ntdll").unwrap();
@@ -83,23 +100,6 @@ impl WindowsFFI {
         let str_nt_unload_driver = CString::new("NtUnloadDriver").unwrap();
         let str_rtl_init_unicode_str = CString::new("RtlInitUnicodeString").unwrap();
         let str_rtl_get_version = CString::new("RtlGetVersion").unwrap();
-        let str_se_load_driver_privilege = CString::new("SeLoadDriverPrivilege").unwrap();
-        let str_driver_path = {
-            let mut driver_location =
-                app_dir(AppDataType::UserData, &APP_INFO, &format!("driver")).unwrap();
-            driver_location.push("lpus.sys");
-            if driver_location.is_file() {
-                let p = driver_location.to_str().unwrap();
-                CString::new(format!("\\??\\{}", p)).unwrap()
-            } else {
-                CString::new("\\SystemRoot\\System32\\DRIVERS\\lpus.sys").unwrap()
-            }
-        };
-        let str_registry_path = CString::new("System\\CurrentControlSet\\Services\\lpus").unwrap();
-        let str_type = CString::new("Type").unwrap();
-        let str_error_control = CString::new("ErrorControl").unwrap();
-        let str_start = CString::new("Start").unwrap();
-        let str_image_path = CString::new("ImagePath").unwrap();
 
         let mut version_info = OSVERSIONINFOW {
             dwOSVersionInfoSize: 0u32,
@@ -128,7 +128,45 @@ impl WindowsFFI {
             nt_unload_driver = transmute(nt_unload_driver_);
             rtl_init_unicode_str = transmute(rtl_init_unicode_str_);
             rtl_get_version = transmute(rtl_get_version_);
+        }
+
+        rtl_get_version(&mut version_info);
+
+        let short_version = WindowsVersion::from_build_number(version_info.dwBuildNumber);
+
+        Self {
+            version_info,
+            short_version,
+            driver_handle: INVALID_HANDLE_VALUE,
+            ntdll,
+            nt_load_driver,
+            nt_unload_driver,
+            rtl_init_unicode_str,
+            rtl_get_version,
+            recorder: None,
+        }
+    }
 
+    pub fn register_service(&self) {
+        // Create the Services\lpus key NtLoadDriver reads and enable SeLoadDriverPrivilege
+        let str_se_load_driver_privilege = CString::new("SeLoadDriverPrivilege").unwrap();
+        let str_driver_path = {
+            let mut driver_location = app_dir(AppDataType::UserData, &APP_INFO, "driver").unwrap();
+            driver_location.push("lpus.sys");
+            if driver_location.is_file() {
+                let p = driver_location.to_str().unwrap();
+                CString::new(format!("\\??\\{}", p)).unwrap()
+            } else {
+                CString::new("\\SystemRoot\\System32\\DRIVERS\\lpus.sys").unwrap()
+            }
+        };
+        let str_registry_path = CString::new(STR_SERVICE_KEY).unwrap();
+        let str_type = CString::new("Type").unwrap();
+        let str_error_control = CString::new("ErrorControl").unwrap();
+        let str_start = CString::new("Start").unwrap();
+        let str_image_path = CString::new("ImagePath").unwrap();
+
+        unsafe {
             // setup registry
             let mut registry_key: HKEY = null_mut();
             RegCreateKeyExA(
@@ -199,34 +237,12 @@ impl WindowsFFI {
             );
             CloseHandle(token_handle);
         }
+    }
 
-        rtl_get_version(&mut version_info);
-
-        let short_version = match version_info.dwBuildNumber {
-            // 2600 => WindowsVersion::WindowsXP,
-            // 6000 | 6001 | 6002 => WindowsVersion::WindowsVista,
-            7600 | 7601 => WindowsVersion::Windows7,
-            9200 | 9600 => WindowsVersion::Windows8,
-            10240 => WindowsVersion::Windows10Legacy,
-            10586 => WindowsVersion::Windows10_2015,
-            14393 => WindowsVersion::Windows10_2016,
-            15063 | 16299 => WindowsVersion::Windows10_2017,
-            17134 | 17763 => WindowsVersion::Windows10_2018,
-            18363 | 18362 => WindowsVersion::Windows10_2019,
-            19041 => WindowsVersion::Windows10_2020,
-            x if x >= 19536 => WindowsVersion::WindowsFastRing,
-            _ => WindowsVersion::WindowsUnknown,
-        };
-
-        Self {
-            version_info,
-            short_version,
-            driver_handle: INVALID_HANDLE_VALUE,
-            ntdll,
-            nt_load_driver,
-            nt_unload_driver,
-            rtl_init_unicode_str,
-            rtl_get_version,
+    pub fn unregister_service(&self) {
+        let str_registry_path = CString::new(STR_SERVICE_KEY).unwrap();
+        unsafe {
+            RegDeleteKeyA(HKEY_LOCAL_MACHINE, str_registry_path.as_ptr());
         }
     }
 
@@ -243,7 +259,7 @@ impl WindowsFFI {
                 0,
                 null_mut(),
                 CREATE_ALWAYS,
-                FILE_ATTRIBUTE_NORMAL,
+                FILE_ATTRIBUTE_NORMAL | FILE_FLAG_OVERLAPPED,
                 null_mut(),
             )
         };
@@ -277,6 +293,25 @@ impl WindowsFFI {
         (self.nt_unload_driver)(&mut str_driver_reg_unicode)
     }
 
+    pub fn close_driver_handle(&mut self) {
+        if self.driver_loaded() {
+            unsafe {
+                CloseHandle(self.driver_handle);
+            }
+            self.driver_handle = INVALID_HANDLE_VALUE;
+        }
+    }
+
+    // Everything needed to tear the driver down without borrowing self, so it can be moved
+    // into a Ctrl-C handler running on another thread
+    pub fn service_cleanup(&self) -> ServiceCleanup {
+        ServiceCleanup {
+            driver_handle: self.driver_handle as usize,
+            nt_unload_driver: self.nt_unload_driver,
+            rtl_init_unicode_str: self.rtl_init_unicode_str,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn get_build_number(&self) -> DWORD {
         self.version_info.dwBuildNumber
@@ -304,29 +339,55 @@ impl WindowsFFI {
 
     pub fn valid_process_time(&self, filetime: u64) -> bool {
         // https://www.frenk.com/2009/12/convert-filetime-to-unix-timestamp/
-        let windows_epoch_diff = 11644473600000 * 10000;
-        if filetime < windows_epoch_diff {
-            return false;
-        }
+        let (boot_time, now_ms) = self.boot_time();
+        process_time_in_range(filetime, boot_time, now_ms)
+    }
+
+    fn boot_time(&self) -> (u64, u64) {
+        // (boot time, now) in milliseconds since 1970
         let system_up_time_ms = unsafe { GetTickCount64() };
-        let process_time_epoch = (filetime - windows_epoch_diff) / 10000; // in milisecond
         let now_ms = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_millis() as u64;
-        let system_start_up_time_ms =
-            now_ms - system_up_time_ms - (10 * 3600 * 1000/* 10 minutes penalty */);
+        (now_ms - system_up_time_ms, now_ms)
+    }
 
-        if process_time_epoch < system_start_up_time_ms {
-            false
-        } else if process_time_epoch > now_ms {
-            false
-        } else {
-            true
+    pub fn start_recording(
+        &mut self,
+        path: &Path,
+        pdb_guid: &str,
+        pdb_age: u32,
+        cache_pages: usize,
+    ) -> BoxResult<()> {
+        // Record every device_io_raw() call from now on, see ioctl_session
+        let (boot_time, recorded_at) = self.boot_time();
+        let info = SessionInfo {
+            build_number: self.version_info.dwBuildNumber,
+            boot_time,
+            recorded_at,
+            pdb_guid: pdb_guid.to_string(),
+            pdb_age,
+            cache_pages,
+        };
+        self.recorder = Some(Mutex::new(IoctlRecorder::create(path, &info)?));
+        Ok(())
+    }
+
+    pub fn flush_recording(&self) {
+        if let Some(recorder) = &self.recorder {
+            if let Ok(mut recorder) = recorder.lock() {
+                recorder.flush().ok();
+            }
         }
     }
 
-    pub fn device_io<T, E>(&self, code: DWORD, inbuf: &mut T, outbuf: &mut E) -> DWORD {
+    pub fn device_io<T, E>(
+        &self,
+        code: DWORD,
+        inbuf: &mut T,
+        outbuf: &mut E,
+    ) -> LpusResult<DWORD> {
         self.device_io_raw(
             code,
             inbuf as *mut _ as *mut c_void,
@@ -343,11 +404,14 @@ impl WindowsFFI {
         input_len: DWORD,
         output_ptr: *mut c_void,
         output_len: DWORD,
-    ) -> DWORD {
+    ) -> LpusResult<DWORD> {
         // println!("driver loaded: {}; device_io_code: {}", self.driver_loaded(), code);
         let mut bytes_returned: DWORD = 0;
-        unsafe {
-            let status = DeviceIoControl(
+        let result = unsafe {
+            // Overlapped handle, wait on our own event so each thread gets its own completion
+            let mut overlapped: OVERLAPPED = zeroed();
+            overlapped.hEvent = CreateEventA(null_mut(), TRUE, 0, null_mut());
+            let mut status = DeviceIoControl(
                 self.driver_handle,
                 code,
                 input_ptr,
@@ -355,12 +419,51 @@ impl WindowsFFI {
                 output_ptr,
                 output_len,
                 &mut bytes_returned,
-                null_mut(),
+                &mut overlapped,
             );
+            if status == 0 && GetLastError() == ERROR_IO_PENDING {
+                status = GetOverlappedResult(
+                    self.driver_handle,
+                    &mut overlapped,
+                    &mut bytes_returned,
+                    TRUE,
+                );
+            }
+            let last_error = GetLastError();
+            if !overlapped.hEvent.is_null() {
+                CloseHandle(overlapped.hEvent);
+            }
             if status == 0 {
-                println!("device io failed: last error {}", GetLastError());
+                Err(LpusError::DeviceIo { code, last_error })
+            } else {
+                Ok(bytes_returned)
             }
         };
-        bytes_returned
+        if let Some(recorder) = &self.recorder {
+            let (input, output) = unsafe {
+                (
+                    slice::from_raw_parts(input_ptr as *const u8, input_len as usize),
+                    slice::from_raw_parts(output_ptr as *const u8, output_len as usize),
+                )
+            };
+            if let Ok(mut recorder) = recorder.lock() {
+                if let Err(e) = recorder.record(code, input, output, bytes_returned) {
+                    println!("Cannot record IOCTL: {}", e);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl IoctlDevice for WindowsFFI {
+    fn ioctl(&self, code: u32, input: &[u8], output: &mut [u8]) -> LpusResult<u32> {
+        self.device_io_raw(
+            code,
+            input.as_ptr() as *mut c_void,
+            input.len() as DWORD,
+            output.as_mut_ptr() as *mut c_void,
+            output.len() as DWORD,
+        )
     }
 }